@@ -1,3 +1,33 @@
 fn main() {
+    // The render cache keys embedded compiles on the Typst crates built into the binary.
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!(
+        "cargo:rustc-env=TIDEFLOW_TYPST_CRATES={}",
+        typst_crate_versions()
+    );
     tauri_build::build()
 }
+
+/// `typst 0.13.1, typst-kit 0.13.1, ...` for every Typst crate in Cargo.lock.
+fn typst_crate_versions() -> String {
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let mut versions = Vec::new();
+    let mut name = None;
+    for line in lock.lines() {
+        if let Some(value) = line.strip_prefix("name = ") {
+            name = Some(value.trim_matches('"').to_string());
+        } else if let Some(value) = line.strip_prefix("version = ") {
+            if let Some(name) = name
+                .take()
+                .filter(|name| name == "typst" || name.starts_with("typst-"))
+            {
+                versions.push(format!("{} {}", name, value.trim_matches('"')));
+            }
+        }
+    }
+    if versions.is_empty() {
+        "unknown".to_string()
+    } else {
+        versions.join(", ")
+    }
+}
@@ -22,21 +22,13 @@
 // exist in the scope when `render` is called.
 #let anchor = id => none
 
-#let tikz_render(asset: str, scale: auto) = {
-  let graphic = image(asset)
-  if scale == auto or type(scale) == str {
-    graphic
-  } else {
-    scale(x: scale, y: scale)[graphic]
-  }
-}
-
 #let admonition-colors = (
   "note": (fill: color.mix(accent-color, rgb(255, 255, 255)), stroke: accent-color),
   "info": (fill: rgb(224, 242, 254), stroke: rgb(186, 230, 253)),
   "tip": (fill: rgb(220, 252, 231), stroke: rgb(187, 247, 208)),
   "warning": (fill: rgb(254, 249, 195), stroke: rgb(253, 224, 71)),
-  "important": (fill: rgb(254, 243, 199), stroke: rgb(251, 191, 36))
+  "important": (fill: rgb(254, 243, 199), stroke: rgb(251, 191, 36)),
+  "caution": (fill: rgb(254, 226, 226), stroke: rgb(252, 165, 165))
 )
 
 #let admonition-titles = (
@@ -44,23 +36,30 @@
   "info": "Info",
   "tip": "Tip",
   "warning": "Warning",
-  "important": "Important"
+  "important": "Important",
+  "caution": "Caution"
 )
 
-#let admonition(kind: str, body) = {
+// Callout box. `title` replaces the kind's default heading; unknown kinds use the note palette.
+#let admonition(kind: str, title: none, body) = {
   let key = kind.lower()
   let palette = admonition-colors.at(key, default: admonition-colors.at("note"))
-  let title = admonition-titles.at(key, default: kind.upper())
+  let heading = if title != none {
+    title
+  } else {
+    admonition-titles.at(key, default: upper(kind.first()) + kind.slice(1))
+  }
   block(
     fill: palette.fill,
     stroke: 0.5pt + palette.stroke,
     inset: 10pt,
     radius: 8pt,
     spacing: 12pt,
+    width: 100%,
   )[
-    text(weight: 600)[#title]
-    v(4pt)
-    body
+    #text(weight: 600)[#heading]
+    #v(4pt, weak: true)
+    #body
   ]
 }
 
@@ -116,6 +115,24 @@
   }
 } else { it };
 
+// Rendered diagram fence. `width` takes the same units as <img width>, `align` is
+// "left", "center" or "right", and a caption turns the diagram into a figure.
+#let diagram_render(asset: str, scale: auto, width: none, align: "center", caption: none) = {
+  let graphic = if width != none {
+    builtin-image(asset, width: parse-length(width))
+  } else {
+    builtin-image(asset)
+  }
+  if type(scale) == int or type(scale) == float {
+    graphic = std.scale(x: scale * 100%, y: scale * 100%, reflow: true, graphic)
+  }
+  if caption != none {
+    graphic = figure(graphic, kind: image, caption: caption)
+  }
+  let position = (left: left, center: center, right: right).at(align, default: center)
+  std.align(position, graphic)
+}
+
 #let margin_x = parse-length(prefs.margin.x)
 #let margin_y = parse-length(prefs.margin.y)
 
@@ -197,6 +214,25 @@
 // Render markdown content with explicit outline suppression
 #show outline: none
 
+// Cross-references ({#fig:…}, {#tbl:…}, {#eq:…}, {#sec:…} and @fig:…). Numbering comes from
+// the preferences; references to headings still work when sections are not numbered.
+#let numbering-pref(key, fallback) = {
+  let value = prefs.at(key, default: fallback)
+  if type(value) == str and value.trim() != "" { value } else { fallback }
+}
+#show figure.where(kind: image): set figure(numbering: numbering-pref("figure_numbering", "1"))
+#show figure.where(kind: table): set figure(numbering: numbering-pref("table_numbering", "1"))
+#let equation-numbering = numbering-pref("equation_numbering", "(1)")
+#let crossref_equation(src, id) = [#mitex(src, numbering: equation-numbering) #label(id)]
+#show ref: it => {
+  let target = it.element
+  if target != none and target.func() == heading and target.numbering == none {
+    link(target.location(), target.body)
+  } else {
+    it
+  }
+}
+
 // Define fallback helpers at top-level so we don't need to pass them as
 // keyword arguments into #render (some cmarker versions reject unexpected
 // keyword arguments). These are safe defaults; the Rust preprocessor may
@@ -231,13 +267,30 @@
   scope: (
     // Override link to use our safe version
     link: safe-link,
-    tikz_render: tikz_render,
+    diagram_render: diagram_render,
+    crossref_equation: crossref_equation,
   ),
   // Note: cmarker 0.1.6 follows standard Markdown line break rules:
   // - Single newline = soft break (ignored in output)
   // - Two spaces + newline = hard break (<br>)
   // - Blank line = paragraph break
   html: (
+    // Table captions from the cross-reference pass. A line starting with <figcaption> would be
+    // an HTML block with its Markdown left unparsed, so they arrive as <tf-caption> and are
+    // tagged the way cmarker 0.1.6 tags <figcaption> for its <figure> handler.
+    "tf-caption": (attrs, body) => [#metadata(("<figcaption>", none))#body],
+    // Citations from `[@key]`, `-@key` and `@key`; only keys of the bibliography reach here.
+    "tf-cite": (attrs, body) => cite(
+      label(attrs.key),
+      form: attrs.at("form", default: "normal"),
+      supplement: attrs.at("supplement", default: none),
+    ),
+    // `> [!KIND] title` callouts from the admonition pass.
+    "tf-admonition": (attrs, body) => admonition(
+      kind: attrs.at("kind", default: "note"),
+      title: attrs.at("title", default: none),
+      body,
+    ),
     // Handle <img src width data-align> so we can control size and alignment
     img: ("void", attrs => {
       // Use safe dictionary access for HTML attributes
@@ -259,3 +312,11 @@
     })
   )
 )
+
+// Bibliography files copied into the build directory by the renderer; `csl` is a built-in
+// style name or the copied .csl file.
+#let bibliography-files = prefs.at("bibliography", default: ())
+#if bibliography-files.len() > 0 {
+  let style = prefs.at("csl", default: "")
+  bibliography(bibliography-files, style: if style == "" { "ieee" } else { style })
+}
@@ -0,0 +1,226 @@
+//! GitHub/Obsidian style callouts.
+//!
+//! A blockquote whose first line is `[!KIND]`, optionally followed by a title, becomes a
+//! `<tf-admonition kind="..." title="...">` element that the template renders with its
+//! `admonition` function:
+//!
+//! ```markdown
+//! > [!WARNING] Back up first
+//! > The migration rewrites **every** file.
+//! ```
+//!
+//! The `>` markers are removed so the content is parsed (and anchored for scroll sync) like
+//! any other Markdown. Obsidian's foldable `[!TIP]-` and `[!TIP]+` are accepted and always
+//! shown expanded. Callouts inside callouts are handled one level per pass.
+
+use crate::edit_map::EditMap;
+use crate::math::escape_xml;
+use lazy_static::lazy_static;
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use regex::Regex;
+use std::ops::Range;
+
+/// Deepest nesting of callouts that is translated.
+const MAX_DEPTH: usize = 8;
+
+lazy_static! {
+    static ref HEADER: Regex =
+        Regex::new(r"^>[ \t]?\[!(?P<kind>[A-Za-z][\w-]*)\][-+]?(?:[ \t]+(?P<title>.*?))?[ \t]*$")
+            .expect("BUG: Invalid regex pattern for admonition headers");
+}
+
+#[derive(Debug, Clone)]
+pub struct AdmonitionOutput {
+    pub markdown: String,
+    pub edit_map: EditMap,
+}
+
+/// Translate the callouts of `markdown`.
+pub fn apply(markdown: &str) -> AdmonitionOutput {
+    let mut output = AdmonitionOutput {
+        markdown: markdown.to_string(),
+        edit_map: EditMap::identity(),
+    };
+    for _ in 0..MAX_DEPTH {
+        let Some((markdown, edit_map)) = translate_outermost(&output.markdown) else {
+            break;
+        };
+        output.edit_map = output.edit_map.then(&edit_map);
+        output.markdown = markdown;
+    }
+    output
+}
+
+/// Translate the callouts that are not inside another blockquote. `None` when there are none.
+fn translate_outermost(markdown: &str) -> Option<(String, EditMap)> {
+    let mut splices: Vec<(Range<usize>, String)> = Vec::new();
+    let mut depth = 0;
+    for (event, range) in Parser::new_ext(markdown, Options::all()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::BlockQuote) => {
+                if depth == 0 {
+                    splices.extend(callout_splices(markdown, range));
+                }
+                depth += 1;
+            }
+            Event::End(Tag::BlockQuote) => depth -= 1,
+            _ => {}
+        }
+    }
+    if splices.is_empty() {
+        return None;
+    }
+
+    let mut output = String::with_capacity(markdown.len());
+    let mut last = 0;
+    for (range, replacement) in &splices {
+        output.push_str(&markdown[last..range.start]);
+        output.push_str(replacement);
+        last = range.end;
+    }
+    output.push_str(&markdown[last..]);
+    let edit_map = EditMap::from_splices(
+        splices
+            .into_iter()
+            .map(|(range, replacement)| (range, replacement.len())),
+    );
+    Some((output, edit_map))
+}
+
+/// Edits turning the blockquote at `range` into an admonition element, or nothing when it is
+/// not a callout.
+fn callout_splices(markdown: &str, range: Range<usize>) -> Vec<(Range<usize>, String)> {
+    let header_end = markdown[range.start..]
+        .find('\n')
+        .map_or(markdown.len(), |i| range.start + i);
+    let header = markdown[range.start..header_end].trim_end_matches('\r');
+    let Some(caps) = HEADER.captures(header) else {
+        return Vec::new();
+    };
+    let line_start = markdown[..range.start].rfind('\n').map_or(0, |i| i + 1);
+    // Continuation lines and the closing tag line up with the `>` of the header, which may
+    // follow a list marker.
+    let indent: String = markdown[line_start..range.start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let mut element = format!("<tf-admonition kind=\"{}\"", caps["kind"].to_lowercase());
+    if let Some(title) = caps
+        .name("title")
+        .filter(|title| !title.as_str().is_empty())
+    {
+        element.push_str(&format!(" title=\"{}\"", escape_xml(title.as_str())));
+    }
+    element.push_str(">\n");
+    let mut splices = vec![(range.start..header_end, element)];
+
+    // Strip one `>` (and the space after it) from every following line of the blockquote;
+    // lazy continuation lines have none.
+    let mut line = header_end + 1;
+    while line < range.end {
+        let end = markdown[line..]
+            .find('\n')
+            .map_or(markdown.len(), |i| line + i);
+        let text = &markdown[line..end];
+        let trimmed = text.trim_start();
+        if let Some(rest) = trimmed.strip_prefix('>') {
+            let marker = line + text.len() - trimmed.len();
+            let len = if rest.starts_with([' ', '\t']) { 2 } else { 1 };
+            splices.push((marker..marker + len, String::new()));
+        }
+        line = end + 1;
+    }
+
+    let close_at = if range.end > 0 && markdown.as_bytes()[range.end - 1] == b'\n' {
+        range.end
+    } else {
+        markdown[range.end..]
+            .find('\n')
+            .map_or(markdown.len(), |i| range.end + i + 1)
+    };
+    let newline = if markdown[..close_at].ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+    splices.push((
+        close_at..close_at,
+        format!("{}\n{}</tf-admonition>\n\n", newline, indent),
+    ));
+    splices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn callouts_become_admonition_elements() {
+        assert_eq!(
+            apply("> [!NOTE]\n> Plain **note**.\n").markdown,
+            "<tf-admonition kind=\"note\">\n\nPlain **note**.\n\n</tf-admonition>\n\n"
+        );
+    }
+
+    #[test]
+    fn foldable_callouts_are_shown_with_their_title() {
+        assert_eq!(
+            apply("> [!WARNING]+ Back up\n> Text\n").markdown,
+            "<tf-admonition kind=\"warning\" title=\"Back up\">\n\nText\n\n</tf-admonition>\n\n"
+        );
+    }
+
+    #[test]
+    fn titles_are_escaped_as_attributes() {
+        assert_eq!(
+            apply("> [!tip]- Tom & \"Jerry\"\n> x\n").markdown,
+            "<tf-admonition kind=\"tip\" title=\"Tom &amp; &quot;Jerry&quot;\">\n\n\
+             x\n\n</tf-admonition>\n\n"
+        );
+    }
+
+    #[test]
+    fn callouts_on_crlf_lines_are_translated() {
+        for (markdown, element) in [
+            ("> [!NOTE]\r\n> Text\r\n", "<tf-admonition kind=\"note\">\n"),
+            (
+                "> [!NOTE] Title\r\n> Text\r\n",
+                "<tf-admonition kind=\"note\" title=\"Title\">\n",
+            ),
+        ] {
+            let output = apply(markdown).markdown;
+            assert!(output.starts_with(element), "{:?}", output);
+            assert!(output.contains("\nText\r\n"), "{:?}", output);
+            assert!(output.ends_with("</tf-admonition>\n\n"), "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn nested_callouts_map_back_to_their_source() {
+        let markdown =
+            "Intro\n\n> [!NOTE]\n> Plain **note**.\n>\n> > [!tip]- Nested \"tip\"\n> > Inner\n";
+        let output = apply(markdown);
+        assert_eq!(
+            output.markdown,
+            "Intro\n\n<tf-admonition kind=\"note\">\n\nPlain **note**.\n\n\
+             <tf-admonition kind=\"tip\" title=\"Nested &quot;tip&quot;\">\n\n\
+             Inner\n\n</tf-admonition>\n\n\
+             \n</tf-admonition>\n\n"
+        );
+        let inner = output.markdown.find("Inner").unwrap();
+        assert_eq!(
+            output.edit_map.to_original(inner),
+            markdown.find("Inner").unwrap()
+        );
+    }
+
+    #[test]
+    fn plain_quotes_and_code_are_left_alone() {
+        for markdown in ["> Quote\n\n```\n> [!NOTE]\n```\n", "> [!]\n> x\n"] {
+            let output = apply(markdown);
+            assert_eq!(output.markdown, markdown);
+            assert!(output.edit_map.is_identity());
+        }
+    }
+}
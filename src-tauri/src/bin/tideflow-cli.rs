@@ -0,0 +1,7 @@
+//! Headless Markdown → PDF renderer sharing the desktop app's pipeline.
+//!
+//! Example: `tideflow-cli render doc.md -o doc.pdf --theme academic --prefs prefs.json`
+
+fn main() -> std::process::ExitCode {
+    tideflow_lib::run_cli()
+}
@@ -0,0 +1,148 @@
+//! Per-job build directories.
+//!
+//! Every render writes its `content.md`, `prefs.json`, `tideflow.typ` and output into its own
+//! workspace under the build root, keyed by document path and job kind:
+//! `<build>/workspaces/<name>-<hash>/<kind>/`. Theme files are installed once into
+//! `<build>/shared/` and imported from there, and Typst packages are read straight from their
+//! package roots, so workspaces only hold per-job files. Together with the render scheduler
+//! this lets two documents, or a preview and an export of the same document, compile in
+//! parallel without clobbering each other.
+
+use crate::environment::RenderEnvironment;
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What a workspace is used for. Each kind of job gets its own directory per document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobKind {
+    Preview,
+    Export,
+    Image,
+}
+
+impl JobKind {
+    fn dir_name(self) -> &'static str {
+        match self {
+            JobKind::Preview => "preview",
+            JobKind::Export => "export",
+            JobKind::Image => "image",
+        }
+    }
+}
+
+/// Directory holding files shared read-only by all workspaces (themes).
+pub fn shared_dir(build_root: &Path) -> PathBuf {
+    build_root.join("shared")
+}
+
+/// Workspace for `document` (a file path, or empty for unsaved content) and `kind`.
+pub fn workspace_dir(build_root: &Path, document: &str, kind: JobKind) -> PathBuf {
+    build_root
+        .join("workspaces")
+        .join(document_dir_name(document))
+        .join(kind.dir_name())
+}
+
+/// Create (if needed) and return the workspace for a job.
+pub fn prepare(env: &dyn RenderEnvironment, document: &str, kind: JobKind) -> Result<PathBuf> {
+    let dir = workspace_dir(&env.build_dir()?, document, kind);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// All job workspaces that currently exist under `build_root`.
+pub fn existing_workspaces(build_root: &Path) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    let Ok(documents) = fs::read_dir(build_root.join("workspaces")) else {
+        return dirs;
+    };
+    for document in documents.flatten() {
+        if let Ok(kinds) = fs::read_dir(document.path()) {
+            dirs.extend(
+                kinds
+                    .flatten()
+                    .map(|kind| kind.path())
+                    .filter(|path| path.is_dir()),
+            );
+        }
+    }
+    dirs
+}
+
+/// Readable, collision-free directory name: file stem plus a short hash of the full path.
+fn document_dir_name(document: &str) -> String {
+    if document.is_empty() {
+        return "untitled".to_string();
+    }
+    let stem: String = Path::new(document)
+        .file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .take(40)
+        .collect();
+    let hash = hex::encode(Sha256::digest(document.as_bytes()));
+    format!("{}-{}", stem, &hash[..12])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::FsEnvironment;
+
+    #[test]
+    fn job_kinds_of_a_document_share_its_directory() {
+        let root = Path::new("/tmp/build");
+        let preview = workspace_dir(root, "/docs/a/report.md", JobKind::Preview);
+        let export = workspace_dir(root, "/docs/a/report.md", JobKind::Export);
+
+        assert_ne!(preview, export);
+        assert_eq!(preview.parent(), export.parent());
+    }
+
+    #[test]
+    fn documents_with_the_same_name_get_their_own_workspaces() {
+        let root = Path::new("/tmp/build");
+        let first = workspace_dir(root, "/docs/a/report.md", JobKind::Preview);
+        let second = workspace_dir(root, "/docs/b/report.md", JobKind::Preview);
+
+        assert_ne!(first, second);
+        assert!(first
+            .parent()
+            .and_then(|p| p.file_name())
+            .is_some_and(|name| name.to_string_lossy().starts_with("report-")));
+    }
+
+    #[test]
+    fn unsaved_content_uses_the_untitled_workspace() {
+        let root = Path::new("/tmp/build");
+        assert!(workspace_dir(root, "", JobKind::Preview).ends_with("untitled/preview"));
+    }
+
+    #[test]
+    fn prepared_workspaces_are_listed() {
+        let root = std::env::temp_dir().join(format!("tf-workspace-{}", uuid::Uuid::new_v4()));
+        let env = FsEnvironment::new(&root);
+
+        let preview = prepare(&env, "/docs/a/report.md", JobKind::Preview).unwrap();
+        let image = prepare(&env, "", JobKind::Image).unwrap();
+        assert!(preview.is_dir() && image.is_dir());
+
+        let mut listed = existing_workspaces(&root.join(".build"));
+        listed.sort();
+        let mut expected = vec![preview, image];
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+//! Pandoc-style citations backed by BibTeX or Hayagriva files.
+//!
+//! The `bibliography` preference names `.bib`, `.yml` or `.yaml` files, relative to the content
+//! directory (or to the document when set in front matter). They are copied into the build
+//! workspace, where the template passes them to Typst's `bibliography` with the `csl` style: a
+//! built-in style name such as `apa`, or a `.csl` file.
+//!
+//! `[see @a, p. 4; @b]` becomes a group of citations with optional prefixes and locators, `-@a`
+//! cites only the year and a bare `@a` becomes an in-text citation. Citations are emitted as
+//! `<tf-cite>` elements, which the template turns into `cite` calls. A key the bibliography
+//! does not define would fail the Typst compile, so it is left as text and reported instead; a
+//! bare `@name` that is not a key (a handle, say) is left alone without a warning.
+
+use crate::crossref;
+use crate::edit_map::{self, EditMap};
+use crate::math::escape_xml;
+use crate::preprocessor::PreprocessorOutput;
+use crate::render_pipeline::write_if_changed;
+use crate::utils;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use regex::{Captures, Match, Regex};
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Workspace directory the bibliography and style files are copied into.
+pub const BIBLIOGRAPHY_DIR: &str = "bibliography";
+
+/// Citation key: word characters, with `-`, `.`, `:`, `/` and `+` only between them so a
+/// sentence can end right after a citation.
+const KEY: &str = r"\w+(?:[-.:/+]\w+)*";
+
+lazy_static! {
+    static ref BIBTEX_ENTRY: Regex = Regex::new(r"@(\w+)\s*[{(]\s*([^,\s{}()]+)\s*,")
+        .expect("BUG: Invalid regex pattern for BibTeX entries");
+    /// `[...]` with an `@` inside, unless it is a link (`[...](url)`, `[...][ref]` or a `[...]:`
+    /// definition), or a bare `@key`.
+    static ref CITATION: Regex = Regex::new(&format!(
+        r"\[(?P<group>[^\[\]]*@[^\[\]]*)\](?P<link>[(\[:])?|@(?P<key>{KEY})"
+    ))
+    .expect("BUG: Invalid regex pattern for citations");
+    /// One `;`-separated citation of a group: `prefix -@key, locator`.
+    static ref CITATION_PART: Regex = Regex::new(&format!(
+        r"(?s)^(?:(?P<prefix>.*?)\s+)?(?P<year>-)?@(?P<key>{KEY})(?:\s*,\s*(?P<locator>.*))?$"
+    ))
+    .expect("BUG: Invalid regex pattern for citation parts");
+}
+
+/// The citation keys of the document's bibliography.
+#[derive(Debug, Clone, Default)]
+pub struct Bibliography {
+    keys: HashSet<String>,
+    warnings: Vec<String>,
+}
+
+impl Bibliography {
+    /// Copy the files named by the `bibliography` and `csl` preferences into `build_dir` and
+    /// point the preferences at the copies. Files that cannot be used are dropped with a
+    /// warning.
+    pub fn install(prefs: &mut JsonValue, content_dir: &Path, build_dir: &Path) -> Result<Self> {
+        let mut bibliography = Self::default();
+        let target_dir = build_dir.join(BIBLIOGRAPHY_DIR);
+        let mut installed = Vec::new();
+
+        let sources: Vec<String> = match prefs.get("bibliography") {
+            Some(JsonValue::String(path)) => vec![path.clone()],
+            Some(JsonValue::Array(paths)) => paths
+                .iter()
+                .filter_map(|path| path.as_str())
+                .map(str::to_string)
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut files = Vec::new();
+        for source in sources.iter().filter(|source| !source.trim().is_empty()) {
+            let path = resolve(source, content_dir);
+            let extension = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or("")
+                .to_lowercase();
+            if !matches!(extension.as_str(), "bib" | "yml" | "yaml") {
+                bibliography.warnings.push(format!(
+                    "Bibliography file `{}` must be a .bib, .yml or .yaml file",
+                    source
+                ));
+                continue;
+            }
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(e) => {
+                    bibliography.warnings.push(format!(
+                        "Bibliography file `{}` could not be read: {}",
+                        source, e
+                    ));
+                    continue;
+                }
+            };
+            match keys_in(&text, &extension) {
+                Ok(keys) => bibliography.keys.extend(keys),
+                Err(e) => {
+                    bibliography.warnings.push(format!(
+                        "Bibliography file `{}` is not valid: {}",
+                        source, e
+                    ));
+                    continue;
+                }
+            }
+            let name = install_file(text.as_bytes(), &path, &target_dir, &mut installed)?;
+            files.push(JsonValue::String(format!("{}/{}", BIBLIOGRAPHY_DIR, name)));
+        }
+        prefs["bibliography"] = JsonValue::Array(files);
+
+        let style = prefs
+            .get("csl")
+            .and_then(|v| v.as_str())
+            .filter(|style| style.to_lowercase().ends_with(".csl"))
+            .map(str::to_string);
+        if let Some(style) = style {
+            let path = resolve(&style, content_dir);
+            prefs["csl"] = match fs::read(&path) {
+                Ok(bytes) => {
+                    let name = install_file(&bytes, &path, &target_dir, &mut installed)?;
+                    JsonValue::String(format!("{}/{}", BIBLIOGRAPHY_DIR, name))
+                }
+                Err(e) => {
+                    bibliography.warnings.push(format!(
+                        "Citation style `{}` could not be read: {}",
+                        style, e
+                    ));
+                    JsonValue::String(String::new())
+                }
+            };
+        }
+
+        // Files from an earlier render would otherwise keep invalidating the render cache.
+        if let Ok(entries) = fs::read_dir(&target_dir) {
+            for entry in entries.flatten() {
+                if !installed.contains(&entry.file_name().to_string_lossy().to_string()) {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
+        }
+        Ok(bibliography)
+    }
+}
+
+fn resolve(path: &str, content_dir: &Path) -> PathBuf {
+    let path = PathBuf::from(path);
+    if path.is_absolute() {
+        path
+    } else {
+        content_dir.join(path)
+    }
+}
+
+/// Write `bytes` into `target_dir` under the source's file name, numbered when two sources share
+/// a name. Returns the name used.
+fn install_file(
+    bytes: &[u8],
+    source: &Path,
+    target_dir: &Path,
+    installed: &mut Vec<String>,
+) -> Result<String> {
+    let file_name = utils::sanitize_filename(
+        &source
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    );
+    let mut name = file_name.clone();
+    let mut counter = 1;
+    while installed.contains(&name) {
+        name = format!("{}-{}", counter, file_name);
+        counter += 1;
+    }
+    write_if_changed(&target_dir.join(&name), bytes)?;
+    installed.push(name.clone());
+    Ok(name)
+}
+
+/// Entry keys of a BibTeX file, or the top-level keys of a Hayagriva file.
+fn keys_in(text: &str, extension: &str) -> Result<Vec<String>> {
+    if extension == "bib" {
+        return Ok(BIBTEX_ENTRY
+            .captures_iter(text)
+            .filter(|caps| {
+                !matches!(
+                    caps[1].to_lowercase().as_str(),
+                    "string" | "preamble" | "comment"
+                )
+            })
+            .map(|caps| caps[2].to_string())
+            .collect());
+    }
+    let entries: BTreeMap<String, serde_yaml::Value> = serde_yaml::from_str(text)?;
+    Ok(entries.into_keys().collect())
+}
+
+/// Turn the citations in `preprocess` into `<tf-cite>` elements and report keys the
+/// bibliography does not define, with their line in `source` or in the included file.
+pub fn apply(preprocess: &mut PreprocessorOutput, source: &str, bibliography: &Bibliography) {
+    preprocess
+        .warnings
+        .extend(bibliography.warnings.iter().cloned());
+    let (markdown, edits, unknown) = rewrite(&preprocess.markdown, &bibliography.keys);
+    for (offset, key) in unknown {
+        let position = preprocess.describe_position(source, offset);
+        preprocess
+            .warnings
+            .push(format!("Unknown citation key `{}` on {}", key, position));
+    }
+    preprocess.transform(|_| (markdown, edits));
+}
+
+/// Rewrite the citations in `markdown`. Also returns the offsets and names of unknown keys.
+fn rewrite(markdown: &str, keys: &HashSet<String>) -> (String, EditMap, Vec<(usize, String)>) {
+    let protected = crossref::protected_ranges(markdown);
+    let mut unknown = Vec::new();
+    let (output, edits) = edit_map::replace_all(&CITATION, markdown, |caps| {
+        let matched = caps.get(0).expect("BUG: capture group 0 always exists");
+        let previous = markdown[..matched.start()].chars().next_back();
+        let skipped = if caps.name("group").is_some() {
+            previous == Some('\\') || caps.name("link").is_some()
+        } else {
+            // Not an e-mail address, a URL path or `-@key` outside brackets.
+            previous.is_some_and(|c| c.is_alphanumeric() || matches!(c, '\\' | '-' | '/'))
+        };
+        if skipped || crossref::is_protected(&protected, matched.start()) {
+            return matched.as_str().to_string();
+        }
+        match caps.name("group") {
+            Some(group) => rewrite_group(group, keys, &mut unknown),
+            None => rewrite_bare(caps, keys),
+        }
+        .unwrap_or_else(|| matched.as_str().to_string())
+    });
+    (output, edits, unknown)
+}
+
+/// A bracketed group, or `None` when it is not a citation group (or cross-references).
+fn rewrite_group(
+    group: Match,
+    keys: &HashSet<String>,
+    unknown: &mut Vec<(usize, String)>,
+) -> Option<String> {
+    let mut citations = Vec::new();
+    let mut all_known = true;
+    for part in group.as_str().split(';') {
+        let part = part.trim();
+        let caps = CITATION_PART.captures(part)?;
+        let key = &caps["key"];
+        if crossref::is_crossref_label(key) {
+            return None;
+        }
+        let prefix = caps
+            .name("prefix")
+            .map(|prefix| format!("{} ", prefix.as_str()))
+            .unwrap_or_default();
+        if keys.contains(key) {
+            let form = if caps.name("year").is_some() {
+                "year"
+            } else {
+                "normal"
+            };
+            let locator = caps.name("locator").map(|locator| locator.as_str());
+            citations.push(format!("{}{}", prefix, cite_element(key, form, locator)));
+        } else {
+            let part_start = part.as_ptr() as usize - group.as_str().as_ptr() as usize;
+            let key_start = caps.name("key").expect("BUG: key is required").start();
+            unknown.push((group.start() + part_start + key_start - 1, key.to_string()));
+            all_known = false;
+            citations.push(part.to_string());
+        }
+    }
+    Some(if all_known {
+        citations.concat()
+    } else {
+        // Escaped, as cmarker reads `[@key]` as a reference to a label.
+        format!("\\[{}\\]", citations.join("; "))
+    })
+}
+
+fn rewrite_bare(caps: &Captures, keys: &HashSet<String>) -> Option<String> {
+    let key = &caps["key"];
+    (keys.contains(key) && !crossref::is_crossref_label(key))
+        .then(|| cite_element(key, "prose", None))
+}
+
+fn cite_element(key: &str, form: &str, locator: Option<&str>) -> String {
+    let mut element = format!("<tf-cite key=\"{}\" form=\"{}\"", escape_xml(key), form);
+    if let Some(locator) = locator.filter(|locator| !locator.is_empty()) {
+        element.push_str(&format!(" supplement=\"{}\"", escape_xml(locator)));
+    }
+    element.push_str("></tf-cite>");
+    element
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagrams::DiagramRegistry;
+
+    #[test]
+    fn citations_become_elements_and_unknown_keys_are_reported() {
+        let keys: HashSet<String> = keys_in(
+            "@string{acm = \"ACM\"}\n@article{smith2020,\n  title = {A}\n}\n\
+             @Book{doe:99, title = {B}}\n",
+            "bib",
+        )
+        .unwrap()
+        .into_iter()
+        .collect();
+        assert_eq!(keys.len(), 2);
+
+        let markdown = "As @smith2020 shows [see @doe:99, p. 4; -@smith2020].\n\n\
+            Also [@nobody], [@fig:arch], [profile](https://example.org/@smith2020), \
+            `@doe:99` and me@doe:99.\n";
+        let (output, edits, unknown) = rewrite(markdown, &keys);
+
+        assert_eq!(
+            output,
+            "As <tf-cite key=\"smith2020\" form=\"prose\"></tf-cite> shows \
+             see <tf-cite key=\"doe:99\" form=\"normal\" supplement=\"p. 4\"></tf-cite>\
+             <tf-cite key=\"smith2020\" form=\"year\"></tf-cite>.\n\n\
+             Also \\[@nobody\\], [@fig:arch], [profile](https://example.org/@smith2020), \
+             `@doe:99` and me@doe:99.\n"
+        );
+        let nobody = markdown.find("@nobody").unwrap();
+        assert_eq!(unknown, vec![(nobody, "nobody".to_string())]);
+        let escaped = output.find("\\[@nobody").unwrap();
+        assert_eq!(edits.to_original(escaped), nobody - 1);
+        assert_eq!(
+            cite_element("k", "normal", Some("\"ch\" 2 & 3")),
+            "<tf-cite key=\"k\" form=\"normal\" supplement=\"&quot;ch&quot; 2 &amp; 3\"></tf-cite>"
+        );
+    }
+
+    #[test]
+    fn unknown_keys_in_included_files_are_reported_in_that_file() {
+        let dir = std::env::temp_dir().join(format!("tideflow-citations-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("chapters")).unwrap();
+        fs::write(dir.join("chapters/one.md"), "# One\n\nAs [@nobody] says.\n").unwrap();
+
+        let source =
+            "---\ntoc: true\n---\nIntro [@missing].\n\n<!-- include: chapters/one.md -->\n";
+        let mut preprocess = crate::preprocessor::preprocess_markdown(
+            source,
+            None,
+            &dir,
+            &DiagramRegistry::builtin(),
+        )
+        .unwrap();
+        apply(&mut preprocess, source, &Bibliography::default());
+
+        assert_eq!(
+            preprocess.warnings,
+            [
+                "Unknown citation key `missing` on line 4",
+                "Unknown citation key `nobody` on line 3 of `chapters/one.md`",
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
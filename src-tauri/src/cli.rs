@@ -0,0 +1,250 @@
+//! Headless command line front-end (`tideflow-cli`).
+//!
+//! Runs the same preprocess → image rewrite → diagrams → Typst pipeline as the desktop app
+//! through [`renderer::export_markdown_to`], with an [`FsEnvironment`] built from flags
+//! instead of a Tauri `AppHandle`, so documents can be built from CI or shell scripts.
+
+use crate::environment::FsEnvironment;
+use crate::preferences::{merge_json, Preferences};
+use crate::renderer;
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value as JsonValue;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+const USAGE: &str = "Usage: tideflow-cli render <input.md> [options]
+
+Options:
+  -o, --output <file>      Output PDF (default: <input>.pdf)
+      --theme <id>         Theme id to apply (overrides prefs)
+      --prefs <file>       Preferences JSON (same format as the app's prefs.json)
+      --root <dir>         Typst root / content directory (default: input's directory)
+      --resources <dir>    Directory holding content/tideflow.typ, themes and packages
+      --typst <path>       Compile with this Typst binary instead of the built-in compiler
+      --tectonic <path>    Tectonic binary used for TikZ blocks
+  -h, --help               Show this help";
+
+#[derive(Debug, Default)]
+struct RenderArgs {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    theme: Option<String>,
+    prefs: Option<PathBuf>,
+    root: Option<PathBuf>,
+    resources: Option<PathBuf>,
+    typst: Option<PathBuf>,
+    tectonic: Option<PathBuf>,
+}
+
+/// Entry point used by `src/bin/tideflow-cli.rs`.
+pub fn run() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("render") => {}
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    }
+
+    let parsed = match parse_render_args(&args[1..]) {
+        Ok(Some(parsed)) => parsed,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match render(&parsed) {
+        Ok(report) => {
+            for warning in &report.warnings {
+                eprintln!("warning: {}", warning);
+            }
+            for (id, message) in &report.diagram_failures {
+                eprintln!("error: diagram {} failed:\n{}", id, message);
+            }
+            println!("{}", report.pdf_path.display());
+            if report.diagram_failures.is_empty() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_render_args(args: &[String]) -> Result<Option<RenderArgs>> {
+    let mut parsed = RenderArgs::default();
+    let mut input: Option<PathBuf> = None;
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| anyhow!("missing value for {}", name))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => parsed.output = Some(PathBuf::from(value(arg)?)),
+            "--theme" => parsed.theme = Some(value(arg)?),
+            "--prefs" => parsed.prefs = Some(PathBuf::from(value(arg)?)),
+            "--root" => parsed.root = Some(PathBuf::from(value(arg)?)),
+            "--resources" => parsed.resources = Some(PathBuf::from(value(arg)?)),
+            "--typst" => parsed.typst = Some(PathBuf::from(value(arg)?)),
+            "--tectonic" => parsed.tectonic = Some(PathBuf::from(value(arg)?)),
+            other if other.starts_with('-') => return Err(anyhow!("unknown option {}", other)),
+            other => {
+                if input.is_some() {
+                    return Err(anyhow!("unexpected argument {}", other));
+                }
+                input = Some(PathBuf::from(other));
+            }
+        }
+    }
+
+    parsed.input = input.ok_or_else(|| anyhow!("missing input file"))?;
+    Ok(Some(parsed))
+}
+
+struct RenderReport {
+    pdf_path: PathBuf,
+    diagram_failures: Vec<(String, String)>,
+    warnings: Vec<String>,
+}
+
+fn render(args: &RenderArgs) -> Result<RenderReport> {
+    let input = args
+        .input
+        .canonicalize()
+        .with_context(|| format!("input file not found: {}", args.input.display()))?;
+
+    let content_dir = match args.root.as_ref() {
+        Some(root) => root
+            .canonicalize()
+            .with_context(|| format!("root directory not found: {}", root.display()))?,
+        None => input.parent().unwrap_or(Path::new(".")).to_path_buf(),
+    };
+    let resource_dir = find_resource_dir(args.resources.as_deref())?;
+
+    // Preferences: defaults, then the optional prefs file, then --theme/--typst.
+    let mut prefs_val = serde_json::to_value(Preferences::default())?;
+    if let Some(prefs_path) = args.prefs.as_ref() {
+        let txt = fs::read_to_string(prefs_path)
+            .with_context(|| format!("failed to read prefs {}", prefs_path.display()))?;
+        let user: JsonValue = serde_json::from_str(&txt)
+            .with_context(|| format!("invalid prefs JSON in {}", prefs_path.display()))?;
+        merge_json(&mut prefs_val, user);
+    }
+    if let Some(theme) = args.theme.as_ref() {
+        prefs_val["theme_id"] = JsonValue::String(theme.clone());
+    }
+    if args.typst.is_some() {
+        prefs_val["typst_backend"] = JsonValue::String("external".to_string());
+    }
+
+    let mut env = FsEnvironment::new(&content_dir)
+        .with_build_dir(content_dir.join(".build").join("cli"))
+        .with_resource_dir(&resource_dir)
+        .with_preferences(prefs_val);
+    if let Some(typst) = args.typst.as_ref() {
+        env = env.with_typst_path(typst);
+    }
+    if let Some(tectonic) = args.tectonic.as_ref() {
+        env = env.with_tectonic_path(tectonic);
+    }
+
+    let output = match args.output.as_ref() {
+        Some(out) if out.is_absolute() => out.clone(),
+        Some(out) => std::env::current_dir()?.join(out),
+        None => input.with_extension("pdf"),
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let env = Arc::new(env);
+    let pdf_path = runtime.block_on(renderer::export_markdown_to(
+        env.clone(),
+        &input.to_string_lossy(),
+        Some(&output),
+    ))?;
+
+    let events = env.events();
+    let warnings = events
+        .iter()
+        .filter(|(event, _)| event == "render-warnings")
+        .filter_map(|(_, payload)| payload["warnings"].as_array())
+        .flatten()
+        .filter_map(|warning| warning.as_str().map(str::to_string))
+        .collect();
+    let diagram_failures = events
+        .into_iter()
+        .filter(|(event, _)| event == "diagram-error")
+        .map(|(_, payload)| {
+            (
+                payload["id"].as_str().unwrap_or_default().to_string(),
+                payload["message"].as_str().unwrap_or_default().to_string(),
+            )
+        })
+        .collect();
+
+    Ok(RenderReport {
+        pdf_path: PathBuf::from(pdf_path),
+        diagram_failures,
+        warnings,
+    })
+}
+
+/// Find the directory that holds `content/tideflow.typ` (the Tauri resource layout).
+fn find_resource_dir(explicit: Option<&Path>) -> Result<PathBuf> {
+    let mut candidates: Vec<PathBuf> = Vec::new();
+    if let Some(dir) = explicit {
+        candidates.push(dir.to_path_buf());
+    }
+    if let Ok(dir) = std::env::var("TIDEFLOW_RESOURCES") {
+        candidates.push(PathBuf::from(dir));
+    }
+    if let Ok(exe_path) = std::env::current_exe() {
+        if let Some(exe_dir) = exe_path.parent() {
+            candidates.push(exe_dir.to_path_buf());
+            candidates.push(exe_dir.join(".."));
+            // target/<profile>/tideflow-cli → src-tauri
+            candidates.push(exe_dir.join("..").join(".."));
+        }
+    }
+    if let Ok(cwd) = std::env::current_dir() {
+        candidates.push(cwd.join("src-tauri"));
+        candidates.push(cwd);
+    }
+
+    candidates
+        .iter()
+        .find(|dir| dir.join("content").join("tideflow.typ").exists())
+        .map(|dir| dir.canonicalize().unwrap_or_else(|_| dir.clone()))
+        .ok_or_else(|| {
+            anyhow!(
+                "tideflow.typ template not found. Pass --resources <dir> \
+                 (containing content/tideflow.typ). Searched: {}",
+                candidates
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
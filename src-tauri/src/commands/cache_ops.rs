@@ -1,4 +1,6 @@
 /// Cache operation commands: manage render cache and temporary files
+use crate::build_workspace;
+use crate::render_cache;
 use crate::utils;
 use serde::Serialize;
 use std::fs;
@@ -9,6 +11,7 @@ use tauri::AppHandle;
 pub struct CacheStats {
     pub cached_documents: usize,
     pub cache_size_mb: f64,
+    pub cache_size_bytes: u64,
     pub cache_hits: usize,
     pub cache_misses: usize,
 }
@@ -24,35 +27,14 @@ pub struct CleanupResponse {
 pub async fn get_cache_stats(app_handle: AppHandle) -> Result<CacheStats, String> {
     let content_dir = utils::get_content_dir(&app_handle)
         .map_err(|e| format!("Failed to get content directory: {}", e))?;
-    let build_dir = content_dir.join(".build");
-
-    let mut cached_documents = 0;
-    let mut cache_size_mb = 0.0;
-
-    if build_dir.exists() {
-        if let Ok(entries) = fs::read_dir(&build_dir) {
-            for entry in entries.flatten() {
-                let path = entry.path();
-                if path
-                    .file_name()
-                    .and_then(|name| name.to_str())
-                    .map(|name| name.starts_with("cached_"))
-                    .unwrap_or(false)
-                {
-                    cached_documents += 1;
-                    if let Ok(metadata) = fs::metadata(&path) {
-                        cache_size_mb += metadata.len() as f64 / (1024.0 * 1024.0);
-                    }
-                }
-            }
-        }
-    }
+    let usage = render_cache::usage(&content_dir.join(".build"));
 
     Ok(CacheStats {
-        cached_documents,
-        cache_size_mb,
-        cache_hits: 0, // Basic cache - no hit/miss tracking for now
-        cache_misses: 0,
+        cached_documents: usage.entries,
+        cache_size_mb: usage.bytes as f64 / (1024.0 * 1024.0),
+        cache_size_bytes: usage.bytes,
+        cache_hits: usage.hits,
+        cache_misses: usage.misses,
     })
 }
 
@@ -61,23 +43,8 @@ pub async fn get_cache_stats(app_handle: AppHandle) -> Result<CacheStats, String
 pub async fn clear_render_cache(app_handle: AppHandle) -> Result<(), String> {
     let content_dir = utils::get_content_dir(&app_handle)
         .map_err(|e| format!("Failed to get content directory: {}", e))?;
-    let build_dir = content_dir.join(".build");
-
-    if build_dir.exists() {
-        if let Ok(entries) = fs::read_dir(&build_dir) {
-            for entry in entries.flatten() {
-                let path = entry.path();
-                if path
-                    .file_name()
-                    .and_then(|name| name.to_str())
-                    .map(|name| name.starts_with("cached_"))
-                    .unwrap_or(false)
-                {
-                    let _ = fs::remove_file(&path);
-                }
-            }
-        }
-    }
+    render_cache::clear(&content_dir.join(".build"))
+        .map_err(|e| format!("Failed to clear render cache: {}", e))?;
 
     println!("🧹 Render cache cleared");
     Ok(())
@@ -104,7 +71,12 @@ pub async fn cleanup_temp_pdfs(
 
     let mut temp_pdfs = Vec::new();
 
+    // Temp PDFs from older versions sit in the build root; current ones in job workspaces.
     let mut search_dirs = vec![build_dir.clone(), build_dir.join("latex")];
+    for workspace in build_workspace::existing_workspaces(&build_dir) {
+        search_dirs.push(workspace.join("latex"));
+        search_dirs.push(workspace);
+    }
     search_dirs.dedup();
 
     for dir in search_dirs {
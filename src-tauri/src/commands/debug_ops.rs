@@ -1,5 +1,5 @@
 /// Debug and diagnostic commands: system inspection and troubleshooting
-use crate::{preferences, utils};
+use crate::{build_workspace, preferences, utils};
 use serde::Serialize;
 use tauri::AppHandle;
 
@@ -68,7 +68,17 @@ pub async fn debug_paths(app_handle: AppHandle) -> Result<DebugPathsResponse, St
     let content_dir = utils::get_content_dir(&app_handle).map_err(|e| e.to_string())?;
     let build_dir = content_dir.join(".build");
     let prefs_path = content_dir.join("prefs.json");
-    let build_prefs_path = build_dir.join("prefs.json");
+    // Each job writes its own prefs.json; report the most recently rendered one.
+    let build_prefs_path = build_workspace::existing_workspaces(&build_dir)
+        .into_iter()
+        .map(|workspace| workspace.join("prefs.json"))
+        .filter_map(|path| {
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+            Some((modified, path))
+        })
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+        .unwrap_or_else(|| build_dir.join("prefs.json"));
 
     // Load current logical preferences via API (source of truth at time of call)
     let prefs_struct = preferences::get_preferences(app_handle.clone())
@@ -1,4 +1,5 @@
 /// File operation commands: CRUD operations for markdown files and directories
+use crate::docx_import;
 use crate::error::AppError;
 use crate::utils;
 use serde::{Deserialize, Serialize};
@@ -158,22 +159,7 @@ pub async fn create_file(
     template: Option<&str>,
     dir_path: Option<&str>,
 ) -> Result<String, String> {
-    // Determine parent directory
-    let parent_dir = match dir_path {
-        Some(dir) => PathBuf::from(dir),
-        None => utils::get_content_dir(&app_handle).map_err(|e| e.to_string())?,
-    };
-
-    // Ensure parent directory exists
-    fs::create_dir_all(&parent_dir).map_err(|e| e.to_string())?;
-
-    // Create file path
-    let file_path = parent_dir.join(name);
-
-    // If file already exists, return error
-    if file_path.exists() {
-        return Err(format!("File already exists: {}", file_path.display()));
-    }
+    let file_path = new_file_path(&app_handle, name, dir_path)?;
 
     // Determine content to write
     let content = match template {
@@ -199,6 +185,92 @@ pub async fn create_file(
     Ok(file_path.to_string_lossy().to_string())
 }
 
+/// Convert a Word document to Markdown in a new file, `name` (default: the document's name
+/// with `.md`) in `dir_path` (default: the content directory). Embedded images are written
+/// to the assets directory.
+#[tauri::command]
+pub async fn import_docx(
+    app_handle: AppHandle,
+    source_path: &str,
+    name: Option<&str>,
+    dir_path: Option<&str>,
+) -> Result<String, String> {
+    let src = Path::new(source_path);
+    if !src.exists() {
+        return Err(AppError::FileNotFound(src.to_path_buf()).to_frontend_message());
+    }
+    let name = match name {
+        Some(name) if Path::new(name).extension().is_some_and(|ext| ext == "md") => {
+            name.to_string()
+        }
+        Some(name) => format!("{}.md", name),
+        None => {
+            let stem = src
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("document");
+            format!("{}.md", stem)
+        }
+    };
+    let file_path = new_file_path(&app_handle, &name, dir_path)?;
+
+    let bytes = fs::read(src).map_err(|e| {
+        AppError::FileRead {
+            path: src.to_path_buf(),
+            source: e,
+        }
+        .to_frontend_message()
+    })?;
+    let assets_dir = utils::get_assets_dir(&app_handle).map_err(|e| e.to_string())?;
+    // Images are written only once the whole document converted, so a failed import leaves
+    // no stray assets behind.
+    let mut images: Vec<(String, Vec<u8>)> = Vec::new();
+    let markdown = docx_import::convert(&bytes, &mut |file_name, data| {
+        let name = utils::unique_asset_name(&assets_dir, file_name, &|name| {
+            images.iter().any(|(taken, _)| taken == name)
+        });
+        images.push((name.clone(), data.to_vec()));
+        Ok(format!("assets/{}", name))
+    })
+    .map_err(|e| format!("Failed to import {}: {}", src.display(), e))?;
+
+    if !images.is_empty() {
+        fs::create_dir_all(&assets_dir).map_err(|e| e.to_string())?;
+    }
+    for (name, data) in images {
+        fs::write(assets_dir.join(name), data).map_err(|e| e.to_string())?;
+    }
+    fs::write(&file_path, markdown).map_err(|e| e.to_string())?;
+
+    Ok(file_path.to_string_lossy().to_string())
+}
+
+/// Path for a new file `name` in `dir_path` (default: the content directory), creating the
+/// directory. Fails when the file already exists.
+fn new_file_path(
+    app_handle: &AppHandle,
+    name: &str,
+    dir_path: Option<&str>,
+) -> Result<PathBuf, String> {
+    // Determine parent directory
+    let parent_dir = match dir_path {
+        Some(dir) => PathBuf::from(dir),
+        None => utils::get_content_dir(app_handle).map_err(|e| e.to_string())?,
+    };
+
+    // Ensure parent directory exists
+    fs::create_dir_all(&parent_dir).map_err(|e| e.to_string())?;
+
+    // Create file path
+    let file_path = parent_dir.join(name);
+
+    // If file already exists, return error
+    if file_path.exists() {
+        return Err(format!("File already exists: {}", file_path.display()));
+    }
+    Ok(file_path)
+}
+
 #[tauri::command]
 pub async fn delete_file(path: &str) -> Result<(), String> {
     let path = Path::new(path);
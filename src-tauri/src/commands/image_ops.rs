@@ -64,38 +64,11 @@ pub async fn import_image_from_path(
     // Read source bytes
     let image_bytes = fs::read(src).map_err(|e| format!("Failed to read image: {}", e))?;
 
-    // Determine destination directory and filename
+    // Copy into the assets directory under a unique name
     let assets_dir = utils::get_assets_dir(&app_handle).map_err(|e| e.to_string())?;
-    fs::create_dir_all(&assets_dir).map_err(|e| e.to_string())?;
-
     let orig_name = src
         .file_name()
         .and_then(|n| n.to_str())
         .unwrap_or("image.png");
-    let mut base = utils::sanitize_filename(orig_name);
-
-    // Ensure unique filename to avoid accidental overwrite
-    let mut dest_path = assets_dir.join(&base);
-    if dest_path.exists() {
-        // Insert a short UUID before extension
-        let (stem, ext) = match dest_path.file_stem().and_then(|s| s.to_str()) {
-            Some(stem) => {
-                let ext = dest_path.extension().and_then(|e| e.to_str()).unwrap_or("");
-                (stem.to_string(), ext.to_string())
-            }
-            None => ("image".to_string(), "".to_string()),
-        };
-        let short = Uuid::new_v4().to_string();
-        let short = &short[0..8];
-        base = if ext.is_empty() {
-            format!("{}-{}", stem, short)
-        } else {
-            format!("{}-{}.{}", stem, short, ext)
-        };
-        dest_path = assets_dir.join(&base);
-    }
-
-    fs::write(&dest_path, image_bytes).map_err(|e| e.to_string())?;
-
-    Ok(format!("assets/{}", base))
+    utils::write_asset(&assets_dir, orig_name, &image_bytes).map_err(|e| e.to_string())
 }
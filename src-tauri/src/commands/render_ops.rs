@@ -1,8 +1,16 @@
+use crate::diagnostics::TypstCompileError;
+use crate::docx_export;
+use crate::epub_export;
+use crate::html_export;
 use crate::image_export;
+use crate::preview_session;
+use crate::project;
+use crate::render_scheduler::RenderCancelled;
 /// Rendering operation commands: compile markdown/typst to PDF
 use crate::renderer::{self, RenderedDocument};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tauri::{AppHandle, Emitter};
 
 #[tauri::command]
@@ -10,21 +18,51 @@ pub async fn render_markdown(
     app_handle: AppHandle,
     file_path: &str,
 ) -> Result<RenderedDocument, String> {
-    match renderer::render_markdown(&app_handle, file_path).await {
+    let result = renderer::render_markdown(Arc::new(app_handle.clone()), file_path).await;
+    emit_preview_result(&app_handle, Some(file_path), result)
+}
+
+/// Report a preview render to the frontend: `compiled` on success, `render-cancelled` when a
+/// newer preview superseded it, `compile-error` otherwise. With the embedded compiler,
+/// `render-cancelled` comes once the superseded compile has finished, not when it is superseded.
+fn emit_preview_result(
+    app_handle: &AppHandle,
+    file_path: Option<&str>,
+    result: anyhow::Result<RenderedDocument>,
+) -> Result<RenderedDocument, String> {
+    match result {
         Ok(document) => {
             let _ = app_handle.emit("compiled", &document);
             Ok(document)
         }
+        Err(e) if e.downcast_ref::<RenderCancelled>().is_some() => {
+            let _ = app_handle.emit("render-cancelled", serde_json::json!({ "file": file_path }));
+            Err(e.to_string())
+        }
         Err(e) => {
-            let _ = app_handle.emit("compile-error", e.to_string());
+            let _ = app_handle.emit("compile-error", compile_error_payload(file_path, &e));
             Err(e.to_string())
         }
     }
 }
 
+/// `compile-error` payload: the full message plus structured diagnostics when the failure
+/// came from the Typst compiler.
+fn compile_error_payload(file_path: Option<&str>, error: &anyhow::Error) -> serde_json::Value {
+    let diagnostics = error
+        .downcast_ref::<TypstCompileError>()
+        .map(|e| e.diagnostics.as_slice())
+        .unwrap_or_default();
+    serde_json::json!({
+        "file": file_path,
+        "message": error.to_string(),
+        "diagnostics": diagnostics,
+    })
+}
+
 #[tauri::command]
 pub async fn export_markdown(app_handle: AppHandle, file_path: &str) -> Result<String, String> {
-    match renderer::export_markdown(&app_handle, file_path).await {
+    match renderer::export_markdown(Arc::new(app_handle.clone()), file_path).await {
         Ok(pdf_path) => {
             // Emit the exported event with the PDF path
             let _ = app_handle.emit("exported", &pdf_path);
@@ -38,6 +76,32 @@ pub async fn export_markdown(app_handle: AppHandle, file_path: &str) -> Result<S
     }
 }
 
+/// Preview the book project of `project_path`: its manifest, the project directory, or a file
+/// inside it.
+#[tauri::command]
+pub async fn render_project(
+    app_handle: AppHandle,
+    project_path: &str,
+) -> Result<RenderedDocument, String> {
+    let result = project::render_project(Arc::new(app_handle.clone()), project_path).await;
+    emit_preview_result(&app_handle, Some(project_path), result)
+}
+
+/// Export the book project of `project_path` to the PDF named in its manifest.
+#[tauri::command]
+pub async fn export_project(app_handle: AppHandle, project_path: &str) -> Result<String, String> {
+    match project::export_project(Arc::new(app_handle.clone()), project_path).await {
+        Ok(pdf_path) => {
+            let _ = app_handle.emit("exported", &pdf_path);
+            Ok(pdf_path)
+        }
+        Err(e) => {
+            let _ = app_handle.emit("export-error", e.to_string());
+            Err(e.to_string())
+        }
+    }
+}
+
 /// Save (copy) an existing PDF (already rendered/exported) to a user-selected destination.
 /// If the source is a markdown path, we compile first to ensure it's up to date, then copy.
 #[tauri::command]
@@ -55,7 +119,7 @@ pub async fn save_pdf_as(
 
     if ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("qmd") {
         // Export (compile) to sibling PDF, then copy that
-        match renderer::export_markdown(&app_handle, file_path).await {
+        match renderer::export_markdown(Arc::new(app_handle.clone()), file_path).await {
             Ok(p) => pdf_source = PathBuf::from(p),
             Err(e) => return Err(e.to_string()),
         }
@@ -90,16 +154,8 @@ pub async fn render_typst(
     format: &str,
     current_file: Option<&str>,
 ) -> Result<RenderedDocument, String> {
-    match renderer::render_typst(&app_handle, content, format, current_file).await {
-        Ok(document) => {
-            let _ = app_handle.emit("compiled", &document);
-            Ok(document)
-        }
-        Err(e) => {
-            let _ = app_handle.emit("compile-error", e.to_string());
-            Err(e.to_string())
-        }
-    }
+    let result = renderer::render_typst(&app_handle, content, format, current_file).await;
+    emit_preview_result(&app_handle, current_file, result)
 }
 
 #[tauri::command]
@@ -108,16 +164,15 @@ pub async fn render_latex(
     content: &str,
     current_file: Option<&str>,
 ) -> Result<RenderedDocument, String> {
-    match renderer::render_latex(&app_handle, content, current_file).await {
-        Ok(document) => {
-            let _ = app_handle.emit("compiled", &document);
-            Ok(document)
-        }
-        Err(e) => {
-            let _ = app_handle.emit("compile-error", e.to_string());
-            Err(e.to_string())
-        }
-    }
+    let result = renderer::render_latex(&app_handle, content, current_file).await;
+    emit_preview_result(&app_handle, current_file, result)
+}
+
+/// Drop the cached preview state of a document that is no longer open.
+#[tauri::command]
+pub async fn close_preview_session(file_path: &str) -> Result<(), String> {
+    preview_session::end_session(file_path);
+    Ok(())
 }
 
 /// Export the current document as PNG
@@ -146,3 +201,50 @@ pub async fn export_as_svg(
         .await
         .map_err(|e| e.to_string())
 }
+
+/// Export the current document as a standalone HTML page. Images are embedded unless
+/// `inline_assets` is false, in which case they are copied next to the page.
+#[tauri::command]
+pub async fn export_as_html(
+    app_handle: AppHandle,
+    content: &str,
+    destination: &str,
+    current_file: Option<&str>,
+    inline_assets: Option<bool>,
+) -> Result<String, String> {
+    html_export::export_html(
+        &app_handle,
+        content,
+        destination,
+        current_file,
+        inline_assets.unwrap_or(true),
+    )
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Export the current document as an EPUB 3 e-book, one chapter per H1/H2 section
+#[tauri::command]
+pub async fn export_as_epub(
+    app_handle: AppHandle,
+    content: &str,
+    destination: &str,
+    current_file: Option<&str>,
+) -> Result<String, String> {
+    epub_export::export_epub(&app_handle, content, destination, current_file)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Export the current document as a Word document
+#[tauri::command]
+pub async fn export_as_docx(
+    app_handle: AppHandle,
+    content: &str,
+    destination: &str,
+    current_file: Option<&str>,
+) -> Result<String, String> {
+    docx_export::export_docx(&app_handle, content, destination, current_file)
+        .await
+        .map_err(|e| e.to_string())
+}
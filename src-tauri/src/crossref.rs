@@ -0,0 +1,497 @@
+//! Pandoc-crossref style labels and references.
+//!
+//! Labels: `![caption](image.png){#fig:id}` alone in a paragraph, a `: caption {#tbl:id}` (or
+//! `Table: caption {#tbl:id}`) paragraph right before or after a table, `$$...$$ {#eq:id}` and
+//! `# Heading {#sec:id}`. References: `@fig:id` and `[@fig:id; @tbl:other]`.
+//!
+//! Figures and tables become cmarker `<figure id="...">` elements, which cmarker labels.
+//! Headings keep their Markdown and get a raw Typst label on the line after them, and equations
+//! are emitted as raw Typst with a label. References
+//! become cmarker's `[@label]` syntax, which renders as a Typst `ref`. A reference to a label
+//! the document does not define would fail the Typst compile, so it is left as text and
+//! reported instead.
+
+use crate::edit_map::EditMap;
+use crate::preprocessor::typst_comment_string;
+use lazy_static::lazy_static;
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use regex::Regex;
+use std::collections::HashSet;
+use std::ops::Range;
+
+/// Label kinds recognised after `@` and in `{#...}`.
+const KINDS: &str = "fig|tbl|eq|sec";
+
+/// Label suffix: word characters, with `-`, `.` and `:` only between them so a sentence can end
+/// right after a reference.
+const ID: &str = r"\w+(?:[-.:]\w+)*";
+
+lazy_static! {
+    static ref FIGURE: Regex = Regex::new(&format!(
+        concat!(
+            r"(?s)^(?P<image>!\[(?P<caption>(?:[^\]]|\][^(])*?)\]\([^)]*\))",
+            r"\s*\{{#(?P<label>fig:{ID})(?:\s[^}}]*)?\}}$",
+        ),
+        ID = ID
+    ))
+    .expect("BUG: Invalid regex pattern for figure labels");
+    static ref TABLE_CAPTION: Regex = Regex::new(&format!(
+        r"(?s)^(?:Table)?:\s+(?P<caption>.*?)\s*\{{#(?P<label>tbl:{ID})\}}$"
+    ))
+    .expect("BUG: Invalid regex pattern for table captions");
+    static ref EQUATION: Regex = Regex::new(&format!(
+        r"\$\$(?P<math>(?:[^$]|\$[^$])+?)\$\$[ \t]*\{{#(?P<label>eq:{ID})\}}"
+    ))
+    .expect("BUG: Invalid regex pattern for equation labels");
+    /// A `{#id .class key=value}` heading attribute block.
+    static ref ATTRIBUTES: Regex =
+        Regex::new(r"[ \t]*\{[^{}\n]*\}").expect("BUG: Invalid regex pattern for attributes");
+    static ref LABEL_LINE: Regex =
+        Regex::new(r#"^<!--raw-typst #label\("(?P<label>sec:[^"]+)"\) -->\s*$"#)
+            .expect("BUG: Invalid regex pattern for heading labels");
+    static ref REFERENCE: Regex = {
+        let reference = format!("@(?:{KINDS}):{ID}");
+        Regex::new(&format!(
+            r"\[(?P<group>{reference}(?:\s*;\s*{reference})*)\]|(?P<bare>{reference})"
+        ))
+        .expect("BUG: Invalid regex pattern for cross-references")
+    };
+}
+
+#[derive(Debug, Clone)]
+pub struct CrossrefOutput {
+    pub markdown: String,
+    pub edit_map: EditMap,
+    pub unresolved: Vec<UnresolvedRef>,
+}
+
+/// A reference to a label that is not defined in the document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnresolvedRef {
+    /// Byte offset of the reference in the input.
+    pub offset: usize,
+    pub label: String,
+}
+
+/// Rewrite cross-reference labels and references in `markdown`.
+pub fn apply(markdown: &str) -> CrossrefOutput {
+    let (labelled, labels, label_map) = rewrite_labels(markdown);
+    let (output, ref_map, unresolved) = rewrite_references(&labelled, &labels);
+    CrossrefOutput {
+        markdown: output,
+        edit_map: label_map.then(&ref_map),
+        unresolved: unresolved
+            .into_iter()
+            .map(|reference| UnresolvedRef {
+                offset: label_map.to_original(reference.offset),
+                label: reference.label,
+            })
+            .collect(),
+    }
+}
+
+/// Whether `label` (without `@`) names a cross-reference target rather than, say, a citation.
+pub fn is_crossref_label(label: &str) -> bool {
+    label
+        .split_once(':')
+        .is_some_and(|(kind, _)| KINDS.split('|').any(|known| known == kind))
+}
+
+/// The `sec:` label of the heading just before `event`, if `event` is the raw Typst label line
+/// the label pass emits after it.
+pub(crate) fn heading_label(event: &Event) -> Option<String> {
+    match event {
+        Event::Html(html) => LABEL_LINE
+            .captures(html)
+            .map(|caps| caps["label"].to_string()),
+        _ => None,
+    }
+}
+
+fn parser(markdown: &str) -> Parser<'_, '_> {
+    Parser::new_ext(
+        markdown,
+        Options::ENABLE_FOOTNOTES
+            | Options::ENABLE_TASKLISTS
+            | Options::ENABLE_STRIKETHROUGH
+            | Options::ENABLE_TABLES
+            | Options::ENABLE_SMART_PUNCTUATION
+            | Options::ENABLE_HEADING_ATTRIBUTES,
+    )
+}
+
+/// Top-level block of the document, as far as labels care.
+enum Block {
+    Paragraph(Range<usize>),
+    Table(Range<usize>),
+    Heading {
+        range: Range<usize>,
+        id: Option<String>,
+    },
+    Other,
+}
+
+struct Scan {
+    blocks: Vec<Block>,
+    /// Code and raw HTML, where nothing is rewritten.
+    protected: Vec<Range<usize>>,
+}
+
+fn scan(markdown: &str) -> Scan {
+    let mut blocks = Vec::new();
+    let mut protected = Vec::new();
+    let mut depth = 0usize;
+
+    for (event, range) in parser(markdown).into_offset_iter() {
+        match event {
+            Event::Start(tag) => {
+                if let Tag::CodeBlock(_) = tag {
+                    protected.push(range);
+                }
+                depth += 1;
+            }
+            Event::End(tag) => {
+                depth -= 1;
+                if depth > 0 {
+                    continue;
+                }
+                blocks.push(match tag {
+                    Tag::Paragraph => Block::Paragraph(range),
+                    Tag::Table(_) => Block::Table(range),
+                    Tag::Heading(_, id, _) => Block::Heading {
+                        range,
+                        id: id.map(str::to_string),
+                    },
+                    _ => Block::Other,
+                });
+            }
+            Event::Code(_) => protected.push(range),
+            Event::Html(_) => {
+                if depth == 0 {
+                    blocks.push(Block::Other);
+                }
+                protected.push(range);
+            }
+            _ => {}
+        }
+    }
+    Scan { blocks, protected }
+}
+
+/// Byte ranges of code and raw HTML in `markdown`, where `@` never starts a reference.
+pub(crate) fn protected_ranges(markdown: &str) -> Vec<Range<usize>> {
+    scan(markdown).protected
+}
+
+pub(crate) fn is_protected(protected: &[Range<usize>], offset: usize) -> bool {
+    protected
+        .iter()
+        .any(|range| range.start <= offset && offset < range.end)
+}
+
+/// Turn labelled figures, tables, equations and headings into labelled elements. Returns the
+/// new text, the labels it defines and the edits.
+fn rewrite_labels(markdown: &str) -> (String, HashSet<String>, EditMap) {
+    let Scan { blocks, protected } = scan(markdown);
+    let mut labels = HashSet::new();
+    let mut splices: Vec<(Range<usize>, String)> = Vec::new();
+
+    for (index, block) in blocks.iter().enumerate() {
+        match block {
+            Block::Paragraph(range) => {
+                let text = markdown[range.clone()].trim_end();
+                if let Some(caps) = FIGURE.captures(text) {
+                    labels.insert(caps["label"].to_string());
+                    splices.push((
+                        range.start..range.start + text.len(),
+                        format!(
+                            "<figure id=\"{}\">\n\n{}<figcaption>{}</figcaption></figure>",
+                            &caps["label"], &caps["image"], &caps["caption"]
+                        ),
+                    ));
+                }
+            }
+            Block::Table(table) => {
+                let caption = [index.checked_sub(1), Some(index + 1)]
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|i| match blocks.get(i) {
+                        Some(Block::Paragraph(range)) => Some(range.clone()),
+                        _ => None,
+                    })
+                    .find_map(|range| {
+                        let text = markdown[range.clone()].trim_end();
+                        TABLE_CAPTION
+                            .captures(text)
+                            .map(|caps| (range.start..range.start + text.len(), caps))
+                    });
+                let Some((caption_range, caps)) = caption else {
+                    continue;
+                };
+                labels.insert(caps["label"].to_string());
+                let open = format!("<figure id=\"{}\">", &caps["label"]);
+                // `<figcaption>` at the start of a line would begin an HTML block and leave
+                // the caption's Markdown unparsed; the template maps `<tf-caption>` onto it.
+                let close = format!("<tf-caption>{}</tf-caption></figure>", &caps["caption"]);
+                let gap = if markdown[..table.end].ends_with('\n') {
+                    "\n"
+                } else {
+                    "\n\n"
+                };
+                if caption_range.start < table.start {
+                    splices.push((caption_range, open));
+                    splices.push((table.end..table.end, format!("{}{}\n", gap, close)));
+                } else {
+                    splices.push((table.start..table.start, format!("{}\n\n", open)));
+                    splices.push((caption_range, close));
+                }
+            }
+            Block::Heading {
+                range,
+                id: Some(id),
+            } if id.starts_with("sec:") => {
+                labels.insert(id.clone());
+                // Only the `{#sec:...}` block goes, so the heading keeps its inline markup
+                let source = &markdown[range.clone()];
+                if let Some(attributes) = ATTRIBUTES
+                    .find_iter(source)
+                    .filter(|attributes| attributes.as_str().contains(&format!("#{}", id)))
+                    .last()
+                {
+                    splices.push((
+                        range.start + attributes.start()..range.start + attributes.end(),
+                        String::new(),
+                    ));
+                }
+                let end = range.start + source.trim_end().len();
+                splices.push((
+                    end..end,
+                    format!(
+                        "\n<!--raw-typst #label(\"{}\") -->",
+                        typst_comment_string(id)
+                    ),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    for caps in EQUATION.captures_iter(markdown) {
+        let matched = caps.get(0).expect("BUG: capture group 0 always exists");
+        if is_protected(&protected, matched.start())
+            || splices
+                .iter()
+                .any(|(range, _)| range.start < matched.end() && matched.start() < range.end)
+        {
+            continue;
+        }
+        labels.insert(caps["label"].to_string());
+        splices.push((
+            matched.range(),
+            format!(
+                "<!--raw-typst #crossref_equation(\"{}\", \"{}\") -->",
+                typst_comment_string(caps["math"].trim()),
+                &caps["label"]
+            ),
+        ));
+    }
+
+    let edit_map = EditMap::from_splices(
+        splices
+            .iter()
+            .map(|(range, text)| (range.clone(), text.len())),
+    );
+    (splice(markdown, splices), labels, edit_map)
+}
+
+/// Rewrite references to defined labels as `[@label]` and escape the rest.
+fn rewrite_references(
+    markdown: &str,
+    labels: &HashSet<String>,
+) -> (String, EditMap, Vec<UnresolvedRef>) {
+    let Scan { protected, .. } = scan(markdown);
+    let mut unresolved = Vec::new();
+    let mut splices = Vec::new();
+
+    for caps in REFERENCE.captures_iter(markdown) {
+        let matched = caps.get(0).expect("BUG: capture group 0 always exists");
+        let escaped_or_inside_word = markdown[..matched.start()]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '\\');
+        if escaped_or_inside_word || is_protected(&protected, matched.start()) {
+            continue;
+        }
+
+        let replacement = if let Some(group) = caps.name("group") {
+            let mut all_resolved = true;
+            let references: Vec<String> = group
+                .as_str()
+                .split(';')
+                .map(|reference| {
+                    let label = reference.trim().trim_start_matches('@');
+                    if labels.contains(label) {
+                        format!("[@{}]", label)
+                    } else {
+                        all_resolved = false;
+                        unresolved.push(UnresolvedRef {
+                            offset: group.start() + reference_offset(group.as_str(), reference),
+                            label: label.to_string(),
+                        });
+                        format!("@{}", label)
+                    }
+                })
+                .collect();
+            if all_resolved && references.len() == 1 {
+                continue;
+            }
+            references.join(", ")
+        } else {
+            let bare = &caps["bare"];
+            let label = &bare[1..];
+            if !labels.contains(label) {
+                unresolved.push(UnresolvedRef {
+                    offset: matched.start(),
+                    label: label.to_string(),
+                });
+                continue;
+            }
+            format!("[{}]", bare)
+        };
+        splices.push((matched.range(), replacement));
+    }
+
+    let edit_map = EditMap::from_splices(
+        splices
+            .iter()
+            .map(|(range, text)| (range.clone(), text.len())),
+    );
+    (splice(markdown, splices), edit_map, unresolved)
+}
+
+/// Offset of the `@` of `reference`, one `;`-separated part of `group`.
+fn reference_offset(group: &str, reference: &str) -> usize {
+    let part_start = reference.as_ptr() as usize - group.as_ptr() as usize;
+    part_start + reference.len() - reference.trim_start().len()
+}
+
+fn splice(markdown: &str, mut splices: Vec<(Range<usize>, String)>) -> String {
+    splices.sort_by_key(|(range, _)| range.start);
+    let mut output = String::with_capacity(markdown.len());
+    let mut last = 0;
+    for (range, text) in splices {
+        output.push_str(&markdown[last..range.start]);
+        output.push_str(&text);
+        last = range.end;
+    }
+    output.push_str(&markdown[last..]);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn figure_labels_become_figures() {
+        let output = apply("![The *pipeline*](arch.png){#fig:arch}\n");
+        assert_eq!(
+            output.markdown,
+            "<figure id=\"fig:arch\">\n\n![The *pipeline*](arch.png)\
+             <figcaption>The *pipeline*</figcaption></figure>\n"
+        );
+    }
+
+    #[test]
+    fn table_captions_wrap_their_table() {
+        let table = "| a | b |\n|---|---|\n| 1 | 2 |\n";
+        let wrapped = "<figure id=\"tbl:times\">\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\n\
+             <tf-caption>Timings</tf-caption></figure>";
+
+        let before = apply(&format!(": Timings {{#tbl:times}}\n\n{}", table));
+        assert!(before.markdown.contains(wrapped));
+
+        let after = apply(&format!("{}\nTable: Timings {{#tbl:times}}\n", table));
+        assert!(after.markdown.contains(wrapped));
+    }
+
+    #[test]
+    fn equation_labels_become_raw_typst() {
+        let output = apply("$$E = mc^2$$ {#eq:energy}\n");
+        assert_eq!(
+            output.markdown,
+            "<!--raw-typst #crossref_equation(\"E = mc^2\", \"eq:energy\") -->\n"
+        );
+    }
+
+    #[test]
+    fn equation_labels_do_not_swallow_earlier_display_math() {
+        let output = apply("$$a^2$$\n\nSome text.\n\n$$b^2$$ {#eq:b}\n");
+        assert_eq!(
+            output.markdown,
+            "$$a^2$$\n\nSome text.\n\n<!--raw-typst #crossref_equation(\"b^2\", \"eq:b\") -->\n"
+        );
+    }
+
+    #[test]
+    fn references_to_defined_labels_resolve() {
+        let output = apply(
+            "![Arch](arch.png){#fig:arch}\n\n$$x$$ {#eq:x}\n\n\
+             See @fig:arch, [@fig:arch; @eq:x] and [@eq:x]. Not `@fig:arch` or me@fig:arch.\n",
+        );
+        assert!(output.markdown.contains(
+            "See [@fig:arch], [@fig:arch], [@eq:x] and [@eq:x]. Not `@fig:arch` or me@fig:arch."
+        ));
+        assert!(output.unresolved.is_empty());
+    }
+
+    #[test]
+    fn unknown_references_are_reported_at_their_source_offset() {
+        let markdown = "# Intro {#sec:intro}\n\nSee @sec:intro and @fig:missing.\n";
+        let output = apply(markdown);
+
+        let missing = markdown.find("@fig:missing").unwrap();
+        assert_eq!(
+            output.unresolved,
+            vec![UnresolvedRef {
+                offset: missing,
+                label: "fig:missing".to_string()
+            }]
+        );
+        let at = output.markdown.find("@fig:missing").unwrap();
+        assert_eq!(output.edit_map.to_original(at), missing);
+    }
+
+    #[test]
+    fn heading_labels_keep_inline_markup() {
+        let output =
+            apply("# The *`a < b`* [case](https://example.com) {#sec:case}\n\nSee @sec:case.\n");
+        assert_eq!(
+            output.markdown,
+            "# The *`a < b`* [case](https://example.com)\n\
+             <!--raw-typst #label(\"sec:case\") -->\n\nSee [@sec:case].\n"
+        );
+
+        let setext = apply("Results & *more* {#sec:results}\n---\n");
+        assert_eq!(
+            setext.markdown,
+            "Results & *more*\n---\n<!--raw-typst #label(\"sec:results\") -->\n"
+        );
+        let label = setext.markdown.lines().nth(2).unwrap();
+        assert_eq!(
+            heading_label(&Event::Html(label.into())),
+            Some("sec:results".to_string())
+        );
+    }
+
+    #[test]
+    fn figure_caption_stops_at_the_image_target() {
+        let output = apply("![One](a.png) and ![Two](b.png){#fig:b}\n");
+        assert!(!output.markdown.contains("<figure"));
+
+        let output = apply("![A *bold* claim](a.png){#fig:a}\n");
+        assert!(output
+            .markdown
+            .contains("<figcaption>A *bold* claim</figcaption>"));
+    }
+}
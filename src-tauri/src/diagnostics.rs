@@ -0,0 +1,340 @@
+//! Structured Typst diagnostics.
+//!
+//! Both compiler backends report problems as [`Diagnostic`]s: the external binary runs with
+//! `--diagnostic-format short` and its output is parsed here, the embedded compiler builds
+//! them from its spans directly. Positions that fall inside the build `content.md` are
+//! translated back to the user's document with the preprocessor's edit map, so the editor can
+//! underline the offending line.
+//!
+//! cmarker evaluates the converted document as a single string, so errors in the content are
+//! usually reported inside the package rather than `content.md`. Those are located by
+//! [`locate_by_probing`], which recompiles prefixes of the document that end at a preview
+//! anchor until it finds the block that introduces the error.
+
+use crate::preprocessor::{offset_to_line_column, PreprocessorOutput};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub hints: Vec<String>,
+    /// File the compiler reported, relative to the Typst root (e.g. a template or package).
+    pub file: Option<String>,
+    /// 1-based position in `file`.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// 1-based position in the user's document, when the diagnostic points into its content.
+    pub source_line: Option<usize>,
+    pub source_column: Option<usize>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+            hints: Vec::new(),
+            file: None,
+            line: None,
+            column: None,
+            source_line: None,
+            source_column: None,
+        }
+    }
+
+    pub fn at(mut self, file: impl Into<String>, line: usize, column: usize) -> Self {
+        self.file = Some(file.into());
+        self.line = Some(line);
+        self.column = Some(column);
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    /// Same layout as Typst's short diagnostic format.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(file), Some(line), Some(column)) = (&self.file, self.line, self.column) {
+            write!(f, "{}:{}:{}: ", file, line, column)?;
+        }
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}", severity, self.message)?;
+        for hint in &self.hints {
+            write!(f, "\n  hint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// A failed Typst compile. `Display` keeps the full compiler output for logs and the
+/// command's error string; `diagnostics` carries the parsed form for the editor.
+#[derive(Debug, thiserror::Error)]
+#[error("{output}")]
+pub struct TypstCompileError {
+    pub output: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Most probe compiles spent locating a single error; enough to bisect 65536 blocks.
+const MAX_PROBES: usize = 16;
+
+lazy_static! {
+    static ref LOCATED: Regex = Regex::new(concat!(
+        r"^(?P<file>.+?):(?P<line>\d+):(?P<column>\d+): ",
+        r"(?P<severity>error|warning)(?:\[[^\]]*\])?: (?P<message>.*)$"
+    ))
+    .expect("BUG: Invalid regex pattern for located diagnostics");
+    static ref UNLOCATED: Regex =
+        Regex::new(r"^(?P<severity>error|warning)(?:\[[^\]]*\])?: (?P<message>.*)$")
+            .expect("BUG: Invalid regex pattern for diagnostics");
+    static ref HINT: Regex = Regex::new(r"^\s*(?:.+?:\d+:\d+: )?hint: (?P<hint>.*)$")
+        .expect("BUG: Invalid regex pattern for diagnostic hints");
+}
+
+/// Parse diagnostics in Typst's short format (`file:line:col: error: message`, optionally
+/// followed by `hint: ...` lines). Lines that are not diagnostics are ignored.
+pub fn parse_short(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for line in output.lines() {
+        let line = line.trim_end();
+        if let Some(caps) = HINT.captures(line) {
+            if let Some(last) = diagnostics.last_mut() {
+                last.hints.push(caps["hint"].to_string());
+            }
+        } else if let Some(caps) = LOCATED.captures(line) {
+            let diagnostic = Diagnostic::new(severity(&caps["severity"]), &caps["message"]);
+            diagnostics.push(match (caps["line"].parse(), caps["column"].parse()) {
+                (Ok(line), Ok(column)) => diagnostic.at(&caps["file"], line, column),
+                _ => diagnostic,
+            });
+        } else if let Some(caps) = UNLOCATED.captures(line) {
+            diagnostics.push(Diagnostic::new(
+                severity(&caps["severity"]),
+                &caps["message"],
+            ));
+        }
+    }
+    diagnostics
+}
+
+fn severity(text: &str) -> Severity {
+    if text == "warning" {
+        Severity::Warning
+    } else {
+        Severity::Error
+    }
+}
+
+/// Fill in `source_line`/`source_column` for diagnostics that point into the build
+/// `content.md`, using the preprocessor's edit map to undo injected anchors, diagram placeholders
+/// and rewritten image paths. `source` is the user's original text.
+pub fn map_to_source(
+    diagnostics: &mut [Diagnostic],
+    preprocess: &PreprocessorOutput,
+    source: &str,
+) {
+    for diagnostic in diagnostics {
+        let in_content = diagnostic
+            .file
+            .as_deref()
+            .and_then(|file| Path::new(file).file_name())
+            .is_some_and(|name| name == "content.md");
+        let (Some(line), Some(column)) = (diagnostic.line, diagnostic.column) else {
+            continue;
+        };
+        if !in_content {
+            continue;
+        }
+        let transformed = line_column_to_offset(&preprocess.markdown, line, column);
+        let original = preprocess.to_original(transformed).min(source.len());
+        let (line, column) = source_position(source, original);
+        diagnostic.source_line = Some(line);
+        diagnostic.source_column = Some(column);
+    }
+}
+
+/// Fill in `source_line`/`source_column` for errors that [`map_to_source`] could not place,
+/// by bisecting over the preview anchors of `preprocess.markdown`. `compile` compiles the
+/// document with the given content and returns its diagnostics (empty on success), or `None`
+/// to give up (e.g. when the render was cancelled). An error is blamed on the anchor of the
+/// last block of the shortest prefix that still reports it; errors that an empty document
+/// reports too come from the template and are left alone.
+pub fn locate_by_probing(
+    diagnostics: &mut [Diagnostic],
+    preprocess: &PreprocessorOutput,
+    source: &str,
+    mut compile: impl FnMut(&str) -> Option<Vec<Diagnostic>>,
+) {
+    let markdown = &preprocess.markdown;
+    // (start of the anchor markup, start of the block it anchors)
+    let anchors: Vec<(usize, usize)> = preprocess
+        .anchors
+        .iter()
+        .filter_map(|anchor| {
+            let markup = format!("<!--raw-typst #label(\"{}\") -->\n", anchor.id);
+            markdown
+                .find(&markup)
+                .map(|start| (start, start + markup.len()))
+        })
+        .collect();
+    if anchors.is_empty() {
+        return;
+    }
+    // Prefix `k` ends right before anchor `k`; prefix `anchors.len()` is the whole document.
+    let cut = |k: usize| anchors.get(k).map_or(markdown.len(), |&(start, _)| start);
+    let mut probes: HashMap<usize, Vec<Diagnostic>> = HashMap::new();
+
+    for diagnostic in diagnostics {
+        if diagnostic.severity != Severity::Error || diagnostic.source_line.is_some() {
+            continue;
+        }
+        let (mut low, mut high) = (0, anchors.len());
+        let mut remaining = MAX_PROBES;
+        while low < high && remaining > 0 {
+            let middle = (low + high) / 2;
+            let found = match probes.entry(middle) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let Some(found) = compile(&markdown[..cut(middle)]) else {
+                        return;
+                    };
+                    remaining -= 1;
+                    entry.insert(found)
+                }
+            };
+            if found
+                .iter()
+                .any(|probe| probe.message == diagnostic.message)
+            {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        if low < high || low == 0 {
+            continue;
+        }
+        let block = anchors[low - 1].1;
+        let original = preprocess.to_original(block).min(source.len());
+        let (line, column) = source_position(source, original);
+        diagnostic.source_line = Some(line);
+        diagnostic.source_column = Some(column);
+    }
+}
+
+/// Byte offset of a 1-based line/column (columns count characters), clamped to the text.
+fn line_column_to_offset(text: &str, line: usize, column: usize) -> usize {
+    let mut offset = 0;
+    for (index, current) in text.split_inclusive('\n').enumerate() {
+        if index + 1 == line {
+            return offset
+                + current
+                    .char_indices()
+                    .nth(column.saturating_sub(1))
+                    .map(|(i, _)| i)
+                    .unwrap_or(current.trim_end_matches('\n').len());
+        }
+        offset += current.len();
+    }
+    text.len()
+}
+
+/// 1-based line and column (in characters) of a byte offset, clamped to the text.
+fn source_position(text: &str, offset: usize) -> (usize, usize) {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let (line, column) = offset_to_line_column(text, offset);
+    (line + 1, column + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagrams::DiagramRegistry;
+    use crate::preprocessor::preprocess_markdown;
+
+    #[test]
+    fn short_diagnostics_map_back_to_the_document() {
+        let source =
+            "# Title\n\nSome text\n\n```tikz\n\\draw (0,0) -- (1,1);\n```\n\nBroken #here\n";
+        let preprocess =
+            preprocess_markdown(source, None, Path::new("."), &DiagramRegistry::builtin()).unwrap();
+        let (line, _) = preprocess
+            .markdown
+            .lines()
+            .enumerate()
+            .find(|(_, text)| text.contains("Broken"))
+            .unwrap();
+
+        let output = format!(
+            "warning: unused package\n\
+             .build/workspaces/a/preview/content.md:{}:8: error: unknown variable: here\n  \
+             hint: escape it with a backslash\n\
+             something else",
+            line + 1
+        );
+        let mut diagnostics = parse_short(&output);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[1].hints, vec!["escape it with a backslash"]);
+
+        map_to_source(&mut diagnostics, &preprocess, source);
+        assert_eq!(diagnostics[0].source_line, None);
+        assert_eq!(diagnostics[1].source_line, Some(9));
+        assert_eq!(diagnostics[1].source_column, Some(8));
+    }
+
+    #[cfg(feature = "embedded-typst")]
+    #[test]
+    fn errors_reported_inside_cmarker_are_located_by_probing() {
+        use std::fs;
+
+        let dir = std::env::temp_dir().join(format!("tf-diagnostics-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("tideflow.typ"),
+            "#import \"@preview/cmarker:0.1.6\": render\n#render(read(\"content.md\"))\n",
+        )
+        .unwrap();
+        let packages = [Path::new(env!("CARGO_MANIFEST_DIR")).join("content/typst/packages")];
+        let compile = |markdown: &str| {
+            fs::write(dir.join("content.md"), markdown).unwrap();
+            Some(
+                match crate::typst_world::compile_pdf(&dir, &dir, &packages, "out.pdf") {
+                    Ok(_) => Vec::new(),
+                    Err(e) => e.downcast::<TypstCompileError>().unwrap().diagnostics,
+                },
+            )
+        };
+
+        let source = "# Title\n\nFine paragraph.\n\n- item\n- ![Missing](missing.png)\n\nAfter.\n";
+        let preprocess =
+            preprocess_markdown(source, None, &dir, &DiagramRegistry::builtin()).unwrap();
+        let mut diagnostics = compile(&preprocess.markdown).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_ne!(diagnostics[0].file.as_deref(), Some("content.md"));
+        map_to_source(&mut diagnostics, &preprocess, source);
+        assert_eq!(diagnostics[0].source_line, None);
+
+        locate_by_probing(&mut diagnostics, &preprocess, source, compile);
+        assert_eq!(diagnostics[0].source_line, Some(6));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,411 @@
+//! Renderers that run a command line: the built-in Graphviz, PlantUML, Mermaid and gnuplot
+//! support, and the languages configured in `diagrams.json`:
+//!
+//! ```json
+//! {
+//!   "ditaa": { "command": ["ditaa", "{input}", "{output}", "--svg"], "input_extension": "txt" },
+//!   "pikchr": { "command": "pikchr --svg-only -" }
+//! }
+//! ```
+//!
+//! `{input}` is a file holding the fence body and `{output}` the file the tool must write; a
+//! command without them reads the body from stdin or writes the image to stdout. `{format}` is
+//! the requested image format (`svg` or `png`). Tools that can only write PDF set
+//! `"output": "pdf"`, and the PDF is converted like a TikZ diagram. A command given as one
+//! string is split on whitespace. Programs are looked up on `PATH` and among the bundled tools.
+
+use super::{tool_command, DiagramContext, DiagramRenderer, DiagramSettings, Rendered};
+use crate::preprocessor::DiagramBlockMeta;
+use crate::render_scheduler::RenderCancelled;
+use crate::utils::paths;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Longest a tool may take for one diagram before it is killed.
+const TOOL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs `program` with `args` after substituting the placeholders.
+#[derive(Debug, Clone)]
+pub struct CommandRenderer {
+    program: String,
+    args: Vec<String>,
+    input_extension: String,
+    /// The tool writes a PDF, which the pipeline converts to the requested format.
+    pdf_output: bool,
+    /// Hint added to the error when the program is missing.
+    install_hint: &'static str,
+}
+
+impl CommandRenderer {
+    fn new(program: &str, args: &[&str], input_extension: &str) -> Self {
+        Self {
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            input_extension: input_extension.to_string(),
+            pdf_output: false,
+            install_hint: "",
+        }
+    }
+
+    fn install_hint(mut self, hint: &'static str) -> Self {
+        self.install_hint = hint;
+        self
+    }
+
+    fn resolve_program(&self, context: &DiagramContext) -> Result<PathBuf> {
+        let program = Path::new(&self.program);
+        if program.components().count() > 1 {
+            if program.exists() {
+                return Ok(program.to_path_buf());
+            }
+        } else if let Some(found) = paths::find_on_system_path(&self.program).or_else(|| {
+            context
+                .env
+                .resource_dir()
+                .map(|dir| paths::bundled_binary_path(&dir, &self.program))
+                .filter(|path| path.exists())
+        }) {
+            return Ok(found);
+        }
+        Err(anyhow!(
+            "`{}` was not found.{}",
+            self.program,
+            if self.install_hint.is_empty() {
+                String::new()
+            } else {
+                format!(" {}", self.install_hint)
+            }
+        ))
+    }
+
+    /// Run the command with `extra` placeholders (e.g. a jar path) besides the standard ones.
+    fn run(
+        &self,
+        context: &DiagramContext,
+        block: &DiagramBlockMeta,
+        work_dir: &Path,
+        extra: &[(&str, String)],
+    ) -> Result<Rendered> {
+        let program = self.resolve_program(context)?;
+        let format = if self.pdf_output {
+            "pdf"
+        } else {
+            block.format.asset_extension()
+        };
+        let input = work_dir.join(format!("diagram.{}", self.input_extension));
+        let output = work_dir.join(format!("diagram.{}", format));
+        let uses = |placeholder: &str| self.args.iter().any(|arg| arg.contains(placeholder));
+        let (file_input, file_output) = (uses("{input}"), uses("{output}"));
+
+        let mut replacements = vec![
+            ("{input}", input.to_string_lossy().to_string()),
+            ("{output}", output.to_string_lossy().to_string()),
+            ("{format}", format.to_string()),
+        ];
+        replacements.extend(extra.iter().cloned());
+        let args = self.args.iter().map(|arg| {
+            replacements
+                .iter()
+                .fold(arg.clone(), |arg, (from, to)| arg.replace(from, to))
+        });
+
+        if file_input {
+            fs::write(&input, &block.source)?;
+        }
+        let mut child = tool_command(&program)
+            .current_dir(work_dir)
+            .args(args)
+            .stdin(if file_input {
+                Stdio::null()
+            } else {
+                Stdio::piped()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("failed to run `{}`: {}", self.program, e))?;
+        // The body is written and the output read on threads of their own, so a tool that
+        // fills its stdout before reading all of stdin cannot deadlock with us.
+        let writer = child.stdin.take().map(|mut stdin| {
+            let source = block.source.clone();
+            thread::spawn(move || {
+                // A tool that exits early closes the pipe; its exit status explains why.
+                let _ = stdin.write_all(source.as_bytes());
+            })
+        });
+        let stdout = read_to_end(child.stdout.take());
+        let stderr = read_to_end(child.stderr.take());
+
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if context.cancel.is_cancelled() {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(RenderCancelled.into());
+            }
+            if start.elapsed() > TOOL_TIMEOUT {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(anyhow!(
+                    "`{}` timed out after {} seconds",
+                    self.program,
+                    TOOL_TIMEOUT.as_secs()
+                ));
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+        if let Some(writer) = writer {
+            let _ = writer.join();
+        }
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        if !status.success() {
+            return Err(anyhow!(
+                "`{}` failed ({}): {}",
+                self.program,
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            ));
+        }
+
+        let bytes = if file_output {
+            fs::read(&output).map_err(|e| {
+                anyhow!(
+                    "`{}` did not write {}: {}",
+                    self.program,
+                    output.display(),
+                    e
+                )
+            })?
+        } else {
+            stdout
+        };
+        if bytes.is_empty() {
+            return Err(anyhow!("`{}` produced no output", self.program));
+        }
+        Ok(if self.pdf_output {
+            Rendered::Pdf(bytes)
+        } else {
+            Rendered::Asset(bytes)
+        })
+    }
+}
+
+/// Collect everything a child writes to `pipe` on a separate thread.
+fn read_to_end<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+impl DiagramRenderer for CommandRenderer {
+    fn cache_salt(&self, _settings: &DiagramSettings) -> String {
+        format!(
+            "{} {} ({})",
+            self.program,
+            self.args.join(" "),
+            self.pdf_output
+        )
+    }
+
+    fn outputs_pdf(&self) -> bool {
+        self.pdf_output
+    }
+
+    fn render(
+        &self,
+        context: &DiagramContext,
+        block: &DiagramBlockMeta,
+        work_dir: &Path,
+    ) -> Result<Rendered> {
+        self.run(context, block, work_dir, &[])
+    }
+}
+
+/// PlantUML through `java -jar plantuml.jar`. The jar is taken from `PLANTUML_JAR`, the
+/// content directory, or the bundled tools.
+struct PlantUmlRenderer(CommandRenderer);
+
+impl PlantUmlRenderer {
+    fn find_jar(context: &DiagramContext) -> Option<PathBuf> {
+        std::env::var_os("PLANTUML_JAR")
+            .map(PathBuf::from)
+            .into_iter()
+            .chain(
+                context
+                    .env
+                    .content_dir()
+                    .ok()
+                    .map(|dir| dir.join("plantuml.jar")),
+            )
+            .chain(
+                context
+                    .env
+                    .resource_dir()
+                    .map(|dir| dir.join("bin").join("plantuml").join("plantuml.jar")),
+            )
+            .find(|path| path.is_file())
+    }
+}
+
+impl DiagramRenderer for PlantUmlRenderer {
+    fn cache_salt(&self, settings: &DiagramSettings) -> String {
+        self.0.cache_salt(settings)
+    }
+
+    fn outputs_pdf(&self) -> bool {
+        self.0.outputs_pdf()
+    }
+
+    fn render(
+        &self,
+        context: &DiagramContext,
+        block: &DiagramBlockMeta,
+        work_dir: &Path,
+    ) -> Result<Rendered> {
+        let jar = Self::find_jar(context).ok_or_else(|| {
+            anyhow!(
+                "plantuml.jar was not found. Put it in the content directory or set PLANTUML_JAR."
+            )
+        })?;
+        let jar = jar.to_string_lossy().to_string();
+        self.0.run(context, block, work_dir, &[("{jar}", jar)])
+    }
+}
+
+/// Built-in renderers with the fence languages they handle.
+pub(super) fn builtin() -> Vec<(&'static [&'static str], Arc<dyn DiagramRenderer>)> {
+    vec![
+        (
+            &["dot", "graphviz"],
+            Arc::new(
+                CommandRenderer::new("dot", &["-T{format}"], "dot")
+                    .install_hint("Install Graphviz to render dot diagrams."),
+            ),
+        ),
+        (
+            &["plantuml", "puml"],
+            Arc::new(PlantUmlRenderer(
+                CommandRenderer::new(
+                    "java",
+                    &["-jar", "{jar}", "-pipe", "-charset", "UTF-8", "-t{format}"],
+                    "puml",
+                )
+                .install_hint("PlantUML needs a Java runtime."),
+            )),
+        ),
+        (
+            &["mermaid"],
+            Arc::new(
+                CommandRenderer::new(
+                    "mmdc",
+                    &["-i", "{input}", "-o", "{output}", "-b", "transparent"],
+                    "mmd",
+                )
+                .install_hint("Install the Mermaid CLI (npm install -g @mermaid-js/mermaid-cli)."),
+            ),
+        ),
+        (
+            &["gnuplot"],
+            Arc::new(
+                CommandRenderer::new(
+                    "gnuplot",
+                    &[
+                        "-e",
+                        "set terminal {format}; set output '{output}'",
+                        "{input}",
+                    ],
+                    "gp",
+                )
+                .install_hint("Install gnuplot to render plots."),
+            ),
+        ),
+    ]
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum CommandLine {
+    Words(Vec<String>),
+    Line(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandConfig {
+    command: CommandLine,
+    #[serde(default)]
+    input_extension: Option<String>,
+    #[serde(default)]
+    output: Option<String>,
+}
+
+/// Renderers configured in `diagrams.json`, by fence language.
+pub(super) type ConfiguredRenderers = Vec<(String, CommandRenderer)>;
+
+/// Parse `diagrams.json`. Entries that cannot be used are skipped with a warning.
+pub(super) fn parse_config(text: &str) -> Result<(ConfiguredRenderers, Vec<String>)> {
+    let entries: BTreeMap<String, serde_json::Value> = serde_json::from_str(text)?;
+    let mut renderers = Vec::new();
+    let mut warnings = Vec::new();
+    for (language, entry) in entries {
+        let config = match serde_json::from_value::<CommandConfig>(entry) {
+            Ok(config) => config,
+            Err(e) => {
+                warnings.push(format!(
+                    "Diagram language `{}` is not valid: {}",
+                    language, e
+                ));
+                continue;
+            }
+        };
+        let mut words = match config.command {
+            CommandLine::Words(words) => words,
+            CommandLine::Line(line) => line.split_whitespace().map(str::to_string).collect(),
+        };
+        if words.is_empty() {
+            warnings.push(format!(
+                "Diagram language `{}` has an empty command",
+                language
+            ));
+            continue;
+        }
+        let pdf_output = match config.output.as_deref() {
+            None | Some("image") => false,
+            Some("pdf") => true,
+            Some(other) => {
+                warnings.push(format!(
+                    "Diagram language `{}`: unknown output `{}`, expected `image` or `pdf`",
+                    language, other
+                ));
+                continue;
+            }
+        };
+        let program = words.remove(0);
+        renderers.push((
+            language,
+            CommandRenderer {
+                program,
+                args: words,
+                input_extension: config.input_extension.unwrap_or_else(|| "txt".to_string()),
+                pdf_output,
+                install_hint: "",
+            },
+        ));
+    }
+    Ok((renderers, warnings))
+}
@@ -0,0 +1,231 @@
+//! Turning renderer output into the asset format Typst loads: PDFs become SVG through
+//! `dvisvgm` or PNG through Pdfium, and failed diagrams get a generated error box. Vector
+//! diagrams are never rasterized behind the user's back: without `dvisvgm` the preprocessor
+//! already asks for PNG and warns (see [`super::DiagramRegistry::rasterizes`]), and a PDF
+//! `dvisvgm` cannot convert fails like any other diagram.
+
+use super::{tool_command, Rendered};
+use crate::environment::RenderEnvironment;
+use crate::preprocessor::DiagramFormat;
+use anyhow::{anyhow, Result};
+use image as image_crate;
+use pdfium_render::prelude::*;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Converts renderer output on the thread that runs the pipeline. Pdfium is only loaded once
+/// a PDF actually needs it.
+pub(super) struct Converter {
+    pdfium_lib: Option<PathBuf>,
+    pdfium: Option<Pdfium>,
+    dvisvgm: Option<PathBuf>,
+}
+
+impl Converter {
+    pub fn new(env: &dyn RenderEnvironment) -> Self {
+        Self {
+            pdfium_lib: env.pdfium_library_path().ok(),
+            pdfium: None,
+            dvisvgm: env.dvisvgm_path(),
+        }
+    }
+
+    pub fn has_dvisvgm(&self) -> bool {
+        self.dvisvgm.is_some()
+    }
+
+    /// Bytes of the asset for `rendered`.
+    pub fn finish(
+        &mut self,
+        rendered: Rendered,
+        format: DiagramFormat,
+        work_dir: &Path,
+        base_name: &str,
+    ) -> Result<Vec<u8>> {
+        match rendered {
+            Rendered::Asset(bytes) => Ok(bytes),
+            Rendered::Pdf(pdf) => self.convert(&pdf, format, work_dir, base_name),
+        }
+    }
+
+    fn convert(
+        &mut self,
+        pdf_bytes: &[u8],
+        format: DiagramFormat,
+        work_dir: &Path,
+        base_name: &str,
+    ) -> Result<Vec<u8>> {
+        if !format.is_vector() {
+            return pdf_bytes_to_png(self.pdfium()?, pdf_bytes);
+        }
+        let dvisvgm = self.dvisvgm.as_deref().ok_or_else(|| {
+            anyhow!("dvisvgm is needed for vector output. Install it or use `format=png`.")
+        })?;
+        pdf_to_svg(dvisvgm, work_dir, base_name, pdf_bytes)
+    }
+
+    fn pdfium(&mut self) -> Result<&Pdfium> {
+        if self.pdfium.is_none() {
+            let bindings = self
+                .pdfium_lib
+                .as_deref()
+                .map(Pdfium::bind_to_library)
+                .unwrap_or_else(Pdfium::bind_to_system_library)
+                .or_else(|_| Pdfium::bind_to_system_library())
+                .map_err(|e| anyhow!("Failed to load Pdfium: {e}"))?;
+            self.pdfium = Some(Pdfium::new(bindings));
+        }
+        Ok(self.pdfium.as_ref().expect("BUG: Pdfium was just bound"))
+    }
+}
+
+/// Convert a single-page PDF to SVG with glyphs as paths, so the SVG needs no fonts.
+fn pdf_to_svg(
+    dvisvgm: &Path,
+    work_dir: &Path,
+    base_name: &str,
+    pdf_bytes: &[u8],
+) -> Result<Vec<u8>> {
+    let output_dir = work_dir.join("out");
+    fs::create_dir_all(&output_dir)?;
+    let pdf_path = output_dir.join(format!("{base_name}.vector.pdf"));
+    let svg_path = output_dir.join(format!("{base_name}.svg"));
+    fs::write(&pdf_path, pdf_bytes)?;
+
+    let output = tool_command(dvisvgm)
+        .current_dir(&output_dir)
+        .arg("--pdf")
+        .arg("--no-fonts")
+        .arg("--page=1")
+        .arg("-o")
+        .arg(&svg_path)
+        .arg(&pdf_path)
+        .output();
+    let _ = fs::remove_file(&pdf_path);
+
+    let output = output?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&svg_path);
+        return Err(anyhow!(
+            "dvisvgm failed (status {}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let svg = fs::read(&svg_path)?;
+    let _ = fs::remove_file(&svg_path);
+    Ok(svg)
+}
+
+/// Rasterize the first page at 288 dpi.
+fn pdf_bytes_to_png(pdfium: &Pdfium, pdf_bytes: &[u8]) -> Result<Vec<u8>> {
+    let document = pdfium
+        .load_pdf_from_byte_slice(pdf_bytes, None)
+        .map_err(|e| anyhow!("Failed to load diagram PDF: {e}"))?;
+    let page = document
+        .pages()
+        .get(0)
+        .map_err(|_| anyhow!("Diagram PDF did not contain any pages"))?;
+
+    let dpi = 288.0;
+    let width_px = ((page.width().value / 72.0) * dpi)
+        .clamp(1.0, 4096.0)
+        .round() as i32;
+    let height_px = ((page.height().value / 72.0) * dpi)
+        .clamp(1.0, 4096.0)
+        .round() as i32;
+
+    let render_config = PdfRenderConfig::new()
+        .set_target_width(width_px)
+        .set_target_height(height_px)
+        .use_print_quality(true)
+        .render_annotations(true)
+        .render_form_data(true);
+
+    let bitmap = page
+        .render_with_config(&render_config)
+        .map_err(|e| anyhow!("Failed to rasterize diagram PDF: {e}"))?;
+
+    let image = bitmap.as_image();
+    let rgba = image.to_rgba8();
+    let (w, h) = (rgba.width(), rgba.height());
+    encode_png(&rgba.into_raw(), w, h)
+}
+
+fn encode_png(rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+    let mut png_bytes = Vec::new();
+    {
+        let mut cursor = Cursor::new(&mut png_bytes);
+        let encoder = image_crate::codecs::png::PngEncoder::new(&mut cursor);
+        use image_crate::ImageEncoder;
+        encoder
+            .write_image(rgba, width, height, image_crate::ColorType::Rgba8)
+            .map_err(|e| anyhow!("Failed to encode diagram PNG: {e}"))?;
+    }
+    Ok(png_bytes)
+}
+
+const ERROR_FILL: [u8; 4] = [254, 226, 226, 255];
+const ERROR_STROKE: [u8; 4] = [220, 38, 38, 255];
+
+/// Error box for a diagram that failed. SVG boxes carry the start of the message; PNG boxes
+/// are plain, the message reaches the user through the `diagram-error` event.
+pub(super) fn error_image(format: DiagramFormat, language: &str, message: &str) -> Result<Vec<u8>> {
+    if !format.is_vector() {
+        let (width, height) = (960, 160);
+        let image = image_crate::RgbaImage::from_fn(width, height, |x, y| {
+            let border = x < 4 || y < 4 || x >= width - 4 || y >= height - 4;
+            image_crate::Rgba(if border { ERROR_STROKE } else { ERROR_FILL })
+        });
+        return encode_png(image.as_raw(), width, height);
+    }
+
+    const LINE_CHARS: usize = 80;
+    const MAX_LINES: usize = 6;
+    let mut lines = vec![format!("{} diagram failed:", language)];
+    for line in message
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        let chars: Vec<char> = line.chars().collect();
+        lines.extend(chars.chunks(LINE_CHARS).map(|chunk| chunk.iter().collect()));
+    }
+    if lines.len() > MAX_LINES {
+        lines.truncate(MAX_LINES);
+        lines[MAX_LINES - 1].push('…');
+    }
+
+    let (width, line_height) = (480.0, 12.0);
+    let height = 16.0 + line_height * lines.len() as f32;
+    let mut svg = format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}pt" height="{h}pt" "#,
+            r#"viewBox="0 0 {w} {h}">"#,
+            r#"<rect x="1" y="1" width="{rw}" height="{rh}" "#,
+            r##"fill="#fee2e2" stroke="#dc2626" stroke-width="2"/>"##,
+            r##"<g font-family="monospace" font-size="9" fill="#b91c1c">"##
+        ),
+        w = width,
+        h = height,
+        rw = width - 2.0,
+        rh = height - 2.0
+    );
+    for (i, line) in lines.iter().enumerate() {
+        svg.push_str(&format!(
+            r#"<text x="8" y="{}" xml:space="preserve">{}</text>"#,
+            20.0 + line_height * i as f32,
+            escape_xml(line)
+        ));
+    }
+    svg.push_str("</g></svg>");
+    Ok(svg.into_bytes())
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
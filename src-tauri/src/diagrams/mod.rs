@@ -0,0 +1,593 @@
+//! Diagrams drawn by external tools from fenced code blocks.
+//!
+//! The preprocessor replaces every fence whose language has a [`DiagramRenderer`] with a
+//! `#diagram_render` placeholder pointing at `diagrams/<id>.<ext>`. Before compiling,
+//! [`prepare_diagram_assets`] renders the blocks that are not cached yet on a bounded pool of
+//! worker threads, converts PDF output to the requested format, caches the results by content
+//! and puts an error box in place of diagrams that failed. Failures are not cached, so they
+//! are reported again (and retried, e.g. once the tool is installed) on every render. TikZ,
+//! Graphviz, PlantUML, Mermaid and gnuplot are built in; `diagrams.json` in the content
+//! directory maps further fence languages to command lines (see [`command`]).
+
+mod command;
+mod convert;
+mod tikz;
+
+use crate::environment::RenderEnvironment;
+use crate::preprocessor::DiagramBlockMeta;
+use crate::render_pipeline::write_if_changed;
+use crate::render_scheduler::CancelToken;
+use anyhow::{Context, Result};
+use convert::Converter;
+use log::error;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+#[cfg(target_os = "windows")]
+use std::os::windows::process::CommandExt;
+
+/// User configuration of command line renderers, in the content directory.
+pub const CONFIG_FILE: &str = "diagrams.json";
+
+/// Bump when the pipeline's output changes for the same inputs.
+const PIPELINE_CACHE_VERSION: u32 = 7;
+
+/// What a renderer produced for one block.
+pub enum Rendered {
+    /// Bytes already in the block's asset format.
+    Asset(Vec<u8>),
+    /// A one-page PDF, converted to the asset format by the pipeline.
+    Pdf(Vec<u8>),
+}
+
+/// Draws the diagrams of one fence language. Renderers run on worker threads, each block in a
+/// scratch directory of its own.
+pub trait DiagramRenderer: Send + Sync {
+    /// Anything besides the block that changes the output (settings, command lines), so that
+    /// changing it invalidates cached diagrams.
+    fn cache_salt(&self, _settings: &DiagramSettings) -> String {
+        String::new()
+    }
+
+    /// Whether the renderer draws PDFs, which only stay vector when `dvisvgm` can convert them.
+    fn outputs_pdf(&self) -> bool {
+        false
+    }
+
+    fn render(
+        &self,
+        context: &DiagramContext,
+        block: &DiagramBlockMeta,
+        work_dir: &Path,
+    ) -> Result<Rendered>;
+
+    /// Shown in place of a diagram that failed to render.
+    fn render_error(
+        &self,
+        _context: &DiagramContext,
+        block: &DiagramBlockMeta,
+        message: &str,
+        _work_dir: &Path,
+    ) -> Result<Rendered> {
+        convert::error_image(block.format, &block.language, message).map(Rendered::Asset)
+    }
+}
+
+/// What renderers get to work with besides their block.
+pub struct DiagramContext<'a> {
+    pub env: &'a dyn RenderEnvironment,
+    pub settings: &'a DiagramSettings,
+    /// Tripped when the render is superseded; running tools are killed.
+    pub cancel: &'a CancelToken,
+}
+
+/// Render-wide diagram settings, resolved once per render.
+#[derive(Debug, Clone, Default)]
+pub struct DiagramSettings {
+    /// Diagrams rendered at once.
+    pub parallel_jobs: usize,
+    /// The `tikz_preamble` preference followed by the project's `tikz-preamble.tex`.
+    pub tikz_preamble: String,
+}
+
+impl DiagramSettings {
+    /// Read the settings from the effective preferences and the project of the document in
+    /// `doc_dir`. `diagram_parallel_jobs` of 0 (or missing) means one job per CPU core.
+    pub fn resolve(env: &dyn RenderEnvironment, doc_dir: &Path, prefs: &JsonValue) -> Self {
+        let parallel_jobs = match prefs.get("diagram_parallel_jobs").and_then(|v| v.as_u64()) {
+            Some(jobs) if jobs > 0 => jobs as usize,
+            _ => thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        Self {
+            parallel_jobs,
+            tikz_preamble: tikz::shared_preamble(env, doc_dir, prefs),
+        }
+    }
+}
+
+/// Renderers by fence language.
+#[derive(Clone)]
+pub struct DiagramRegistry {
+    renderers: BTreeMap<String, Arc<dyn DiagramRenderer>>,
+    /// Problems with `diagrams.json`, reported with the document's warnings.
+    warnings: Vec<String>,
+    /// `dvisvgm` is available to convert PDF diagrams to SVG.
+    has_dvisvgm: bool,
+}
+
+impl Default for DiagramRegistry {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl DiagramRegistry {
+    /// The built-in renderers only, assuming `dvisvgm` is available.
+    pub fn builtin() -> Self {
+        let mut registry = Self {
+            renderers: BTreeMap::new(),
+            warnings: Vec::new(),
+            has_dvisvgm: true,
+        };
+        registry.register("tikz", Arc::new(tikz::TikzRenderer));
+        for (languages, renderer) in command::builtin() {
+            for language in languages {
+                registry.register(language, renderer.clone());
+            }
+        }
+        registry
+    }
+
+    /// The built-in renderers plus the languages configured in the content directory's
+    /// `diagrams.json`, which may also replace built-in ones.
+    pub fn load(env: &dyn RenderEnvironment) -> Self {
+        let mut registry = Self::builtin().with_dvisvgm(env.dvisvgm_path().is_some());
+        let Ok(path) = env.content_dir().map(|dir| dir.join(CONFIG_FILE)) else {
+            return registry;
+        };
+        let Ok(text) = fs::read_to_string(&path) else {
+            return registry;
+        };
+        match command::parse_config(&text) {
+            Ok((renderers, warnings)) => {
+                for (language, renderer) in renderers {
+                    registry.register(&language, Arc::new(renderer));
+                }
+                registry.warnings = warnings;
+            }
+            Err(e) => registry
+                .warnings
+                .push(format!("{} is not valid: {}", CONFIG_FILE, e)),
+        }
+        registry
+    }
+
+    /// Whether `dvisvgm` can convert PDF diagrams to SVG (see [`Self::rasterizes`]).
+    pub fn with_dvisvgm(mut self, available: bool) -> Self {
+        self.has_dvisvgm = available;
+        self
+    }
+
+    pub fn register(&mut self, language: &str, renderer: Arc<dyn DiagramRenderer>) {
+        self.renderers
+            .insert(language.to_ascii_lowercase(), renderer);
+    }
+
+    pub fn handles(&self, language: &str) -> bool {
+        self.renderers.contains_key(language)
+    }
+
+    fn get(&self, language: &str) -> Option<&Arc<dyn DiagramRenderer>> {
+        self.renderers.get(language)
+    }
+
+    /// Fence languages with a renderer, sorted.
+    pub fn languages(&self) -> Vec<&str> {
+        self.renderers.keys().map(String::as_str).collect()
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    pub fn has_dvisvgm(&self) -> bool {
+        self.has_dvisvgm
+    }
+
+    /// Whether vector output of `language` has to be rasterized: its renderer draws PDFs and
+    /// `dvisvgm` is missing.
+    pub fn rasterizes(&self, language: &str) -> bool {
+        !self.has_dvisvgm
+            && self
+                .get(language)
+                .is_some_and(|renderer| renderer.outputs_pdf())
+    }
+
+    /// Everything besides the blocks themselves that decides the rendered diagrams.
+    pub fn fingerprint(&self, settings: &DiagramSettings) -> String {
+        self.renderers
+            .iter()
+            .map(|(language, renderer)| format!("{}={}", language, renderer.cache_salt(settings)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Progress of the diagrams rendered by one render, sent after each block finishes.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiagramProgress {
+    pub done: usize,
+    pub total: usize,
+    pub id: String,
+}
+
+/// Command running a diagram tool; on Windows it is spawned without flashing a console window.
+fn tool_command<S: AsRef<OsStr>>(program: S) -> Command {
+    #[cfg(target_os = "windows")]
+    {
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        let mut cmd = Command::new(program);
+        cmd.creation_flags(CREATE_NO_WINDOW);
+        cmd
+    }
+    #[cfg(not(target_os = "windows"))]
+    {
+        Command::new(program)
+    }
+}
+
+/// Result of rendering one block on a worker thread.
+enum Outcome {
+    Rendered(Rendered),
+    /// The diagram failed; `artifact` is the error box shown in its place.
+    Failed {
+        message: String,
+        artifact: Result<Rendered>,
+    },
+}
+
+/// Ensure the asset of every diagram block exists in the build directory. Blocks that are not
+/// cached yet are rendered in parallel, up to the `diagram_parallel_jobs` preference, with a
+/// `diagram-progress` event per finished block and a `diagram-error` event per block replaced
+/// by an error box. Assets of diagrams no longer in the document are removed. Returns the
+/// number of diagrams that failed; output showing their error boxes should not be cached.
+pub fn prepare_diagram_assets(
+    env: &dyn RenderEnvironment,
+    registry: &DiagramRegistry,
+    blocks: &[DiagramBlockMeta],
+    build_dir: &Path,
+    settings: &DiagramSettings,
+    cancel: &CancelToken,
+) -> Result<usize> {
+    let cache_dir = build_dir.join("diagram-cache");
+    let work_dir = build_dir.join("diagram-work");
+    let assets_dir = build_dir.join("diagrams");
+    fs::create_dir_all(&cache_dir)?;
+    fs::create_dir_all(&work_dir)?;
+
+    let context = DiagramContext {
+        env,
+        settings,
+        cancel,
+    };
+    let mut converter = Converter::new(env);
+    let cache_file = |key: &str, block: &DiagramBlockMeta| {
+        cache_dir.join(format!("{}.{}", key, block.format.asset_extension()))
+    };
+
+    // Diagrams that are not cached yet, each key once even if the diagram repeats.
+    let has_dvisvgm = converter.has_dvisvgm();
+    let mut keys = Vec::with_capacity(blocks.len());
+    let mut pending = Vec::new();
+    for block in blocks {
+        let Some(renderer) = registry.get(&block.language) else {
+            continue;
+        };
+        let key = cache_key(block, &renderer.cache_salt(settings), has_dvisvgm);
+        if !cache_file(&key, block).exists() && !pending.iter().any(|(k, _, _)| *k == key) {
+            pending.push((key.clone(), block, renderer.clone()));
+        }
+        keys.push((key, block));
+    }
+
+    // Tools run on a bounded set of worker threads, each block in its own work directory.
+    // Pdfium is not thread-safe, so results are converted and cached here as they arrive.
+    let total = pending.len();
+    let queue = Mutex::new(pending.iter());
+    let (sender, receiver) = mpsc::channel();
+    // Error boxes by key; they are written to the assets only.
+    let mut failed: HashMap<&str, Vec<u8>> = HashMap::new();
+    thread::scope(|scope| -> Result<()> {
+        for _ in 0..settings.parallel_jobs.clamp(1, total.max(1)) {
+            let sender = sender.clone();
+            let (queue, work_dir, context) = (&queue, &work_dir, &context);
+            scope.spawn(move || loop {
+                if context.cancel.is_cancelled() {
+                    break;
+                }
+                let next = queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+                let Some((key, block, renderer)) = next else {
+                    break;
+                };
+                let block_dir = work_dir.join(key);
+                let outcome = render_block(renderer.as_ref(), context, block, &block_dir);
+                if sender.send((key, *block, outcome)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        for (done, (key, block, outcome)) in receiver.into_iter().enumerate() {
+            cancel.check()?;
+            let block_dir = work_dir.join(key);
+            let format = block.format;
+            // Converting can fail as well (e.g. `dvisvgm` on a PDF it cannot handle).
+            let (message, artifact) = match outcome {
+                Outcome::Rendered(rendered) => {
+                    match converter.finish(rendered, format, &block_dir, key) {
+                        Ok(bytes) => {
+                            fs::write(cache_file(key, block), bytes)?;
+                            (None, None)
+                        }
+                        Err(err) => (Some(format!("{:#}", err)), None),
+                    }
+                }
+                Outcome::Failed { message, artifact } => (Some(message), Some(artifact)),
+            };
+            if let Some(message) = message {
+                error!(
+                    "[diagrams] failed to render block {}: {}",
+                    block.id, message
+                );
+                env.emit(
+                    "diagram-error",
+                    serde_json::json!({ "id": block.id, "message": message }),
+                );
+                let bytes = match artifact {
+                    Some(artifact) => artifact
+                        .and_then(|artifact| converter.finish(artifact, format, &block_dir, key)),
+                    None => convert::error_image(format, &block.language, &message),
+                }
+                .with_context(|| {
+                    format!(
+                        "failed to create fallback artifact for diagram {}",
+                        block.id
+                    )
+                })?;
+                failed.insert(key.as_str(), bytes);
+            }
+            let _ = fs::remove_dir_all(&block_dir);
+            env.emit(
+                "diagram-progress",
+                serde_json::json!(DiagramProgress {
+                    done: done + 1,
+                    total,
+                    id: block.id.clone(),
+                }),
+            );
+        }
+        Ok(())
+    })?;
+
+    // Asset paths are stable, so unchanged diagrams keep their file (and Typst its cache).
+    let mut active_outputs = HashSet::new();
+    for (key, block) in &keys {
+        let dest_path = build_dir.join(&block.asset_path);
+        let bytes = match failed.get(key.as_str()) {
+            Some(bytes) => bytes.clone(),
+            None => fs::read(cache_file(key, block))?,
+        };
+        write_if_changed(&dest_path, &bytes)?;
+        active_outputs.insert(dest_path);
+    }
+
+    // Remove stale files from the diagram output directory to avoid bloat
+    if let Ok(entries) = fs::read_dir(&assets_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_type().map(|t| t.is_file()).unwrap_or(false)
+                && !active_outputs.contains(&path)
+            {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    Ok(failed.len())
+}
+
+fn render_block(
+    renderer: &dyn DiagramRenderer,
+    context: &DiagramContext,
+    block: &DiagramBlockMeta,
+    work_dir: &Path,
+) -> Outcome {
+    let rendered = fs::create_dir_all(work_dir)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| renderer.render(context, block, work_dir));
+    match rendered {
+        Ok(rendered) => Outcome::Rendered(rendered),
+        Err(err) => {
+            let message = err.to_string();
+            // A cancelled render is discarded, so it gets no error box.
+            let artifact = match context.cancel.check() {
+                Ok(()) => renderer.render_error(context, block, &message, work_dir),
+                Err(cancelled) => Err(cancelled.into()),
+            };
+            Outcome::Failed { message, artifact }
+        }
+    }
+}
+
+/// Key of a rendered diagram: its content hash, the renderer's salt and the pipeline version.
+/// Vector output also depends on whether `dvisvgm` was available to convert PDFs, so
+/// installing it replaces cached raster fallbacks.
+fn cache_key(block: &DiagramBlockMeta, salt: &str, has_dvisvgm: bool) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(PIPELINE_CACHE_VERSION.to_le_bytes());
+    hasher.update((salt.len() as u64).to_le_bytes());
+    hasher.update(salt.as_bytes());
+    if block.format.is_vector() {
+        hasher.update([u8::from(has_dvisvgm)]);
+    }
+    hasher.update(block.content_hash.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::environment::FsEnvironment;
+    use crate::preprocessor::preprocess_markdown;
+
+    #[test]
+    fn configured_commands_render_and_fall_back_to_error_boxes() {
+        let root = std::env::temp_dir().join(format!("tf-diagrams-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            root.join(CONFIG_FILE),
+            r#"{ "svgtext": { "command": "cat" }, "broken": { "command": ["sh", "-c", "echo nope >&2; exit 3"] } }"#,
+        )
+        .unwrap();
+        let env = FsEnvironment::new(root.clone());
+        let registry = DiagramRegistry::load(&env);
+        assert!(registry.handles("svgtext") && registry.handles("dot"));
+        assert!(registry.warnings().is_empty());
+
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="4"/>"#;
+        let source = format!("```svgtext\n{}\n```\n\n```broken\nx\n```\n", svg);
+        let preprocess = preprocess_markdown(&source, None, Path::new("."), &registry).unwrap();
+        assert_eq!(preprocess.diagram_blocks.len(), 2);
+        assert!(preprocess.markdown.contains("#diagram_render"));
+
+        let build_dir = root.join(".build");
+        let settings = DiagramSettings {
+            parallel_jobs: 2,
+            ..Default::default()
+        };
+        let failures = prepare_diagram_assets(
+            &env,
+            &registry,
+            &preprocess.diagram_blocks,
+            &build_dir,
+            &settings,
+            &CancelToken::default(),
+        )
+        .unwrap();
+        assert_eq!(failures, 1);
+
+        let [drawn, failed] = &preprocess.diagram_blocks[..] else {
+            panic!("expected two blocks");
+        };
+        let drawn_asset = fs::read_to_string(build_dir.join(&drawn.asset_path)).unwrap();
+        assert_eq!(drawn_asset.trim(), svg);
+        let failed_asset = fs::read_to_string(build_dir.join(&failed.asset_path)).unwrap();
+        assert!(failed_asset.contains("nope"));
+
+        let events = env.events();
+        let errors: Vec<_> = events
+            .iter()
+            .filter(|(e, _)| e == "diagram-error")
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].1["id"], failed.id.as_str());
+        assert_eq!(
+            events
+                .iter()
+                .filter(|(e, _)| e == "diagram-progress")
+                .count(),
+            2
+        );
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn tools_that_write_before_reading_their_input_do_not_block() {
+        let root = std::env::temp_dir().join(format!("tf-diagrams-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        // Both pipes get more than their buffer holds before the tool reads stdin.
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="4"/>"#;
+        let script = format!(
+            "printf '%s' '{}'; head -c 200000 /dev/zero | tr '\\0' ' '; cat >/dev/null",
+            svg
+        );
+        let config = serde_json::json!({ "chatty": { "command": ["sh", "-c", script] } });
+        fs::write(root.join(CONFIG_FILE), config.to_string()).unwrap();
+        let env = FsEnvironment::new(root.clone());
+        let registry = DiagramRegistry::load(&env);
+        let source = format!("```chatty\n{}\n```\n", "x".repeat(200_000));
+        let preprocess = preprocess_markdown(&source, None, Path::new("."), &registry).unwrap();
+        let build_dir = root.join(".build");
+
+        let failures = prepare_diagram_assets(
+            &env,
+            &registry,
+            &preprocess.diagram_blocks,
+            &build_dir,
+            &DiagramSettings::default(),
+            &CancelToken::default(),
+        )
+        .unwrap();
+        assert_eq!(failures, 0);
+        let asset = build_dir.join(&preprocess.diagram_blocks[0].asset_path);
+        assert_eq!(fs::read_to_string(asset).unwrap().trim(), svg);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn failed_diagrams_are_rendered_again_next_time() {
+        let root = std::env::temp_dir().join(format!("tf-diagrams-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        let tool = root.join("tool.sh");
+        let config = serde_json::json!({ "late": { "command": ["sh", tool] } });
+        fs::write(root.join(CONFIG_FILE), config.to_string()).unwrap();
+        let env = FsEnvironment::new(root.clone());
+        let registry = DiagramRegistry::load(&env);
+        let preprocess =
+            preprocess_markdown("```late\nx\n```\n", None, Path::new("."), &registry).unwrap();
+        let build_dir = root.join(".build");
+        let prepare = || {
+            prepare_diagram_assets(
+                &env,
+                &registry,
+                &preprocess.diagram_blocks,
+                &build_dir,
+                &DiagramSettings::default(),
+                &CancelToken::default(),
+            )
+            .unwrap()
+        };
+
+        // The tool is missing: both renders report the failure.
+        assert_eq!(prepare(), 1);
+        assert_eq!(prepare(), 1);
+        let errors = |env: &FsEnvironment| {
+            env.events()
+                .iter()
+                .filter(|(e, _)| e == "diagram-error")
+                .count()
+        };
+        assert_eq!(errors(&env), 2);
+
+        // Once it works, the diagram is drawn instead of the cached error box.
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="4"/>"#;
+        fs::write(&tool, format!("echo '{}'", svg)).unwrap();
+        assert_eq!(prepare(), 0);
+        assert_eq!(errors(&env), 2);
+        let asset = build_dir.join(&preprocess.diagram_blocks[0].asset_path);
+        assert_eq!(fs::read_to_string(asset).unwrap().trim(), svg);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -1,107 +1,142 @@
-use crate::preprocessor::TikzBlockMeta;
+//! TikZ diagrams, compiled with Tectonic as standalone LaTeX documents.
+//!
+//! Each diagram gets a small preamble (`tikz` and a few common libraries), then its fence's
+//! presets (`preset=circuits`, `preset=pgfplots`), the shared preamble (the `tikz_preamble`
+//! preference and the project's `tikz-preamble.tex`), the fence's `preamble=` option and any
+//! preamble commands found in the fence itself.
+
+use super::{convert, DiagramContext, DiagramRenderer, DiagramSettings, Rendered};
+use crate::environment::RenderEnvironment;
+use crate::preprocessor::DiagramBlockMeta;
 use crate::tex::tectonic_command;
-use crate::utils;
-use anyhow::{anyhow, Context, Result};
-use image as image_crate;
-use log::error;
-use pdfium_render::prelude::*;
-use sha2::{Digest, Sha256};
-use std::collections::HashSet;
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde_json::Value as JsonValue;
 use std::fs;
-use std::io::Cursor;
 use std::path::Path;
-use tauri::AppHandle;
-
-/// Ensure all TikZ assets referenced in the current markdown exist in the build directory.
-/// Compiles each diagram with the external Tectonic CLI and caches the PDF output so that
-/// repeated renders reuse prior results without re-running LaTeX.
-pub fn prepare_tikz_assets(
-    app_handle: &AppHandle,
-    blocks: &[TikzBlockMeta],
-    build_dir: &Path,
-) -> Result<()> {
-    if blocks.is_empty() {
-        return Ok(());
-    }
 
-    let tectonic_path = utils::get_tectonic_path(app_handle)?;
-    let pdfium_lib = utils::get_pdfium_library_path(app_handle)?;
-    let pdfium_bindings = Pdfium::bind_to_library(&pdfium_lib)
-        .or_else(|_| Pdfium::bind_to_system_library())
-        .map_err(|e| anyhow!("Failed to load Pdfium: {e}"))?;
-    let pdfium = Pdfium::new(pdfium_bindings);
-    let cache_dir = build_dir.join("tikz-cache");
-    let work_dir = build_dir.join("tikz-work");
-    fs::create_dir_all(&cache_dir)?;
-    fs::create_dir_all(&work_dir)?;
-
-    let mut active_outputs = HashSet::new();
-
-    for block in blocks {
-        let key = cache_key(block);
-        let cache_file = cache_dir.join(format!("{}.{}", key, block.asset_extension));
-        if !cache_file.exists() {
-            match compile_block(&tectonic_path, &pdfium, &work_dir, &key, block) {
-                Ok(bytes) => {
-                    fs::write(&cache_file, bytes)?;
-                }
-                Err(err) => {
-                    error!("[tikz] failed to compile block {}: {}", block.id, err);
-                    let fallback = build_error_artifact(
-                        &tectonic_path,
-                        &pdfium,
-                        &work_dir,
-                        &key,
-                        &err.to_string(),
-                    )
-                    .with_context(|| {
-                        format!(
-                            "failed to create fallback artifact for TikZ block {}",
-                            block.id
-                        )
-                    })?;
-                    fs::write(&cache_file, fallback)?;
-                }
-            }
+/// File with LaTeX prepended to every diagram, looked up next to the document and then in the
+/// content directory.
+pub const SHARED_PREAMBLE_FILE: &str = "tikz-preamble.tex";
+
+/// Package bundle loaded with a TikZ fence's `preset=` option (`preset="circuits,pgfplots"`
+/// selects several).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TikzPreset {
+    /// Electrical circuits with `circuitikz`.
+    Circuits,
+    /// Plots with `pgfplots`.
+    Pgfplots,
+}
+
+impl TikzPreset {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "circuits" | "circuitikz" => Some(TikzPreset::Circuits),
+            "pgfplots" | "plots" => Some(TikzPreset::Pgfplots),
+            _ => None,
         }
+    }
 
-        let dest_path = build_dir.join(&block.asset_path);
-        if let Some(parent) = dest_path.parent() {
-            fs::create_dir_all(parent)?;
+    /// LaTeX added to the preamble of diagrams using the preset.
+    fn preamble(self) -> &'static str {
+        match self {
+            TikzPreset::Circuits => "\\usepackage{circuitikz}\n",
+            TikzPreset::Pgfplots => "\\usepackage{pgfplots}\n\\pgfplotsset{compat=1.18}\n",
         }
-        if dest_path.exists() {
-            fs::remove_file(&dest_path)?;
+    }
+
+    /// Presets named in a fence's `preset` option, without repeats.
+    fn from_option(value: Option<&str>) -> Vec<Self> {
+        let mut presets = Vec::new();
+        for preset in value
+            .iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(TikzPreset::parse)
+        {
+            if !presets.contains(&preset) {
+                presets.push(preset);
+            }
         }
-        fs::copy(&cache_file, &dest_path)?;
-        active_outputs.insert(dest_path);
+        presets
     }
+}
 
-    // Remove stale files from the tikz output directory to avoid bloat
-    let tikz_dir = build_dir.join("tikz");
-    if tikz_dir.exists() {
-        if let Ok(entries) = fs::read_dir(&tikz_dir) {
-            for entry in entries.flatten() {
-                let path = entry.path();
-                if entry.file_type().map(|t| t.is_file()).unwrap_or(false)
-                    && !active_outputs.contains(&path)
-                {
-                    let _ = fs::remove_file(path);
-                }
+/// The `tikz_preamble` preference followed by the first `tikz-preamble.tex` found next to the
+/// document or in the content directory.
+pub(super) fn shared_preamble(
+    env: &dyn RenderEnvironment,
+    doc_dir: &Path,
+    prefs: &JsonValue,
+) -> String {
+    let pref = prefs.get("tikz_preamble").and_then(|v| v.as_str());
+    let file = [Some(doc_dir.to_path_buf()), env.content_dir().ok()]
+        .into_iter()
+        .flatten()
+        .map(|dir| dir.join(SHARED_PREAMBLE_FILE))
+        .find(|path| path.is_file())
+        .and_then(|path| match fs::read_to_string(&path) {
+            Ok(text) => Some(text),
+            Err(e) => {
+                warn!("[tikz] failed to read {}: {}", path.display(), e);
+                None
+            }
+        });
+
+    let mut preamble = String::new();
+    for part in [pref, file.as_deref()].into_iter().flatten() {
+        if !part.trim().is_empty() {
+            preamble.push_str(part);
+            if !part.ends_with('\n') {
+                preamble.push('\n');
             }
         }
     }
+    preamble
+}
+
+pub(super) struct TikzRenderer;
+
+impl DiagramRenderer for TikzRenderer {
+    fn cache_salt(&self, settings: &DiagramSettings) -> String {
+        settings.tikz_preamble.clone()
+    }
+
+    fn outputs_pdf(&self) -> bool {
+        true
+    }
+
+    fn render(
+        &self,
+        context: &DiagramContext,
+        block: &DiagramBlockMeta,
+        work_dir: &Path,
+    ) -> Result<Rendered> {
+        let tectonic_path = context.env.tectonic_path()?;
+        let latex = build_latex(block, &context.settings.tikz_preamble);
+        compile_tex(&tectonic_path, work_dir, &block.id, &latex).map(Rendered::Pdf)
+    }
 
-    Ok(())
+    fn render_error(
+        &self,
+        context: &DiagramContext,
+        block: &DiagramBlockMeta,
+        message: &str,
+        work_dir: &Path,
+    ) -> Result<Rendered> {
+        // The LaTeX error box matches the diagram's look; without Tectonic use the plain one.
+        let base_name = format!("{}-error", block.id);
+        match context.env.tectonic_path() {
+            Ok(tectonic_path) => {
+                error_artifact_pdf(&tectonic_path, work_dir, &base_name, message).map(Rendered::Pdf)
+            }
+            Err(_) => convert::error_image(block.format, "TikZ", message).map(Rendered::Asset),
+        }
+    }
 }
 
-fn compile_block(
-    tectonic_path: &Path,
-    pdfium: &Pdfium,
-    work_dir: &Path,
-    cache_key: &str,
-    block: &TikzBlockMeta,
-) -> Result<Vec<u8>> {
-    let (extracted_preamble, body) = split_tikz_preamble_from_body(&block.diagram);
+fn build_latex(block: &DiagramBlockMeta, shared_preamble: &str) -> String {
+    let (extracted_preamble, body) = split_tikz_preamble_from_body(&block.source);
 
     // Load a few general-purpose libraries so common arrow tips/coordinate math work
     // even when the fence doesn't specify a custom preamble.
@@ -112,7 +147,13 @@ fn compile_block(
 ",
     );
 
-    if let Some(user_preamble) = block.preamble.as_ref() {
+    // Presets first, so the shared and per-fence preambles can configure their packages.
+    for preset in TikzPreset::from_option(block.options.get("preset").map(String::as_str)) {
+        latex.push_str(preset.preamble());
+    }
+    latex.push_str(shared_preamble);
+
+    if let Some(user_preamble) = block.options.get("preamble") {
         latex.push_str(user_preamble);
         if !user_preamble.ends_with('\n') {
             latex.push('\n');
@@ -131,9 +172,7 @@ fn compile_block(
         latex.push('\n');
     }
     latex.push_str("\\end{document}\n");
-
-    let pdf_bytes = compile_tex(tectonic_path, work_dir, cache_key, &latex)?;
-    pdf_bytes_to_png(pdfium, &pdf_bytes)
+    latex
 }
 
 fn split_tikz_preamble_from_body(diagram: &str) -> (String, String) {
@@ -243,23 +282,10 @@ fn compile_tex(
     Ok(bytes)
 }
 
-const TIKZ_PIPELINE_CACHE_VERSION: u32 = 3;
-
-fn cache_key(block: &TikzBlockMeta) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(TIKZ_PIPELINE_CACHE_VERSION.to_le_bytes());
-    hasher.update(block.diagram.as_bytes());
-    if let Some(preamble) = block.preamble.as_ref() {
-        hasher.update(preamble.as_bytes());
-    }
-    hex::encode(hasher.finalize())
-}
-
-fn build_error_artifact(
+fn error_artifact_pdf(
     tectonic_path: &Path,
-    pdfium: &Pdfium,
     work_dir: &Path,
-    cache_key: &str,
+    base_name: &str,
     message: &str,
 ) -> Result<Vec<u8>> {
     let preview = truncate_message(message);
@@ -273,54 +299,7 @@ fn build_error_artifact(
 ",
         escaped
     );
-    let pdf = compile_tex(tectonic_path, work_dir, cache_key, &latex)?;
-    pdf_bytes_to_png(pdfium, &pdf)
-}
-
-fn pdf_bytes_to_png(pdfium: &Pdfium, pdf_bytes: &[u8]) -> Result<Vec<u8>> {
-    let document = pdfium
-        .load_pdf_from_byte_slice(pdf_bytes, None)
-        .map_err(|e| anyhow!("Failed to load TikZ PDF: {e}"))?;
-    let page = document
-        .pages()
-        .get(0)
-        .map_err(|_| anyhow!("TikZ PDF did not contain any pages"))?;
-
-    let dpi = 288.0;
-    let width_px = ((page.width().value / 72.0) * dpi)
-        .clamp(1.0, 4096.0)
-        .round() as i32;
-    let height_px = ((page.height().value / 72.0) * dpi)
-        .clamp(1.0, 4096.0)
-        .round() as i32;
-
-    let render_config = PdfRenderConfig::new()
-        .set_target_width(width_px)
-        .set_target_height(height_px)
-        .use_print_quality(true)
-        .render_annotations(true)
-        .render_form_data(true);
-
-    let bitmap = page
-        .render_with_config(&render_config)
-        .map_err(|e| anyhow!("Failed to rasterize TikZ PDF: {e}"))?;
-
-    let image = bitmap.as_image();
-    let rgba = image.to_rgba8();
-    let (w, h) = (rgba.width(), rgba.height());
-    let data = rgba.into_raw();
-
-    let mut png_bytes = Vec::new();
-    {
-        let mut cursor = Cursor::new(&mut png_bytes);
-        let mut encoder = image_crate::codecs::png::PngEncoder::new(&mut cursor);
-        use image_crate::ColorType;
-        encoder
-            .encode(&data, w, h, ColorType::Rgba8)
-            .map_err(|e| anyhow!("Failed to encode TikZ PNG: {e}"))?;
-    }
-
-    Ok(png_bytes)
+    compile_tex(tectonic_path, work_dir, base_name, &latex)
 }
 
 fn truncate_message(message: &str) -> String {
@@ -0,0 +1,1730 @@
+//! DOCX export for reviewers who work in Word.
+//!
+//! The document is preprocessed as for the other exports (see `html_export`), then the
+//! pulldown-cmark event stream is mapped onto WordprocessingML: headings, paragraphs, lists with
+//! task boxes, tables, footnotes, code, links and images, plus the preprocessor's figures,
+//! captions, admonitions and citations. Math becomes Office Math (see `math`), diagram assets
+//! are embedded as pictures, and the styles are derived from the document's font, size,
+//! spacing and colour preferences.
+//!
+//! Word only shows SVG pictures (`svgBlip`) from Word 2016 on; older readers get a blank
+//! placeholder unless the SVG just wraps a PNG, which is then embedded directly.
+
+use crate::build_workspace::{self, JobKind};
+use crate::crossref;
+use crate::environment::RenderEnvironment;
+use crate::html_export::{self, AssetTarget, Assets, Formula, Heading, Numbering};
+use crate::math::{self, escape_xml};
+use crate::preferences::Preferences;
+use crate::render_pipeline::RenderConfig;
+use crate::render_scheduler::{self, RenderJob};
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use lazy_static::lazy_static;
+use pulldown_cmark::{Alignment, Event, LinkType, Parser, Tag};
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+const NAMESPACES: &str = concat!(
+    r#"xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main" "#,
+    r#"xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships" "#,
+    r#"xmlns:m="http://schemas.openxmlformats.org/officeDocument/2006/math" "#,
+    r#"xmlns:wp="http://schemas.openxmlformats.org/drawingml/2006/wordprocessingDrawing" "#,
+    r#"xmlns:a="http://schemas.openxmlformats.org/drawingml/2006/main" "#,
+    r#"xmlns:pic="http://schemas.openxmlformats.org/drawingml/2006/picture""#,
+);
+const RELATIONSHIPS: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n";
+
+lazy_static! {
+    static ref EMBEDDED_PNG: Regex = Regex::new(r#"data:image/png;base64,([A-Za-z0-9+/=\s]+)"#)
+        .expect("BUG: Invalid regex pattern for embedded PNGs");
+    static ref SVG_SIZE: Regex =
+        Regex::new(r#"\s(?P<name>width|height|viewBox)="(?P<value>[^"]*)""#)
+            .expect("BUG: Invalid regex pattern for SVG sizes");
+    static ref REFERENCE_PLACEHOLDER: Regex = Regex::new(r"\{\{tf-ref:(?P<label>[^}]*)\}\}")
+        .expect("BUG: Invalid regex pattern for reference placeholders");
+    static ref RAW_HTML: Regex =
+        Regex::new(r#"<tf-ref label="(?P<label>[^"]*)">|(?P<br><br\s*/?>)|<[^>]*>"#)
+            .expect("BUG: Invalid regex pattern for raw HTML");
+}
+
+/// English Metric Units per pixel at 96 dpi, and per twip (a twentieth of a point).
+const EMU_PER_PX: f64 = 9525.0;
+const EMU_PER_TWIP: f64 = 635.0;
+
+/// Export `content` (the document `current_file`, if saved) as a Word document at
+/// `destination`.
+pub async fn export_docx(
+    env: &dyn RenderEnvironment,
+    content: &str,
+    destination: &str,
+    current_file: Option<&str>,
+) -> Result<String> {
+    let document = current_file.unwrap_or("");
+    let slot = render_scheduler::acquire(RenderJob::Export(document)).await?;
+
+    let content_dir = env.content_dir()?;
+    let build_dir = build_workspace::prepare(env, document, JobKind::Export)?;
+    let config = RenderConfig {
+        env,
+        build_dir: build_dir.clone(),
+        content_dir: content_dir.clone(),
+        typst_root: content_dir.clone(),
+        cancel: slot.token().clone(),
+    };
+    let base_dir = current_file
+        .and_then(|file| Path::new(file).parent())
+        .unwrap_or(Path::new("."));
+    let (preprocess, prefs) =
+        html_export::prepare_document(&config, content, document, base_dir, "docx")?;
+
+    let assets = Assets::new(&content_dir, &build_dir, base_dir, AssetTarget::Inline);
+    let mut load_image = |src: &str| {
+        let path = assets.locate(src)?;
+        Image::load(&path)
+            .map_err(|e| log::warn!("Could not export image {}: {}", path.display(), e))
+            .ok()
+    };
+    let docx = write_document(&preprocess.markdown, &prefs, &mut load_image);
+
+    let fallback_title = current_file
+        .and_then(|file| Path::new(file).file_stem())
+        .map(|stem| stem.to_string_lossy().to_string());
+    let title = html_export::document_title(&prefs, &docx.headings, fallback_title);
+
+    let output = Path::new(destination);
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut zip = ZipWriter::new(fs::File::create(output)?);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, data) in package(&docx, &prefs, &title) {
+        zip.start_file(name, options)?;
+        zip.write_all(&data)?;
+    }
+    zip.finish()?;
+    Ok(output.to_string_lossy().to_string())
+}
+
+/// A picture for the document: data Word can show, and the SVG it stands in for.
+pub(crate) struct Image {
+    data: Vec<u8>,
+    extension: &'static str,
+    svg: Option<Vec<u8>>,
+    /// Size in pixels at 96 dpi.
+    width: f64,
+    height: f64,
+}
+
+impl Image {
+    fn load(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        let is_svg = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"));
+        if is_svg {
+            Self::from_svg(bytes)
+        } else {
+            Self::from_raster(bytes)
+        }
+    }
+
+    /// PNG, JPEG and GIF are embedded as they are; other formats are converted to PNG.
+    fn from_raster(bytes: Vec<u8>) -> Result<Self> {
+        let reader = image::io::Reader::new(Cursor::new(&bytes)).with_guessed_format()?;
+        let format = reader.format();
+        let (width, height) = reader.into_dimensions()?;
+        let (data, extension) = match format {
+            Some(image::ImageFormat::Png) => (bytes, "png"),
+            Some(image::ImageFormat::Jpeg) => (bytes, "jpeg"),
+            Some(image::ImageFormat::Gif) => (bytes, "gif"),
+            _ => {
+                let mut png = Vec::new();
+                image::load_from_memory(&bytes)?
+                    .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)?;
+                (png, "png")
+            }
+        };
+        Ok(Self {
+            data,
+            extension,
+            svg: None,
+            width: width as f64,
+            height: height as f64,
+        })
+    }
+
+    fn from_svg(svg: Vec<u8>) -> Result<Self> {
+        let text = String::from_utf8_lossy(&svg);
+        // An SVG that only wraps a PNG is as good as the PNG itself
+        if let Some(png) = embedded_png(&text) {
+            if let Ok(image) = Self::from_raster(png) {
+                return Ok(image);
+            }
+        }
+        let (width, height) = svg_size(&text).unwrap_or((300.0, 150.0));
+        let mut blank = Vec::new();
+        image::DynamicImage::new_rgba8(1, 1)
+            .write_to(&mut Cursor::new(&mut blank), image::ImageOutputFormat::Png)?;
+        Ok(Self {
+            data: blank,
+            extension: "png",
+            svg: Some(svg),
+            width,
+            height,
+        })
+    }
+}
+
+fn embedded_png(svg: &str) -> Option<Vec<u8>> {
+    let data: String = EMBEDDED_PNG
+        .captures(svg)?
+        .get(1)?
+        .as_str()
+        .split_whitespace()
+        .collect();
+    BASE64.decode(data).ok()
+}
+
+/// Size of an SVG in pixels, from the root's `width` and `height` or its `viewBox`.
+fn svg_size(svg: &str) -> Option<(f64, f64)> {
+    let root = &svg[svg.find("<svg")?..];
+    let root = &root[..root.find('>')?];
+    let mut attributes = HashMap::new();
+    for caps in SVG_SIZE.captures_iter(root) {
+        attributes.insert(caps["name"].to_string(), caps["value"].to_string());
+    }
+    let pixels = |value: &str| {
+        let number = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let number: f64 = number.trim().parse().ok()?;
+        Some(if value.ends_with("pt") {
+            number * 96.0 / 72.0
+        } else {
+            number
+        })
+    };
+    if let (Some(width), Some(height)) = (attributes.get("width"), attributes.get("height")) {
+        if let (Some(width), Some(height)) = (pixels(width), pixels(height)) {
+            return Some((width, height));
+        }
+    }
+    let view_box: Vec<f64> = attributes
+        .get("viewBox")?
+        .split([' ', ','])
+        .filter_map(|n| n.parse().ok())
+        .collect();
+    (view_box.len() == 4).then(|| (view_box[2], view_box[3]))
+}
+
+struct Relationship {
+    id: String,
+    kind: &'static str,
+    target: String,
+    external: bool,
+}
+
+/// A document written from markdown, before it is packaged.
+struct Docx {
+    body: String,
+    footnotes: String,
+    /// Numbering instances, by id from 1: whether the list is ordered, and its start.
+    lists: Vec<(bool, u64)>,
+    relationships: Vec<Relationship>,
+    footnote_relationships: Vec<Relationship>,
+    media: Vec<(String, Vec<u8>)>,
+    headings: Vec<Heading>,
+}
+
+/// Page size and margins in twips.
+struct Page {
+    width: i64,
+    height: i64,
+    margin_x: i64,
+    margin_y: i64,
+}
+
+impl Page {
+    fn new(prefs: &Preferences) -> Self {
+        let (width, height) = match prefs.papersize.as_str() {
+            "us-letter" => (12240, 15840),
+            "us-legal" => (12240, 20160),
+            "a3" => (16838, 23811),
+            "a5" => (8391, 11906),
+            _ => (11906, 16838),
+        };
+        Self {
+            width,
+            height,
+            margin_x: twips(&prefs.margin.x, prefs.font_size).unwrap_or(1440),
+            margin_y: twips(&prefs.margin.y, prefs.font_size).unwrap_or(1440),
+        }
+    }
+
+    fn text_width(&self) -> i64 {
+        (self.width - 2 * self.margin_x).max(1440)
+    }
+}
+
+/// Twips in a CSS or Typst length such as `2.5cm`, `1in`, `12pt` or `0.65em`; `em` is
+/// relative to `font_size`.
+fn twips(length: &str, font_size: f32) -> Option<i64> {
+    let length = length.trim();
+    let unit = length.find(|c: char| c.is_ascii_alphabetic())?;
+    let value: f32 = length[..unit].trim().parse().ok()?;
+    let points = match &length[unit..] {
+        "pt" => value,
+        "in" => value * 72.0,
+        "cm" => value * 72.0 / 2.54,
+        "mm" => value * 72.0 / 25.4,
+        "em" => value * font_size,
+        "px" => value * 0.75,
+        _ => return None,
+    };
+    Some((points * 20.0).round() as i64)
+}
+
+/// A CSS hex colour as Word writes it (`1E40AF`).
+fn hex_color(color: &str) -> Option<String> {
+    let hex = color.trim().strip_prefix('#')?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    match hex.len() {
+        6 => Some(hex.to_uppercase()),
+        3 => Some(
+            hex.chars()
+                .flat_map(|c| [c, c])
+                .collect::<String>()
+                .to_uppercase(),
+        ),
+        _ => None,
+    }
+}
+
+/// `color` mixed half and half with white.
+fn tint(color: &str) -> String {
+    (0..3)
+        .map(|i| {
+            let channel = u8::from_str_radix(&color[i * 2..i * 2 + 2], 16).unwrap_or(0);
+            format!("{:02X}", (channel as u16 + 255) / 2)
+        })
+        .collect()
+}
+
+/// Word bookmark name for a label or heading id: letters, digits and underscores only.
+fn bookmark_name(label: &str) -> String {
+    let name: String = label
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .take(36)
+        .collect();
+    format!("tf_{}", name)
+}
+
+fn text_run(text: &str) -> String {
+    format!(
+        "<w:r><w:t xml:space=\"preserve\">{}</w:t></w:r>",
+        escape_xml(text)
+    )
+}
+
+const PAGE_BREAK: &str = "<w:p><w:r><w:br w:type=\"page\"/></w:r></w:p>";
+
+struct PendingHeading {
+    level: usize,
+    id: Option<String>,
+    text: String,
+    runs: String,
+}
+
+struct Table {
+    alignments: Vec<Alignment>,
+    head: bool,
+    cell: usize,
+    cell_has_paragraph: bool,
+}
+
+/// State of the pass from markdown events to WordprocessingML.
+struct Writer<'a> {
+    prefs: &'a Preferences,
+    formulas: &'a [Formula],
+    load_image: &'a mut dyn FnMut(&str) -> Option<Image>,
+    numbering: Numbering<'a>,
+    page: Page,
+    docx: Docx,
+    /// Footnote being written, with its id.
+    footnote: Option<(usize, String)>,
+    footnote_ids: HashMap<String, usize>,
+    /// The next paragraph starts with the footnote's number.
+    footnote_mark: bool,
+    heading: Option<PendingHeading>,
+    /// Image whose alt text is being collected.
+    image: Option<(String, String)>,
+    paragraph_open: bool,
+    bold: usize,
+    italic: usize,
+    strike: usize,
+    links: usize,
+    /// Numbering ids of the open lists.
+    lists: Vec<usize>,
+    /// The next paragraph is the first of a list item and gets its bullet or number.
+    item_start: bool,
+    quotes: usize,
+    admonitions: Vec<String>,
+    figure_depth: usize,
+    code_block: bool,
+    table: Option<Table>,
+    /// Reference text and label of the figure whose caption comes next.
+    caption: Option<(String, String)>,
+    bookmarks: usize,
+    drawings: usize,
+}
+
+/// Write preprocessed `markdown` as a Word document body. `load_image` loads every image
+/// source.
+fn write_document(
+    markdown: &str,
+    prefs: &Preferences,
+    load_image: &mut dyn FnMut(&str) -> Option<Image>,
+) -> Docx {
+    let (markdown, formulas) = html_export::extract_math(markdown);
+    let markdown = html_export::mark_references(&markdown);
+
+    let mut writer = Writer {
+        prefs,
+        formulas: &formulas,
+        load_image,
+        numbering: Numbering::new(prefs),
+        page: Page::new(prefs),
+        docx: Docx {
+            body: String::new(),
+            footnotes: String::new(),
+            lists: Vec::new(),
+            relationships: Vec::new(),
+            footnote_relationships: Vec::new(),
+            media: Vec::new(),
+            headings: Vec::new(),
+        },
+        footnote: None,
+        footnote_ids: HashMap::new(),
+        footnote_mark: false,
+        heading: None,
+        image: None,
+        paragraph_open: false,
+        bold: 0,
+        italic: 0,
+        strike: 0,
+        links: 0,
+        lists: Vec::new(),
+        item_start: false,
+        quotes: 0,
+        admonitions: Vec::new(),
+        figure_depth: 0,
+        code_block: false,
+        table: None,
+        caption: None,
+        bookmarks: 0,
+        drawings: 0,
+    };
+    if prefs.cover_page {
+        writer.cover();
+    }
+    if prefs.toc {
+        writer.table_of_contents();
+    }
+    let mut parser = Parser::new_ext(&markdown, html_export::markdown_options()).peekable();
+    while let Some(event) = parser.next() {
+        if let (Event::End(Tag::Heading(..)), Some(heading)) = (&event, writer.heading.as_mut()) {
+            if let Some(label) = parser.peek().and_then(crossref::heading_label) {
+                heading.id = Some(label);
+                parser.next();
+            }
+        }
+        writer.event(event);
+    }
+    writer.close_paragraph();
+    writer.section_properties();
+
+    // References are written as placeholders until every number is known
+    let references = &writer.numbering.references;
+    let link_references = |xml: &str| {
+        REFERENCE_PLACEHOLDER
+            .replace_all(xml, |caps: &Captures| {
+                let label = &caps["label"];
+                escape_xml(references.get(label).map_or(label, String::as_str))
+            })
+            .into_owned()
+    };
+    writer.docx.body = link_references(&writer.docx.body);
+    writer.docx.footnotes = link_references(&writer.docx.footnotes);
+    writer.docx
+}
+
+impl Writer<'_> {
+    /// Where XML is written: the pending heading, the open footnote or the body.
+    fn out(&mut self) -> &mut String {
+        match (&mut self.heading, &mut self.footnote) {
+            (Some(heading), _) => &mut heading.runs,
+            (None, Some((_, footnote))) => footnote,
+            (None, None) => &mut self.docx.body,
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) if self.code_block => self.code_lines(&text),
+            Event::Text(text) => self.run(&text, false),
+            Event::Code(text) => self.run(&text, true),
+            Event::Html(html) => self.html(&html),
+            Event::FootnoteReference(label) => {
+                let id = self.footnote_id(&label);
+                self.ensure_paragraph();
+                self.out().push_str(&format!(
+                    "<w:r><w:rPr><w:rStyle w:val=\"FootnoteReference\"/></w:rPr>\
+                     <w:footnoteReference w:id=\"{}\"/></w:r>",
+                    id
+                ));
+            }
+            Event::SoftBreak => self.run(" ", false),
+            Event::HardBreak => {
+                self.ensure_paragraph();
+                self.out().push_str("<w:r><w:br/></w:r>");
+            }
+            Event::Rule => {
+                self.close_paragraph();
+                self.out().push_str(
+                    "<w:p><w:pPr><w:pBdr><w:bottom w:val=\"single\" w:sz=\"6\" w:space=\"1\" \
+                     w:color=\"auto\"/></w:pBdr></w:pPr></w:p>",
+                );
+            }
+            Event::TaskListMarker(checked) => self.run(if checked { "☒ " } else { "☐ " }, false),
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            // Opened by its first run, so display math does not leave an empty paragraph
+            Tag::Paragraph => self.close_paragraph(),
+            Tag::Heading(level, id, _) => {
+                self.close_paragraph();
+                self.heading = Some(PendingHeading {
+                    level: level as usize,
+                    id: id.map(str::to_string),
+                    text: String::new(),
+                    runs: String::new(),
+                });
+                // The heading writes its own paragraph
+                self.paragraph_open = true;
+            }
+            Tag::BlockQuote => {
+                self.close_paragraph();
+                self.quotes += 1;
+            }
+            Tag::CodeBlock(_) => {
+                self.close_paragraph();
+                self.code_block = true;
+            }
+            Tag::List(start) => {
+                self.close_paragraph();
+                self.docx.lists.push((start.is_some(), start.unwrap_or(1)));
+                self.lists.push(self.docx.lists.len());
+            }
+            Tag::Item => {
+                self.close_paragraph();
+                self.item_start = true;
+            }
+            Tag::FootnoteDefinition(label) => {
+                self.close_paragraph();
+                let id = self.footnote_id(&label);
+                self.footnote = Some((id, String::new()));
+                self.footnote_mark = true;
+            }
+            Tag::Table(alignments) => {
+                self.close_paragraph();
+                let justification = if self.figure_depth > 0 {
+                    "<w:jc w:val=\"center\"/>"
+                } else {
+                    ""
+                };
+                let grid = "<w:gridCol/>".repeat(alignments.len());
+                self.out().push_str(&format!(
+                    "<w:tbl><w:tblPr><w:tblStyle w:val=\"Table\"/>\
+                     <w:tblW w:w=\"0\" w:type=\"auto\"/>{}</w:tblPr><w:tblGrid>{}</w:tblGrid>",
+                    justification, grid
+                ));
+                self.table = Some(Table {
+                    alignments,
+                    head: false,
+                    cell: 0,
+                    cell_has_paragraph: false,
+                });
+            }
+            Tag::TableHead | Tag::TableRow => {
+                let head = matches!(tag, Tag::TableHead);
+                if let Some(table) = self.table.as_mut() {
+                    table.head = head;
+                    table.cell = 0;
+                }
+                self.out().push_str(if head {
+                    "<w:tr><w:trPr><w:tblHeader/></w:trPr>"
+                } else {
+                    "<w:tr>"
+                });
+            }
+            Tag::TableCell => {
+                if let Some(table) = self.table.as_mut() {
+                    table.cell_has_paragraph = false;
+                }
+                self.out().push_str("<w:tc>");
+            }
+            Tag::Emphasis => self.italic += 1,
+            Tag::Strong => self.bold += 1,
+            Tag::Strikethrough => self.strike += 1,
+            Tag::Link(kind, url, _) => {
+                self.ensure_paragraph();
+                let open = match url.strip_prefix('#') {
+                    Some(anchor) => {
+                        format!("<w:hyperlink w:anchor=\"{}\">", bookmark_name(anchor))
+                    }
+                    None => {
+                        let target = match kind {
+                            LinkType::Email => format!("mailto:{}", url),
+                            _ => url.to_string(),
+                        };
+                        let id = self.relationship("hyperlink", target, true);
+                        format!("<w:hyperlink r:id=\"{}\">", id)
+                    }
+                };
+                self.out().push_str(&open);
+                self.links += 1;
+            }
+            Tag::Image(_, url, _) => self.image = Some((url.to_string(), String::new())),
+        }
+    }
+
+    fn end(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph => self.close_paragraph(),
+            Tag::Heading(..) => {
+                if let Some(pending) = self.heading.take() {
+                    self.paragraph_open = false;
+                    let heading = self
+                        .numbering
+                        .heading(pending.level, pending.id, &pending.text);
+                    self.heading_paragraph(heading, &pending.runs);
+                }
+            }
+            Tag::BlockQuote => {
+                self.close_paragraph();
+                self.quotes = self.quotes.saturating_sub(1);
+            }
+            Tag::CodeBlock(_) => {
+                self.close_paragraph();
+                self.code_block = false;
+            }
+            Tag::List(_) => {
+                self.close_paragraph();
+                self.lists.pop();
+            }
+            Tag::Item => {
+                self.close_paragraph();
+                self.item_start = false;
+            }
+            Tag::FootnoteDefinition(_) => {
+                if self.footnote_mark {
+                    self.ensure_paragraph();
+                }
+                self.close_paragraph();
+                if let Some((id, xml)) = self.footnote.take() {
+                    self.docx
+                        .footnotes
+                        .push_str(&format!("<w:footnote w:id=\"{}\">{}</w:footnote>", id, xml));
+                }
+            }
+            Tag::Table(_) => {
+                self.out().push_str("</w:tbl>");
+                self.table = None;
+            }
+            Tag::TableHead | Tag::TableRow => self.out().push_str("</w:tr>"),
+            Tag::TableCell => {
+                self.close_paragraph();
+                let mut xml = String::new();
+                if let Some(table) = self.table.as_mut() {
+                    // Every cell needs a paragraph
+                    if !table.cell_has_paragraph {
+                        xml.push_str("<w:p/>");
+                    }
+                    table.cell += 1;
+                }
+                xml.push_str("</w:tc>");
+                self.out().push_str(&xml);
+            }
+            Tag::Emphasis => self.italic = self.italic.saturating_sub(1),
+            Tag::Strong => self.bold = self.bold.saturating_sub(1),
+            Tag::Strikethrough => self.strike = self.strike.saturating_sub(1),
+            Tag::Link(..) => {
+                self.out().push_str("</w:hyperlink>");
+                self.links = self.links.saturating_sub(1);
+            }
+            Tag::Image(..) => {
+                if let Some((url, alt)) = self.image.take() {
+                    self.picture(&url, &alt, None);
+                }
+            }
+        }
+    }
+
+    fn ensure_paragraph(&mut self) {
+        if !self.paragraph_open {
+            let properties = self.paragraph_properties();
+            self.open_paragraph(&properties);
+        }
+    }
+
+    fn open_paragraph(&mut self, properties: &str) {
+        let mut xml = String::from("<w:p>");
+        if !properties.is_empty() {
+            xml.push_str(&format!("<w:pPr>{}</w:pPr>", properties));
+        }
+        if self.footnote_mark {
+            self.footnote_mark = false;
+            xml.push_str(
+                "<w:r><w:rPr><w:rStyle w:val=\"FootnoteReference\"/></w:rPr><w:footnoteRef/></w:r>",
+            );
+            xml.push_str(&text_run(" "));
+        }
+        self.out().push_str(&xml);
+        self.paragraph_open = true;
+        if let Some(table) = self.table.as_mut() {
+            table.cell_has_paragraph = true;
+        }
+    }
+
+    fn close_paragraph(&mut self) {
+        if self.paragraph_open && self.heading.is_none() {
+            self.out().push_str("</w:p>");
+            self.paragraph_open = false;
+        }
+    }
+
+    /// Paragraph properties for the context the next paragraph is in.
+    fn paragraph_properties(&mut self) -> String {
+        let mut style = None;
+        let mut numbering = String::new();
+        let mut indent = String::new();
+        let mut justification = None;
+        if self.footnote.is_some() {
+            style = Some("FootnoteText");
+        }
+        if self.code_block {
+            style = Some("SourceCode");
+        } else if let Some(table) = &self.table {
+            style = Some("Compact");
+            justification = match table.alignments.get(table.cell) {
+                Some(Alignment::Center) => Some("center"),
+                Some(Alignment::Right) => Some("right"),
+                _ => None,
+            };
+        } else if let Some(&list) = self.lists.last() {
+            style = Some("ListParagraph");
+            let level = self.lists.len() - 1;
+            if self.item_start {
+                self.item_start = false;
+                numbering = format!(
+                    "<w:numPr><w:ilvl w:val=\"{}\"/><w:numId w:val=\"{}\"/></w:numPr>",
+                    level, list
+                );
+            } else {
+                indent = format!("<w:ind w:left=\"{}\"/>", 720 * (level + 1));
+            }
+        } else if self.quotes > 0 {
+            style = Some("Quote");
+        }
+        if self.figure_depth > 0 {
+            justification = Some("center");
+        }
+
+        let mut properties = String::new();
+        if let Some(style) = style {
+            properties.push_str(&format!("<w:pStyle w:val=\"{}\"/>", style));
+        }
+        properties.push_str(&numbering);
+        if let Some(kind) = self.admonitions.last() {
+            properties.push_str(&self.admonition_properties(kind));
+        }
+        properties.push_str(&indent);
+        if let Some(justification) = justification {
+            properties.push_str(&format!("<w:jc w:val=\"{}\"/>", justification));
+        }
+        properties
+    }
+
+    /// Border and shading of an admonition's paragraphs, matching the template's palette.
+    /// Word draws consecutive paragraphs with the same border as one box.
+    fn admonition_properties(&self, kind: &str) -> String {
+        let accent = hex_color(&self.prefs.accent_color).unwrap_or_else(|| "1E40AF".into());
+        let (fill, border) = match kind {
+            "info" => ("E0F2FE".to_string(), "BAE6FD".to_string()),
+            "tip" => ("DCFCE7".to_string(), "BBF7D0".to_string()),
+            "warning" => ("FEF9C3".to_string(), "FDE047".to_string()),
+            "important" => ("FEF3C7".to_string(), "FBBF24".to_string()),
+            "caution" => ("FEE2E2".to_string(), "FCA5A5".to_string()),
+            _ => (tint(&accent), accent),
+        };
+        let side = |name: &str| {
+            format!(
+                "<w:{} w:val=\"single\" w:sz=\"4\" w:space=\"4\" w:color=\"{}\"/>",
+                name, border
+            )
+        };
+        format!(
+            "<w:pBdr>{}{}{}{}</w:pBdr><w:shd w:val=\"clear\" w:color=\"auto\" w:fill=\"{}\"/>",
+            side("top"),
+            side("left"),
+            side("bottom"),
+            side("right"),
+            fill
+        )
+    }
+
+    /// Write `text` as a run with the current formatting, or as inline code.
+    fn run(&mut self, text: &str, code: bool) {
+        if let Some((_, alt)) = self.image.as_mut() {
+            alt.push_str(text);
+            return;
+        }
+        if text.is_empty() {
+            return;
+        }
+        if let Some(heading) = self.heading.as_mut() {
+            heading.text.push_str(text);
+        }
+        self.ensure_paragraph();
+
+        let mut properties = String::new();
+        if code {
+            properties.push_str("<w:rStyle w:val=\"VerbatimChar\"/>");
+        } else if self.links > 0 {
+            properties.push_str("<w:rStyle w:val=\"Hyperlink\"/>");
+        }
+        if self.bold > 0 || self.table.as_ref().is_some_and(|table| table.head) {
+            properties.push_str("<w:b/>");
+        }
+        if self.italic > 0 {
+            properties.push_str("<w:i/>");
+        }
+        if self.strike > 0 {
+            properties.push_str("<w:strike/>");
+        }
+        let mut xml = String::from("<w:r>");
+        if !properties.is_empty() {
+            xml.push_str(&format!("<w:rPr>{}</w:rPr>", properties));
+        }
+        xml.push_str(&format!(
+            "<w:t xml:space=\"preserve\">{}</w:t></w:r>",
+            escape_xml(text)
+        ));
+        self.out().push_str(&xml);
+    }
+
+    /// Code block text: a paragraph per line.
+    fn code_lines(&mut self, text: &str) {
+        for line in text.split_inclusive('\n') {
+            self.ensure_paragraph();
+            let content = line.trim_end_matches(['\n', '\r']);
+            if !content.is_empty() {
+                let run = text_run(content);
+                self.out().push_str(&run);
+            }
+            if line.ends_with('\n') {
+                self.close_paragraph();
+            }
+        }
+    }
+
+    fn footnote_id(&mut self, label: &str) -> usize {
+        let next = self.footnote_ids.len() + 1;
+        *self.footnote_ids.entry(label.to_string()).or_insert(next)
+    }
+
+    /// Add a relationship of the part being written; returns its id.
+    fn relationship(&mut self, kind: &'static str, target: String, external: bool) -> String {
+        let count = self.docx.relationships.len() + self.docx.footnote_relationships.len();
+        // rId1 to rId4 are the fixed parts of the document
+        let id = format!("rId{}", count + 5);
+        let relationship = Relationship {
+            id: id.clone(),
+            kind,
+            target,
+            external,
+        };
+        if self.footnote.is_some() {
+            self.docx.footnote_relationships.push(relationship);
+        } else {
+            self.docx.relationships.push(relationship);
+        }
+        id
+    }
+
+    fn bookmark(&mut self, label: &str) -> (String, String) {
+        self.bookmarks += 1;
+        (
+            format!(
+                "<w:bookmarkStart w:id=\"{}\" w:name=\"{}\"/>",
+                self.bookmarks,
+                bookmark_name(label)
+            ),
+            format!("<w:bookmarkEnd w:id=\"{}\"/>", self.bookmarks),
+        )
+    }
+
+    fn heading_paragraph(&mut self, heading: Heading, runs: &str) {
+        let (start, end) = self.bookmark(&heading.id);
+        let number = heading
+            .number
+            .as_ref()
+            .map(|number| text_run(&format!("{} ", number)))
+            .unwrap_or_default();
+        let xml = format!(
+            "<w:p><w:pPr><w:pStyle w:val=\"Heading{}\"/></w:pPr>{}{}{}{}</w:p>",
+            heading.level, start, number, runs, end
+        );
+        self.out().push_str(&xml);
+        self.docx.headings.push(heading);
+    }
+
+    /// Embed the image `src` in the current paragraph, `width` wide (a percentage of the text
+    /// width or a length) or at its own size; never wider than the text.
+    fn picture(&mut self, src: &str, alt: &str, width: Option<&str>) {
+        self.ensure_paragraph();
+        let Some(image) = (self.load_image)(src) else {
+            let text = if alt.is_empty() { src } else { alt };
+            self.run(&format!("[{}]", text), false);
+            return;
+        };
+
+        let max_width = self.page.text_width() as f64 * EMU_PER_TWIP;
+        let (mut cx, mut cy) = (image.width * EMU_PER_PX, image.height * EMU_PER_PX);
+        let target = width.and_then(|width| match width.trim().strip_suffix('%') {
+            Some(percent) => percent
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .map(|p| max_width * p / 100.0),
+            None => twips(width, self.prefs.font_size).map(|t| t as f64 * EMU_PER_TWIP),
+        });
+        let target = target.unwrap_or(cx).min(max_width);
+        if cx > 0.0 && target > 0.0 {
+            cy *= target / cx;
+            cx = target;
+        }
+
+        let index = self.docx.media.len() + 1;
+        let name = format!("image{}.{}", index, image.extension);
+        self.docx.media.push((name.clone(), image.data));
+        let blip = self.relationship("image", format!("media/{}", name), false);
+        let svg = match image.svg {
+            Some(svg) => {
+                let svg_name = format!("image{}.svg", index);
+                self.docx.media.push((svg_name.clone(), svg));
+                let id = self.relationship("image", format!("media/{}", svg_name), false);
+                format!(
+                    "<a:extLst><a:ext uri=\"{{96DAC541-7B7A-43D3-8B79-37D633B846F1}}\">\
+                     <asvg:svgBlip \
+                     xmlns:asvg=\"http://schemas.microsoft.com/office/drawing/2016/SVG/main\" \
+                     r:embed=\"{}\"/></a:ext></a:extLst>",
+                    id
+                )
+            }
+            None => String::new(),
+        };
+
+        self.drawings += 1;
+        let (cx, cy) = (cx.round() as i64, cy.round() as i64);
+        let xml = format!(
+            "<w:r><w:drawing><wp:inline distT=\"0\" distB=\"0\" distL=\"0\" distR=\"0\">\
+             <wp:extent cx=\"{cx}\" cy=\"{cy}\"/><wp:docPr id=\"{id}\" name=\"Picture {id}\" \
+             descr=\"{alt}\"/>\
+             <a:graphic><a:graphicData \
+             uri=\"http://schemas.openxmlformats.org/drawingml/2006/picture\">\
+             <pic:pic><pic:nvPicPr><pic:cNvPr id=\"{id}\" name=\"{name}\"/><pic:cNvPicPr/>\
+             </pic:nvPicPr>\
+             <pic:blipFill><a:blip r:embed=\"{blip}\">{svg}</a:blip><a:stretch><a:fillRect/>\
+             </a:stretch></pic:blipFill>\
+             <pic:spPr><a:xfrm><a:off x=\"0\" y=\"0\"/><a:ext cx=\"{cx}\" cy=\"{cy}\"/></a:xfrm>\
+             <a:prstGeom prst=\"rect\"><a:avLst/></a:prstGeom></pic:spPr></pic:pic>\
+             </a:graphicData></a:graphic></wp:inline></w:drawing></w:r>",
+            cx = cx,
+            cy = cy,
+            id = self.drawings,
+            alt = escape_xml(alt),
+            name = name,
+            blip = blip,
+            svg = svg
+        );
+        self.out().push_str(&xml);
+    }
+
+    /// Handle a piece of raw HTML: the preprocessor's markup becomes Word content, other tags
+    /// are dropped and their text kept.
+    fn html(&mut self, html: &str) {
+        let mut last = 0;
+        for caps in html_export::MARKUP.captures_iter(html) {
+            let matched = caps.get(0).expect("BUG: capture group 0 always exists");
+            self.raw_html(&html[last..matched.start()]);
+            self.markup(&caps);
+            last = matched.end();
+        }
+        self.raw_html(&html[last..]);
+    }
+
+    fn raw_html(&mut self, html: &str) {
+        let mut last = 0;
+        for caps in RAW_HTML.captures_iter(html) {
+            let matched = caps.get(0).expect("BUG: capture group 0 always exists");
+            self.html_text(&html[last..matched.start()]);
+            if let Some(label) = caps.name("label") {
+                // Linked to the label's bookmark; the text is filled in at the end
+                self.ensure_paragraph();
+                let xml = format!(
+                    "<w:hyperlink w:anchor=\"{}\">{}</w:hyperlink>",
+                    bookmark_name(label.as_str()),
+                    text_run(&format!("{{{{tf-ref:{}}}}}", label.as_str()))
+                );
+                self.out().push_str(&xml);
+            } else if caps.name("br").is_some() {
+                self.ensure_paragraph();
+                self.out().push_str("<w:r><w:br/></w:r>");
+            }
+            last = matched.end();
+        }
+        self.html_text(&html[last..]);
+    }
+
+    fn html_text(&mut self, text: &str) {
+        if !text.trim().is_empty() {
+            self.run(text.trim_end_matches('\n'), false);
+        }
+    }
+
+    fn markup(&mut self, caps: &Captures) {
+        if let Some(typst) = caps.name("typst") {
+            return self.raw_typst(typst.as_str().trim());
+        }
+        if let Some(level) = caps.name("level") {
+            self.close_paragraph();
+            let text = html_export::strip_tags(&caps["text"]);
+            let id = html_export::attribute(&caps["heading_attrs"], "id");
+            let heading = self
+                .numbering
+                .heading(level.as_str().parse().unwrap_or(1), id, &text);
+            self.heading_paragraph(heading, &text_run(&text));
+            return;
+        }
+        if let Some(src) = caps.name("src") {
+            let alt = html_export::attribute(&caps[0], "alt").unwrap_or_default();
+            return self.picture(src.as_str(), &alt, None);
+        }
+
+        let attrs = caps.name("attrs").map_or("", |attrs| attrs.as_str());
+        match &caps["tag"] {
+            "<figure" => {
+                self.figure_depth += 1;
+                let label = html_export::attribute(attrs, "id").unwrap_or_default();
+                let kind = if label.starts_with("tbl:") {
+                    "tbl"
+                } else {
+                    "fig"
+                };
+                let (_, reference) = self.numbering.next(kind, Some(&label));
+                self.caption = Some((reference, label));
+            }
+            "</figure" => self.figure_depth = self.figure_depth.saturating_sub(1),
+            "<figcaption" | "<tf-caption" => {
+                self.close_paragraph();
+                self.open_paragraph("<w:pStyle w:val=\"Caption\"/>");
+                if let Some((reference, label)) = self.caption.take() {
+                    let (start, end) = self.bookmark(&label);
+                    let xml = format!("{}{}{}", start, text_run(&format!("{}: ", reference)), end);
+                    self.out().push_str(&xml);
+                }
+            }
+            "</figcaption" | "</tf-caption" => self.close_paragraph(),
+            "<tf-math" => {
+                let formula = html_export::attribute(attrs, "i")
+                    .and_then(|i| i.parse::<usize>().ok())
+                    .and_then(|i| self.formulas.get(i));
+                if let Some(formula) = formula {
+                    let (latex, display, label) = (
+                        formula.latex.clone(),
+                        formula.display,
+                        formula.label.clone(),
+                    );
+                    self.formula(&latex, display, label.as_deref());
+                }
+            }
+            "<tf-cite" => self.run(&html_export::citation_text(attrs), false),
+            "<tf-admonition" => {
+                self.close_paragraph();
+                let kind =
+                    html_export::attribute(attrs, "kind").unwrap_or_else(|| "note".to_string());
+                let title = html_export::attribute(attrs, "title")
+                    .unwrap_or_else(|| html_export::capitalize(&kind));
+                self.admonitions.push(kind);
+                self.bold += 1;
+                self.run(&title, false);
+                self.bold -= 1;
+                self.close_paragraph();
+            }
+            "</tf-admonition" => {
+                self.close_paragraph();
+                self.admonitions.pop();
+            }
+            _ => {}
+        }
+    }
+
+    /// Inline math joins the paragraph; display math gets its own, with the number of a
+    /// labelled equation right-aligned.
+    fn formula(&mut self, latex: &str, display: bool, label: Option<&str>) {
+        let omml = math::to_omml(latex);
+        if !display {
+            self.ensure_paragraph();
+            self.out().push_str(&omml);
+            return;
+        }
+        self.close_paragraph();
+        let xml = match label {
+            Some(label) => {
+                let (number, _) = self.numbering.next("eq", Some(label));
+                let (start, end) = self.bookmark(label);
+                let width = self.page.text_width();
+                format!(
+                    "<w:p><w:pPr><w:tabs><w:tab w:val=\"center\" w:pos=\"{}\"/>\
+                     <w:tab w:val=\"right\" w:pos=\"{}\"/></w:tabs></w:pPr>\
+                     <w:r><w:tab/></w:r>{}<w:r><w:tab/></w:r>{}{}{}</w:p>",
+                    width / 2,
+                    width,
+                    omml,
+                    start,
+                    text_run(&number),
+                    end
+                )
+            }
+            None => format!("<w:p><m:oMathPara>{}</m:oMathPara></w:p>", omml),
+        };
+        self.out().push_str(&xml);
+    }
+
+    /// Handle a `<!--raw-typst ... -->` comment: diagrams become pictures, page breaks stay,
+    /// anything else is Typst only.
+    fn raw_typst(&mut self, typst: &str) {
+        if let Some(args) = typst.strip_prefix("#diagram_render(") {
+            let args = html_export::diagram_args(args.trim_end_matches(')'));
+            let Some(asset) = args.get("asset") else {
+                return;
+            };
+            self.close_paragraph();
+            let justification = match args.get("align").map(String::as_str) {
+                Some("left") => "left",
+                Some("right") => "right",
+                _ => "center",
+            };
+            self.open_paragraph(&format!("<w:jc w:val=\"{}\"/>", justification));
+            self.picture(asset, "", args.get("width").map(String::as_str));
+            self.close_paragraph();
+            if let Some(caption) = args.get("caption") {
+                let (_, reference) = self.numbering.next("fig", None);
+                self.open_paragraph("<w:pStyle w:val=\"Caption\"/>");
+                let run = text_run(&format!("{}: {}", reference, caption));
+                self.out().push_str(&run);
+                self.close_paragraph();
+            }
+        } else if typst.starts_with("#pagebreak") {
+            self.close_paragraph();
+            self.out().push_str(PAGE_BREAK);
+        }
+    }
+
+    fn cover(&mut self) {
+        let prefs = self.prefs;
+        if !prefs.cover_image.trim().is_empty() {
+            self.open_paragraph("<w:jc w:val=\"center\"/>");
+            self.picture(prefs.cover_image.trim(), "", Some(&prefs.cover_image_width));
+            self.close_paragraph();
+        }
+        for (style, text) in [
+            ("Title", &prefs.cover_title),
+            ("Subtitle", &prefs.cover_writer),
+        ] {
+            if !text.trim().is_empty() {
+                let xml = format!(
+                    "<w:p><w:pPr><w:pStyle w:val=\"{}\"/></w:pPr>{}</w:p>",
+                    style,
+                    text_run(text.trim())
+                );
+                self.out().push_str(&xml);
+            }
+        }
+        self.out().push_str(PAGE_BREAK);
+    }
+
+    /// A table of contents field, filled in by Word when the document is opened.
+    fn table_of_contents(&mut self) {
+        let xml = format!(
+            "<w:p><w:pPr><w:pStyle w:val=\"TOCHeading\"/></w:pPr>{}</w:p>\
+             <w:p><w:r><w:fldChar w:fldCharType=\"begin\" w:dirty=\"true\"/></w:r>\
+             <w:r><w:instrText xml:space=\"preserve\"> TOC \\o \"1-3\" \\h \\z \\u </w:instrText>\
+             </w:r>\
+             <w:r><w:fldChar w:fldCharType=\"separate\"/></w:r>{}\
+             <w:r><w:fldChar w:fldCharType=\"end\"/></w:r></w:p>{}",
+            text_run(html_export::toc_title(self.prefs)),
+            text_run("Update fields to show the table of contents."),
+            PAGE_BREAK
+        );
+        self.out().push_str(&xml);
+    }
+
+    fn section_properties(&mut self) {
+        let page = &self.page;
+        let xml = format!(
+            "<w:sectPr><w:pgSz w:w=\"{}\" w:h=\"{}\"/><w:pgMar w:top=\"{y}\" w:right=\"{x}\" \
+             w:bottom=\"{y}\" w:left=\"{x}\" w:header=\"720\" w:footer=\"720\" w:gutter=\"0\"/>\
+             </w:sectPr>",
+            page.width,
+            page.height,
+            x = page.margin_x,
+            y = page.margin_y
+        );
+        self.docx.body.push_str(&xml);
+    }
+}
+
+/// Every part of the package, by path.
+fn package(docx: &Docx, prefs: &Preferences, title: &str) -> Vec<(String, Vec<u8>)> {
+    let background = hex_color(&prefs.page_bg_color).filter(|color| color != "FFFFFF");
+    let mut parts = vec![
+        ("[Content_Types].xml".to_string(), CONTENT_TYPES.to_string()),
+        ("_rels/.rels".to_string(), PACKAGE_RELATIONSHIPS.to_string()),
+        (
+            "docProps/core.xml".to_string(),
+            format!(
+                "{}<cp:coreProperties xmlns:cp=\"\
+                 http://schemas.openxmlformats.org/package/2006/metadata/core-properties\" \
+                 xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+                 xmlns:dcterms=\"http://purl.org/dc/terms/\" \
+                 xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\"><dc:title>{}</dc:title>\
+                 <dc:creator>{}</dc:creator>\
+                 <dcterms:created xsi:type=\"dcterms:W3CDTF\">{}</dcterms:created>\
+                 </cp:coreProperties>",
+                XML_HEADER,
+                escape_xml(title),
+                escape_xml(prefs.cover_writer.trim()),
+                chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ")
+            ),
+        ),
+        (
+            "word/document.xml".to_string(),
+            format!(
+                "{}<w:document {}>{}<w:body>{}</w:body></w:document>",
+                XML_HEADER,
+                NAMESPACES,
+                background
+                    .as_ref()
+                    .map(|color| format!("<w:background w:color=\"{}\"/>", color))
+                    .unwrap_or_default(),
+                docx.body
+            ),
+        ),
+        ("word/styles.xml".to_string(), styles(prefs)),
+        ("word/numbering.xml".to_string(), numbering(&docx.lists)),
+        (
+            "word/footnotes.xml".to_string(),
+            format!(
+                "{}<w:footnotes {}>\
+                 <w:footnote w:type=\"separator\" w:id=\"-1\"><w:p><w:r><w:separator/></w:r></w:p>\
+                 </w:footnote>\
+                 <w:footnote w:type=\"continuationSeparator\" w:id=\"0\"><w:p><w:r>\
+                 <w:continuationSeparator/></w:r></w:p></w:footnote>\
+                 {}</w:footnotes>",
+                XML_HEADER, NAMESPACES, docx.footnotes
+            ),
+        ),
+        (
+            "word/settings.xml".to_string(),
+            format!(
+                "{}<w:settings \
+                 xmlns:w=\"http://schemas.openxmlformats.org/wordprocessingml/2006/main\">{}\
+                 <w:defaultTabStop w:val=\"720\"/>{}\
+                 <w:compat><w:compatSetting w:name=\"compatibilityMode\" \
+                 w:uri=\"http://schemas.microsoft.com/office/word\" w:val=\"15\"/></w:compat>\
+                 </w:settings>",
+                XML_HEADER,
+                if background.is_some() {
+                    "<w:displayBackgroundShape/>"
+                } else {
+                    ""
+                },
+                if prefs.toc {
+                    "<w:updateFields w:val=\"true\"/>"
+                } else {
+                    ""
+                }
+            ),
+        ),
+    ];
+    let fixed = [
+        ("rId1", "styles", "styles.xml"),
+        ("rId2", "numbering", "numbering.xml"),
+        ("rId3", "footnotes", "footnotes.xml"),
+        ("rId4", "settings", "settings.xml"),
+    ]
+    .map(|(id, kind, target)| Relationship {
+        id: id.to_string(),
+        kind,
+        target: target.to_string(),
+        external: false,
+    });
+    parts.push((
+        "word/_rels/document.xml.rels".to_string(),
+        relationships(fixed.iter().chain(&docx.relationships)),
+    ));
+    if !docx.footnote_relationships.is_empty() {
+        parts.push((
+            "word/_rels/footnotes.xml.rels".to_string(),
+            relationships(docx.footnote_relationships.iter()),
+        ));
+    }
+
+    let mut parts: Vec<(String, Vec<u8>)> = parts
+        .into_iter()
+        .map(|(name, text)| (name, text.into_bytes()))
+        .collect();
+    for (name, data) in &docx.media {
+        parts.push((format!("word/media/{}", name), data.clone()));
+    }
+    parts
+}
+
+fn relationships<'r>(relationships: impl Iterator<Item = &'r Relationship>) -> String {
+    let mut xml = format!(
+        "{}<Relationships xmlns=\"http://schemas.openxmlformats.org/package/2006/relationships\">",
+        XML_HEADER
+    );
+    for relationship in relationships {
+        xml.push_str(&format!(
+            "<Relationship Id=\"{}\" Type=\"{}/{}\" Target=\"{}\"{}/>",
+            relationship.id,
+            RELATIONSHIPS,
+            relationship.kind,
+            escape_xml(&relationship.target),
+            if relationship.external {
+                " TargetMode=\"External\""
+            } else {
+                ""
+            }
+        ));
+    }
+    xml.push_str("</Relationships>");
+    xml
+}
+
+const CONTENT_TYPES: &str = concat!(
+    "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n",
+    "<Types xmlns=\"http://schemas.openxmlformats.org/package/2006/content-types\">",
+    "<Default Extension=\"rels\" ContentType=\"\
+     application/vnd.openxmlformats-package.relationships+xml\"/>",
+    "<Default Extension=\"xml\" ContentType=\"application/xml\"/>",
+    "<Default Extension=\"png\" ContentType=\"image/png\"/>",
+    "<Default Extension=\"jpeg\" ContentType=\"image/jpeg\"/>",
+    "<Default Extension=\"gif\" ContentType=\"image/gif\"/>",
+    "<Default Extension=\"svg\" ContentType=\"image/svg+xml\"/>",
+    "<Override PartName=\"/word/document.xml\" ContentType=\"\
+     application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml\"/>",
+    "<Override PartName=\"/word/styles.xml\" ContentType=\"\
+     application/vnd.openxmlformats-officedocument.wordprocessingml.styles+xml\"/>",
+    "<Override PartName=\"/word/numbering.xml\" ContentType=\"\
+     application/vnd.openxmlformats-officedocument.wordprocessingml.numbering+xml\"/>",
+    "<Override PartName=\"/word/footnotes.xml\" ContentType=\"\
+     application/vnd.openxmlformats-officedocument.wordprocessingml.footnotes+xml\"/>",
+    "<Override PartName=\"/word/settings.xml\" ContentType=\"\
+     application/vnd.openxmlformats-officedocument.wordprocessingml.settings+xml\"/>",
+    "<Override PartName=\"/docProps/core.xml\" ContentType=\"\
+     application/vnd.openxmlformats-package.core-properties+xml\"/>",
+    "</Types>",
+);
+
+const PACKAGE_RELATIONSHIPS: &str = concat!(
+    "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n",
+    "<Relationships xmlns=\"http://schemas.openxmlformats.org/package/2006/relationships\">",
+    "<Relationship Id=\"rId1\" Type=\"\
+     http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument\" \
+     Target=\"word/document.xml\"/>",
+    "<Relationship Id=\"rId2\" Type=\"\
+     http://schemas.openxmlformats.org/package/2006/relationships/metadata/core-properties\" \
+     Target=\"docProps/core.xml\"/>",
+    "</Relationships>",
+);
+
+/// Bullet lists use the first abstract numbering, ordered lists the second; every list gets
+/// its own instance so its numbering starts over.
+fn numbering(lists: &[(bool, u64)]) -> String {
+    let mut xml = format!(
+        "{}<w:numbering xmlns:w=\"http://schemas.openxmlformats.org/wordprocessingml/2006/main\">",
+        XML_HEADER
+    );
+    for (id, ordered) in [(0, false), (1, true)] {
+        xml.push_str(&format!(
+            "<w:abstractNum w:abstractNumId=\"{}\"><w:multiLevelType w:val=\"hybridMultilevel\"/>",
+            id
+        ));
+        for level in 0..9 {
+            let (format, text) = if ordered {
+                let format = ["decimal", "lowerLetter", "lowerRoman"][level % 3];
+                (format, format!("%{}.", level + 1))
+            } else {
+                ("bullet", ["•", "◦", "▪"][level % 3].to_string())
+            };
+            xml.push_str(&format!(
+                "<w:lvl w:ilvl=\"{}\"><w:start w:val=\"1\"/><w:numFmt w:val=\"{}\"/>\
+                 <w:lvlText w:val=\"{}\"/>\
+                 <w:lvlJc w:val=\"left\"/><w:pPr><w:ind w:left=\"{}\" w:hanging=\"360\"/></w:pPr>\
+                 </w:lvl>",
+                level,
+                format,
+                text,
+                720 * (level + 1)
+            ));
+        }
+        xml.push_str("</w:abstractNum>");
+    }
+    for (index, &(ordered, start)) in lists.iter().enumerate() {
+        xml.push_str(&format!(
+            "<w:num w:numId=\"{}\"><w:abstractNumId w:val=\"{}\"/>",
+            index + 1,
+            u8::from(ordered)
+        ));
+        if ordered {
+            for level in 0..9 {
+                xml.push_str(&format!(
+                    "<w:lvlOverride w:ilvl=\"{}\"><w:startOverride w:val=\"{}\"/></w:lvlOverride>",
+                    level, start
+                ));
+            }
+        }
+        xml.push_str("</w:num>");
+    }
+    xml.push_str("</w:numbering>");
+    xml
+}
+
+/// Styles from the font, size, spacing and colour preferences, named as Word names its
+/// built-in styles so they map onto the reviewer's own.
+fn styles(prefs: &Preferences) -> String {
+    let half_points = |scale: f32| (prefs.font_size * scale * 2.0).round() as i64;
+    let size = |scale: f32| {
+        format!(
+            "<w:sz w:val=\"{0}\"/><w:szCs w:val=\"{0}\"/>",
+            half_points(scale)
+        )
+    };
+    let fonts = |name: &str| {
+        if name.trim().is_empty() {
+            String::new()
+        } else {
+            format!(
+                "<w:rFonts w:ascii=\"{0}\" w:hAnsi=\"{0}\" w:eastAsia=\"{0}\" w:cs=\"{0}\"/>",
+                escape_xml(name.trim())
+            )
+        }
+    };
+    let color = |color: Option<String>| {
+        color
+            .map(|color| format!("<w:color w:val=\"{}\"/>", color))
+            .unwrap_or_default()
+    };
+    let accent = hex_color(&prefs.accent_color).unwrap_or_else(|| "1E40AF".to_string());
+    let after = twips(&prefs.paragraph_spacing, prefs.font_size).unwrap_or(160);
+    let line = (prefs.line_height * 240.0).round() as i64;
+
+    let mut xml = format!(
+        "{}<w:styles xmlns:w=\"http://schemas.openxmlformats.org/wordprocessingml/2006/main\">\
+         <w:docDefaults><w:rPrDefault><w:rPr>{}{}{}</w:rPr></w:rPrDefault>\
+         <w:pPrDefault><w:pPr><w:spacing w:after=\"{}\" w:line=\"{}\" w:lineRule=\"auto\"/></w:pPr>\
+         </w:pPrDefault>\
+         </w:docDefaults>",
+        XML_HEADER,
+        fonts(&prefs.fonts.main),
+        color(hex_color(&prefs.font_color)),
+        size(1.0),
+        after,
+        line
+    );
+    let mut paragraph = |id: &str, name: &str, properties: &str, run: &str| {
+        xml.push_str(&format!(
+            "<w:style w:type=\"paragraph\" w:styleId=\"{}\"><w:name w:val=\"{}\"/>",
+            id, name
+        ));
+        if id != "Normal" {
+            xml.push_str("<w:basedOn w:val=\"Normal\"/><w:next w:val=\"Normal\"/>");
+        }
+        xml.push_str(&format!(
+            "<w:qFormat/><w:pPr>{}</w:pPr><w:rPr>{}</w:rPr></w:style>",
+            properties, run
+        ));
+    };
+    paragraph("Normal", "Normal", "", "");
+    let scale = prefs.heading_scale;
+    for (level, factor) in [2.0, 1.6, 1.3, 1.1, 1.0, 1.0].into_iter().enumerate() {
+        paragraph(
+            &format!("Heading{}", level + 1),
+            &format!("heading {}", level + 1),
+            &format!(
+                "<w:keepNext/><w:spacing w:before=\"{}\" w:after=\"120\"/>\
+                 <w:outlineLvl w:val=\"{}\"/>",
+                if level == 0 { 360 } else { 240 },
+                level
+            ),
+            &format!(
+                "<w:b/>{}{}",
+                color(Some(accent.clone())),
+                size(factor * scale)
+            ),
+        );
+    }
+    let centered = "<w:jc w:val=\"center\"/>";
+    paragraph(
+        "Title",
+        "Title",
+        &format!("<w:spacing w:before=\"2400\" w:after=\"240\"/>{}", centered),
+        &format!("<w:b/>{}", size(2.5 * scale)),
+    );
+    paragraph("Subtitle", "Subtitle", centered, &size(1.3));
+    paragraph(
+        "TOCHeading",
+        "TOC Heading",
+        "<w:keepNext/><w:spacing w:before=\"240\" w:after=\"120\"/>",
+        &format!("<w:b/>{}{}", color(Some(accent.clone())), size(1.6 * scale)),
+    );
+    paragraph(
+        "Caption",
+        "caption",
+        centered,
+        &format!("<w:i/>{}", size(0.9)),
+    );
+    paragraph(
+        "Quote",
+        "Quote",
+        &format!(
+            "<w:pBdr><w:left w:val=\"single\" w:sz=\"12\" w:space=\"8\" w:color=\"{}\"/></w:pBdr>\
+             <w:ind w:left=\"360\"/>",
+            accent
+        ),
+        "",
+    );
+    paragraph(
+        "SourceCode",
+        "Source Code",
+        "<w:shd w:val=\"clear\" w:color=\"auto\" w:fill=\"F3F4F6\"/>\
+         <w:spacing w:after=\"0\" w:line=\"240\" w:lineRule=\"auto\"/>",
+        &format!("{}{}", fonts(&prefs.fonts.mono), size(0.9)),
+    );
+    paragraph(
+        "Compact",
+        "Compact",
+        "<w:spacing w:before=\"0\" w:after=\"0\"/>",
+        "",
+    );
+    paragraph(
+        "ListParagraph",
+        "List Paragraph",
+        "<w:spacing w:after=\"60\"/>",
+        "",
+    );
+    paragraph(
+        "FootnoteText",
+        "footnote text",
+        "<w:spacing w:after=\"0\" w:line=\"240\" w:lineRule=\"auto\"/>",
+        &size(0.85),
+    );
+
+    let character = |id: &str, name: &str, run: &str| {
+        format!(
+            "<w:style w:type=\"character\" w:styleId=\"{}\"><w:name w:val=\"{}\"/><w:rPr>{}</w:rPr>\
+             </w:style>",
+            id, name, run
+        )
+    };
+    xml.push_str(&character(
+        "VerbatimChar",
+        "Verbatim Char",
+        &format!("{}{}", fonts(&prefs.fonts.mono), size(0.9)),
+    ));
+    xml.push_str(&character(
+        "Hyperlink",
+        "Hyperlink",
+        &format!("{}<w:u w:val=\"single\"/>", color(Some(accent.clone()))),
+    ));
+    xml.push_str(&character(
+        "FootnoteReference",
+        "footnote reference",
+        "<w:vertAlign w:val=\"superscript\"/>",
+    ));
+    let border = |side: &str| {
+        format!(
+            "<w:{} w:val=\"single\" w:sz=\"4\" w:space=\"0\" w:color=\"A0A0A0\"/>",
+            side
+        )
+    };
+    xml.push_str(&format!(
+        "<w:style w:type=\"table\" w:styleId=\"Table\"><w:name w:val=\"Table\"/><w:tblPr>\
+         <w:tblBorders>{}</w:tblBorders>\
+         <w:tblCellMar><w:left w:w=\"108\" w:type=\"dxa\"/><w:right w:w=\"108\" w:type=\"dxa\"/>\
+         </w:tblCellMar></w:tblPr></w:style>",
+        ["top", "left", "bottom", "right", "insideH", "insideV"]
+            .map(border)
+            .concat()
+    ));
+    xml.push_str("</w:styles>");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The document for `markdown` with default preferences, where only `/assets/plot.png`
+    /// loads (as a 200×100 pixel PNG).
+    fn document(markdown: &str) -> Docx {
+        let mut load_image = |src: &str| {
+            (src == "/assets/plot.png").then(|| Image {
+                data: vec![1, 2, 3],
+                extension: "png",
+                svg: None,
+                width: 200.0,
+                height: 100.0,
+            })
+        };
+        write_document(markdown, &Preferences::default(), &mut load_image)
+    }
+
+    #[test]
+    fn headings_are_numbered_and_bookmarked() {
+        let body = document("# Intro\n\nText\n").body;
+        assert!(body.contains(
+            "<w:p><w:pPr><w:pStyle w:val=\"Heading1\"/></w:pPr>\
+             <w:bookmarkStart w:id=\"1\" w:name=\"tf_intro\"/>\
+             <w:r><w:t xml:space=\"preserve\">1 </w:t></w:r><w:r>\
+             <w:t xml:space=\"preserve\">Intro</w:t></w:r>"
+        ));
+        assert!(body.ends_with("</w:sectPr>"));
+    }
+
+    #[test]
+    fn equations_are_numbered_and_referenced() {
+        let body = document(concat!(
+            "See [@eq:energy] and $a_1$.\n\n",
+            "<!--raw-typst #crossref_equation(\"E = mc^2\", \"eq:energy\") -->\n",
+        ))
+        .body;
+        assert!(body.contains(
+            "<w:hyperlink w:anchor=\"tf_eq_energy\"><w:r>\
+             <w:t xml:space=\"preserve\">Equation (1)</w:t></w:r></w:hyperlink>"
+        ));
+        assert!(body.contains("<m:oMath><m:sSub>"));
+        assert!(body.contains(
+            "<w:r><w:tab/></w:r><w:bookmarkStart w:id=\"1\" w:name=\"tf_eq_energy\"/>\
+             <w:r><w:t xml:space=\"preserve\">(1)</w:t></w:r>"
+        ));
+    }
+
+    #[test]
+    fn links_and_code_keep_their_formatting() {
+        let docx = document("**[docs](https://example.com)** and `x`.\n");
+        assert!(docx.body.contains(
+            "<w:hyperlink r:id=\"rId5\"><w:r><w:rPr><w:rStyle w:val=\"Hyperlink\"/><w:b/></w:rPr>"
+        ));
+        assert!(docx.body.contains(
+            "<w:rStyle w:val=\"VerbatimChar\"/></w:rPr><w:t xml:space=\"preserve\">x</w:t>"
+        ));
+        assert_eq!(docx.relationships[0].target, "https://example.com");
+    }
+
+    #[test]
+    fn footnotes_go_to_their_own_part() {
+        let docx = document("Text[^n].\n\n[^n]: A [note](https://example.org).\n");
+        assert!(docx.body.contains("<w:footnoteReference w:id=\"1\"/>"));
+        assert!(!docx.body.contains("A </w:t>"));
+        assert!(docx.footnotes.starts_with(
+            "<w:footnote w:id=\"1\"><w:p><w:pPr><w:pStyle w:val=\"FootnoteText\"/></w:pPr><w:r>\
+             <w:rPr><w:rStyle w:val=\"FootnoteReference\"/></w:rPr><w:footnoteRef/></w:r>"
+        ));
+        assert_eq!(docx.footnote_relationships[0].target, "https://example.org");
+    }
+
+    #[test]
+    fn task_lists_keep_their_start_and_checkboxes() {
+        let docx = document("3. [x] done\n4. [ ] todo\n");
+        assert!(docx.body.contains(
+            "<w:numPr><w:ilvl w:val=\"0\"/><w:numId w:val=\"1\"/></w:numPr></w:pPr><w:r>\
+             <w:t xml:space=\"preserve\">☒ </w:t></w:r>"
+        ));
+        assert!(docx.body.contains("<w:t xml:space=\"preserve\">☐ </w:t>"));
+        assert_eq!(docx.lists, [(true, 3)]);
+    }
+
+    #[test]
+    fn nested_lists_number_by_level() {
+        let docx = document("- a\n  - b\n\n    more\n- c\n");
+        assert!(docx.body.contains(
+            "<w:numPr><w:ilvl w:val=\"1\"/><w:numId w:val=\"2\"/></w:numPr></w:pPr><w:r>\
+             <w:t xml:space=\"preserve\">b</w:t>"
+        ));
+        // Later paragraphs of an item are indented to its level instead of numbered
+        assert!(docx.body.contains(
+            "<w:pPr><w:pStyle w:val=\"ListParagraph\"/><w:ind w:left=\"1440\"/></w:pPr><w:r>\
+             <w:t xml:space=\"preserve\">more</w:t>"
+        ));
+        assert!(docx.body.contains(
+            "<w:numPr><w:ilvl w:val=\"0\"/><w:numId w:val=\"1\"/></w:numPr></w:pPr><w:r>\
+             <w:t xml:space=\"preserve\">c</w:t>"
+        ));
+        assert_eq!(docx.lists, [(false, 1), (false, 1)]);
+    }
+
+    #[test]
+    fn tables_keep_alignment_and_empty_cells() {
+        let body = document("| A | B |\n|:-:|---|\n| 1 | |\n").body;
+        assert!(body.contains("<w:tblGrid><w:gridCol/><w:gridCol/></w:tblGrid>"));
+        assert!(body.contains(
+            "<w:tc><w:p><w:pPr><w:pStyle w:val=\"Compact\"/><w:jc w:val=\"center\"/></w:pPr>\
+             <w:r><w:rPr><w:b/></w:rPr>"
+        ));
+        assert!(body.contains("<w:tc><w:p/></w:tc>"));
+    }
+
+    #[test]
+    fn figures_are_sized_and_captioned() {
+        let docx = document(
+            "<figure id=\"fig:plot\">\n\n![Plot](/assets/plot.png)<figcaption>Plot</figcaption>\
+             </figure>\n",
+        );
+        assert!(docx
+            .body
+            .contains("<wp:extent cx=\"1905000\" cy=\"952500\"/>"));
+        assert!(docx.body.contains(
+            "<w:bookmarkStart w:id=\"1\" w:name=\"tf_fig_plot\"/>\
+             <w:r><w:t xml:space=\"preserve\">Figure 1: </w:t></w:r>"
+        ));
+        assert_eq!(docx.media[0].0, "image1.png");
+    }
+}
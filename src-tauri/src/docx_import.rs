@@ -0,0 +1,1297 @@
+//! DOCX import: a Word document becomes Markdown.
+//!
+//! The package's XML is parsed into a small element tree, then walked: paragraphs become
+//! paragraphs, headings (by style name or outline level), list items (with their bullet or
+//! number from `numbering.xml`), quotes or code blocks; tables become pipe tables; bold,
+//! italic, strikethrough and code runs keep their formatting; footnotes are collected at the
+//! end; Office Math becomes LaTeX in `$...$`. Images are handed to a callback that stores them
+//! and returns the path to link.
+//!
+//! Anything without a Markdown counterpart (colours, fonts, comments, tracked deletions) is
+//! dropped, keeping the text.
+
+use crate::math;
+use anyhow::{anyhow, bail, Result};
+use quick_xml::events::{BytesStart, Event as XmlEvent};
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// Convert the Word document `docx` to Markdown. `save_image` stores an embedded image under
+/// (about) the given file name and returns the path to link it with.
+pub fn convert(
+    docx: &[u8],
+    save_image: &mut dyn FnMut(&str, &[u8]) -> Result<String>,
+) -> Result<String> {
+    let mut archive = ZipArchive::new(Cursor::new(docx))?;
+    let document = read_part(&mut archive, "word/document.xml")?
+        .ok_or_else(|| anyhow!("Not a Word document: word/document.xml is missing"))?;
+    let document = parse(&document)?;
+    let styles = match read_part(&mut archive, "word/styles.xml")? {
+        Some(xml) => Styles::new(&parse(&xml)?),
+        None => Styles::default(),
+    };
+    let numbering = match read_part(&mut archive, "word/numbering.xml")? {
+        Some(xml) => Numbering::new(&parse(&xml)?),
+        None => Numbering::default(),
+    };
+    let footnotes = match read_part(&mut archive, "word/footnotes.xml")? {
+        Some(xml) => parse(&xml)?,
+        None => Element::default(),
+    };
+    let relationships = read_relationships(&mut archive, "word/_rels/document.xml.rels")?;
+    let footnote_relationships = read_relationships(&mut archive, "word/_rels/footnotes.xml.rels")?;
+
+    let mut importer = Importer {
+        archive,
+        styles,
+        numbering,
+        counters: HashMap::new(),
+        footnotes: Vec::new(),
+        images: HashMap::new(),
+        save_image,
+    };
+    let mut blocks = Vec::new();
+    if let Some(body) = document.child("w:body") {
+        importer.blocks(body, &relationships, &mut blocks)?;
+    }
+    let mut markdown = join(&blocks);
+
+    // Footnotes in the order they are referenced; a footnote may reference another
+    let definitions: HashMap<&str, &Element> = footnotes
+        .children_named("w:footnote")
+        .filter_map(|footnote| Some((footnote.attribute("w:id")?, footnote)))
+        .collect();
+    let mut index = 0;
+    while index < importer.footnotes.len() {
+        let id = importer.footnotes[index].clone();
+        index += 1;
+        let Some(footnote) = definitions.get(id.as_str()) else {
+            continue;
+        };
+        let mut paragraphs = Vec::new();
+        for paragraph in footnote.children_named("w:p") {
+            let text = importer.paragraph_text(paragraph, &footnote_relationships)?;
+            if !text.is_empty() {
+                paragraphs.push(text);
+            }
+        }
+        markdown.push_str(&format!(
+            "\n\n[^{}]: {}",
+            index,
+            paragraphs.join("\n\n    ")
+        ));
+    }
+    markdown.push('\n');
+    Ok(markdown)
+}
+
+/// An element of the parsed XML, with its qualified name (`w:p`).
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|child| match child {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    fn children_named<'e>(&'e self, name: &'e str) -> impl Iterator<Item = &'e Element> {
+        self.elements().filter(move |element| element.name == name)
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|element| element.name == name)
+    }
+
+    /// `w:val` of the child `name`.
+    fn value(&self, name: &str) -> Option<&str> {
+        let child = self.child(name)?;
+        child
+            .attribute("w:val")
+            .or_else(|| child.attribute("m:val"))
+    }
+
+    /// First descendant whose name, without prefix, is `local`.
+    fn find(&self, local: &str) -> Option<&Element> {
+        self.elements().find_map(|element| {
+            let name = element.name.rsplit(':').next().unwrap_or("");
+            if name == local {
+                Some(element)
+            } else {
+                element.find(local)
+            }
+        })
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::new();
+        for child in &self.children {
+            match child {
+                Node::Text(content) => text.push_str(content),
+                Node::Element(element) => text.push_str(&element.text()),
+            }
+        }
+        text
+    }
+}
+
+/// Parse an XML part into its root element.
+fn parse(xml: &str) -> Result<Element> {
+    fn element(start: &BytesStart) -> Result<Element> {
+        let mut attributes = Vec::new();
+        for attribute in start.attributes() {
+            let attribute = attribute?;
+            attributes.push((
+                String::from_utf8_lossy(attribute.key.as_ref()).into_owned(),
+                attribute.unescape_value()?.into_owned(),
+            ));
+        }
+        Ok(Element {
+            name: String::from_utf8_lossy(start.name().as_ref()).into_owned(),
+            attributes,
+            children: Vec::new(),
+        })
+    }
+
+    let mut reader = Reader::from_str(xml);
+    let mut stack = vec![Element::default()];
+    loop {
+        let parent = stack
+            .last_mut()
+            .expect("BUG: the document node is never popped");
+        match reader.read_event()? {
+            XmlEvent::Start(start) => {
+                let element = element(&start)?;
+                stack.push(element);
+            }
+            XmlEvent::Empty(start) => parent.children.push(Node::Element(element(&start)?)),
+            XmlEvent::End(_) => {
+                let element = stack.pop().expect("BUG: the document node is never popped");
+                let Some(parent) = stack.last_mut() else {
+                    bail!("Unbalanced XML");
+                };
+                parent.children.push(Node::Element(element));
+            }
+            XmlEvent::Text(text) => parent
+                .children
+                .push(Node::Text(text.unescape()?.into_owned())),
+            XmlEvent::CData(data) => parent.children.push(Node::Text(
+                String::from_utf8_lossy(&data.into_inner()).into_owned(),
+            )),
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+    }
+    let document = stack.swap_remove(0);
+    document
+        .children
+        .into_iter()
+        .find_map(|child| match child {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+        .ok_or_else(|| anyhow!("Empty XML part"))
+}
+
+fn read_part(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Option<String>> {
+    Ok(read_bytes(archive, name)?.map(|bytes| String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+fn read_bytes(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Option<Vec<u8>>> {
+    let mut file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+/// Targets of a part's relationships by id: part paths in the package for internal
+/// targets, URLs for external ones.
+fn read_relationships(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> Result<HashMap<String, String>> {
+    let Some(xml) = read_part(archive, name)? else {
+        return Ok(HashMap::new());
+    };
+    let mut targets = HashMap::new();
+    for relationship in parse(&xml)?.children_named("Relationship") {
+        let (Some(id), Some(target)) = (
+            relationship.attribute("Id"),
+            relationship.attribute("Target"),
+        ) else {
+            continue;
+        };
+        let target = if relationship.attribute("TargetMode") == Some("External") {
+            target.to_string()
+        } else {
+            part_path(target)
+        };
+        targets.insert(id.to_string(), target);
+    }
+    Ok(targets)
+}
+
+/// Path in the package of a target relative to `word/`.
+fn part_path(target: &str) -> String {
+    if let Some(absolute) = target.strip_prefix('/') {
+        return absolute.to_string();
+    }
+    let mut parts = vec!["word"];
+    for part in target.split('/') {
+        match part {
+            ".." => {
+                parts.pop();
+            }
+            "." | "" => {}
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+#[derive(Default)]
+struct Style {
+    name: String,
+    based_on: Option<String>,
+    outline_level: Option<usize>,
+    numbering: Option<(String, usize)>,
+}
+
+/// Paragraph styles by id.
+#[derive(Default)]
+struct Styles(HashMap<String, Style>);
+
+impl Styles {
+    fn new(styles: &Element) -> Self {
+        let mut map = HashMap::new();
+        for style in styles.children_named("w:style") {
+            let Some(id) = style.attribute("w:styleId") else {
+                continue;
+            };
+            let properties = style.child("w:pPr");
+            map.insert(
+                id.to_string(),
+                Style {
+                    name: style.value("w:name").unwrap_or(id).to_string(),
+                    based_on: style.value("w:basedOn").map(str::to_string),
+                    outline_level: properties
+                        .and_then(|p| p.value("w:outlineLvl"))
+                        .and_then(|level| level.parse().ok()),
+                    numbering: properties.and_then(list_numbering),
+                },
+            );
+        }
+        Self(map)
+    }
+
+    /// The style `id` and the styles it is based on.
+    fn chain<'s>(&'s self, id: &'s str) -> impl Iterator<Item = (&'s str, Option<&'s Style>)> {
+        let mut next = Some(id);
+        std::iter::from_fn(move || {
+            let id = next?;
+            let style = self.0.get(id);
+            next = style.and_then(|style| style.based_on.as_deref());
+            Some((id, style))
+        })
+        .take(10)
+    }
+
+    fn heading_level(&self, id: &str) -> Option<usize> {
+        self.chain(id).find_map(|(id, style)| {
+            let name = style.map_or(id, |style| style.name.as_str()).to_lowercase();
+            if name == "title" {
+                return Some(1);
+            }
+            if let Some(level) = name
+                .strip_prefix("heading")
+                .and_then(|level| level.trim().parse::<usize>().ok())
+            {
+                return Some(level.clamp(1, 6));
+            }
+            let level = style?.outline_level?;
+            (level < 9).then(|| (level + 1).min(6))
+        })
+    }
+
+    /// Whether paragraphs of style `id` are code, or quotes.
+    fn is_code(&self, id: &str) -> bool {
+        self.matches(id, &["code", "preformatted", "verbatim"])
+    }
+
+    fn is_quote(&self, id: &str) -> bool {
+        self.matches(id, &["quote", "block text"])
+    }
+
+    fn matches(&self, id: &str, words: &[&str]) -> bool {
+        self.chain(id).any(|(id, style)| {
+            let name = style.map_or(id, |style| style.name.as_str()).to_lowercase();
+            words.iter().any(|word| name.contains(word))
+        })
+    }
+
+    fn numbering(&self, id: &str) -> Option<(String, usize)> {
+        self.chain(id)
+            .find_map(|(_, style)| style?.numbering.clone())
+    }
+}
+
+/// Numbering id and level of paragraph properties; numbering id 0 removes the numbering.
+fn list_numbering(properties: &Element) -> Option<(String, usize)> {
+    let numbering = properties.child("w:numPr")?;
+    let id = numbering.value("w:numId")?;
+    let level = numbering
+        .value("w:ilvl")
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(0);
+    Some((id.to_string(), level))
+}
+
+/// The list levels of `numbering.xml`: whether each is ordered, and where it starts.
+#[derive(Default)]
+struct Numbering {
+    abstracts: HashMap<String, HashMap<usize, (bool, u64)>>,
+    /// Abstract numbering and start overrides of each numbering instance.
+    instances: HashMap<String, (String, HashMap<usize, u64>)>,
+}
+
+impl Numbering {
+    fn new(numbering: &Element) -> Self {
+        let mut abstracts = HashMap::new();
+        for definition in numbering.children_named("w:abstractNum") {
+            let Some(id) = definition.attribute("w:abstractNumId") else {
+                continue;
+            };
+            let mut levels = HashMap::new();
+            for level in definition.children_named("w:lvl") {
+                let Some(index) = level.attribute("w:ilvl").and_then(|i| i.parse().ok()) else {
+                    continue;
+                };
+                let format = level.value("w:numFmt").unwrap_or("decimal");
+                if format == "none" {
+                    continue;
+                }
+                let start = level
+                    .value("w:start")
+                    .and_then(|start| start.parse().ok())
+                    .unwrap_or(1);
+                levels.insert(index, (format != "bullet", start));
+            }
+            abstracts.insert(id.to_string(), levels);
+        }
+
+        let mut instances = HashMap::new();
+        for instance in numbering.children_named("w:num") {
+            let (Some(id), Some(definition)) = (
+                instance.attribute("w:numId"),
+                instance.value("w:abstractNumId"),
+            ) else {
+                continue;
+            };
+            let overrides = instance
+                .children_named("w:lvlOverride")
+                .filter_map(|level| {
+                    let index = level.attribute("w:ilvl")?.parse().ok()?;
+                    let start = level.value("w:startOverride")?.parse().ok()?;
+                    Some((index, start))
+                })
+                .collect();
+            instances.insert(id.to_string(), (definition.to_string(), overrides));
+        }
+        Self {
+            abstracts,
+            instances,
+        }
+    }
+
+    /// Whether level `level` of list `id` is ordered, and its start; `None` when it is not
+    /// a list.
+    fn level(&self, id: &str, level: usize) -> Option<(bool, u64)> {
+        let (definition, overrides) = self.instances.get(id)?;
+        let &(ordered, start) = self.abstracts.get(definition)?.get(&level)?;
+        Some((ordered, overrides.get(&level).copied().unwrap_or(start)))
+    }
+}
+
+enum Block {
+    Text(String),
+    Item(String),
+    Quote(String),
+    Code(String),
+}
+
+/// Join blocks: list items are kept together, code lines and quote paragraphs merged.
+fn join(blocks: &[Block]) -> String {
+    let mut markdown = String::new();
+    let mut previous: Option<&Block> = None;
+    for block in blocks {
+        let separator = match (previous, block) {
+            (None, _) => "",
+            (Some(Block::Item(_)), Block::Item(_)) => "\n",
+            (Some(Block::Quote(_)), Block::Quote(_)) => "\n>\n",
+            _ => "\n\n",
+        };
+        markdown.push_str(separator);
+        match block {
+            Block::Text(text) | Block::Item(text) => markdown.push_str(text),
+            Block::Quote(text) => markdown.push_str(&format!("> {}", text)),
+            Block::Code(code) => markdown.push_str(&format!("```\n{}\n```", code)),
+        }
+        previous = Some(block);
+    }
+    markdown
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+struct Format {
+    bold: bool,
+    italic: bool,
+    strike: bool,
+    code: bool,
+}
+
+/// Inline content: text with its formatting, or Markdown written already.
+enum Inline {
+    Text(Format, String),
+    Markdown(String),
+}
+
+const HARD_BREAK: &str = "\\\n";
+
+struct Importer<'a> {
+    archive: ZipArchive<Cursor<&'a [u8]>>,
+    styles: Styles,
+    numbering: Numbering,
+    /// Item counters of each list by level.
+    counters: HashMap<String, Vec<u64>>,
+    /// Ids of the referenced footnotes, in order.
+    footnotes: Vec<String>,
+    /// Links to the images stored, by part path.
+    images: HashMap<String, String>,
+    save_image: &'a mut dyn FnMut(&str, &[u8]) -> Result<String>,
+}
+
+impl Importer<'_> {
+    fn blocks(
+        &mut self,
+        container: &Element,
+        relationships: &HashMap<String, String>,
+        blocks: &mut Vec<Block>,
+    ) -> Result<()> {
+        for element in container.elements() {
+            match element.name.as_str() {
+                "w:p" => self.paragraph(element, relationships, blocks)?,
+                "w:tbl" => {
+                    let table = self.table(element, relationships)?;
+                    blocks.push(Block::Text(table));
+                }
+                "w:sdt" => {
+                    if let Some(content) = element.child("w:sdtContent") {
+                        self.blocks(content, relationships, blocks)?;
+                    }
+                }
+                "w:customXml" | "w:ins" => self.blocks(element, relationships, blocks)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn paragraph(
+        &mut self,
+        paragraph: &Element,
+        relationships: &HashMap<String, String>,
+        blocks: &mut Vec<Block>,
+    ) -> Result<()> {
+        let properties = paragraph.child("w:pPr");
+        let style = properties.and_then(|p| p.value("w:pStyle")).unwrap_or("");
+
+        if !style.is_empty() && self.styles.is_code(style) {
+            let code = code_text(paragraph);
+            match blocks.last_mut() {
+                Some(Block::Code(previous)) => {
+                    previous.push('\n');
+                    previous.push_str(&code);
+                }
+                _ => blocks.push(Block::Code(code)),
+            }
+            return Ok(());
+        }
+
+        let text = self.paragraph_text(paragraph, relationships)?;
+        if text.is_empty() {
+            return Ok(());
+        }
+        let numbering = properties
+            .and_then(list_numbering)
+            .or_else(|| self.styles.numbering(style))
+            .and_then(|(id, level)| {
+                let (ordered, start) = self.numbering.level(&id, level)?;
+                Some((id, level, ordered, start))
+            });
+
+        let block = if let Some(level) = self.styles.heading_level(style) {
+            Block::Text(format!("{} {}", "#".repeat(level), text))
+        } else if let Some((id, level, ordered, start)) = numbering {
+            let marker = if ordered {
+                format!("{}.", self.count(&id, level, start))
+            } else {
+                "-".to_string()
+            };
+            Block::Item(format!("{}{} {}", "    ".repeat(level), marker, text))
+        } else if !style.is_empty() && self.styles.is_quote(style) {
+            Block::Quote(text)
+        } else {
+            Block::Text(text)
+        };
+        blocks.push(block);
+        Ok(())
+    }
+
+    /// Number of the next item at `level` of list `id`; deeper levels start over.
+    fn count(&mut self, id: &str, level: usize, start: u64) -> u64 {
+        let counters = self.counters.entry(id.to_string()).or_default();
+        counters.truncate(level + 1);
+        while counters.len() <= level {
+            counters.push(0);
+        }
+        counters[level] = if counters[level] == 0 {
+            start
+        } else {
+            counters[level] + 1
+        };
+        counters[level]
+    }
+
+    /// A paragraph's content as inline Markdown.
+    fn paragraph_text(
+        &mut self,
+        paragraph: &Element,
+        relationships: &HashMap<String, String>,
+    ) -> Result<String> {
+        let mut inlines = Vec::new();
+        self.inlines(paragraph, relationships, &mut inlines)?;
+        let text = render(&inlines);
+        let text = text.trim().trim_end_matches(HARD_BREAK).trim_end();
+        Ok(escape_block_start(text))
+    }
+
+    fn inlines(
+        &mut self,
+        container: &Element,
+        relationships: &HashMap<String, String>,
+        inlines: &mut Vec<Inline>,
+    ) -> Result<()> {
+        for element in container.elements() {
+            match element.name.as_str() {
+                "w:r" => self.run(element, relationships, inlines)?,
+                "w:hyperlink" => {
+                    let mut content = Vec::new();
+                    self.inlines(element, relationships, &mut content)?;
+                    let text = render(&content);
+                    let target = match element.attribute("r:id") {
+                        Some(id) => relationships.get(id).cloned(),
+                        None => element.attribute("w:anchor").map(|a| format!("#{}", a)),
+                    };
+                    inlines.push(Inline::Markdown(match target {
+                        Some(target) if !text.trim().is_empty() => {
+                            format!("[{}]({})", text, target.replace(' ', "%20"))
+                        }
+                        _ => text,
+                    }));
+                }
+                "m:oMath" => inlines.push(Inline::Markdown(format!("${}$", omml(element)))),
+                "m:oMathPara" => {
+                    for formula in element.children_named("m:oMath") {
+                        inlines.push(Inline::Markdown(format!("$${}$$", omml(formula))));
+                    }
+                }
+                "w:ins" | "w:smartTag" | "w:sdt" | "w:sdtContent" | "w:fldSimple"
+                | "w:customXml" => self.inlines(element, relationships, inlines)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn run(
+        &mut self,
+        run: &Element,
+        relationships: &HashMap<String, String>,
+        inlines: &mut Vec<Inline>,
+    ) -> Result<()> {
+        let format = run.child("w:rPr").map(run_format).unwrap_or_default();
+        for element in run.elements() {
+            match element.name.as_str() {
+                "w:t" => inlines.push(Inline::Text(format, element.text())),
+                "w:tab" => inlines.push(Inline::Text(format, " ".to_string())),
+                "w:noBreakHyphen" => inlines.push(Inline::Text(format, "-".to_string())),
+                // Page and column breaks have no Markdown counterpart
+                "w:br" | "w:cr" => {
+                    if matches!(element.attribute("w:type"), None | Some("textWrapping")) {
+                        inlines.push(Inline::Markdown(HARD_BREAK.to_string()));
+                    }
+                }
+                "w:footnoteReference" => {
+                    if let Some(id) = element.attribute("w:id") {
+                        let index = match self.footnotes.iter().position(|f| f == id) {
+                            Some(index) => index,
+                            None => {
+                                self.footnotes.push(id.to_string());
+                                self.footnotes.len() - 1
+                            }
+                        };
+                        inlines.push(Inline::Markdown(format!("[^{}]", index + 1)));
+                    }
+                }
+                "w:drawing" | "w:pict" | "w:object" | "mc:AlternateContent" => {
+                    if let Some(image) = self.image(element, relationships)? {
+                        inlines.push(Inline::Markdown(image));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Store the image of a drawing and link it.
+    fn image(
+        &mut self,
+        drawing: &Element,
+        relationships: &HashMap<String, String>,
+    ) -> Result<Option<String>> {
+        // Prefer the SVG Word keeps next to its PNG fallback
+        let blip = drawing.find("blip");
+        let id = blip
+            .and_then(|blip| blip.find("svgBlip"))
+            .and_then(|svg| svg.attribute("r:embed"))
+            .or_else(|| blip?.attribute("r:embed"))
+            .or_else(|| drawing.find("imagedata")?.attribute("r:id"));
+        let Some(path) = id.and_then(|id| relationships.get(id)) else {
+            return Ok(None);
+        };
+
+        let link = match self.images.get(path) {
+            Some(link) => link.clone(),
+            None => {
+                let Some(data) = read_bytes(&mut self.archive, path)? else {
+                    return Ok(None);
+                };
+                let name = Path::new(path)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or("image.png");
+                let link = (self.save_image)(name, &data)?;
+                self.images.insert(path.clone(), link.clone());
+                link
+            }
+        };
+        let alt = drawing
+            .find("docPr")
+            .and_then(|properties| properties.attribute("descr"))
+            .unwrap_or("");
+        Ok(Some(format!("![{}]({})", escape_text(alt), link)))
+    }
+
+    /// A pipe table; the first row is the header. Cell paragraphs are joined with `<br>`.
+    fn table(
+        &mut self,
+        table: &Element,
+        relationships: &HashMap<String, String>,
+    ) -> Result<String> {
+        let mut rows = Vec::new();
+        for row in table.children_named("w:tr") {
+            let mut cells = Vec::new();
+            for cell in row.children_named("w:tc") {
+                let mut paragraphs = Vec::new();
+                for paragraph in cell_paragraphs(cell) {
+                    let text = self.paragraph_text(paragraph, relationships)?;
+                    if !text.is_empty() {
+                        paragraphs.push(text.replace(HARD_BREAK, "<br>").replace('|', "\\|"));
+                    }
+                }
+                cells.push(paragraphs.join("<br>"));
+                let span = cell
+                    .child("w:tcPr")
+                    .and_then(|properties| properties.value("w:gridSpan"))
+                    .and_then(|span| span.parse::<usize>().ok())
+                    .unwrap_or(1);
+                for _ in 1..span {
+                    cells.push(String::new());
+                }
+            }
+            rows.push(cells);
+        }
+        let columns = rows.iter().map(Vec::len).max().unwrap_or(0).max(1);
+        if rows.is_empty() {
+            rows.push(Vec::new());
+        }
+
+        let line = |cells: &[String]| {
+            let mut line = String::from("|");
+            for column in 0..columns {
+                line.push_str(&format!(
+                    " {} |",
+                    cells.get(column).map_or("", String::as_str)
+                ));
+            }
+            line
+        };
+        let mut lines = vec![line(&rows[0]), format!("|{}", " --- |".repeat(columns))];
+        lines.extend(rows[1..].iter().map(|row| line(row)));
+        Ok(lines.join("\n"))
+    }
+}
+
+/// Paragraphs of a table cell, including those of nested tables.
+fn cell_paragraphs(cell: &Element) -> Vec<&Element> {
+    let mut paragraphs = Vec::new();
+    for element in cell.elements() {
+        match element.name.as_str() {
+            "w:p" => paragraphs.push(element),
+            "w:tbl" | "w:tr" | "w:tc" | "w:sdt" | "w:sdtContent" => {
+                paragraphs.extend(cell_paragraphs(element))
+            }
+            _ => {}
+        }
+    }
+    paragraphs
+}
+
+fn run_format(properties: &Element) -> Format {
+    let on = |name: &str| {
+        properties
+            .child(name)
+            .is_some_and(|toggle| !matches!(toggle.attribute("w:val"), Some("0" | "false" | "off")))
+    };
+    let style = properties.value("w:rStyle").unwrap_or("").to_lowercase();
+    let font = properties
+        .child("w:rFonts")
+        .and_then(|fonts| fonts.attribute("w:ascii"))
+        .unwrap_or("")
+        .to_lowercase();
+    Format {
+        bold: on("w:b"),
+        italic: on("w:i"),
+        strike: on("w:strike") || on("w:dstrike"),
+        code: style.contains("verbatim")
+            || style.contains("code")
+            || ["courier", "consolas", "menlo", "mono"]
+                .iter()
+                .any(|name| font.contains(name)),
+    }
+}
+
+/// The text of a code paragraph, as it is.
+fn code_text(paragraph: &Element) -> String {
+    let mut text = String::new();
+    for element in paragraph.elements() {
+        match element.name.as_str() {
+            "w:t" => text.push_str(&element.text()),
+            "w:tab" => text.push('\t'),
+            "w:br" | "w:cr" => text.push('\n'),
+            "w:pPr" | "w:rPr" => {}
+            _ => text.push_str(&code_text(element)),
+        }
+    }
+    text
+}
+
+/// Write inline content as Markdown, merging neighbouring text of the same format.
+fn render(inlines: &[Inline]) -> String {
+    let mut merged: Vec<(Option<Format>, String)> = Vec::new();
+    for inline in inlines {
+        match inline {
+            Inline::Text(format, text) => match merged.last_mut() {
+                Some((Some(last), previous)) if last == format => previous.push_str(text),
+                _ => merged.push((Some(*format), text.clone())),
+            },
+            Inline::Markdown(markdown) => merged.push((None, markdown.clone())),
+        }
+    }
+
+    let mut markdown = String::new();
+    for (format, text) in merged {
+        let Some(format) = format else {
+            markdown.push_str(&text);
+            continue;
+        };
+        if format.code && !text.trim().is_empty() {
+            let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+            let fence = "`".repeat(longest + 1);
+            let padding = if text.starts_with('`') || text.ends_with('`') {
+                " "
+            } else {
+                ""
+            };
+            markdown.push_str(&format!("{0}{1}{2}{1}{0}", fence, padding, text));
+            continue;
+        }
+        // Emphasis markers go around the text, not its surrounding spaces
+        let core = text.trim();
+        if core.is_empty() {
+            markdown.push_str(&text);
+            continue;
+        }
+        let start = text.len() - text.trim_start().len();
+        let mut open = String::new();
+        if format.strike {
+            open.push_str("~~");
+        }
+        if format.bold {
+            open.push_str("**");
+        }
+        if format.italic {
+            open.push('*');
+        }
+        let close: String = open.chars().rev().collect();
+        markdown.push_str(&text[..start]);
+        markdown.push_str(&open);
+        markdown.push_str(&escape_text(core));
+        markdown.push_str(&close);
+        markdown.push_str(&text[start + core.len()..]);
+    }
+    markdown
+}
+
+/// Escape the characters Markdown (and `$` math) would interpret.
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '$' | '~') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escape a paragraph start that Markdown would read as a heading, quote, list or rule.
+fn escape_block_start(text: &str) -> String {
+    if text.starts_with(['#', '>', '-', '+', '=', '|']) {
+        return format!("\\{}", text);
+    }
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits > 0 && text[digits..].starts_with(['.', ')']) {
+        return format!("{}\\{}", &text[..digits], &text[digits..]);
+    }
+    text.to_string()
+}
+
+/// Office Math as LaTeX.
+fn omml(element: &Element) -> String {
+    let mut latex = String::new();
+    for child in element.elements() {
+        push_math(&mut latex, &omml_element(child));
+    }
+    latex.trim().to_string()
+}
+
+/// Append `piece`, with a space when it would run into a command name.
+fn push_math(latex: &mut String, piece: &str) {
+    let letters = latex.len()
+        - latex
+            .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+            .len();
+    let after_command = letters > 0 && latex[..latex.len() - letters].ends_with('\\');
+    if after_command && piece.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        latex.push(' ');
+    }
+    latex.push_str(piece);
+}
+
+/// `latex` as a script base or command argument: braced unless it is one symbol.
+fn group(latex: &str) -> String {
+    let single = latex.chars().count() == 1
+        || (latex.starts_with('\\') && latex[1..].chars().all(|c| c.is_ascii_alphabetic()));
+    if single && !latex.is_empty() {
+        latex.to_string()
+    } else {
+        format!("{{{}}}", latex)
+    }
+}
+
+fn omml_child(element: &Element, name: &str) -> String {
+    element.child(name).map(omml).unwrap_or_default()
+}
+
+/// A character property of an OMML element, like the `m:chr` of `m:naryPr`.
+fn omml_property<'e>(element: &'e Element, properties: &str, name: &str) -> Option<&'e str> {
+    element.child(properties)?.child(name)?.attribute("m:val")
+}
+
+fn omml_element(element: &Element) -> String {
+    match element.name.as_str() {
+        "m:r" => math_run(element),
+        "m:f" => {
+            let (numerator, denominator) =
+                (omml_child(element, "m:num"), omml_child(element, "m:den"));
+            match omml_property(element, "m:fPr", "m:type") {
+                Some("lin") => format!("{}/{}", group(&numerator), group(&denominator)),
+                Some("noBar") => format!("\\binom{{{}}}{{{}}}", numerator, denominator),
+                _ => format!("\\frac{{{}}}{{{}}}", numerator, denominator),
+            }
+        }
+        "m:sSub" => format!(
+            "{}_{{{}}}",
+            group(&omml_child(element, "m:e")),
+            omml_child(element, "m:sub")
+        ),
+        "m:sSup" => format!(
+            "{}^{{{}}}",
+            group(&omml_child(element, "m:e")),
+            omml_child(element, "m:sup")
+        ),
+        "m:sSubSup" => format!(
+            "{}_{{{}}}^{{{}}}",
+            group(&omml_child(element, "m:e")),
+            omml_child(element, "m:sub"),
+            omml_child(element, "m:sup")
+        ),
+        "m:sPre" => format!(
+            "{{}}_{{{}}}^{{{}}}{}",
+            omml_child(element, "m:sub"),
+            omml_child(element, "m:sup"),
+            group(&omml_child(element, "m:e"))
+        ),
+        "m:rad" => {
+            let degree = omml_child(element, "m:deg");
+            let radicand = omml_child(element, "m:e");
+            if degree.is_empty() {
+                format!("\\sqrt{{{}}}", radicand)
+            } else {
+                format!("\\sqrt[{}]{{{}}}", degree, radicand)
+            }
+        }
+        "m:d" => {
+            let open = omml_property(element, "m:dPr", "m:begChr").unwrap_or("(");
+            let close = omml_property(element, "m:dPr", "m:endChr").unwrap_or(")");
+            let separator = omml_property(element, "m:dPr", "m:sepChr").unwrap_or("|");
+            let separator = if separator.is_empty() {
+                ", ".to_string()
+            } else {
+                math_symbol(separator)
+            };
+            let inner = element
+                .children_named("m:e")
+                .map(omml)
+                .collect::<Vec<_>>()
+                .join(&separator);
+            // Sized delimiters only where the content is taller than a line
+            let tall = ["\\frac", "\\sum", "\\int", "\\prod", "\\begin", "\\binom"]
+                .iter()
+                .any(|command| inner.contains(command));
+            if tall {
+                format!(
+                    "\\left{} {} \\right{}",
+                    delimiter(open),
+                    inner,
+                    delimiter(close)
+                )
+            } else {
+                format!("{}{}{}", math_symbol(open), inner, math_symbol(close))
+            }
+        }
+        "m:nary" => {
+            let operator = omml_property(element, "m:naryPr", "m:chr").unwrap_or("∫");
+            let mut latex = math_symbol(operator);
+            let lower = omml_child(element, "m:sub");
+            let upper = omml_child(element, "m:sup");
+            if !lower.is_empty() {
+                latex.push_str(&format!("_{{{}}}", lower));
+            }
+            if !upper.is_empty() {
+                latex.push_str(&format!("^{{{}}}", upper));
+            }
+            format!("{} {}", latex, omml_child(element, "m:e"))
+        }
+        "m:func" => {
+            let mut latex = omml_child(element, "m:fName");
+            push_math(&mut latex, &omml_child(element, "m:e"));
+            latex
+        }
+        "m:limLow" | "m:limUpp" => {
+            let base = omml_child(element, "m:e");
+            let limit = omml_child(element, "m:lim");
+            let function = base.strip_prefix('\\').is_some_and(math::is_function);
+            match (element.name.as_str(), function) {
+                ("m:limLow", true) => format!("{}_{{{}}}", base, limit),
+                ("m:limLow", false) => format!("\\underset{{{}}}{{{}}}", limit, base),
+                _ => format!("\\overset{{{}}}{{{}}}", limit, base),
+            }
+        }
+        "m:acc" => {
+            let accent = omml_property(element, "m:accPr", "m:chr")
+                .and_then(|chr| chr.chars().next())
+                .unwrap_or('\u{302}');
+            let command = math::accent_for(accent).unwrap_or("hat");
+            format!("\\{}{{{}}}", command, omml_child(element, "m:e"))
+        }
+        "m:bar" => {
+            let command = match omml_property(element, "m:barPr", "m:pos") {
+                Some("top") => "overline",
+                _ => "underline",
+            };
+            format!("\\{}{{{}}}", command, omml_child(element, "m:e"))
+        }
+        "m:groupChr" => {
+            let command = match omml_property(element, "m:groupChrPr", "m:pos") {
+                Some("top") => "overbrace",
+                _ => "underbrace",
+            };
+            format!("\\{}{{{}}}", command, omml_child(element, "m:e"))
+        }
+        "m:m" => {
+            let rows: Vec<String> = element
+                .children_named("m:mr")
+                .map(|row| {
+                    row.children_named("m:e")
+                        .map(omml)
+                        .collect::<Vec<_>>()
+                        .join(" & ")
+                })
+                .collect();
+            format!("\\begin{{matrix}} {} \\end{{matrix}}", rows.join(" \\\\ "))
+        }
+        "m:eqArr" => {
+            let rows: Vec<String> = element.children_named("m:e").map(omml).collect();
+            format!(
+                "\\begin{{aligned}} {} \\end{{aligned}}",
+                rows.join(" \\\\ ")
+            )
+        }
+        "m:borderBox" => format!("\\boxed{{{}}}", omml_child(element, "m:e")),
+        name if name.ends_with("Pr") => String::new(),
+        _ => omml(element),
+    }
+}
+
+/// A math run: normal text becomes `\text`, upright words functions or `\mathrm`, symbols
+/// their commands.
+fn math_run(run: &Element) -> String {
+    let text: String = run.children_named("m:t").map(Element::text).collect();
+    let properties = run.child("m:rPr");
+    if properties.is_some_and(|p| p.child("m:nor").is_some()) {
+        return format!("\\text{{{}}}", text.replace(['{', '}'], ""));
+    }
+    let upright = properties.and_then(|p| p.value("m:sty")) == Some("p");
+    let word = text.chars().count() > 1 && text.chars().all(|c| c.is_ascii_alphabetic());
+    if upright && word {
+        return if math::is_function(&text) {
+            format!("\\{}", text)
+        } else {
+            format!("\\mathrm{{{}}}", text)
+        };
+    }
+
+    let mut latex = String::new();
+    for c in text.chars() {
+        let mut buffer = [0; 4];
+        push_math(&mut latex, &math_symbol(c.encode_utf8(&mut buffer)));
+    }
+    latex
+}
+
+/// LaTeX for one symbol of math text.
+fn math_symbol(symbol: &str) -> String {
+    match symbol {
+        "{" | "}" | "%" | "#" | "$" => format!("\\{}", symbol),
+        "\\" => "\\backslash".to_string(),
+        "−" => "-".to_string(),
+        "′" => "'".to_string(),
+        "∣" => "|".to_string(),
+        _ => match math::command_for(symbol) {
+            Some(command) => format!("\\{}", command),
+            None => symbol.to_string(),
+        },
+    }
+}
+
+/// A `\left`/`\right` delimiter; an empty one is `.`.
+fn delimiter(symbol: &str) -> String {
+    if symbol.is_empty() {
+        ".".to_string()
+    } else {
+        math_symbol(symbol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    const NAMESPACES: &str = r#"xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main" xmlns:m="http://schemas.openxmlformats.org/officeDocument/2006/math" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships" xmlns:a="http://schemas.openxmlformats.org/drawingml/2006/main""#;
+
+    /// Import a Word document with `body` and the other `parts`. Returns the Markdown and the
+    /// images saved.
+    fn import(body: &str, parts: &[(&str, &[u8])]) -> (String, Vec<(String, Vec<u8>)>) {
+        let document = format!(
+            "<w:document {}><w:body>{}</w:body></w:document>",
+            NAMESPACES, body
+        );
+        let mut docx = Vec::new();
+        let mut zip = ZipWriter::new(Cursor::new(&mut docx));
+        for &(name, data) in [("word/document.xml", document.as_bytes())]
+            .iter()
+            .chain(parts)
+        {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+        drop(zip);
+
+        let mut saved = Vec::new();
+        let markdown = convert(&docx, &mut |name, data| {
+            saved.push((name.to_string(), data.to_vec()));
+            Ok(format!("assets/{}", name))
+        })
+        .unwrap();
+        (markdown, saved)
+    }
+
+    #[test]
+    fn headings_and_run_formatting_become_markdown() {
+        let (markdown, _) = import(
+            r#"<w:p><w:pPr><w:pStyle w:val="Heading2"/></w:pPr><w:r><w:t>Results</w:t></w:r></w:p>
+            <w:p><w:r><w:rPr><w:b/></w:rPr><w:t xml:space="preserve">Bold </w:t></w:r><w:r><w:rPr><w:b/><w:i w:val="0"/></w:rPr><w:t>run</w:t></w:r><w:r><w:t xml:space="preserve"> and </w:t></w:r><w:r><w:rPr><w:i/></w:rPr><w:t>x_1</w:t></w:r></w:p>
+            <w:p><w:r><w:t>1. Not a list</w:t></w:r></w:p>"#,
+            &[],
+        );
+        assert_eq!(
+            markdown,
+            "## Results\n\n**Bold run** and *x\\_1*\n\n1\\. Not a list\n"
+        );
+    }
+
+    #[test]
+    fn footnotes_keep_their_links() {
+        let footnotes = format!(
+            r#"<w:footnotes {}><w:footnote w:type="separator" w:id="-1"><w:p><w:r><w:separator/></w:r></w:p></w:footnote>
+            <w:footnote w:id="2"><w:p><w:r><w:footnoteRef/></w:r><w:r><w:t xml:space="preserve"> See </w:t></w:r><w:hyperlink r:id="rId1"><w:r><w:t>docs</w:t></w:r></w:hyperlink></w:p></w:footnote></w:footnotes>"#,
+            NAMESPACES
+        );
+        let relationships = r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/hyperlink" Target="https://example.com" TargetMode="External"/></Relationships>"#;
+        let (markdown, _) = import(
+            r#"<w:p><w:r><w:t>Text</w:t></w:r><w:r><w:rPr><w:rStyle w:val="FootnoteReference"/></w:rPr><w:footnoteReference w:id="2"/></w:r></w:p>"#,
+            &[
+                ("word/footnotes.xml", footnotes.as_bytes()),
+                ("word/_rels/footnotes.xml.rels", relationships.as_bytes()),
+            ],
+        );
+        assert_eq!(
+            markdown,
+            "Text[^1]\n\n[^1]: See [docs](https://example.com)\n"
+        );
+    }
+
+    #[test]
+    fn numbered_lists_keep_their_start_and_nesting() {
+        let numbering = r#"<w:numbering xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main">
+            <w:abstractNum w:abstractNumId="0"><w:lvl w:ilvl="0"><w:start w:val="3"/><w:numFmt w:val="decimal"/></w:lvl><w:lvl w:ilvl="1"><w:numFmt w:val="bullet"/></w:lvl></w:abstractNum>
+            <w:num w:numId="1"><w:abstractNumId w:val="0"/></w:num></w:numbering>"#;
+        let (markdown, _) = import(
+            r#"<w:p><w:pPr><w:numPr><w:ilvl w:val="0"/><w:numId w:val="1"/></w:numPr></w:pPr><w:r><w:t>First</w:t></w:r></w:p>
+            <w:p><w:pPr><w:numPr><w:ilvl w:val="1"/><w:numId w:val="1"/></w:numPr></w:pPr><w:r><w:t>Nested</w:t></w:r></w:p>
+            <w:p><w:pPr><w:numPr><w:ilvl w:val="0"/><w:numId w:val="1"/></w:numPr></w:pPr><w:r><w:t>Second</w:t></w:r></w:p>"#,
+            &[("word/numbering.xml", numbering.as_bytes())],
+        );
+        assert_eq!(markdown, "3. First\n    - Nested\n4. Second\n");
+    }
+
+    #[test]
+    fn tables_escape_pipes_and_pad_spanned_cells() {
+        let relationships = r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId9" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/image" Target="media/image1.png"/></Relationships>"#;
+        let (markdown, saved) = import(
+            r#"<w:tbl><w:tr><w:tc><w:p><w:r><w:t>A</w:t></w:r></w:p></w:tc><w:tc><w:p><w:r><w:t>B|C</w:t></w:r></w:p></w:tc></w:tr>
+            <w:tr><w:tc><w:tcPr><w:gridSpan w:val="2"/></w:tcPr><w:p><w:r><w:drawing><a:blip r:embed="rId9"/></w:drawing></w:r></w:p></w:tc></w:tr></w:tbl>"#,
+            &[
+                ("word/_rels/document.xml.rels", relationships.as_bytes()),
+                ("word/media/image1.png", &[1, 2, 3]),
+            ],
+        );
+        assert_eq!(
+            markdown,
+            "| A | B\\|C |\n| --- | --- |\n| ![](assets/image1.png) |  |\n"
+        );
+        assert_eq!(saved, [("image1.png".to_string(), vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn source_code_paragraphs_become_one_code_block() {
+        let (markdown, _) = import(
+            r#"<w:p><w:pPr><w:pStyle w:val="SourceCode"/></w:pPr><w:r><w:t>let x = 1;</w:t></w:r></w:p>
+            <w:p><w:pPr><w:pStyle w:val="SourceCode"/></w:pPr><w:r><w:t>*x</w:t></w:r></w:p>"#,
+            &[],
+        );
+        assert_eq!(markdown, "```\nlet x = 1;\n*x\n```\n");
+    }
+
+    #[test]
+    fn office_math_becomes_latex() {
+        let (markdown, _) = import(
+            r#"<w:p><w:r><w:t xml:space="preserve">where </w:t></w:r><m:oMath><m:f><m:num><m:r><m:t>a</m:t></m:r></m:num><m:den><m:sSup><m:e><m:r><m:t>β</m:t></m:r></m:e><m:sup><m:r><m:t>2</m:t></m:r></m:sup></m:sSup></m:den></m:f><m:r><m:t>≤</m:t></m:r><m:rad><m:radPr><m:degHide m:val="1"/></m:radPr><m:deg/><m:e><m:r><m:t>x</m:t></m:r></m:e></m:rad></m:oMath></w:p>
+            <w:p><m:oMathPara><m:oMath><m:nary><m:naryPr><m:chr m:val="∑"/></m:naryPr><m:sub><m:r><m:t>i</m:t></m:r></m:sub><m:sup><m:r><m:t>n</m:t></m:r></m:sup><m:e><m:r><m:t>i</m:t></m:r></m:e></m:nary></m:oMath></m:oMathPara></w:p>"#,
+            &[],
+        );
+        assert_eq!(
+            markdown,
+            "where $\\frac{a}{\\beta^{2}}\\leq\\sqrt{x}$\n\n$$\\sum_{i}^{n} i$$\n"
+        );
+    }
+
+    #[test]
+    fn exported_math_survives_a_round_trip() {
+        for latex in [
+            r"\frac{a}{b_1}",
+            r"\sqrt[3]{x} + y^2",
+            r"\left( \frac{a}{2} \right)",
+            r"\begin{pmatrix} a & b \\ c & d \end{pmatrix}",
+        ] {
+            let omml = math::to_omml(latex);
+            let (markdown, _) = import(&format!("<w:p>{}</w:p>", omml), &[]);
+            let imported = markdown
+                .trim()
+                .strip_prefix('$')
+                .and_then(|markdown| markdown.strip_suffix('$'))
+                .unwrap_or_else(|| panic!("{} imported as {}", latex, markdown));
+            assert_eq!(
+                math::to_omml(imported),
+                omml,
+                "{} imported as {}",
+                latex,
+                imported
+            );
+        }
+    }
+}
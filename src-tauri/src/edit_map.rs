@@ -0,0 +1,301 @@
+//! Offset bookkeeping for text transformations.
+//!
+//! The preprocessor and the image path rewriter splice text into the user's markdown before
+//! it is written as `content.md`. Each stage describes what it changed as an [`EditMap`];
+//! maps compose with [`EditMap::then`], so the final map relates any byte offset in
+//! `content.md` to the user's document and back (diagnostics, scroll sync).
+
+use regex::{Captures, Regex};
+use std::ops::Range;
+
+/// One replacement: `original` bytes of the input became `transformed` bytes of the output.
+/// An empty `original` range is an insertion, an empty `transformed` range a deletion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub original: Range<usize>,
+    pub transformed: Range<usize>,
+}
+
+/// Sorted, non-overlapping edits between an input text and its transformed output. Text
+/// outside the edits was copied unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EditMap {
+    edits: Vec<Edit>,
+}
+
+impl EditMap {
+    /// Map for a transformation that changed nothing.
+    pub fn identity() -> Self {
+        Self::default()
+    }
+
+    /// Build a map from splices applied to the input: each `(range, len)` replaced `range` of
+    /// the input with `len` bytes. Splices must not overlap.
+    pub fn from_splices(splices: impl IntoIterator<Item = (Range<usize>, usize)>) -> Self {
+        let mut splices: Vec<_> = splices.into_iter().collect();
+        splices.sort_by_key(|(range, _)| (range.start, range.end));
+
+        let mut shift: isize = 0;
+        let edits = splices
+            .into_iter()
+            .map(|(original, len)| {
+                let start = (original.start as isize + shift) as usize;
+                shift += len as isize - original.len() as isize;
+                Edit {
+                    original,
+                    transformed: start..start + len,
+                }
+            })
+            .collect();
+        Self::normalized(edits)
+    }
+
+    #[cfg(test)]
+    pub fn edits(&self) -> &[Edit] {
+        &self.edits
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.edits.is_empty()
+    }
+
+    /// Position in the input of byte `offset` of the output. Offsets inside inserted or
+    /// replaced text map to the start of the text it replaced.
+    pub fn to_original(&self, offset: usize) -> usize {
+        let mut shift: isize = 0;
+        for edit in &self.edits {
+            if offset < edit.transformed.start {
+                break;
+            }
+            if offset < edit.transformed.end {
+                return edit.original.start;
+            }
+            shift = edit.original.end as isize - edit.transformed.end as isize;
+        }
+        (offset as isize + shift).max(0) as usize
+    }
+
+    /// Position in the output of byte `offset` of the input. Offsets inside replaced or
+    /// deleted text map to the start of its replacement; text inserted exactly at `offset`
+    /// comes before it.
+    #[cfg(test)]
+    pub fn to_transformed(&self, offset: usize) -> usize {
+        let mut shift: isize = 0;
+        for edit in &self.edits {
+            if offset < edit.original.start {
+                break;
+            }
+            if offset < edit.original.end {
+                return edit.transformed.start;
+            }
+            shift = edit.transformed.end as isize - edit.original.end as isize;
+        }
+        (offset as isize + shift).max(0) as usize
+    }
+
+    /// Compose with the map of a transformation applied to this map's output, giving a map
+    /// from this map's input to `next`'s output.
+    pub fn then(&self, next: &EditMap) -> EditMap {
+        if self.is_identity() {
+            return next.clone();
+        }
+        if next.is_identity() {
+            return self.clone();
+        }
+
+        let first = self.pieces();
+        let mut edits = Vec::new();
+        for piece in next.pieces() {
+            // `piece.original` is a range of the intermediate text: find what produced it.
+            let middle = &piece.original;
+            let sources = first
+                .iter()
+                .filter(|source| overlaps(&source.transformed, middle));
+
+            if piece.copied {
+                // Copied through unchanged, so only the first map's edits carry over.
+                for source in sources.filter(|source| !source.copied) {
+                    let start = source.transformed.start.max(middle.start);
+                    let end = source.transformed.end.min(middle.end);
+                    edits.push(Edit {
+                        original: source.original.clone(),
+                        transformed: piece.transformed.start + (start - middle.start)
+                            ..piece.transformed.start + (end - middle.start),
+                    });
+                }
+            } else {
+                let original = sources
+                    .map(|source| {
+                        if source.copied {
+                            let start = source.transformed.start.max(middle.start);
+                            let end = source.transformed.end.min(middle.end);
+                            source.original.start + (start - source.transformed.start)
+                                ..source.original.start + (end - source.transformed.start)
+                        } else {
+                            source.original.clone()
+                        }
+                    })
+                    .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+                    .unwrap_or_else(|| {
+                        let at = self.to_original(middle.start);
+                        at..at
+                    });
+                edits.push(Edit {
+                    original,
+                    transformed: piece.transformed,
+                });
+            }
+        }
+        Self::normalized(edits)
+    }
+
+    /// The map as consecutive pieces covering both texts, alternating between copied text and
+    /// edits. The last piece is copied text running to the end.
+    fn pieces(&self) -> Vec<Piece> {
+        let mut pieces = Vec::with_capacity(self.edits.len() * 2 + 1);
+        let (mut original, mut transformed) = (0, 0);
+        for edit in &self.edits {
+            if original < edit.original.start {
+                pieces.push(Piece {
+                    copied: true,
+                    original: original..edit.original.start,
+                    transformed: transformed..edit.transformed.start,
+                });
+            }
+            pieces.push(Piece {
+                copied: false,
+                original: edit.original.clone(),
+                transformed: edit.transformed.clone(),
+            });
+            original = edit.original.end;
+            transformed = edit.transformed.end;
+        }
+        pieces.push(Piece {
+            copied: true,
+            original: original..usize::MAX,
+            transformed: transformed..usize::MAX,
+        });
+        pieces
+    }
+
+    /// Sort edits and merge overlapping ones (pieces of one edit split by a later stage), so
+    /// lookups can scan them in order.
+    fn normalized(mut edits: Vec<Edit>) -> Self {
+        edits.sort_by_key(|edit| {
+            (
+                edit.transformed.start,
+                edit.original.start,
+                edit.transformed.end,
+            )
+        });
+        let mut merged: Vec<Edit> = Vec::with_capacity(edits.len());
+        for edit in edits {
+            if edit.original.is_empty() && edit.transformed.is_empty() {
+                continue;
+            }
+            match merged.last_mut() {
+                Some(last)
+                    if intersects(&last.original, &edit.original)
+                        || intersects(&last.transformed, &edit.transformed) =>
+                {
+                    last.original.end = last.original.end.max(edit.original.end);
+                    last.transformed.end = last.transformed.end.max(edit.transformed.end);
+                }
+                _ => merged.push(edit),
+            }
+        }
+        Self { edits: merged }
+    }
+}
+
+struct Piece {
+    copied: bool,
+    original: Range<usize>,
+    transformed: Range<usize>,
+}
+
+/// Whether a piece of the intermediate text at `range` contributes to `middle`. Empty ranges
+/// (deletions and insertions) count when they sit inside the other range or at its start.
+fn overlaps(range: &Range<usize>, middle: &Range<usize>) -> bool {
+    if range.is_empty() {
+        middle.start <= range.start && range.start < middle.end
+    } else if middle.is_empty() {
+        range.start < middle.start && middle.start < range.end
+    } else {
+        range.start < middle.end && middle.start < range.end
+    }
+}
+
+/// Whether two ranges share a position; an empty range shares its position with a range that
+/// contains it.
+fn intersects(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a == b
+        || (a.start < b.end && b.start < a.end)
+        || (a.is_empty() && b.start <= a.start && a.start < b.end)
+        || (b.is_empty() && a.start <= b.start && b.start < a.end)
+}
+
+/// `Regex::replace_all` that also reports what it changed.
+pub fn replace_all(
+    regex: &Regex,
+    text: &str,
+    mut replacement: impl FnMut(&Captures) -> String,
+) -> (String, EditMap) {
+    let mut output = String::with_capacity(text.len());
+    let mut splices = Vec::new();
+    let mut last = 0;
+    for caps in regex.captures_iter(text) {
+        let matched = caps.get(0).expect("BUG: capture group 0 always exists");
+        let replaced = replacement(&caps);
+        output.push_str(&text[last..matched.start()]);
+        if replaced != matched.as_str() {
+            splices.push((matched.range(), replaced.len()));
+        }
+        output.push_str(&replaced);
+        last = matched.end();
+    }
+    output.push_str(&text[last..]);
+    (output, EditMap::from_splices(splices))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composed_maps_relate_input_and_final_output() {
+        // "abcdef" -> "abXXcdef" -> "abXYYYdef" -> "abXYYYf"
+        let insert = EditMap::from_splices([(2..2, 2)]);
+        let replace = EditMap::from_splices([(3..5, 3)]);
+        let delete = EditMap::from_splices([(6..8, 0)]);
+        let map = insert.then(&replace).then(&delete);
+
+        assert_eq!(
+            map.edits(),
+            &[
+                Edit {
+                    original: 2..3,
+                    transformed: 2..6
+                },
+                Edit {
+                    original: 3..5,
+                    transformed: 6..6
+                },
+            ]
+        );
+        assert_eq!(map.to_original(1), 1);
+        assert_eq!(map.to_original(4), 2);
+        assert_eq!(map.to_original(6), 5);
+        assert_eq!(map.to_transformed(1), 1);
+        assert_eq!(map.to_transformed(2), 2);
+        assert_eq!(map.to_transformed(4), 6);
+        assert_eq!(map.to_transformed(5), 6);
+
+        let (text, rewritten) = replace_all(&Regex::new(r"\d+").unwrap(), "a1b22c", |caps| {
+            "#".repeat(caps[0].len() + 1)
+        });
+        assert_eq!(text, "a##b###c");
+        assert_eq!(rewritten.to_original(7), 5);
+        assert_eq!(rewritten.to_transformed(3), 4);
+    }
+}
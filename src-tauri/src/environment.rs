@@ -0,0 +1,338 @@
+//! Host environment for the render pipeline.
+//!
+//! The pipeline needs a handful of directories, a few external binaries and a place to
+//! send progress/debug events. [`RenderEnvironment`] captures exactly that so the same
+//! code runs inside the Tauri app (via `AppHandle`), in the headless CLI, or in tests
+//! against a plain directory ([`FsEnvironment`]).
+
+use crate::preferences::Preferences;
+use crate::utils;
+use anyhow::{anyhow, Result};
+use serde_json::Value as JsonValue;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Everything the render pipeline needs from its host.
+pub trait RenderEnvironment: Send + Sync {
+    /// User content directory. Used as the Typst root and holds `prefs.json` and `assets/`.
+    fn content_dir(&self) -> Result<PathBuf>;
+
+    /// Root for intermediate build files. Each job gets its own workspace below it (holding
+    /// `content.md`, `prefs.json`, PDFs); see `build_workspace`.
+    fn build_dir(&self) -> Result<PathBuf> {
+        Ok(self.content_dir()?.join(".build"))
+    }
+
+    /// Directory imported and referenced images are copied into.
+    fn assets_dir(&self) -> Result<PathBuf> {
+        let assets_dir = self.content_dir()?.join("assets");
+        if !assets_dir.exists() {
+            fs::create_dir_all(&assets_dir)?;
+        }
+        Ok(assets_dir)
+    }
+
+    /// Bundled resources (template, themes, Typst packages, tool binaries), if any.
+    fn resource_dir(&self) -> Option<PathBuf>;
+
+    /// Source of the `tideflow.typ` template that gets copied into the build directory.
+    fn template_path(&self) -> Result<PathBuf>;
+
+    /// Canonical preferences as JSON (before per-render rewrites such as cover images).
+    fn preferences(&self) -> Result<JsonValue> {
+        let prefs_path = self.content_dir()?.join("prefs.json");
+        if prefs_path.exists() {
+            let txt = fs::read_to_string(&prefs_path)?;
+            self.emit("prefs-dump", JsonValue::String(txt.clone()));
+            Ok(serde_json::from_str(&txt)?)
+        } else {
+            Ok(JsonValue::Object(serde_json::Map::new()))
+        }
+    }
+
+    fn typst_path(&self) -> Result<PathBuf>;
+
+    fn tectonic_path(&self) -> Result<PathBuf>;
+
+    fn pdfium_library_path(&self) -> Result<PathBuf>;
+
+    /// `dvisvgm` for converting diagram PDFs to SVG. Without it vector diagrams are rasterized.
+    fn dvisvgm_path(&self) -> Option<PathBuf> {
+        utils::paths::find_on_system_path("dvisvgm").or_else(|| {
+            self.resource_dir()
+                .map(|dir| utils::paths::bundled_binary_path(&dir, "dvisvgm"))
+                .filter(|path| path.exists())
+        })
+    }
+
+    /// Event sink for progress and debug notifications. Failures are ignored.
+    fn emit(&self, event: &str, payload: JsonValue);
+}
+
+impl RenderEnvironment for AppHandle {
+    fn content_dir(&self) -> Result<PathBuf> {
+        utils::get_content_dir(self)
+    }
+
+    fn assets_dir(&self) -> Result<PathBuf> {
+        utils::get_assets_dir(self)
+    }
+
+    fn resource_dir(&self) -> Option<PathBuf> {
+        self.path().resource_dir().ok()
+    }
+
+    fn template_path(&self) -> Result<PathBuf> {
+        // Prefer the dev workspace template during development
+        if let Ok(cwd) = std::env::current_dir() {
+            let dev_tpl = cwd.join("src-tauri").join("content").join("tideflow.typ");
+            if dev_tpl.exists() {
+                return Ok(dev_tpl);
+            }
+        }
+
+        let template_src = utils::get_content_dir(self)?.join("tideflow.typ");
+        if template_src.exists() {
+            return Ok(template_src);
+        }
+
+        // Attempt to restore the template from resources into the user content directory
+        if let Ok(restored) = utils::ensure_tideflow_template_exists(self) {
+            if restored.exists() {
+                return Ok(restored);
+            }
+        }
+
+        Err(anyhow!(
+            "tideflow.typ template not found at {}",
+            template_src.display()
+        ))
+    }
+
+    fn typst_path(&self) -> Result<PathBuf> {
+        utils::get_typst_path(self)
+    }
+
+    fn tectonic_path(&self) -> Result<PathBuf> {
+        utils::get_tectonic_path(self)
+    }
+
+    fn pdfium_library_path(&self) -> Result<PathBuf> {
+        utils::get_pdfium_library_path(self)
+    }
+
+    fn emit(&self, event: &str, payload: JsonValue) {
+        Emitter::emit(self, event, payload).ok();
+    }
+}
+
+type EventSink = Box<dyn Fn(&str, &JsonValue) + Send + Sync>;
+
+/// Plain filesystem environment for the CLI, tests and library consumers.
+///
+/// Binaries default to the system `PATH` and then `<resource_dir>/bin/<tool>/<platform>/`.
+/// Events are recorded and can be inspected with [`FsEnvironment::events`].
+pub struct FsEnvironment {
+    content_dir: PathBuf,
+    build_dir: Option<PathBuf>,
+    resource_dir: Option<PathBuf>,
+    preferences: Option<JsonValue>,
+    typst_path: Option<PathBuf>,
+    tectonic_path: Option<PathBuf>,
+    event_sink: Option<EventSink>,
+    events: Mutex<Vec<(String, JsonValue)>>,
+}
+
+impl FsEnvironment {
+    pub fn new(content_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            content_dir: content_dir.into(),
+            build_dir: None,
+            resource_dir: None,
+            preferences: None,
+            typst_path: None,
+            tectonic_path: None,
+            event_sink: None,
+            events: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Use a build directory other than `<content_dir>/.build`.
+    pub fn with_build_dir(mut self, build_dir: impl Into<PathBuf>) -> Self {
+        self.build_dir = Some(build_dir.into());
+        self
+    }
+
+    /// Directory laid out like the app's resources (`content/tideflow.typ`, `bin/...`).
+    pub fn with_resource_dir(mut self, resource_dir: impl Into<PathBuf>) -> Self {
+        self.resource_dir = Some(resource_dir.into());
+        self
+    }
+
+    /// Use these preferences instead of `<content_dir>/prefs.json`.
+    pub fn with_preferences(mut self, preferences: JsonValue) -> Self {
+        self.preferences = Some(preferences);
+        self
+    }
+
+    pub fn with_typst_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.typst_path = Some(path.into());
+        self
+    }
+
+    pub fn with_tectonic_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tectonic_path = Some(path.into());
+        self
+    }
+
+    /// Forward events to a callback in addition to recording them.
+    pub fn with_event_sink(
+        mut self,
+        sink: impl Fn(&str, &JsonValue) + Send + Sync + 'static,
+    ) -> Self {
+        self.event_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Events emitted so far, in order.
+    pub fn events(&self) -> Vec<(String, JsonValue)> {
+        self.events
+            .lock()
+            .map(|events| events.clone())
+            .unwrap_or_default()
+    }
+
+    fn locate_tool(&self, explicit: Option<&PathBuf>, tool: &str) -> Result<PathBuf> {
+        if let Some(path) = explicit {
+            return Ok(path.clone());
+        }
+        if let Some(found) = utils::paths::find_on_system_path(tool) {
+            return Ok(found);
+        }
+        if let Some(resource_dir) = self.resource_dir.as_ref() {
+            let bundled = utils::paths::bundled_binary_path(resource_dir, tool);
+            if bundled.exists() {
+                return Ok(bundled);
+            }
+        }
+        Err(anyhow!(
+            "{} binary not found on PATH or in the resource directory",
+            tool
+        ))
+    }
+}
+
+impl RenderEnvironment for FsEnvironment {
+    fn content_dir(&self) -> Result<PathBuf> {
+        if !self.content_dir.exists() {
+            fs::create_dir_all(&self.content_dir)?;
+        }
+        Ok(self.content_dir.clone())
+    }
+
+    fn build_dir(&self) -> Result<PathBuf> {
+        match self.build_dir.as_ref() {
+            Some(dir) => Ok(dir.clone()),
+            None => Ok(self.content_dir.join(".build")),
+        }
+    }
+
+    fn resource_dir(&self) -> Option<PathBuf> {
+        self.resource_dir.clone()
+    }
+
+    fn template_path(&self) -> Result<PathBuf> {
+        let mut candidates = Vec::new();
+        if let Some(resource_dir) = self.resource_dir.as_ref() {
+            candidates.push(resource_dir.join("content").join("tideflow.typ"));
+        }
+        candidates.push(self.content_dir.join("tideflow.typ"));
+
+        candidates
+            .iter()
+            .find(|p| p.exists())
+            .cloned()
+            .ok_or_else(|| {
+                anyhow!(
+                    "tideflow.typ template not found. Looked in: {}",
+                    candidates
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+
+    fn preferences(&self) -> Result<JsonValue> {
+        if let Some(prefs) = self.preferences.as_ref() {
+            return Ok(prefs.clone());
+        }
+        let prefs_path = self.content_dir.join("prefs.json");
+        if prefs_path.exists() {
+            Ok(serde_json::from_str(&fs::read_to_string(&prefs_path)?)?)
+        } else {
+            Ok(serde_json::to_value(Preferences::default())?)
+        }
+    }
+
+    fn typst_path(&self) -> Result<PathBuf> {
+        self.locate_tool(self.typst_path.as_ref(), "typst")
+    }
+
+    fn tectonic_path(&self) -> Result<PathBuf> {
+        self.locate_tool(self.tectonic_path.as_ref(), "tectonic")
+    }
+
+    fn pdfium_library_path(&self) -> Result<PathBuf> {
+        utils::paths::find_pdfium_library(self.resource_dir.as_deref())
+    }
+
+    fn emit(&self, event: &str, payload: JsonValue) {
+        if let Some(sink) = self.event_sink.as_ref() {
+            sink(event, &payload);
+        }
+        if let Ok(mut events) = self.events.lock() {
+            events.push((event.to_string(), payload));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fs_environment_defaults_to_content_relative_dirs() {
+        let root = std::env::temp_dir().join(format!("tideflow-env-{}", uuid::Uuid::new_v4()));
+        let env = FsEnvironment::new(&root);
+
+        assert_eq!(env.content_dir().unwrap(), root);
+        assert_eq!(env.build_dir().unwrap(), root.join(".build"));
+        assert_eq!(env.assets_dir().unwrap(), root.join("assets"));
+        assert!(root.join("assets").exists());
+
+        let prefs = env.preferences().unwrap();
+        assert_eq!(prefs["theme_id"], "default");
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn fs_environment_records_and_forwards_events() {
+        let forwarded = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let sink = forwarded.clone();
+        let env = FsEnvironment::new(std::env::temp_dir()).with_event_sink(move |event, _| {
+            sink.lock().unwrap().push(event.to_string());
+        });
+
+        env.emit("render-debug", serde_json::json!({ "toc": true }));
+
+        let events = env.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, "render-debug");
+        assert_eq!(*forwarded.lock().unwrap(), vec!["render-debug".to_string()]);
+    }
+}
@@ -0,0 +1,479 @@
+//! EPUB 3 export.
+//!
+//! The document is rendered as for an HTML export (see `html_export`) and split into one XHTML
+//! chapter per H1 and H2 section, with links between chapters pointed at the right file. Images
+//! and diagram assets are packaged with the chapters, the navigation document lists the
+//! headings, and `cover_title`, `cover_writer` and `cover_image` become the book's metadata and
+//! cover page.
+
+use crate::build_workspace::{self, JobKind};
+use crate::environment::RenderEnvironment;
+use crate::html_export::{self, AssetTarget, Assets, HtmlBody};
+use crate::math::escape_xml;
+use crate::preferences::Preferences;
+use crate::render_pipeline::RenderConfig;
+use crate::render_scheduler::{self, RenderJob};
+use anyhow::Result;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+const LANGUAGE: &str = "en";
+
+lazy_static! {
+    static ref VOID_ELEMENT: Regex =
+        Regex::new(r"<(?P<tag>area|br|col|hr|img|input|meta|wbr)\b(?P<attrs>[^>]*?)\s*/?>")
+            .expect("BUG: Invalid regex pattern for void elements");
+    static ref ID: Regex =
+        Regex::new(r#"\sid="([^"]+)""#).expect("BUG: Invalid regex pattern for element ids");
+    static ref FRAGMENT_LINK: Regex =
+        Regex::new(r##"href="#([^"]+)""##).expect("BUG: Invalid regex pattern for fragment links");
+}
+
+/// Export `content` (the document `current_file`, if saved) as an EPUB at `destination`.
+pub async fn export_epub(
+    env: &dyn RenderEnvironment,
+    content: &str,
+    destination: &str,
+    current_file: Option<&str>,
+) -> Result<String> {
+    let document = current_file.unwrap_or("");
+    let slot = render_scheduler::acquire(RenderJob::Export(document)).await?;
+
+    let content_dir = env.content_dir()?;
+    let build_dir = build_workspace::prepare(env, document, JobKind::Export)?;
+    let config = RenderConfig {
+        env,
+        build_dir: build_dir.clone(),
+        content_dir: content_dir.clone(),
+        typst_root: content_dir.clone(),
+        cancel: slot.token().clone(),
+    };
+    let base_dir = current_file
+        .and_then(|file| Path::new(file).parent())
+        .unwrap_or(Path::new("."));
+    let (preprocess, prefs) =
+        html_export::prepare_document(&config, content, document, base_dir, "epub")?;
+
+    // Images are collected in the build directory, then packaged
+    let images_dir = build_dir.join("epub-images");
+    if images_dir.exists() {
+        fs::remove_dir_all(&images_dir)?;
+    }
+    let target = AssetTarget::Directory {
+        dir: images_dir.clone(),
+        href: "images".to_string(),
+    };
+    let mut assets = Assets::new(&content_dir, &build_dir, base_dir, target);
+    let body =
+        html_export::render_body(&preprocess.markdown, &prefs, &mut |src| assets.resolve(src));
+    let cover = Some(prefs.cover_image.trim())
+        .filter(|image| !image.is_empty())
+        .map(|image| assets.resolve(image))
+        .filter(|src| src.starts_with("images/"));
+
+    let mut images = Vec::new();
+    if images_dir.is_dir() {
+        for entry in fs::read_dir(&images_dir)? {
+            images.push(entry?.file_name().to_string_lossy().to_string());
+        }
+    }
+    images.sort();
+
+    let fallback_title = current_file
+        .and_then(|file| Path::new(file).file_stem())
+        .map(|stem| stem.to_string_lossy().to_string());
+    let title = html_export::document_title(&prefs, &body.headings, fallback_title);
+
+    let output = Path::new(destination);
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut zip = ZipWriter::new(fs::File::create(output)?);
+    // The mimetype comes first and uncompressed, so readers can identify the file
+    zip.start_file(
+        "mimetype",
+        FileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    zip.write_all(b"application/epub+zip")?;
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, text) in documents(&prefs, &body, &title, cover.as_deref(), &images) {
+        zip.start_file(name, deflated)?;
+        zip.write_all(text.as_bytes())?;
+    }
+    for image in &images {
+        zip.start_file(format!("OEBPS/images/{}", image), deflated)?;
+        zip.write_all(&fs::read(images_dir.join(image))?)?;
+    }
+    zip.finish()?;
+    Ok(output.to_string_lossy().to_string())
+}
+
+struct Chapter {
+    file: String,
+    title: String,
+    html: String,
+}
+
+/// The text files of the book: container, package and navigation documents, stylesheet, cover
+/// page and chapters. `cover` is the cover image and `images` the files of `OEBPS/images`.
+fn documents(
+    prefs: &Preferences,
+    body: &HtmlBody,
+    title: &str,
+    cover: Option<&str>,
+    images: &[String],
+) -> Vec<(String, String)> {
+    let chapters = chapters(body, title);
+    let files: HashMap<&str, &str> = chapters
+        .iter()
+        .flat_map(|chapter| {
+            ID.captures_iter(&chapter.html).map(|caps| {
+                (
+                    caps.get(1).map_or("", |id| id.as_str()),
+                    chapter.file.as_str(),
+                )
+            })
+        })
+        .collect();
+    let cover_page = prefs.cover_page || cover.is_some();
+
+    let mut documents = vec![
+        ("META-INF/container.xml".to_string(), CONTAINER.to_string()),
+        (
+            "OEBPS/content.opf".to_string(),
+            package(prefs, title, &chapters, cover, cover_page, images),
+        ),
+        (
+            "OEBPS/nav.xhtml".to_string(),
+            navigation(prefs, body, &chapters, &files),
+        ),
+        (
+            "OEBPS/style.css".to_string(),
+            html_export::stylesheet(prefs),
+        ),
+    ];
+    if cover_page {
+        let mut html = String::from("<section class=\"cover\" epub:type=\"cover\">\n");
+        if let Some(src) = cover {
+            html.push_str(&format!(
+                "<img src=\"{}\" alt=\"{}\" style=\"width: {}\" />\n",
+                escape_xml(src),
+                escape_xml(title),
+                escape_xml(&prefs.cover_image_width)
+            ));
+        }
+        html.push_str(&format!(
+            "<h1 class=\"cover-title\">{}</h1>\n",
+            escape_xml(title)
+        ));
+        if !prefs.cover_writer.trim().is_empty() {
+            html.push_str(&format!(
+                "<p class=\"cover-writer\">{}</p>\n",
+                escape_xml(prefs.cover_writer.trim())
+            ));
+        }
+        html.push_str("</section>\n");
+        documents.push(("OEBPS/cover.xhtml".to_string(), xhtml(title, &html)));
+    }
+    for chapter in &chapters {
+        // Links to an element in another chapter name its file
+        let html =
+            FRAGMENT_LINK.replace_all(&chapter.html, |caps: &Captures| match files.get(&caps[1]) {
+                Some(&file) if file != chapter.file => format!("href=\"{}#{}\"", file, &caps[1]),
+                _ => caps[0].to_string(),
+            });
+        documents.push((
+            format!("OEBPS/{}", chapter.file),
+            xhtml(&chapter.title, &html),
+        ));
+    }
+    documents
+}
+
+/// The body's sections as chapters, skipping empty ones. Each is titled by its first heading.
+fn chapters(body: &HtmlBody, title: &str) -> Vec<Chapter> {
+    let mut chapters: Vec<Chapter> = body
+        .sections
+        .iter()
+        .filter(|section| !section.trim().is_empty())
+        .enumerate()
+        .map(|(index, section)| {
+            let chapter_title = body
+                .headings
+                .iter()
+                .find(|heading| section.contains(&format!("id=\"{}\"", escape_xml(&heading.id))))
+                .map_or_else(|| title.to_string(), |heading| heading.label());
+            Chapter {
+                file: format!("chapter-{:03}.xhtml", index + 1),
+                title: chapter_title,
+                html: section.clone(),
+            }
+        })
+        .collect();
+    if chapters.is_empty() {
+        chapters.push(Chapter {
+            file: "chapter-001.xhtml".to_string(),
+            title: title.to_string(),
+            html: String::new(),
+        });
+    }
+    chapters
+}
+
+const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+/// The package document: metadata, every file of the book and the reading order.
+fn package(
+    prefs: &Preferences,
+    title: &str,
+    chapters: &[Chapter],
+    cover: Option<&str>,
+    cover_page: bool,
+    images: &[String],
+) -> String {
+    let mut metadata = format!(
+        "    <dc:identifier id=\"book-id\">urn:uuid:{}</dc:identifier>\n    \
+         <dc:title>{}</dc:title>\n    <dc:language>{}</dc:language>\n    \
+         <meta property=\"dcterms:modified\">{}</meta>\n",
+        uuid::Uuid::new_v4(),
+        escape_xml(title),
+        LANGUAGE,
+        chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ")
+    );
+    if !prefs.cover_writer.trim().is_empty() {
+        metadata.push_str(&format!(
+            "    <dc:creator>{}</dc:creator>\n",
+            escape_xml(prefs.cover_writer.trim())
+        ));
+    }
+
+    let mut manifest = String::from(
+        "    <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n    \
+         <item id=\"style\" href=\"style.css\" media-type=\"text/css\"/>\n",
+    );
+    let mut spine = String::new();
+    if cover_page {
+        manifest.push_str(
+            "    <item id=\"cover\" href=\"cover.xhtml\" media-type=\"application/xhtml+xml\"/>\n",
+        );
+        spine.push_str("    <itemref idref=\"cover\"/>\n");
+    }
+    if prefs.toc {
+        spine.push_str("    <itemref idref=\"nav\"/>\n");
+    }
+    for (index, image) in images.iter().enumerate() {
+        let href = format!("images/{}", image);
+        let properties = if cover == Some(href.as_str()) {
+            // EPUB 2 readers look for the cover in the metadata
+            metadata.push_str(&format!(
+                "    <meta name=\"cover\" content=\"image-{}\"/>\n",
+                index + 1
+            ));
+            " properties=\"cover-image\""
+        } else {
+            ""
+        };
+        manifest.push_str(&format!(
+            "    <item id=\"image-{}\" href=\"{}\" media-type=\"{}\"{}/>\n",
+            index + 1,
+            escape_xml(&href),
+            html_export::mime_type(Path::new(image)),
+            properties
+        ));
+    }
+    for (index, chapter) in chapters.iter().enumerate() {
+        let properties = if chapter.html.contains("<math") {
+            " properties=\"mathml\""
+        } else {
+            ""
+        };
+        manifest.push_str(&format!(
+            "    <item id=\"chapter-{}\" href=\"{}\" media-type=\"application/xhtml+xml\"{}/>\n",
+            index + 1,
+            chapter.file,
+            properties
+        ));
+        spine.push_str(&format!("    <itemref idref=\"chapter-{}\"/>\n", index + 1));
+    }
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"book-id\" xml:lang=\"{}\">\n  \
+         <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n{}  </metadata>\n  \
+         <manifest>\n{}  </manifest>\n  <spine>\n{}  </spine>\n</package>\n",
+        LANGUAGE, metadata, manifest, spine
+    )
+}
+
+/// The navigation document: the headings as nested lists, or the chapters when there are none.
+fn navigation(
+    prefs: &Preferences,
+    body: &HtmlBody,
+    chapters: &[Chapter],
+    files: &HashMap<&str, &str>,
+) -> String {
+    let entries: Vec<(usize, String, String)> = if body.headings.is_empty() {
+        chapters
+            .iter()
+            .map(|chapter| (1, chapter.file.clone(), chapter.title.clone()))
+            .collect()
+    } else {
+        body.headings
+            .iter()
+            .map(|heading| {
+                let file = files.get(heading.id.as_str()).copied().unwrap_or("");
+                (
+                    heading.level,
+                    format!("{}#{}", file, heading.id),
+                    heading.label(),
+                )
+            })
+            .collect()
+    };
+
+    let mut list = String::new();
+    let mut levels: Vec<usize> = Vec::new();
+    for (level, href, text) in entries {
+        match levels.last() {
+            // A sibling or a heading further out closes the open entries
+            Some(&last) if level <= last => {
+                list.push_str("</li>\n");
+                while levels.len() > 1 && levels.last().is_some_and(|&last| level < last) {
+                    levels.pop();
+                    list.push_str("</ol>\n</li>\n");
+                }
+            }
+            _ => {
+                list.push_str("<ol>\n");
+                levels.push(level);
+            }
+        }
+        list.push_str(&format!(
+            "<li><a href=\"{}\">{}</a>",
+            escape_xml(&href),
+            escape_xml(&text)
+        ));
+    }
+    for _ in &levels {
+        list.push_str("</li>\n</ol>\n");
+    }
+
+    let toc_title = html_export::toc_title(prefs);
+    xhtml(
+        toc_title,
+        &format!(
+            "<nav epub:type=\"toc\" id=\"toc\">\n<h1>{}</h1>\n{}</nav>\n",
+            escape_xml(toc_title),
+            list
+        ),
+    )
+}
+
+/// An XHTML content document around `html`, with the void elements HTML allows unclosed
+/// closed.
+fn xhtml(title: &str, html: &str) -> String {
+    let html = VOID_ELEMENT
+        .replace_all(html, "<$tag$attrs />")
+        .replace("&nbsp;", "&#160;");
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" xml:lang=\"{lang}\" lang=\"{lang}\">\n\
+         <head>\n<meta charset=\"utf-8\" />\n<title>{title}</title>\n\
+         <link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\" />\n</head>\n\
+         <body>\n{html}</body>\n</html>\n",
+        lang = LANGUAGE,
+        title = escape_xml(title),
+        html = html
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MARKDOWN: &str = "Preface with a footnote[^1].\n\n# One {#one}\n\nSee [@sec:two].<br>\n\n## One point one\n\n> ## Quoted\n\n# Two {#sec:two}\n\n[^1]: The note.\n";
+
+    /// The files of a book "Book" written by Ada, with a cover image, made from `MARKDOWN`.
+    fn book() -> Vec<(String, String)> {
+        let prefs = Preferences {
+            toc: true,
+            cover_writer: "Ada".to_string(),
+            ..Preferences::default()
+        };
+        let body = html_export::render_body(MARKDOWN, &prefs, &mut |src| src.to_string());
+        documents(
+            &prefs,
+            &body,
+            "Book",
+            Some("images/cover.png"),
+            &["cover.png".to_string()],
+        )
+    }
+
+    fn document<'a>(documents: &'a [(String, String)], name: &str) -> &'a str {
+        documents
+            .iter()
+            .find(|(file, _)| file == name)
+            .map(|(_, text)| text.as_str())
+            .unwrap_or_else(|| panic!("{} missing", name))
+    }
+
+    #[test]
+    fn sections_become_xhtml_chapters() {
+        let documents = book();
+        assert_eq!(
+            documents
+                .iter()
+                .filter(|(file, _)| file.contains("chapter-"))
+                .count(),
+            4
+        );
+        let one = document(&documents, "OEBPS/chapter-002.xhtml");
+        assert!(one.contains("<title>1 One</title>"));
+        assert!(one.contains("<br />"));
+        // A heading inside a quote does not start a chapter of its own
+        assert!(document(&documents, "OEBPS/chapter-003.xhtml").contains("Quoted"));
+    }
+
+    #[test]
+    fn links_point_at_the_chapter_holding_their_target() {
+        let documents = book();
+        assert!(document(&documents, "OEBPS/chapter-001.xhtml")
+            .contains("href=\"chapter-004.xhtml#1\""));
+        assert!(document(&documents, "OEBPS/chapter-002.xhtml")
+            .contains("href=\"chapter-004.xhtml#sec:two\""));
+    }
+
+    #[test]
+    fn navigation_nests_headings_by_level() {
+        let documents = book();
+        assert!(document(&documents, "OEBPS/nav.xhtml").contains(
+            "<li><a href=\"chapter-002.xhtml#one\">1 One</a><ol>\n<li><a href=\"chapter-003.xhtml#one-point-one\">1.1 One point one</a></li>\n<li><a href=\"chapter-003.xhtml#quoted\">1.2 Quoted</a></li>\n</ol>\n</li>\n<li><a href=\"chapter-004.xhtml#sec:two\">2 Two</a>"
+        ));
+    }
+
+    #[test]
+    fn package_lists_metadata_cover_and_reading_order() {
+        let documents = book();
+        let package = document(&documents, "OEBPS/content.opf");
+        assert!(package.contains("<dc:creator>Ada</dc:creator>"));
+        assert!(package.contains(
+            "<item id=\"image-1\" href=\"images/cover.png\" media-type=\"image/png\" properties=\"cover-image\"/>"
+        ));
+        assert!(package.contains(
+            "<itemref idref=\"cover\"/>\n    <itemref idref=\"nav\"/>\n    <itemref idref=\"chapter-1\"/>"
+        ));
+    }
+}
@@ -0,0 +1,282 @@
+//! YAML front matter with per-document preference overrides.
+//!
+//! A document may start with a `---` YAML block closed by `---` or `...`. Keys that name
+//! document preferences (`theme_id`, `papersize`, `margin`, `toc`, `cover_title`, `fonts`,
+//! `numberSections`, ...) are layered over the global `prefs.json` for that document only.
+//! Unknown keys, application-level settings and values of the wrong type are skipped and
+//! reported as warnings instead of failing the render.
+
+use crate::preferences::Preferences;
+use serde_json::{Map, Value as JsonValue};
+use std::path::Path;
+
+/// Preferences that describe the application rather than a document.
+const APP_ONLY_KEYS: &[&str] = &[
+    "render_debounce_ms",
+    "focused_preview_enabled",
+    "preserve_scroll_position",
+    "confirm_exit_on_unsaved",
+    "typst_path",
+    "typst_backend",
+    "render_cache_max_mb",
+    "diagram_parallel_jobs",
+];
+
+/// Parsed front matter of a document.
+#[derive(Debug, Clone, Default)]
+pub struct FrontMatter {
+    /// Validated preference overrides, in `prefs.json` shape.
+    pub overrides: Map<String, JsonValue>,
+    pub warnings: Vec<String>,
+}
+
+/// Split a leading front matter block off `markdown`. The block is blanked out rather than
+/// removed (every character but newlines becomes a space) so byte offsets and line numbers of
+/// the remaining content are unchanged.
+pub fn extract(markdown: &str) -> (FrontMatter, String) {
+    let Some((yaml, end)) = find_block(markdown) else {
+        return (FrontMatter::default(), markdown.to_string());
+    };
+
+    let mut body = String::with_capacity(markdown.len());
+    for c in markdown[..end].chars() {
+        if c == '\n' || c == '\r' {
+            body.push(c);
+        } else {
+            body.push_str(&" ".repeat(c.len_utf8()));
+        }
+    }
+    body.push_str(&markdown[end..]);
+
+    (parse(yaml), body)
+}
+
+/// Locate the YAML text and the end offset (after the closing fence line) of the block.
+fn find_block(markdown: &str) -> Option<(&str, usize)> {
+    let mut lines = markdown.split_inclusive('\n');
+    let first = lines.next()?;
+    if first.trim_end() != "---" {
+        return None;
+    }
+
+    // As in pandoc, a `---` followed by a blank line is a horizontal rule, not front matter.
+    let mut lines = lines.peekable();
+    if lines.peek().is_some_and(|line| line.trim().is_empty()) {
+        return None;
+    }
+
+    let yaml_start = first.len();
+    let mut offset = yaml_start;
+    for line in lines {
+        let fence = line.trim_end();
+        if fence == "---" || fence == "..." {
+            return Some((&markdown[yaml_start..offset], offset + line.len()));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+fn parse(yaml: &str) -> FrontMatter {
+    let mut front_matter = FrontMatter::default();
+    let value: JsonValue = match serde_yaml::from_str(yaml) {
+        Ok(value) => value,
+        Err(e) => {
+            front_matter
+                .warnings
+                .push(format!("Front matter is not valid YAML: {}", e));
+            return front_matter;
+        }
+    };
+
+    let entries = match value {
+        JsonValue::Object(entries) => entries,
+        JsonValue::Null => return front_matter,
+        _ => {
+            front_matter
+                .warnings
+                .push("Front matter must be a mapping of preference names to values".to_string());
+            return front_matter;
+        }
+    };
+
+    let defaults = serde_json::to_value(Preferences::default()).unwrap_or_default();
+    for (key, value) in entries {
+        if APP_ONLY_KEYS.contains(&key.as_str()) {
+            front_matter.warnings.push(format!(
+                "Front matter key `{}` is an application setting and cannot be set per document",
+                key
+            ));
+            continue;
+        }
+        let Some(expected) = defaults.get(&key) else {
+            front_matter
+                .warnings
+                .push(format!("Unknown front matter key `{}`", key));
+            continue;
+        };
+        if let Some(value) = coerce(&key, value, expected, &mut front_matter.warnings) {
+            front_matter.overrides.insert(key, value);
+        }
+    }
+    front_matter
+}
+
+/// Check `value` against the type of the default preference, converting scalars to strings
+/// where a string is expected (e.g. `cover_title: 2024`). Returns `None` after recording a
+/// warning when the value cannot be used.
+fn coerce(
+    key: &str,
+    value: JsonValue,
+    expected: &JsonValue,
+    warnings: &mut Vec<String>,
+) -> Option<JsonValue> {
+    let invalid = |warnings: &mut Vec<String>, kind: &str| {
+        warnings.push(format!(
+            "Front matter key `{}` must be {}; ignoring it",
+            key, kind
+        ));
+        None
+    };
+
+    match (expected, value) {
+        (JsonValue::Bool(_), value @ JsonValue::Bool(_)) => Some(value),
+        (JsonValue::Bool(_), _) => invalid(warnings, "true or false"),
+        (JsonValue::Number(_), value @ JsonValue::Number(_)) => Some(value),
+        (JsonValue::Number(_), _) => invalid(warnings, "a number"),
+        (JsonValue::String(_), value @ JsonValue::String(_)) => Some(value),
+        (JsonValue::String(_), JsonValue::Number(n)) => Some(JsonValue::String(n.to_string())),
+        (JsonValue::String(_), JsonValue::Bool(b)) => Some(JsonValue::String(b.to_string())),
+        (JsonValue::String(_), _) => invalid(warnings, "a string"),
+        (JsonValue::Object(fields), JsonValue::Object(entries)) => {
+            let mut checked = Map::new();
+            for (field, value) in entries {
+                let path = format!("{}.{}", key, field);
+                match fields.get(&field) {
+                    Some(expected) => {
+                        if let Some(value) = coerce(&path, value, expected, warnings) {
+                            checked.insert(field, value);
+                        }
+                    }
+                    None => warnings.push(format!("Unknown front matter key `{}`", path)),
+                }
+            }
+            Some(JsonValue::Object(checked))
+        }
+        (JsonValue::Object(_), _) => invalid(warnings, "a mapping"),
+        // Lists of strings (e.g. `bibliography`) also accept a single string.
+        (JsonValue::Array(_), JsonValue::String(s)) => Some(JsonValue::Array(vec![s.into()])),
+        (JsonValue::Array(_), JsonValue::Array(items))
+            if items.iter().all(JsonValue::is_string) =>
+        {
+            Some(JsonValue::Array(items))
+        }
+        (JsonValue::Array(_), _) => invalid(warnings, "a string or a list of strings"),
+        _ => invalid(warnings, "a supported value"),
+    }
+}
+
+/// Resolve a relative `cover_image`, `bibliography` or `csl` against the document's directory,
+/// so front matter paths behave like image links in the document body.
+pub fn resolve_relative_paths(overrides: &mut Map<String, JsonValue>, base_dir: &Path) {
+    let resolve = |path: &mut String| {
+        let candidate = base_dir.join(&*path);
+        if !path.is_empty() && Path::new(path).is_relative() && candidate.exists() {
+            *path = candidate.to_string_lossy().to_string();
+        }
+    };
+    for key in ["cover_image", "csl"] {
+        if let Some(JsonValue::String(path)) = overrides.get_mut(key) {
+            resolve(path);
+        }
+    }
+    if let Some(JsonValue::Array(paths)) = overrides.get_mut("bibliography") {
+        for path in paths {
+            if let JsonValue::String(path) = path {
+                resolve(path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn front_matter_is_blanked_in_place() {
+        let source = "---\ntheme_id: academic\n---\n# Title\n";
+        let (front_matter, body) = extract(source);
+
+        assert_eq!(body.len(), source.len());
+        assert_eq!(body.lines().count(), source.lines().count());
+        assert!(body.trim_start().starts_with("# Title"));
+        assert_eq!(front_matter.overrides["theme_id"], "academic");
+        assert!(front_matter.warnings.is_empty());
+    }
+
+    #[test]
+    fn nested_preferences_are_validated_by_field() {
+        let source = "---\nmargin:\n  x: 1in\nfonts: { main: Inter, size: 3 }\n---\n";
+        let (front_matter, _) = extract(source);
+
+        assert_eq!(front_matter.overrides["margin"]["x"], "1in");
+        assert_eq!(front_matter.overrides["fonts"]["main"], "Inter");
+        assert_eq!(
+            front_matter.warnings,
+            vec!["Unknown front matter key `fonts.size`"]
+        );
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_skipped() {
+        let (front_matter, _) = extract("---\ntoc: yes\ncover_title: 2024\n---\n");
+
+        assert!(!front_matter.overrides.contains_key("toc"));
+        assert_eq!(front_matter.overrides["cover_title"], "2024");
+        assert_eq!(
+            front_matter.warnings,
+            vec!["Front matter key `toc` must be true or false; ignoring it"]
+        );
+    }
+
+    #[test]
+    fn app_only_and_unknown_keys_are_rejected() {
+        let (front_matter, _) = extract("---\nauthor: Ann\ntypst_backend: external\n---\n");
+
+        assert!(front_matter.overrides.is_empty());
+        assert_eq!(
+            front_matter.warnings,
+            vec![
+                "Unknown front matter key `author`",
+                "Front matter key `typst_backend` is an application setting and cannot be set per document",
+            ]
+        );
+    }
+
+    #[test]
+    fn documents_without_front_matter_are_unchanged() {
+        for source in ["# No front matter\n---\n", "---\n\nText\n\n---\n"] {
+            let (none, unchanged) = extract(source);
+            assert!(none.overrides.is_empty() && none.warnings.is_empty());
+            assert_eq!(unchanged, source);
+        }
+    }
+
+    #[test]
+    fn relative_paths_resolve_against_the_document() {
+        let dir = std::env::temp_dir().join(format!("tf-front-matter-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("refs.bib"), "").unwrap();
+
+        let (mut front_matter, _) =
+            extract("---\nbibliography: refs.bib\ncover_image: missing.png\n---\n");
+        resolve_relative_paths(&mut front_matter.overrides, &dir);
+
+        let expected = dir.join("refs.bib").to_string_lossy().to_string();
+        assert_eq!(front_matter.overrides["bibliography"][0], expected.as_str());
+        assert_eq!(front_matter.overrides["cover_image"], "missing.png");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,1356 @@
+//! Standalone HTML export.
+//!
+//! The document goes through the same preprocessing as a PDF export (includes, admonitions,
+//! diagrams, cross-references, citations, image path rewriting), then pulldown-cmark renders
+//! it instead of Typst. The Typst-specific markup the preprocessor leaves behind is turned
+//! into HTML along the way: diagram placeholders become figures of the rendered assets,
+//! labelled figures, tables, equations and sections are numbered, and references link to
+//! them. Math becomes MathML (see `math`), images and diagrams are inlined as data URIs or
+//! copied into a `<name>_files` directory next to the page, and the stylesheet is derived from
+//! the document's preferences.
+//!
+//! Citations are shown by key; formatting them against the bibliography needs Typst.
+
+use crate::build_workspace::{self, JobKind};
+use crate::citations;
+use crate::crossref;
+use crate::diagrams::{self, DiagramRegistry, DiagramSettings};
+use crate::environment::RenderEnvironment;
+use crate::front_matter;
+use crate::math::{self, escape_xml};
+use crate::preferences::{merge_json, Preferences};
+use crate::preprocessor::{preprocess_markdown, PreprocessorOutput};
+use crate::render_pipeline::{self, RenderConfig};
+use crate::render_scheduler::{self, RenderJob};
+use crate::renderer;
+use crate::utils;
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use lazy_static::lazy_static;
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+use regex::{Captures, Regex};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    static ref MARKED_REFERENCE: Regex = Regex::new(r"\[@(?P<label>(?:fig|tbl|eq|sec):[^\]\s]+)\]")
+        .expect("BUG: Invalid regex pattern for cross-references");
+    static ref REFERENCE: Regex = Regex::new(r#"<tf-ref label="(?P<label>[^"]*)"></tf-ref>"#)
+        .expect("BUG: Invalid regex pattern for reference markers");
+    /// The preprocessor's markup in raw HTML.
+    pub(crate) static ref MARKUP: Regex = Regex::new(concat!(
+        r"(?s)<!--raw-typst(?P<typst>.*?)-->",
+        r#"|<h(?P<level>[1-6])(?P<heading_attrs>\s[^>]*)>(?P<text>.*?)</h[1-6]>"#,
+        r#"|<img\b[^>]*?\ssrc=["'](?P<src>[^"']+)["'][^>]*>"#,
+        r"|(?P<tag></?(?:figure|figcaption|tf-caption|tf-math|tf-cite|tf-admonition))(?P<attrs>\s[^>]*)?>",
+    ))
+    .expect("BUG: Invalid regex pattern for preprocessor markup");
+    static ref EQUATION: Regex =
+        Regex::new(r#"<!--raw-typst #crossref_equation\("(?P<math>(?:[^"\\]|\\.)*)", "(?P<label>[^"]+)"\) -->"#)
+            .expect("BUG: Invalid regex pattern for labelled equations");
+    static ref ATTRIBUTE: Regex = Regex::new(r#"(?P<name>[\w-]+)="(?P<value>[^"]*)""#)
+        .expect("BUG: Invalid regex pattern for attributes");
+    static ref DIAGRAM_ARGUMENT: Regex =
+        Regex::new(r#"(?P<name>\w+): (?:"(?P<string>(?:[^"\\]|\\.)*)"|(?P<value>[^,]+))"#)
+            .expect("BUG: Invalid regex pattern for diagram arguments");
+    static ref TAG: Regex = Regex::new(r"<[^>]*>").expect("BUG: Invalid regex for tags");
+}
+
+/// Export `content` (the document `current_file`, if saved) as an HTML page at `destination`.
+/// With `inline_assets`, images are embedded in the page; otherwise they are copied into a
+/// directory next to it.
+pub async fn export_html(
+    env: &dyn RenderEnvironment,
+    content: &str,
+    destination: &str,
+    current_file: Option<&str>,
+    inline_assets: bool,
+) -> Result<String> {
+    let document = current_file.unwrap_or("");
+    let slot = render_scheduler::acquire(RenderJob::Export(document)).await?;
+
+    let content_dir = env.content_dir()?;
+    let build_dir = build_workspace::prepare(env, document, JobKind::Export)?;
+    let config = RenderConfig {
+        env,
+        build_dir: build_dir.clone(),
+        content_dir: content_dir.clone(),
+        typst_root: content_dir.clone(),
+        cancel: slot.token().clone(),
+    };
+    let base_dir = current_file
+        .and_then(|file| Path::new(file).parent())
+        .unwrap_or(Path::new("."));
+    let (preprocess, prefs) = prepare_document(&config, content, document, base_dir, "html")?;
+
+    let output = Path::new(destination);
+    let target = if inline_assets {
+        AssetTarget::Inline
+    } else {
+        let stem = output
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| anyhow!("Invalid output filename"))?;
+        let name = format!("{}_files", stem);
+        AssetTarget::Directory {
+            dir: output.parent().unwrap_or(Path::new(".")).join(&name),
+            href: name,
+        }
+    };
+    let mut assets = Assets::new(&content_dir, &build_dir, base_dir, target);
+    let body = render_body(&preprocess.markdown, &prefs, &mut |src| assets.resolve(src));
+    let cover_image = (prefs.cover_page && !prefs.cover_image.is_empty())
+        .then(|| assets.resolve(&prefs.cover_image));
+
+    let fallback_title = current_file
+        .and_then(|file| Path::new(file).file_stem())
+        .map(|stem| stem.to_string_lossy().to_string());
+    let page = page(&prefs, &body, cover_image.as_deref(), fallback_title);
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(output, page)?;
+    Ok(output.to_string_lossy().to_string())
+}
+
+/// Preprocess `content` for an export that does not go through Typst: front matter, image
+/// paths, preferences, citations and diagram assets, as for a PDF export. Returns the
+/// preprocessed document and the preferences it is exported with.
+pub(crate) fn prepare_document(
+    config: &RenderConfig,
+    content: &str,
+    document: &str,
+    base_dir: &Path,
+    format: &str,
+) -> Result<(PreprocessorOutput, Preferences)> {
+    let env = config.env;
+    let assets_root = env.assets_dir().ok();
+    let diagrams = DiagramRegistry::load(env);
+    let mut preprocess = preprocess_markdown(
+        content,
+        Some(Path::new(document)).filter(|_| !document.is_empty()),
+        base_dir,
+        &diagrams,
+    )?;
+    front_matter::resolve_relative_paths(&mut preprocess.prefs_overrides, base_dir);
+    preprocess.transform(|markdown| {
+        utils::rewrite_image_paths_in_markdown(markdown, base_dir, assets_root.as_deref())
+    });
+
+    let prefs = render_pipeline::setup_prefs(
+        config,
+        &format!("markdown-export-{}", format),
+        &preprocess.prefs_overrides,
+    )?;
+    citations::apply(&mut preprocess, content, &prefs.bibliography);
+    renderer::emit_warnings(env, document, &preprocess.warnings);
+
+    let diagram_settings = DiagramSettings::resolve(env, base_dir, &prefs.prefs_json);
+    diagrams::prepare_diagram_assets(
+        env,
+        &diagrams,
+        &preprocess.diagram_blocks,
+        &config.build_dir,
+        &diagram_settings,
+        &config.cancel,
+    )?;
+
+    // Keys missing from the preferences keep their defaults; values of the wrong type fail.
+    let mut preferences = serde_json::to_value(Preferences::default())?;
+    merge_json(&mut preferences, prefs.prefs_json);
+    let preferences = serde_json::from_value(preferences)
+        .with_context(|| format!("invalid preferences for the {} export", format))?;
+    Ok((preprocess, preferences))
+}
+
+/// Where the images of an exported page end up.
+pub(crate) enum AssetTarget {
+    /// Embedded as data URIs.
+    Inline,
+    /// Copied into `dir`, which the page refers to as `href`.
+    Directory { dir: PathBuf, href: String },
+}
+
+/// Resolves the image sources of preprocessed markdown to files and places them for the page.
+pub(crate) struct Assets<'a> {
+    content_dir: &'a Path,
+    build_dir: &'a Path,
+    base_dir: &'a Path,
+    target: AssetTarget,
+    /// Sources already placed, with their new source.
+    placed: HashMap<PathBuf, String>,
+    names: HashSet<String>,
+}
+
+impl<'a> Assets<'a> {
+    pub(crate) fn new(
+        content_dir: &'a Path,
+        build_dir: &'a Path,
+        base_dir: &'a Path,
+        target: AssetTarget,
+    ) -> Self {
+        Self {
+            content_dir,
+            build_dir,
+            base_dir,
+            target,
+            placed: HashMap::new(),
+            names: HashSet::new(),
+        }
+    }
+
+    /// The file an image source refers to. Image paths are root-relative to the content
+    /// directory or absolute after rewriting; diagram assets live in the build directory.
+    pub(crate) fn locate(&self, src: &str) -> Option<PathBuf> {
+        let lower = src.to_ascii_lowercase();
+        if lower.contains("://") || lower.starts_with("data:") {
+            return None;
+        }
+        let candidates = if src.starts_with("diagrams/") {
+            vec![self.build_dir.join(src)]
+        } else if let Some(relative) = src.strip_prefix('/') {
+            vec![self.content_dir.join(relative), PathBuf::from(src)]
+        } else {
+            vec![self.base_dir.join(src), PathBuf::from(src)]
+        };
+        candidates.into_iter().find(|path| path.is_file())
+    }
+
+    /// The source to use in the page for `src`; unchanged when it is not a local file.
+    pub(crate) fn resolve(&mut self, src: &str) -> String {
+        let Some(path) = self.locate(src) else {
+            return src.to_string();
+        };
+        if let Some(placed) = self.placed.get(&path) {
+            return placed.clone();
+        }
+        let placed = match self.place(&path) {
+            Ok(placed) => placed,
+            Err(e) => {
+                log::warn!("Could not export image {}: {}", path.display(), e);
+                src.to_string()
+            }
+        };
+        self.placed.insert(path, placed.clone());
+        placed
+    }
+
+    fn place(&mut self, path: &Path) -> Result<String> {
+        match &self.target {
+            AssetTarget::Inline => {
+                let bytes = fs::read(path)?;
+                Ok(format!(
+                    "data:{};base64,{}",
+                    mime_type(path),
+                    BASE64.encode(bytes)
+                ))
+            }
+            AssetTarget::Directory { dir, href } => {
+                let name = unique_name(&mut self.names, path);
+                fs::create_dir_all(dir)?;
+                fs::copy(path, dir.join(&name))?;
+                Ok(format!("{}/{}", href, name))
+            }
+        }
+    }
+}
+
+/// The file name of `path`, numbered when another file already took it.
+pub(crate) fn unique_name(names: &mut HashSet<String>, path: &Path) -> String {
+    let stem = path
+        .file_stem()
+        .map(|stem| utils::sanitize_filename(&stem.to_string_lossy()))
+        .unwrap_or_else(|| "image".to_string());
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy().to_lowercase()))
+        .unwrap_or_default();
+    let mut name = format!("{}{}", stem, extension);
+    let mut counter = 1;
+    while !names.insert(name.clone()) {
+        name = format!("{}-{}{}", stem, counter, extension);
+        counter += 1;
+    }
+    name
+}
+
+pub(crate) fn mime_type(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .as_deref()
+    {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("webp") => "image/webp",
+        Some("bmp") => "image/bmp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// A heading of the exported document.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Heading {
+    pub level: usize,
+    pub id: String,
+    /// Section number, when sections are numbered.
+    pub number: Option<String>,
+    pub text: String,
+}
+
+impl Heading {
+    /// The heading as listed in a table of contents, with its number.
+    pub(crate) fn label(&self) -> String {
+        match &self.number {
+            Some(number) => format!("{} {}", number, self.text),
+            None => self.text.clone(),
+        }
+    }
+}
+
+/// A document rendered to HTML, without the page around it.
+#[derive(Debug, Clone)]
+pub(crate) struct HtmlBody {
+    /// The HTML split before every H1 and H2 heading that is not nested in another element,
+    /// so e-books can put each in its own file.
+    pub sections: Vec<String>,
+    pub headings: Vec<Heading>,
+}
+
+/// Marks where a new section starts while the body is rendered.
+const SECTION_BREAK: &str = "<!--tf-section-->";
+
+/// A formula taken out of the markdown before it is parsed.
+pub(crate) struct Formula {
+    pub latex: String,
+    pub display: bool,
+    pub label: Option<String>,
+}
+
+/// Markdown extensions of the exports, as far as pulldown-cmark has them.
+pub(crate) fn markdown_options() -> Options {
+    Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TABLES
+        | Options::ENABLE_HEADING_ATTRIBUTES
+}
+
+/// Numbers of sections, figures, tables and equations, counted in document order as the
+/// template counts them, and the text of references to their labels.
+pub(crate) struct Numbering<'a> {
+    prefs: &'a Preferences,
+    /// Section counters per heading level.
+    sections: [usize; 6],
+    /// Figure, table and equation counters.
+    counters: HashMap<&'static str, usize>,
+    ids: HashSet<String>,
+    /// Text of a reference to each label, e.g. `Figure 2`.
+    pub references: HashMap<String, String>,
+}
+
+impl<'a> Numbering<'a> {
+    pub(crate) fn new(prefs: &'a Preferences) -> Self {
+        Self {
+            prefs,
+            sections: [0; 6],
+            counters: HashMap::new(),
+            ids: HashSet::new(),
+            references: HashMap::new(),
+        }
+    }
+
+    /// Register a heading; returns it with a unique id and its section number.
+    pub(crate) fn heading(&mut self, level: usize, id: Option<String>, text: &str) -> Heading {
+        let level = level.clamp(1, 6);
+        let number = self.prefs.number_sections.then(|| {
+            self.sections[level - 1] += 1;
+            self.sections[level..].iter_mut().for_each(|n| *n = 0);
+            self.sections[..level]
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join(".")
+        });
+        let id = self.unique_id(id.unwrap_or_else(|| slug(text)));
+        let reference = match &number {
+            Some(number) => format!("Section {}", number),
+            None => text.trim().to_string(),
+        };
+        self.references.insert(id.clone(), reference);
+        Heading {
+            level,
+            id,
+            number,
+            text: text.trim().to_string(),
+        }
+    }
+
+    fn unique_id(&mut self, id: String) -> String {
+        let id = if id.is_empty() {
+            "section".to_string()
+        } else {
+            id
+        };
+        let mut unique = id.clone();
+        let mut counter = 1;
+        while !self.ids.insert(unique.clone()) {
+            unique = format!("{}-{}", id, counter);
+            counter += 1;
+        }
+        unique
+    }
+
+    /// Number the next `kind` (`fig`, `tbl` or `eq`), labelled `label` if it has one. Returns
+    /// the number and the text referring to it, e.g. `(2)` and `Equation (2)`.
+    pub(crate) fn next(&mut self, kind: &'static str, label: Option<&str>) -> (String, String) {
+        let counter = self.counters.entry(kind).or_insert(0);
+        *counter += 1;
+        let (supplement, pattern) = match kind {
+            "tbl" => ("Table", &self.prefs.table_numbering),
+            "eq" => ("Equation", &self.prefs.equation_numbering),
+            _ => ("Figure", &self.prefs.figure_numbering),
+        };
+        let number = format_number(pattern, *counter);
+        let reference = format!("{} {}", supplement, number);
+        if let Some(label) = label {
+            self.references.insert(label.to_string(), reference.clone());
+        }
+        (number, reference)
+    }
+}
+
+/// Render preprocessed `markdown` to HTML. `resolve_src` maps every image source.
+pub(crate) fn render_body(
+    markdown: &str,
+    prefs: &Preferences,
+    resolve_src: &mut dyn FnMut(&str) -> String,
+) -> HtmlBody {
+    let (markdown, formulas) = extract_math(markdown);
+    let markdown = mark_references(&markdown);
+
+    let mut rewriter = Rewriter {
+        formulas: &formulas,
+        resolve_src,
+        numbering: Numbering::new(prefs),
+        headings: Vec::new(),
+        caption: None,
+        depth: 0,
+        figure_depth: 0,
+        aside_depth: 0,
+    };
+    let mut events = Vec::new();
+    let mut heading: Option<PendingHeading> = None;
+    let mut skipped_paragraphs = Vec::new();
+    let mut parser = Parser::new_ext(&markdown, markdown_options()).peekable();
+    while let Some(event) = parser.next() {
+        let event = match event {
+            Event::Start(Tag::Heading(level, id, _)) => {
+                heading = Some(PendingHeading {
+                    level: level as usize,
+                    id: id.map(str::to_string),
+                    text: String::new(),
+                    events: Vec::new(),
+                });
+                continue;
+            }
+            Event::End(Tag::Heading(..)) => {
+                if let Some(mut pending) = heading.take() {
+                    if let Some(label) = parser.peek().and_then(crossref::heading_label) {
+                        pending.id = Some(label);
+                        parser.next();
+                    }
+                    let (open, close) = rewriter.heading(pending.level, pending.id, &pending.text);
+                    events.push(Event::Html(open.into()));
+                    events.extend(pending.events);
+                    events.push(Event::Html(close.into()));
+                }
+                continue;
+            }
+            // Figures hold their image and caption directly, not in a paragraph
+            Event::Start(Tag::Paragraph) => {
+                let skip = rewriter.figure_depth > 0;
+                skipped_paragraphs.push(skip);
+                if skip {
+                    continue;
+                }
+                Event::Start(Tag::Paragraph)
+            }
+            Event::End(Tag::Paragraph) => {
+                if skipped_paragraphs.pop().unwrap_or(false) {
+                    continue;
+                }
+                Event::End(Tag::Paragraph)
+            }
+            Event::Start(Tag::Image(kind, url, title)) => {
+                let url = (rewriter.resolve_src)(&url);
+                Event::Start(Tag::Image(kind, url.into(), title))
+            }
+            Event::Html(html) => Event::Html(rewriter.html(&html).into()),
+            event => event,
+        };
+        match &event {
+            Event::Start(_) => rewriter.depth += 1,
+            Event::End(_) => rewriter.depth -= 1,
+            _ => {}
+        }
+        match heading.as_mut() {
+            Some(pending) => {
+                if let Event::Text(text) | Event::Code(text) = &event {
+                    pending.text.push_str(text);
+                }
+                pending.events.push(event);
+            }
+            None => events.push(event),
+        }
+    }
+
+    let mut html = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut html, events.into_iter());
+    let html = REFERENCE
+        .replace_all(&html, |caps: &Captures| {
+            let label = &caps["label"];
+            let text = rewriter
+                .numbering
+                .references
+                .get(label)
+                .cloned()
+                .unwrap_or_else(|| label.to_string());
+            format!(
+                "<a class=\"xref\" href=\"#{}\">{}</a>",
+                escape_xml(label),
+                escape_xml(&text)
+            )
+        })
+        .into_owned();
+    HtmlBody {
+        sections: html.split(SECTION_BREAK).map(str::to_string).collect(),
+        headings: rewriter.headings,
+    }
+}
+
+struct PendingHeading<'a> {
+    level: usize,
+    id: Option<String>,
+    text: String,
+    events: Vec<Event<'a>>,
+}
+
+/// State of the pass that turns the preprocessor's markup into HTML.
+struct Rewriter<'a> {
+    formulas: &'a [Formula],
+    resolve_src: &'a mut dyn FnMut(&str) -> String,
+    numbering: Numbering<'a>,
+    headings: Vec<Heading>,
+    /// Label of the figure whose caption comes next, e.g. `Table 1`.
+    caption: Option<String>,
+    /// Nesting of markdown elements, figures and admonitions at the current event.
+    depth: usize,
+    figure_depth: usize,
+    aside_depth: usize,
+}
+
+impl Rewriter<'_> {
+    /// Register a heading; returns its opening and closing tags.
+    fn heading(&mut self, level: usize, id: Option<String>, text: &str) -> (String, String) {
+        let heading = self.numbering.heading(level, id, text);
+        let level = heading.level;
+        let top_level = self.depth == 0 && self.figure_depth == 0 && self.aside_depth == 0;
+        let mut open = if level <= 2 && top_level {
+            SECTION_BREAK.to_string()
+        } else {
+            String::new()
+        };
+        open.push_str(&format!("<h{} id=\"{}\">", level, escape_xml(&heading.id)));
+        if let Some(number) = &heading.number {
+            open.push_str(&format!(
+                "<span class=\"section-number\">{}</span> ",
+                number
+            ));
+        }
+        self.headings.push(heading);
+        (open, format!("</h{}>", level))
+    }
+
+    /// Rewrite the preprocessor's markup in a piece of raw HTML.
+    fn html(&mut self, html: &str) -> String {
+        MARKUP
+            .replace_all(html, |caps: &Captures| self.markup(caps))
+            .into_owned()
+    }
+
+    fn markup(&mut self, caps: &Captures) -> String {
+        if let Some(typst) = caps.name("typst") {
+            return self.raw_typst(typst.as_str().trim());
+        }
+        if let Some(level) = caps.name("level") {
+            let level = level.as_str().parse().unwrap_or(1);
+            let text = strip_tags(&caps["text"]);
+            let (open, close) = self.heading(level, attribute(&caps["heading_attrs"], "id"), &text);
+            return format!("{}{}{}", open, escape_xml(&text), close);
+        }
+        if let Some(src) = caps.name("src") {
+            let resolved = (self.resolve_src)(src.as_str());
+            return caps[0].replacen(src.as_str(), &resolved, 1);
+        }
+
+        let attrs = caps.name("attrs").map_or("", |attrs| attrs.as_str());
+        match &caps["tag"] {
+            "<figure" => {
+                self.figure_depth += 1;
+                let label = attribute(attrs, "id").unwrap_or_default();
+                let kind = if label.starts_with("tbl:") {
+                    "tbl"
+                } else {
+                    "fig"
+                };
+                let (_, caption) = self.numbering.next(kind, Some(&label));
+                self.caption = Some(caption);
+                format!("<figure id=\"{}\">", escape_xml(&label))
+            }
+            "</figure" => {
+                self.figure_depth = self.figure_depth.saturating_sub(1);
+                "</figure>".to_string()
+            }
+            "<figcaption" | "<tf-caption" => match self.caption.take() {
+                Some(caption) => format!(
+                    "<figcaption><span class=\"caption-label\">{}:</span> ",
+                    caption
+                ),
+                None => "<figcaption>".to_string(),
+            },
+            "</figcaption" | "</tf-caption" => "</figcaption>".to_string(),
+            "<tf-math" => {
+                let formula = attribute(attrs, "i")
+                    .and_then(|i| i.parse::<usize>().ok())
+                    .and_then(|i| self.formulas.get(i));
+                let Some(formula) = formula else {
+                    return String::new();
+                };
+                let mathml = math::to_mathml(&formula.latex, formula.display);
+                match &formula.label {
+                    Some(label) => {
+                        let (number, _) = self.numbering.next("eq", Some(label));
+                        format!(
+                            "<span class=\"equation\" id=\"{}\">{}<span class=\"equation-number\">{}</span></span>",
+                            escape_xml(label),
+                            mathml,
+                            number
+                        )
+                    }
+                    None => mathml,
+                }
+            }
+            "</tf-math" => String::new(),
+            "<tf-cite" => format!(
+                "<cite class=\"citation\">{}",
+                escape_xml(&citation_text(attrs))
+            ),
+            "</tf-cite" => "</cite>".to_string(),
+            "<tf-admonition" => {
+                let kind = attribute(attrs, "kind").unwrap_or_else(|| "note".to_string());
+                let title = attribute(attrs, "title").unwrap_or_else(|| capitalize(&kind));
+                self.aside_depth += 1;
+                format!(
+                    "<aside class=\"admonition admonition-{}\"><p class=\"admonition-title\">{}</p>",
+                    escape_xml(&kind),
+                    escape_xml(&title)
+                )
+            }
+            "</tf-admonition" => {
+                self.aside_depth = self.aside_depth.saturating_sub(1);
+                "</aside>".to_string()
+            }
+            _ => caps[0].to_string(),
+        }
+    }
+
+    /// Replace a `<!--raw-typst ... -->` comment: diagrams become figures, page breaks
+    /// survive for print, and scroll-sync anchors disappear.
+    fn raw_typst(&mut self, typst: &str) -> String {
+        if let Some(args) = typst.strip_prefix("#diagram_render(") {
+            let args = diagram_args(args.trim_end_matches(')'));
+            let Some(asset) = args.get("asset") else {
+                return String::new();
+            };
+            let src = (self.resolve_src)(asset);
+            let mut style = String::new();
+            if let Some(width) = args.get("width") {
+                style.push_str(&format!(" style=\"width: {}\"", escape_xml(width)));
+            }
+            let align = args.get("align").map_or("center", String::as_str);
+            let mut figure = format!(
+                "<figure class=\"diagram\" style=\"text-align: {}\"><img src=\"{}\" alt=\"\"{} />",
+                escape_xml(align),
+                escape_xml(&src),
+                style
+            );
+            if let Some(caption) = args.get("caption") {
+                let (_, label) = self.numbering.next("fig", None);
+                figure.push_str(&format!(
+                    "<figcaption><span class=\"caption-label\">{}:</span> {}</figcaption>",
+                    label,
+                    escape_xml(caption)
+                ));
+            }
+            figure.push_str("</figure>");
+            figure
+        } else if typst.starts_with("#pagebreak") {
+            "<div class=\"page-break\"></div>".to_string()
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// Text shown for a `<tf-cite>` element with `attrs`: the key, with its supplement.
+pub(crate) fn citation_text(attrs: &str) -> String {
+    let key = attribute(attrs, "key").unwrap_or_default();
+    match attribute(attrs, "form").as_deref() {
+        Some("prose") | Some("year") => key,
+        _ => match attribute(attrs, "supplement") {
+            Some(supplement) => format!("[{}, {}]", key, supplement),
+            None => format!("[{}]", key),
+        },
+    }
+}
+
+/// Take the math out of `markdown`, replacing each formula with a `<tf-math>` element that
+/// pulldown-cmark passes through untouched: labelled equations from the cross-reference pass,
+/// then `$$...$$` and `$...$` outside code.
+pub(crate) fn extract_math(markdown: &str) -> (String, Vec<Formula>) {
+    let mut formulas = Vec::new();
+    let placeholder = |index: usize| format!("<tf-math i=\"{}\"></tf-math>", index);
+
+    let markdown = EQUATION
+        .replace_all(markdown, |caps: &Captures| {
+            formulas.push(Formula {
+                latex: unescape_typst_string(&caps["math"]),
+                display: true,
+                label: Some(caps["label"].to_string()),
+            });
+            placeholder(formulas.len() - 1)
+        })
+        .into_owned();
+
+    let protected = crossref::protected_ranges(&markdown);
+    let mut output = String::with_capacity(markdown.len());
+    let mut last = 0;
+    for (range, display) in math_spans(&markdown, &protected) {
+        let delimiter = if display { 2 } else { 1 };
+        formulas.push(Formula {
+            latex: markdown[range.start + delimiter..range.end - delimiter]
+                .trim()
+                .to_string(),
+            display,
+            label: None,
+        });
+        output.push_str(&markdown[last..range.start]);
+        output.push_str(&placeholder(formulas.len() - 1));
+        last = range.end;
+    }
+    output.push_str(&markdown[last..]);
+    (output, formulas)
+}
+
+/// `$$...$$` (display) and `$...$` (inline) spans outside `protected`, with Pandoc's rules for
+/// inline math: no space after the opening or before the closing `$`, and no digit after it.
+fn math_spans(markdown: &str, protected: &[Range<usize>]) -> Vec<(Range<usize>, bool)> {
+    let bytes = markdown.as_bytes();
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if let Some(range) = protected.iter().find(|r| r.start <= i && i < r.end) {
+            i = range.end;
+            continue;
+        }
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'$' if bytes.get(i + 1) == Some(&b'$') => match markdown[i + 2..].find("$$") {
+                Some(len) => {
+                    spans.push((i..i + len + 4, true));
+                    i += len + 4;
+                }
+                None => i += 2,
+            },
+            b'$' => match inline_math_end(markdown, i) {
+                Some(end) => {
+                    spans.push((i..end, false));
+                    i = end;
+                }
+                None => i += 1,
+            },
+            _ => i += 1,
+        }
+    }
+    spans
+}
+
+/// End (after the closing `$`) of inline math opening at `start`, if it is math.
+fn inline_math_end(markdown: &str, start: usize) -> Option<usize> {
+    let bytes = markdown.as_bytes();
+    let first = bytes.get(start + 1)?;
+    if first.is_ascii_whitespace() {
+        return None;
+    }
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            // Math does not span paragraphs
+            b'\n'
+                if markdown[i + 1..]
+                    .trim_start_matches([' ', '\t'])
+                    .starts_with('\n') =>
+            {
+                return None
+            }
+            b'$' => {
+                let closes = !bytes[i - 1].is_ascii_whitespace()
+                    && !bytes.get(i + 1).is_some_and(u8::is_ascii_digit);
+                return closes.then_some(i + 1);
+            }
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Mark the `[@fig:id]` references left by the cross-reference pass, to be linked once the
+/// numbers are known.
+pub(crate) fn mark_references(markdown: &str) -> String {
+    let protected = crossref::protected_ranges(markdown);
+    MARKED_REFERENCE
+        .replace_all(markdown, |caps: &Captures| {
+            let matched = caps.get(0).expect("BUG: capture group 0 always exists");
+            if crossref::is_protected(&protected, matched.start()) {
+                matched.as_str().to_string()
+            } else {
+                format!("<tf-ref label=\"{}\"></tf-ref>", &caps["label"])
+            }
+        })
+        .into_owned()
+}
+
+/// The value of attribute `name` in `attrs`.
+pub(crate) fn attribute(attrs: &str, name: &str) -> Option<String> {
+    ATTRIBUTE
+        .captures_iter(attrs)
+        .find(|caps| &caps["name"] == name)
+        .map(|caps| unescape_xml(&caps["value"]))
+}
+
+/// Decode the character references in an attribute value, like cmarker does for the
+/// template. Unknown references are kept as they are.
+fn unescape_xml(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').and_then(|end| {
+            let character = match &rest[1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                reference => {
+                    let code = match reference
+                        .strip_prefix("#x")
+                        .or(reference.strip_prefix("#X"))
+                    {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => reference.strip_prefix('#')?.parse().ok()?,
+                    };
+                    char::from_u32(code)?
+                }
+            };
+            Some((character, end + 1))
+        });
+        match decoded {
+            Some((character, length)) => {
+                output.push(character);
+                rest = &rest[length..];
+            }
+            None => {
+                output.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Arguments of a `#diagram_render(...)` placeholder.
+pub(crate) fn diagram_args(args: &str) -> HashMap<String, String> {
+    DIAGRAM_ARGUMENT
+        .captures_iter(args)
+        .map(|caps| {
+            let value = match caps.name("string") {
+                Some(string) => unescape_typst_string(string.as_str()),
+                None => caps["value"].trim().to_string(),
+            };
+            (caps["name"].to_string(), value)
+        })
+        .collect()
+}
+
+/// Undo `preprocessor::typst_comment_string`.
+fn unescape_typst_string(input: &str) -> String {
+    let input = input.replace("\\u{3e}", ">");
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('r') => output.push('\r'),
+            Some('t') => output.push('\t'),
+            Some(other) => output.push(other),
+            None => output.push('\\'),
+        }
+    }
+    output
+}
+
+pub(crate) fn strip_tags(html: &str) -> String {
+    TAG.replace_all(html, "").into_owned()
+}
+
+/// Heading id from its text, as GitHub makes them.
+fn slug(text: &str) -> String {
+    text.trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            ' ' => Some('-'),
+            _ => None,
+        })
+        .collect()
+}
+
+pub(crate) fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+/// Format `n` with a Typst numbering pattern such as `1`, `(1)`, `a` or `I`.
+pub(crate) fn format_number(pattern: &str, n: usize) -> String {
+    let Some(index) = pattern.find(['1', 'a', 'A', 'i', 'I']) else {
+        return n.to_string();
+    };
+    let number = match &pattern[index..index + 1] {
+        "a" => alphabetic(n),
+        "A" => alphabetic(n).to_uppercase(),
+        "i" => roman(n).to_lowercase(),
+        "I" => roman(n),
+        _ => n.to_string(),
+    };
+    format!("{}{}{}", &pattern[..index], number, &pattern[index + 1..])
+}
+
+fn alphabetic(mut n: usize) -> String {
+    let mut letters = Vec::new();
+    while n > 0 {
+        n -= 1;
+        letters.push((b'a' + (n % 26) as u8) as char);
+        n /= 26;
+    }
+    letters.iter().rev().collect()
+}
+
+fn roman(mut n: usize) -> String {
+    const NUMERALS: &[(usize, &str)] = &[
+        (1000, "M"),
+        (900, "CM"),
+        (500, "D"),
+        (400, "CD"),
+        (100, "C"),
+        (90, "XC"),
+        (50, "L"),
+        (40, "XL"),
+        (10, "X"),
+        (9, "IX"),
+        (5, "V"),
+        (4, "IV"),
+        (1, "I"),
+    ];
+    let mut roman = String::new();
+    for &(value, numeral) in NUMERALS {
+        while n >= value {
+            roman.push_str(numeral);
+            n -= value;
+        }
+    }
+    roman
+}
+
+/// Title of an exported document: the cover title, else its first heading, else `fallback`.
+pub(crate) fn document_title(
+    prefs: &Preferences,
+    headings: &[Heading],
+    fallback: Option<String>,
+) -> String {
+    Some(prefs.cover_title.trim().to_string())
+        .filter(|title| !title.is_empty())
+        .or_else(|| headings.first().map(|heading| heading.text.clone()))
+        .or(fallback)
+        .unwrap_or_else(|| "Document".to_string())
+}
+
+/// The complete page: head with stylesheet, cover, table of contents and body.
+fn page(
+    prefs: &Preferences,
+    body: &HtmlBody,
+    cover_image: Option<&str>,
+    fallback_title: Option<String>,
+) -> String {
+    let title = document_title(prefs, &body.headings, fallback_title);
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n",
+        escape_xml(&title),
+        stylesheet(prefs)
+    );
+    if prefs.cover_page {
+        page.push_str("<header class=\"cover\">\n");
+        if let Some(src) = cover_image {
+            page.push_str(&format!(
+                "<img src=\"{}\" alt=\"\" style=\"width: {}\" />\n",
+                escape_xml(src),
+                escape_xml(&prefs.cover_image_width)
+            ));
+        }
+        if !prefs.cover_title.trim().is_empty() {
+            page.push_str(&format!(
+                "<h1 class=\"cover-title\">{}</h1>\n",
+                escape_xml(prefs.cover_title.trim())
+            ));
+        }
+        if !prefs.cover_writer.trim().is_empty() {
+            page.push_str(&format!(
+                "<p class=\"cover-writer\">{}</p>\n",
+                escape_xml(prefs.cover_writer.trim())
+            ));
+        }
+        page.push_str("</header>\n");
+    }
+    if prefs.toc && !body.headings.is_empty() {
+        page.push_str(&table_of_contents(prefs, &body.headings));
+    }
+    page.push_str("<main>\n");
+    page.push_str(&body.sections.concat());
+    page.push_str("</main>\n</body>\n</html>\n");
+    page
+}
+
+/// A `<nav>` linking to `headings`.
+fn table_of_contents(prefs: &Preferences, headings: &[Heading]) -> String {
+    let mut toc = format!(
+        "<nav class=\"toc\">\n<h2>{}</h2>\n<ul>\n",
+        escape_xml(toc_title(prefs))
+    );
+    for heading in headings {
+        toc.push_str(&format!(
+            "<li class=\"toc-level-{}\"><a href=\"#{}\">{}</a></li>\n",
+            heading.level,
+            escape_xml(&heading.id),
+            escape_xml(&heading.label())
+        ));
+    }
+    toc.push_str("</ul>\n</nav>\n");
+    toc
+}
+
+pub(crate) fn toc_title(prefs: &Preferences) -> &str {
+    match prefs.toc_title.trim() {
+        "" => "Contents",
+        title => title,
+    }
+}
+
+/// CSS for the exported page, from the font, size, spacing and colour preferences. The
+/// admonition palettes match the template's.
+pub(crate) fn stylesheet(prefs: &Preferences) -> String {
+    let font = |name: &str, generic: &str| {
+        if name.trim().is_empty() {
+            generic.to_string()
+        } else {
+            format!("\"{}\", {}", name.trim().replace('"', ""), generic)
+        }
+    };
+    let scale = prefs.heading_scale;
+    format!(
+        r#"body {{
+  font-family: {main};
+  font-size: {size}pt;
+  line-height: {line_height};
+  color: {color};
+  background: {background};
+  max-width: 46em;
+  margin: 0 auto;
+  padding: 2em 1.5em;
+}}
+p, ul, ol, table, pre, blockquote, figure {{ margin: 0 0 {spacing}; }}
+h1, h2, h3, h4, h5, h6 {{ color: {accent}; line-height: 1.25; margin: 1.4em 0 0.6em; }}
+h1 {{ font-size: {h1:.2}em; }}
+h2 {{ font-size: {h2:.2}em; }}
+h3 {{ font-size: {h3:.2}em; }}
+h4, h5, h6 {{ font-size: {h4:.2}em; }}
+a {{ color: {accent}; }}
+code, pre, kbd {{ font-family: {mono}; font-size: 0.9em; }}
+pre {{ background: rgba(0, 0, 0, 0.05); padding: 0.75em 1em; border-radius: 4px; overflow-x: auto; }}
+blockquote {{ border-left: 3px solid {accent}; padding-left: 1em; color: inherit; opacity: 0.85; }}
+table {{ border-collapse: collapse; }}
+th, td {{ border: 1px solid rgba(0, 0, 0, 0.2); padding: 0.3em 0.6em; }}
+img {{ max-width: 100%; height: auto; }}
+img[data-align="center"] {{ display: block; margin: 0 auto; }}
+img[data-align="right"] {{ display: block; margin-left: auto; }}
+figure {{ text-align: center; }}
+figure table {{ margin: 0 auto; }}
+figcaption {{ font-size: 0.9em; margin-top: 0.4em; }}
+.caption-label, .section-number {{ font-weight: 600; }}
+.equation {{ display: flex; align-items: center; justify-content: center; gap: 2em; margin: 0.8em 0; }}
+.equation-number {{ margin-left: auto; }}
+math[display="block"] {{ margin: 0.8em 0; }}
+.admonition {{ border: 0.5pt solid; border-radius: 8px; padding: 10px; margin: 12px 0; }}
+.admonition-title {{ font-weight: 600; margin-bottom: 4px; }}
+.admonition-note {{ background: color-mix(in srgb, {accent} 50%, white); border-color: {accent}; }}
+.admonition-info {{ background: rgb(224, 242, 254); border-color: rgb(186, 230, 253); }}
+.admonition-tip {{ background: rgb(220, 252, 231); border-color: rgb(187, 247, 208); }}
+.admonition-warning {{ background: rgb(254, 249, 195); border-color: rgb(253, 224, 71); }}
+.admonition-important {{ background: rgb(254, 243, 199); border-color: rgb(251, 191, 36); }}
+.admonition-caution {{ background: rgb(254, 226, 226); border-color: rgb(252, 165, 165); }}
+.cover {{ text-align: center; margin: 4em 0; }}
+.cover-title {{ font-size: 2.5em; }}
+.toc ul {{ list-style: none; padding-left: 0; }}
+{toc_levels}
+.page-break {{ break-after: page; }}
+"#,
+        main = font(&prefs.fonts.main, "serif"),
+        mono = font(&prefs.fonts.mono, "monospace"),
+        size = prefs.font_size,
+        line_height = prefs.line_height,
+        color = prefs.font_color,
+        background = prefs.page_bg_color,
+        spacing = prefs.paragraph_spacing,
+        accent = prefs.accent_color,
+        h1 = 2.0 * scale,
+        h2 = 1.6 * scale,
+        h3 = 1.3 * scale,
+        h4 = 1.1 * scale,
+        toc_levels = (2..=6)
+            .map(|level| format!(".toc-level-{} {{ padding-left: {}em; }}", level, level - 1))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::FsEnvironment;
+    use crate::render_scheduler::CancelToken;
+
+    /// The HTML body for `markdown`, with every image source prefixed by `resolved:`.
+    fn html(markdown: &str, prefs: &Preferences) -> String {
+        render_body(markdown, prefs, &mut |src| format!("resolved:{}", src))
+            .sections
+            .concat()
+    }
+
+    #[test]
+    fn headings_are_numbered_and_start_sections() {
+        let markdown = "<!--raw-typst #label(\"tf-doc-start\") -->\n# Intro {#sec:intro}\n\nText\n";
+        let body = render_body(markdown, &Preferences::default(), &mut |src| {
+            src.to_string()
+        });
+        assert_eq!(body.sections.len(), 2);
+        assert_eq!(
+            body.headings,
+            [Heading {
+                level: 1,
+                id: "sec:intro".to_string(),
+                number: Some("1".to_string()),
+                text: "Intro".to_string(),
+            }]
+        );
+        assert!(body.sections[1]
+            .contains("<h1 id=\"sec:intro\"><span class=\"section-number\">1</span> Intro</h1>"));
+        assert!(!body.sections.concat().contains("raw-typst"));
+    }
+
+    #[test]
+    fn references_link_to_numbered_targets() {
+        let html = html(
+            concat!(
+                "# Intro {#sec:intro}\n\n",
+                "See [@fig:plot], [@eq:energy] and [@sec:intro].\n\n",
+                "<figure id=\"fig:plot\">\n\n![Plot](/assets/plot.png)<figcaption>Plot</figcaption></figure>\n\n",
+                "<!--raw-typst #crossref_equation(\"E = mc^2\", \"eq:energy\") -->\n",
+            ),
+            &Preferences::default(),
+        );
+        assert!(html.contains("<a class=\"xref\" href=\"#fig:plot\">Figure 1</a>"));
+        assert!(html.contains("<a class=\"xref\" href=\"#eq:energy\">Equation (1)</a>"));
+        assert!(html.contains("<a class=\"xref\" href=\"#sec:intro\">Section 1</a>"));
+    }
+
+    #[test]
+    fn figures_and_diagrams_share_the_figure_counter() {
+        let html = html(
+            concat!(
+                "<figure id=\"fig:plot\">\n\n![Plot](/assets/plot.png)<figcaption>Plot</figcaption></figure>\n\n",
+                "<!--raw-typst #diagram_render(asset: \"diagrams/a.svg\", scale: auto, caption: \"Flow\") -->\n",
+            ),
+            &Preferences::default(),
+        );
+        assert!(html.contains("<img src=\"resolved:/assets/plot.png\" alt=\"Plot\" />"));
+        assert!(!html.contains("<p><img"));
+        assert!(html.contains("<span class=\"caption-label\">Figure 1:</span> Plot</figcaption>"));
+        assert!(html.contains("src=\"resolved:diagrams/a.svg\""));
+        assert!(html.contains("<span class=\"caption-label\">Figure 2:</span> Flow"));
+    }
+
+    #[test]
+    fn labelled_equations_follow_the_numbering_preference() {
+        let markdown = "<!--raw-typst #crossref_equation(\"E = mc^2\", \"eq:energy\") -->\n";
+        assert!(html(markdown, &Preferences::default())
+            .contains("<span class=\"equation-number\">(1)</span>"));
+        let prefs = Preferences {
+            equation_numbering: "(i)".to_string(),
+            ..Preferences::default()
+        };
+        assert!(html(markdown, &prefs).contains("<span class=\"equation-number\">(i)</span>"));
+    }
+
+    #[test]
+    fn dollar_signs_are_math_only_by_pandoc_rules() {
+        let (markdown, formulas) = extract_math("Costs $5 and $x^2$, `$y$`, $ z$ and $$a$$.");
+        assert_eq!(
+            markdown,
+            "Costs $5 and <tf-math i=\"0\"></tf-math>, `$y$`, $ z$ and <tf-math i=\"1\"></tf-math>."
+        );
+        let latex: Vec<_> = formulas
+            .iter()
+            .map(|formula| (formula.latex.as_str(), formula.display))
+            .collect();
+        assert_eq!(latex, [("x^2", false), ("a", true)]);
+        assert!(extract_math("$a\n\nb$").1.is_empty());
+        assert!(html("costs $5 and $x^2$", &Preferences::default()).contains("costs $5 and <math"));
+    }
+
+    #[test]
+    fn admonitions_and_citations_become_html() {
+        let html = html(
+            "<tf-admonition kind=\"tip\">\n\nUse `$x$` <tf-cite key=\"doe\" form=\"normal\" supplement=\"p. 4\"></tf-cite>.\n\n</tf-admonition>\n",
+            &Preferences::default(),
+        );
+        assert!(html.contains(
+            "<aside class=\"admonition admonition-tip\"><p class=\"admonition-title\">Tip</p>"
+        ));
+        assert!(html.contains("<code>$x$</code>"));
+        assert!(html.contains("<cite class=\"citation\">[doe, p. 4]</cite>"));
+        assert_eq!(citation_text("key=\"doe\" form=\"prose\""), "doe");
+    }
+
+    #[test]
+    fn numbering_patterns_format_like_typst() {
+        assert_eq!(format_number("1", 12), "12");
+        assert_eq!(format_number("(i)", 4), "(iv)");
+        assert_eq!(format_number("I.", 1994), "MCMXCIV.");
+        assert_eq!(format_number("a)", 28), "ab)");
+        assert_eq!(format_number("A", 26), "Z");
+    }
+
+    #[test]
+    fn page_has_cover_and_table_of_contents() {
+        let prefs = Preferences {
+            cover_page: true,
+            cover_title: "Tom & Jerry".to_string(),
+            toc: true,
+            ..Preferences::default()
+        };
+        let body = render_body("# One\n\n## Two\n", &prefs, &mut |src| src.to_string());
+        let page = page(&prefs, &body, None, None);
+        assert!(page.contains("<title>Tom &amp; Jerry</title>"));
+        assert!(page.contains("<h1 class=\"cover-title\">Tom &amp; Jerry</h1>"));
+        assert!(page.contains("<nav class=\"toc\">\n<h2>Contents</h2>"));
+        assert!(page.contains("<li class=\"toc-level-2\"><a href=\"#two\">"));
+    }
+
+    #[test]
+    fn labelled_headings_keep_their_label_as_id() {
+        let markdown = crate::crossref::apply("# The *best* case {#sec:best}\n\nSee @sec:best.\n");
+        let body = render_body(&markdown.markdown, &Preferences::default(), &mut |src| {
+            src.to_string()
+        });
+        let html = body.sections.concat();
+        assert!(html.contains("<h1 id=\"sec:best\">"));
+        assert!(html.contains("The <em>best</em> case</h1>"));
+        assert!(html.contains("<a class=\"xref\" href=\"#sec:best\">Section 1</a>"));
+        assert!(!html.contains("raw-typst"));
+    }
+
+    #[test]
+    fn admonition_titles_survive_escaping() {
+        let markdown = crate::admonitions::apply("> [!WARNING] Tom & \"Jerry\" <3\n> Body\n");
+        assert!(markdown
+            .markdown
+            .contains("title=\"Tom &amp; &quot;Jerry&quot; &lt;3\""));
+        let body = render_body(&markdown.markdown, &Preferences::default(), &mut |src| {
+            src.to_string()
+        });
+        assert!(body
+            .sections
+            .concat()
+            .contains("<p class=\"admonition-title\">Tom &amp; &quot;Jerry&quot; &lt;3</p>"));
+        assert_eq!(
+            attribute(r#"x="&#65;&#x42;&bogus; &""#, "x").unwrap(),
+            "AB&bogus; &"
+        );
+    }
+
+    #[test]
+    fn invalid_preferences_fail_the_export() {
+        let dir = std::env::temp_dir().join(format!("tf-html-{}", uuid::Uuid::new_v4()));
+        let build_dir = dir.join("build");
+        fs::create_dir_all(&build_dir).unwrap();
+        let prepare = |prefs: serde_json::Value| {
+            let env = FsEnvironment::new(&dir).with_preferences(prefs);
+            let config = RenderConfig {
+                env: &env,
+                build_dir: build_dir.clone(),
+                content_dir: dir.clone(),
+                typst_root: dir.clone(),
+                cancel: CancelToken::default(),
+            };
+            prepare_document(&config, "# Title\n", "", &dir, "html").map(|(_, prefs)| prefs)
+        };
+
+        // Missing keys fall back to their defaults.
+        let prefs = prepare(serde_json::json!({ "toc": false })).unwrap();
+        assert!(!prefs.toc);
+        assert_eq!(prefs.papersize, Preferences::default().papersize);
+
+        let error = prepare(serde_json::json!({ "toc": "yes" })).unwrap_err();
+        assert!(format!("{:#}", error).contains("invalid preferences for the html export"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
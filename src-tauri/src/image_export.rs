@@ -2,22 +2,20 @@
 ///
 /// This module provides functions to export Typst documents to image formats.
 /// Separated from the main renderer to simplify merging with Free version.
+use crate::build_workspace::{self, JobKind};
+use crate::citations;
+use crate::diagrams::{self, DiagramRegistry, DiagramSettings};
+use crate::environment::RenderEnvironment;
+use crate::front_matter;
 use crate::preprocessor::preprocess_markdown;
 use crate::render_pipeline::{self, RenderConfig};
-use crate::tikz;
+use crate::render_scheduler::{self, RenderJob};
+use crate::renderer;
 use crate::utils;
 use anyhow::{anyhow, Context, Result};
 use std::fs;
 use std::path::Path;
 use std::process::Stdio;
-use std::sync::Arc;
-use tauri::{AppHandle, Emitter};
-use tokio::sync::Mutex;
-
-// Reuse the render mutex from renderer to prevent concurrent operations
-lazy_static::lazy_static! {
-    static ref IMAGE_EXPORT_MUTEX: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
-}
 
 /// Export markdown to PNG or SVG using Typst
 ///
@@ -27,7 +25,7 @@ lazy_static::lazy_static! {
 ///
 /// Multi-page documents will create separate numbered files (e.g., doc-1.png, doc-2.png)
 pub async fn export_as_image(
-    app_handle: &AppHandle,
+    env: &dyn RenderEnvironment,
     content: &str,
     destination: &str,
     format: &str,               // "png" or "svg"
@@ -42,25 +40,23 @@ pub async fn export_as_image(
         ));
     }
 
-    // Acquire lock to prevent multiple simultaneous exports
-    let _lock = IMAGE_EXPORT_MUTEX.lock().await;
+    // Scheduled like other exports; the image workspace is separate from PDF renders
+    let document = current_file.unwrap_or("");
+    let slot = render_scheduler::acquire(RenderJob::Export(document)).await?;
 
     // Setup directories
-    let content_dir = utils::get_content_dir(app_handle)?;
-    let build_dir = content_dir.join(".build");
-    fs::create_dir_all(&build_dir)?;
+    let content_dir = env.content_dir()?;
+    let build_dir = build_workspace::prepare(env, document, JobKind::Image)?;
 
     // Setup render configuration
     let config = RenderConfig {
-        app_handle,
+        env,
         build_dir: build_dir.clone(),
         content_dir: content_dir.clone(),
         typst_root: content_dir.clone(),
+        cancel: slot.token().clone(),
     };
 
-    // Setup preferences
-    render_pipeline::setup_prefs(&config, &format!("markdown-export-{}", format))?;
-
     // Preprocess markdown content
     let base_dir = if let Some(file_path) = current_file {
         Path::new(file_path).parent().unwrap_or(Path::new("."))
@@ -68,19 +64,41 @@ pub async fn export_as_image(
         Path::new(".")
     };
 
-    let assets_root = utils::get_assets_dir(app_handle).ok();
+    let assets_root = env.assets_dir().ok();
     let assets_root_ref = assets_root.as_deref();
-    let preprocess = preprocess_markdown(content)?;
-    let md_content =
-        utils::rewrite_image_paths_in_markdown(&preprocess.markdown, base_dir, assets_root_ref);
-    fs::write(build_dir.join("content.md"), md_content)?;
-    tikz::prepare_tikz_assets(app_handle, &preprocess.tikz_blocks, &build_dir)?;
+    let diagrams = DiagramRegistry::load(env);
+    let mut preprocess =
+        preprocess_markdown(content, current_file.map(Path::new), base_dir, &diagrams)?;
+    front_matter::resolve_relative_paths(&mut preprocess.prefs_overrides, base_dir);
+    preprocess.transform(|markdown| {
+        utils::rewrite_image_paths_in_markdown(markdown, base_dir, assets_root_ref)
+    });
+
+    // Setup preferences with the document's front matter applied
+    let prefs = render_pipeline::setup_prefs(
+        &config,
+        &format!("markdown-export-{}", format),
+        &preprocess.prefs_overrides,
+    )?;
+    citations::apply(&mut preprocess, content, &prefs.bibliography);
+    renderer::emit_warnings(env, document, &preprocess.warnings);
+    fs::write(build_dir.join("content.md"), &preprocess.markdown)?;
+    let diagram_settings = DiagramSettings::resolve(env, base_dir, &prefs.prefs_json);
+    diagrams::prepare_diagram_assets(
+        env,
+        &diagrams,
+        &preprocess.diagram_blocks,
+        &build_dir,
+        &diagram_settings,
+        &config.cancel,
+    )?;
 
     // Setup template
     render_pipeline::setup_template(&config, &format!("markdown-export-{}", format))?;
 
     // Get Typst binary path
-    let typst_path = utils::get_typst_path(app_handle)
+    let typst_path = env
+        .typst_path()
         .context("Typst binary not found. Please install Typst system-wide or download and place in bin/typst/<platform>/ directory.")?;
 
     // Compile to image format
@@ -154,9 +172,7 @@ pub async fn export_as_image(
     let result_path = output_path.to_string_lossy().to_string();
 
     // Emit success event
-    app_handle
-        .emit(&format!("exported-{}", format), result_path.clone())
-        .ok();
+    env.emit(&format!("exported-{}", format), result_path.clone().into());
 
     Ok(result_path)
 }
@@ -0,0 +1,441 @@
+//! File includes for documents split into chapters.
+//!
+//! A line holding only `<!-- include: chapters/02-method.md -->` or Quarto's
+//! `{{< include chapters/02-method.md >}}` is replaced by that file. Paths are relative to the
+//! including file, included files may include others, and an include that would loop back to a
+//! file being included is skipped with a warning. Front matter of included files is ignored,
+//! and their relative image paths are rebased so they resolve against their own directory.
+//!
+//! The expanded text keeps track of where each included file landed, so anchors and warnings
+//! can point into the file they came from.
+
+use crate::edit_map::{self, EditMap};
+use crate::front_matter;
+use crate::preprocessor::offset_to_line_column;
+use lazy_static::lazy_static;
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use regex::Regex;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    static ref DIRECTIVE: Regex = Regex::new(
+        r"(?m)^[ \t]*(?:<!--\s*include:\s*(?P<comment>.+?)\s*-->|\{\{<\s*include\s+(?P<shortcode>.+?)\s*>\}\})[ \t]*\r?$",
+    )
+    .expect("BUG: Invalid regex pattern for include directives");
+    static ref IMAGE_PATH: Regex =
+        Regex::new(r#"(?P<before>!\[[^\]]*\]\(<?|<img\b[^>]*?\ssrc=["'])(?P<path>[^)\s"'>]+)"#)
+            .expect("BUG: Invalid regex pattern for image paths");
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IncludeOutput {
+    pub markdown: String,
+    /// Relates `markdown` to the input. Included text counts as inserted in place of the
+    /// directive.
+    pub edit_map: EditMap,
+    /// Files included directly by the input, with the files they include.
+    pub includes: Vec<IncludedFile>,
+    /// Include targets that could not be read; the output changes once they appear.
+    pub missing: Vec<PathBuf>,
+    pub warnings: Vec<String>,
+}
+
+/// A file whose content fills part of the expanded text.
+#[derive(Debug, Clone)]
+pub struct IncludedFile {
+    path: PathBuf,
+    /// The file as read, for line numbers.
+    source: String,
+    /// Where the file's expanded content sits in the including text.
+    range: Range<usize>,
+    /// Relates the file's expanded content to `source`.
+    edit_map: EditMap,
+    includes: Vec<IncludedFile>,
+}
+
+impl IncludedFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+/// Every file in `includes`, nested includes included.
+pub fn files(includes: &[IncludedFile]) -> Vec<PathBuf> {
+    includes
+        .iter()
+        .flat_map(|file| std::iter::once(file.path.clone()).chain(files(&file.includes)))
+        .collect()
+}
+
+/// The innermost included file holding `offset` of the expanded text, with the offset in that
+/// file. `None` when the offset belongs to the including text itself.
+pub fn locate(includes: &[IncludedFile], offset: usize) -> Option<(&IncludedFile, usize)> {
+    let file = includes
+        .iter()
+        .find(|file| file.range.start <= offset && offset < file.range.end)?;
+    let relative = offset - file.range.start;
+    Some(
+        locate(&file.includes, relative)
+            .unwrap_or_else(|| (file, file.edit_map.to_original(relative))),
+    )
+}
+
+/// Replace the include directives of `markdown`, a document in `base_dir`. `document` is the
+/// document's own file, if saved, so that including it again is caught as a cycle.
+pub fn expand(markdown: &str, document: Option<&Path>, base_dir: &Path) -> IncludeOutput {
+    let mut output = IncludeOutput::default();
+    let root_dir = base_dir
+        .canonicalize()
+        .unwrap_or_else(|_| base_dir.to_path_buf());
+    let mut stack: Vec<PathBuf> = document
+        .map(|path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf()))
+        .into_iter()
+        .collect();
+    let (markdown, edit_map, includes) = expand_text(
+        markdown,
+        base_dir,
+        None,
+        &root_dir,
+        &mut stack,
+        &mut output.missing,
+        &mut output.warnings,
+    );
+    output.markdown = markdown;
+    output.edit_map = edit_map;
+    output.includes = includes;
+    output
+}
+
+/// Expand the directives of `text` (the file `path`, or the document when `None`), whose
+/// relative includes resolve against `dir`. `stack` holds the files being included, and
+/// targets that cannot be read are added to `missing`.
+fn expand_text(
+    text: &str,
+    dir: &Path,
+    path: Option<&Path>,
+    root_dir: &Path,
+    stack: &mut Vec<PathBuf>,
+    missing: &mut Vec<PathBuf>,
+    warnings: &mut Vec<String>,
+) -> (String, EditMap, Vec<IncludedFile>) {
+    let code_blocks = code_block_ranges(text);
+    let mut output = String::with_capacity(text.len());
+    let mut splices = Vec::new();
+    let mut includes = Vec::new();
+    let mut last = 0;
+
+    for caps in DIRECTIVE.captures_iter(text) {
+        let matched = caps.get(0).expect("BUG: capture group 0 always exists");
+        if code_blocks
+            .iter()
+            .any(|range| range.start <= matched.start() && matched.start() < range.end)
+        {
+            continue;
+        }
+        let target = caps
+            .name("comment")
+            .or_else(|| caps.name("shortcode"))
+            .map(|target| target.as_str().trim_matches(|c| c == '"' || c == '\''))
+            .unwrap_or_default();
+        output.push_str(&text[last..matched.start()]);
+        last = matched.end();
+
+        let (line, _) = offset_to_line_column(text, matched.start());
+        let location = match path {
+            Some(path) => format!(
+                "line {} of `{}`",
+                line + 1,
+                path.strip_prefix(root_dir).unwrap_or(path).display()
+            ),
+            None => format!("line {}", line + 1),
+        };
+        let start = output.len();
+        if let Some((content, mut file)) = include_file(
+            &dir.join(target),
+            target,
+            &location,
+            root_dir,
+            stack,
+            missing,
+            warnings,
+        ) {
+            output.push_str(&content);
+            file.range = start..output.len();
+            includes.push(file);
+        }
+        splices.push((matched.range(), output.len() - start));
+    }
+    output.push_str(&text[last..]);
+    (output, EditMap::from_splices(splices), includes)
+}
+
+/// Read and expand the file at `path`. Returns its expanded content and its record, whose
+/// range the caller fills in.
+fn include_file(
+    path: &Path,
+    target: &str,
+    location: &str,
+    root_dir: &Path,
+    stack: &mut Vec<PathBuf>,
+    missing: &mut Vec<PathBuf>,
+    warnings: &mut Vec<String>,
+) -> Option<(String, IncludedFile)> {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if stack.contains(&path) {
+        warnings.push(format!(
+            "Include of `{}` on {} would include it again; skipping it",
+            target, location
+        ));
+        return None;
+    }
+    let source = match fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(e) => {
+            warnings.push(format!(
+                "Cannot include `{}` on {}: {}",
+                target, location, e
+            ));
+            missing.push(path);
+            return None;
+        }
+    };
+
+    // Front matter is blanked in place, so `body` keeps the offsets of `source`.
+    let (_, body) = front_matter::extract(&source);
+    let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    let (body, image_map) = rebase_image_paths(&body, &dir, root_dir);
+
+    stack.push(path.clone());
+    let (expanded, include_map, includes) =
+        expand_text(&body, &dir, Some(&path), root_dir, stack, missing, warnings);
+    stack.pop();
+
+    let expanded = expanded.trim_end_matches('\n').to_string();
+    Some((
+        expanded,
+        IncludedFile {
+            path,
+            source,
+            range: 0..0,
+            edit_map: image_map.then(&include_map),
+            includes,
+        },
+    ))
+}
+
+/// Make the relative image paths of a file in `dir` relative to the document's directory, which
+/// the renderer resolves them against. `assets/` paths already resolve from the content root.
+fn rebase_image_paths(markdown: &str, dir: &Path, root_dir: &Path) -> (String, EditMap) {
+    let prefix = relative_dir(root_dir, dir);
+    if prefix.is_empty() {
+        return (markdown.to_string(), EditMap::identity());
+    }
+    edit_map::replace_all(&IMAGE_PATH, markdown, |caps| {
+        let path = &caps["path"];
+        // Root-relative, asset, URL, data: and drive-letter paths stay as they are
+        if path.starts_with('/') || path.starts_with("assets/") || path.contains(':') {
+            caps[0].to_string()
+        } else {
+            format!("{}{}{}", &caps["before"], prefix, path)
+        }
+    })
+}
+
+/// `to` relative to `from`, with forward slashes and a trailing one (empty when they are the
+/// same directory). Absolute when they share no prefix, e.g. on different drives.
+fn relative_dir(from: &Path, to: &Path) -> String {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts = Vec::new();
+    if common == 0 {
+        parts.push(
+            to.iter()
+                .collect::<PathBuf>()
+                .to_string_lossy()
+                .replace('\\', "/"),
+        );
+    } else {
+        parts.extend((common..from.len()).map(|_| "..".to_string()));
+        parts.extend(
+            to[common..]
+                .iter()
+                .map(|part| part.as_os_str().to_string_lossy().to_string()),
+        );
+    }
+    parts
+        .iter()
+        .map(|part| format!("{}/", part.trim_end_matches('/')))
+        .collect()
+}
+
+fn code_block_ranges(markdown: &str) -> Vec<Range<usize>> {
+    Parser::new_ext(markdown, Options::all())
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Start(Tag::CodeBlock(_)) => Some(range),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A temporary directory holding `files`, by relative path.
+    fn project(files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tideflow-includes-{}", uuid::Uuid::new_v4()));
+        for (path, text) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn includes_expand_recursively_without_front_matter() {
+        let dir = project(&[
+            (
+                "chapters/one.md",
+                "---\ntitle: One\n---\n# One\n\n{{< include two.md >}}\n",
+            ),
+            ("chapters/two.md", "# Two\n"),
+        ]);
+        let output = expand(
+            "Intro\n\n<!-- include: chapters/one.md -->\n\nOutro\n",
+            None,
+            &dir,
+        );
+        // Front matter is blanked rather than removed, keeping offsets into the file
+        assert!(!output.markdown.contains("title"));
+        assert!(output.markdown.ends_with("\n# One\n\n# Two\n\nOutro\n"));
+        assert!(output.warnings.is_empty(), "{:?}", output.warnings);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn image_paths_are_relative_to_the_document() {
+        let dir = project(&[(
+            "chapters/one.md",
+            "![fig](fig.png) ![logo](assets/logo.png)\n",
+        )]);
+        let output = expand("<!-- include: chapters/one.md -->\n", None, &dir);
+        assert_eq!(
+            output.markdown,
+            "![fig](chapters/fig.png) ![logo](assets/logo.png)\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn directives_in_code_blocks_are_left_alone() {
+        let dir = project(&[]);
+        let root = "```\n<!-- include: x.md -->\n```\n";
+        let output = expand(root, None, &dir);
+        assert_eq!(output.markdown, root);
+        assert!(output.warnings.is_empty(), "{:?}", output.warnings);
+    }
+
+    #[test]
+    fn cycles_and_missing_files_are_reported_where_they_are_included() {
+        let dir = project(&[
+            ("chapters/one.md", "# One\n\n<!-- include: two.md -->\n"),
+            (
+                "chapters/two.md",
+                "# Two\n\n<!-- include: ../chapters/one.md -->\n",
+            ),
+        ]);
+        let output = expand(
+            "<!-- include: chapters/one.md -->\n\n<!-- include: gone.md -->\n",
+            None,
+            &dir,
+        );
+        assert!(output.markdown.contains("# One") && output.markdown.contains("# Two"));
+        assert_eq!(output.warnings.len(), 2, "{:?}", output.warnings);
+        assert_eq!(
+            output.warnings[0],
+            "Include of `../chapters/one.md` on line 3 of `chapters/two.md` would include it again; skipping it"
+        );
+        assert!(output.warnings[1].starts_with("Cannot include `gone.md` on line 3: "));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn directives_on_crlf_lines_are_expanded() {
+        let dir = project(&[("chapters/one.md", "# One\r\n")]);
+        let output = expand(
+            "Intro\r\n\r\n<!-- include: chapters/one.md -->\r\n{{< include chapters/one.md >}}\r\n",
+            None,
+            &dir,
+        );
+        assert_eq!(output.markdown, "Intro\r\n\r\n# One\r\n# One\r\n");
+        assert!(output.warnings.is_empty(), "{:?}", output.warnings);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_document_cannot_include_itself() {
+        let root = "# Main\n\n<!-- include: main.md -->\n";
+        let dir = project(&[("main.md", root)]);
+        let output = expand(root, Some(&dir.join("main.md")), &dir);
+        assert_eq!(output.markdown, "# Main\n\n\n");
+        assert_eq!(
+            output.warnings,
+            ["Include of `main.md` on line 3 would include it again; skipping it"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chapters_cannot_include_the_document() {
+        let root = "# Main\n\n<!-- include: one.md -->\n";
+        let dir = project(&[
+            ("main.md", root),
+            ("one.md", "# One\n\n{{< include main.md >}}\n"),
+        ]);
+        let output = expand(root, Some(&dir.join("main.md")), &dir);
+        assert_eq!(output.markdown, "# Main\n\n# One\n");
+        assert_eq!(
+            output.warnings,
+            ["Include of `main.md` on line 3 of `one.md` would include it again; skipping it"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn offsets_locate_their_included_file() {
+        let dir = project(&[
+            (
+                "chapters/one.md",
+                "---\ntitle: One\n---\n# One\n\n<!-- include: two.md -->\n",
+            ),
+            ("chapters/two.md", "# Two\n"),
+        ]);
+        let output = expand("Intro\n\n<!-- include: chapters/one.md -->\n", None, &dir);
+        let chapters = dir.join("chapters").canonicalize().unwrap();
+
+        let (file, offset) =
+            locate(&output.includes, output.markdown.find("# Two").unwrap()).unwrap();
+        assert_eq!(file.path(), chapters.join("two.md"));
+        assert_eq!(offset, 0);
+        let (file, offset) =
+            locate(&output.includes, output.markdown.find("# One").unwrap()).unwrap();
+        assert_eq!(file.path(), chapters.join("one.md"));
+        assert_eq!(&file.source()[offset..offset + 5], "# One");
+        assert!(locate(&output.includes, 0).is_none());
+        assert_eq!(output.edit_map.to_original(0), 0);
+        assert_eq!(
+            files(&output.includes),
+            [chapters.join("one.md"), chapters.join("two.md")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
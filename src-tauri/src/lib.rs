@@ -1,14 +1,48 @@
+mod admonitions;
+mod build_workspace;
+mod citations;
+mod cli;
 mod commands;
+mod crossref;
+mod diagnostics;
+mod diagrams;
+mod docx_export;
+mod docx_import;
+mod edit_map;
+mod environment;
+mod epub_export;
 mod error;
+mod front_matter;
+mod html_export;
 mod image_export;
+mod includes;
+mod math;
 mod preferences;
 mod preprocessor;
+mod preview_session;
+mod project;
+mod render_cache;
 mod render_pipeline;
+mod render_scheduler;
 mod renderer;
 mod tex;
-mod tikz;
+#[cfg(feature = "embedded-typst")]
+mod typst_world;
 mod utils;
 
+// Library API: render Markdown without the Tauri shell by supplying a `RenderEnvironment`
+// (e.g. `FsEnvironment`) instead of an `AppHandle`.
+pub use environment::{FsEnvironment, RenderEnvironment};
+pub use preprocessor::{AnchorEntry, EditorPosition, PdfPosition, SourceMapPayload};
+pub use preview_session::RenderTimings;
+pub use render_scheduler::RenderCancelled;
+pub use renderer::{export_markdown, export_markdown_to, render_markdown, RenderedDocument};
+
+/// Entry point for the headless `tideflow-cli` binary.
+pub fn run_cli() -> std::process::ExitCode {
+    cli::run()
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
@@ -32,13 +66,20 @@ pub fn run() {
             commands::rename_file,
             commands::import_image,
             commands::import_image_from_path,
+            commands::import_docx,
             commands::render_markdown,
             commands::export_markdown,
+            commands::render_project,
+            commands::export_project,
             commands::save_pdf_as,
             commands::render_typst,
             commands::render_latex,
+            commands::close_preview_session,
             commands::export_as_png,
             commands::export_as_svg,
+            commands::export_as_html,
+            commands::export_as_epub,
+            commands::export_as_docx,
             commands::typst_diagnostics,
             commands::get_cache_stats,
             commands::clear_render_cache,
@@ -0,0 +1,1237 @@
+//! LaTeX math for the exports that do not go through Typst.
+//!
+//! Covers the LaTeX people write between `$` signs in Markdown: scripts and primes, fractions,
+//! binomials and roots, Greek letters and the common symbols, function names, `\text` and the
+//! `\mathbf` family, accents, `\left...\right` and `\big` delimiters, and the matrix, cases
+//! and aligned environments. A formula is parsed into a [`Node`] tree, which the exports write
+//! as MathML, or as OMML for Word. Commands outside that set are kept as their source text.
+
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Ident(String),
+    Number(String),
+    Operator(String),
+    /// A delimiter that grows with its content.
+    Delimiter(String),
+    Text(String),
+    /// Horizontal space, as a CSS length.
+    Space(&'static str),
+    Row(Vec<Node>),
+    /// `line` is false for binomials.
+    Fraction {
+        numerator: Box<Node>,
+        denominator: Box<Node>,
+        line: bool,
+    },
+    Root {
+        index: Option<Box<Node>>,
+        body: Box<Node>,
+    },
+    /// Sub- and superscripts; `limits` puts them under and over, as for `\sum`.
+    Scripts {
+        base: Box<Node>,
+        sub: Option<Box<Node>>,
+        sup: Option<Box<Node>>,
+        limits: bool,
+    },
+    /// `\left( ... \right)`; an empty delimiter stands for `.`.
+    Fenced {
+        open: String,
+        close: String,
+        body: Box<Node>,
+    },
+    /// Rows of cells. `aligned` tables alternate right- and left-aligned columns.
+    Table {
+        rows: Vec<Vec<Node>>,
+        aligned: bool,
+    },
+    /// `\mathbf{...}` and friends, by MathML `mathvariant`.
+    Styled {
+        variant: &'static str,
+        body: Box<Node>,
+    },
+    /// `\hat{x}`, `\overline{x}`, `\underline{x}`...
+    Accent {
+        body: Box<Node>,
+        accent: String,
+        under: bool,
+    },
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Node::Row(vec![])
+    }
+}
+
+impl Node {
+    fn row(mut nodes: Vec<Node>) -> Node {
+        if nodes.len() == 1 {
+            nodes.pop().expect("BUG: one node")
+        } else {
+            Node::Row(nodes)
+        }
+    }
+}
+
+/// Parse the LaTeX formula `latex`.
+pub fn parse(latex: &str) -> Node {
+    let mut parser = Parser {
+        source: latex,
+        chars: latex.char_indices().peekable(),
+    };
+    parser.table_or_row(Stop::End, false)
+}
+
+/// MathML for `latex`, as a `<math>` element in display or inline style.
+pub fn to_mathml(latex: &str, display: bool) -> String {
+    let mut out = String::from("<math xmlns=\"http://www.w3.org/1998/Math/MathML\"");
+    if display {
+        out.push_str(" display=\"block\"");
+    }
+    out.push('>');
+    write_mathml(&parse(latex), None, &mut out);
+    out.push_str("</math>");
+    out
+}
+
+/// What ends a row being parsed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    End,
+    Brace,
+    Bracket,
+    Right,
+    Environment,
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, c)| c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.chars.next();
+        }
+    }
+
+    fn at_command(&mut self, name: &str) -> bool {
+        let Some(&(i, '\\')) = self.chars.peek() else {
+            return false;
+        };
+        let rest = &self.source[i + 1..];
+        // A command named by letters must not run on into more letters
+        rest.starts_with(name)
+            && !(name.starts_with(|c: char| c.is_ascii_alphabetic())
+                && rest[name.len()..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_alphabetic()))
+    }
+
+    /// Rows separated by `\\` with cells separated by `&`, or a single row when there are none.
+    fn table_or_row(&mut self, stop: Stop, aligned: bool) -> Node {
+        let mut rows = vec![vec![]];
+        loop {
+            let cell = self.row(stop);
+            rows.last_mut()
+                .expect("BUG: rows is never empty")
+                .push(cell);
+            match self.peek() {
+                Some('&') => {
+                    self.chars.next();
+                }
+                Some('\\') if self.at_command("\\") => {
+                    self.chars.next();
+                    self.chars.next();
+                    rows.push(vec![]);
+                }
+                _ => break,
+            }
+        }
+        if rows.len() == 1 && rows[0].len() == 1 && !aligned {
+            return rows.pop().and_then(|mut row| row.pop()).unwrap_or_default();
+        }
+        // A trailing `\\` leaves an empty last row
+        if rows.len() > 1
+            && rows
+                .last()
+                .is_some_and(|row| row.len() == 1 && row[0] == Node::Row(vec![]))
+        {
+            rows.pop();
+        }
+        Node::Table { rows, aligned }
+    }
+
+    /// Atoms up to `stop`, a cell separator or a row separator, which are not consumed.
+    fn row(&mut self, stop: Stop) -> Node {
+        let mut nodes = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None => break,
+                Some('}') if stop == Stop::Brace => break,
+                Some(']') if stop == Stop::Bracket => break,
+                Some('}') => {
+                    // Unbalanced; drop it
+                    self.chars.next();
+                    continue;
+                }
+                Some('&') => break,
+                Some('\\')
+                    if self.at_command("\\")
+                        || (stop == Stop::Right && self.at_command("right"))
+                        || (stop == Stop::Environment && self.at_command("end")) =>
+                {
+                    break
+                }
+                _ => {}
+            }
+            let atom = self.atom(false);
+            nodes.push(self.scripts(atom));
+        }
+        Node::row(nodes)
+    }
+
+    /// Attach the `^`, `_` and `'` following `base`.
+    fn scripts(&mut self, base: Node) -> Node {
+        let (mut sub, mut sup) = (None, None);
+        let mut primes = String::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some('^') => {
+                    self.chars.next();
+                    sup = Some(self.argument());
+                }
+                Some('_') => {
+                    self.chars.next();
+                    sub = Some(self.argument());
+                }
+                Some('\'') => {
+                    self.chars.next();
+                    primes.push('′');
+                }
+                _ => break,
+            }
+        }
+        if !primes.is_empty() {
+            let prime = Node::Operator(primes);
+            sup = Some(match sup {
+                Some(sup) => Node::Row(vec![prime, sup]),
+                None => prime,
+            });
+        }
+        if sub.is_none() && sup.is_none() {
+            return base;
+        }
+        let limits = matches!(&base, Node::Operator(op) if LIMIT_OPERATORS.contains(&op.as_str()))
+            || matches!(&base, Node::Ident(name) if LIMIT_FUNCTIONS.contains(&name.as_str()));
+        Node::Scripts {
+            base: Box::new(base),
+            sub: sub.map(Box::new),
+            sup: sup.map(Box::new),
+            limits,
+        }
+    }
+
+    /// A command or script argument: a group or a single token.
+    fn argument(&mut self) -> Node {
+        self.skip_whitespace();
+        self.atom(true)
+    }
+
+    /// The text of a `{...}` group, unparsed.
+    fn raw_group(&mut self) -> String {
+        self.skip_whitespace();
+        if self.peek() != Some('{') {
+            return self
+                .chars
+                .next()
+                .map(|(_, c)| c.to_string())
+                .unwrap_or_default();
+        }
+        self.chars.next();
+        let mut depth = 0;
+        let mut text = String::new();
+        for (_, c) in self.chars.by_ref() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 0 => break,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            text.push(c);
+        }
+        text
+    }
+
+    /// One atom. A `single` atom is one digit rather than a whole number, as in `x^12`.
+    fn atom(&mut self, single: bool) -> Node {
+        let Some((_, c)) = self.chars.next() else {
+            return Node::Row(vec![]);
+        };
+        match c {
+            '{' => {
+                let group = self.table_or_row(Stop::Brace, false);
+                self.chars.next();
+                group
+            }
+            '\\' => self.command(),
+            '0'..='9' => {
+                let mut number = c.to_string();
+                while let Some(next) = self.peek().filter(|_| !single) {
+                    match next {
+                        d if d.is_ascii_digit() => number.push(d),
+                        '.' => {
+                            let mut ahead = self.chars.clone();
+                            ahead.next();
+                            if !ahead.peek().is_some_and(|&(_, d)| d.is_ascii_digit()) {
+                                break;
+                            }
+                            number.push('.');
+                        }
+                        _ => break,
+                    }
+                    self.chars.next();
+                }
+                Node::Number(number)
+            }
+            c if c.is_alphabetic() => Node::Ident(c.to_string()),
+            '~' => Node::Space("0.25em"),
+            '-' => Node::Operator("−".to_string()),
+            '*' => Node::Operator("∗".to_string()),
+            '^' | '_' => self.scripts(Node::Row(vec![])),
+            c => Node::Operator(c.to_string()),
+        }
+    }
+
+    fn command(&mut self) -> Node {
+        let mut name = String::new();
+        while let Some(c) = self.peek().filter(char::is_ascii_alphabetic) {
+            name.push(c);
+            self.chars.next();
+        }
+        if name.is_empty() {
+            let Some((_, c)) = self.chars.next() else {
+                return Node::Operator("\\".to_string());
+            };
+            return match c {
+                ',' => Node::Space("0.1667em"),
+                ':' | '>' => Node::Space("0.2222em"),
+                ';' => Node::Space("0.2778em"),
+                '!' => Node::Space("-0.1667em"),
+                ' ' => Node::Space("0.25em"),
+                '|' => Node::Operator("‖".to_string()),
+                c => Node::Operator(c.to_string()),
+            };
+        }
+
+        if let Some(symbol) = lookup(GREEK, &name) {
+            return Node::Ident(symbol.to_string());
+        }
+        if let Some(symbol) = lookup(SYMBOLS, &name) {
+            return Node::Operator(symbol.to_string());
+        }
+        if FUNCTIONS.contains(&name.as_str()) {
+            return Node::Ident(name);
+        }
+        if let Some(variant) = lookup(VARIANTS, &name) {
+            let body = self.argument();
+            return Node::Styled {
+                variant,
+                body: Box::new(body),
+            };
+        }
+        if let Some(accent) = lookup(ACCENTS, &name) {
+            let body = self.argument();
+            return Node::Accent {
+                body: Box::new(body),
+                accent: accent.to_string(),
+                under: matches!(name.as_str(), "underline" | "underbrace"),
+            };
+        }
+
+        match name.as_str() {
+            "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                let numerator = self.argument();
+                let denominator = self.argument();
+                Node::Fraction {
+                    numerator: Box::new(numerator),
+                    denominator: Box::new(denominator),
+                    line: true,
+                }
+            }
+            "binom" | "dbinom" | "tbinom" => {
+                let numerator = self.argument();
+                let denominator = self.argument();
+                Node::Fenced {
+                    open: "(".to_string(),
+                    close: ")".to_string(),
+                    body: Box::new(Node::Fraction {
+                        numerator: Box::new(numerator),
+                        denominator: Box::new(denominator),
+                        line: false,
+                    }),
+                }
+            }
+            "sqrt" => {
+                self.skip_whitespace();
+                let index = if self.peek() == Some('[') {
+                    self.chars.next();
+                    let index = self.row(Stop::Bracket);
+                    self.chars.next();
+                    Some(Box::new(index))
+                } else {
+                    None
+                };
+                Node::Root {
+                    index,
+                    body: Box::new(self.argument()),
+                }
+            }
+            "text" | "textrm" | "textnormal" | "mbox" | "textit" | "textbf" => {
+                Node::Text(self.raw_group())
+            }
+            "operatorname" => Node::Ident(self.raw_group()),
+            "left" => {
+                let open = self.delimiter();
+                let body = self.row(Stop::Right);
+                let close = if self.at_command("right") {
+                    self.chars.nth(5);
+                    self.delimiter()
+                } else {
+                    String::new()
+                };
+                Node::Fenced {
+                    open,
+                    close,
+                    body: Box::new(body),
+                }
+            }
+            "big" | "Big" | "bigg" | "Bigg" | "bigl" | "bigr" | "Bigl" | "Bigr" | "biggl"
+            | "biggr" | "Biggl" | "Biggr" | "middle" => Node::Delimiter(self.delimiter()),
+            "begin" => self.environment(),
+            "quad" => Node::Space("1em"),
+            "qquad" => Node::Space("2em"),
+            "displaystyle" | "textstyle" | "limits" | "nolimits" | "nonumber" | "notag" => {
+                Node::Row(vec![])
+            }
+            _ => Node::Text(format!("\\{}", name)),
+        }
+    }
+
+    /// The delimiter after `\left`, `\right` or `\big`; empty for `.`.
+    fn delimiter(&mut self) -> String {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some((_, '.')) | None => String::new(),
+            Some((_, '\\')) => match self.command() {
+                Node::Operator(symbol) => symbol,
+                _ => String::new(),
+            },
+            Some((_, c)) => c.to_string(),
+        }
+    }
+
+    /// `\begin{name} ... \end{name}`, after `\begin`.
+    fn environment(&mut self) -> Node {
+        let name = self.raw_group();
+        let name = name.trim_end_matches('*');
+        if name == "array" {
+            // Column specification
+            self.raw_group();
+        }
+        let aligned = matches!(
+            name,
+            "aligned" | "align" | "alignat" | "split" | "eqnarray" | "flalign"
+        );
+        let table = self.table_or_row(Stop::Environment, true);
+        if self.at_command("end") {
+            self.chars.nth(3);
+            self.raw_group();
+        }
+        let table = match table {
+            Node::Table { rows, .. } => Node::Table { rows, aligned },
+            other => other,
+        };
+        let (open, close) = match name {
+            "pmatrix" => ("(", ")"),
+            "bmatrix" => ("[", "]"),
+            "Bmatrix" => ("{", "}"),
+            "vmatrix" => ("|", "|"),
+            "Vmatrix" => ("‖", "‖"),
+            "cases" => ("{", ""),
+            _ => return table,
+        };
+        Node::Fenced {
+            open: open.to_string(),
+            close: close.to_string(),
+            body: Box::new(table),
+        }
+    }
+}
+
+fn lookup<T: Copy>(table: &[(&str, T)], name: &str) -> Option<T> {
+    table
+        .iter()
+        .find(|(key, _)| *key == name)
+        .map(|&(_, value)| value)
+}
+
+/// Write `node` as MathML; `variant` is the `mathvariant` of an enclosing `\mathbf`-style command.
+fn write_mathml(node: &Node, variant: Option<&str>, out: &mut String) {
+    let leaf = |out: &mut String, tag: &str, text: &str, attrs: &str| {
+        out.push('<');
+        out.push_str(tag);
+        if let Some(variant) = variant {
+            out.push_str(&format!(" mathvariant=\"{}\"", variant));
+        }
+        out.push_str(attrs);
+        out.push('>');
+        out.push_str(&escape_xml(text));
+        out.push_str("</");
+        out.push_str(tag);
+        out.push('>');
+    };
+    let wrap = |out: &mut String, tag: &str, attrs: &str, children: &[&Node]| {
+        out.push_str(&format!("<{}{}>", tag, attrs));
+        for child in children {
+            write_mathml(child, variant, out);
+        }
+        out.push_str(&format!("</{}>", tag));
+    };
+    match node {
+        Node::Ident(name) => leaf(out, "mi", name, ""),
+        Node::Number(number) => leaf(out, "mn", number, ""),
+        Node::Operator(op) => leaf(out, "mo", op, ""),
+        Node::Delimiter(op) => leaf(out, "mo", op, " stretchy=\"true\" symmetric=\"true\""),
+        Node::Text(text) => leaf(out, "mtext", text, ""),
+        Node::Space(width) => out.push_str(&format!("<mspace width=\"{}\"/>", width)),
+        Node::Row(nodes) => {
+            out.push_str("<mrow>");
+            for node in nodes {
+                write_mathml(node, variant, out);
+            }
+            out.push_str("</mrow>");
+        }
+        Node::Fraction {
+            numerator,
+            denominator,
+            line,
+        } => wrap(
+            out,
+            "mfrac",
+            if *line { "" } else { " linethickness=\"0\"" },
+            &[numerator, denominator],
+        ),
+        Node::Root { index: None, body } => wrap(out, "msqrt", "", &[body]),
+        Node::Root {
+            index: Some(index),
+            body,
+        } => wrap(out, "mroot", "", &[body, index]),
+        Node::Scripts {
+            base,
+            sub,
+            sup,
+            limits,
+        } => {
+            let (under, over, both) = if *limits {
+                ("munder", "mover", "munderover")
+            } else {
+                ("msub", "msup", "msubsup")
+            };
+            match (sub, sup) {
+                (Some(sub), Some(sup)) => wrap(out, both, "", &[base, sub, sup]),
+                (Some(sub), None) => wrap(out, under, "", &[base, sub]),
+                (None, Some(sup)) => wrap(out, over, "", &[base, sup]),
+                (None, None) => write_mathml(base, variant, out),
+            }
+        }
+        Node::Fenced { open, close, body } => {
+            let fence = |out: &mut String, delimiter: &str| {
+                if !delimiter.is_empty() {
+                    out.push_str(&format!(
+                        "<mo fence=\"true\" stretchy=\"true\">{}</mo>",
+                        escape_xml(delimiter)
+                    ));
+                }
+            };
+            out.push_str("<mrow>");
+            fence(out, open);
+            write_mathml(body, variant, out);
+            fence(out, close);
+            out.push_str("</mrow>");
+        }
+        Node::Table { rows, aligned } => {
+            out.push_str(if *aligned {
+                "<mtable displaystyle=\"true\" columnalign=\"right left\">"
+            } else {
+                "<mtable>"
+            });
+            for row in rows {
+                out.push_str("<mtr>");
+                for cell in row {
+                    out.push_str("<mtd>");
+                    write_mathml(cell, variant, out);
+                    out.push_str("</mtd>");
+                }
+                out.push_str("</mtr>");
+            }
+            out.push_str("</mtable>");
+        }
+        Node::Styled { variant, body } => write_mathml(body, Some(variant), out),
+        Node::Accent {
+            body,
+            accent,
+            under,
+        } => {
+            let tag = if *under { "munder" } else { "mover" };
+            let attr = if *under { "accentunder" } else { "accent" };
+            out.push_str(&format!("<{} {}=\"true\">", tag, attr));
+            write_mathml(body, variant, out);
+            out.push_str(&format!(
+                "<mo stretchy=\"true\">{}</mo></{}>",
+                escape_xml(accent),
+                tag
+            ));
+        }
+    }
+}
+
+/// Office Math (OMML) for `latex`, as an `<m:oMath>` element.
+pub fn to_omml(latex: &str) -> String {
+    let mut out = String::from("<m:oMath>");
+    write_omml(&parse(latex), None, &mut out);
+    out.push_str("</m:oMath>");
+    out
+}
+
+/// Write `node` as OMML; `variant` is the `mathvariant` of an enclosing `\mathbf`-style command.
+fn write_omml(node: &Node, variant: Option<&str>, out: &mut String) {
+    let run = |out: &mut String, text: &str, upright: bool| {
+        let style = match variant {
+            Some("bold") => "<m:sty m:val=\"b\"/>",
+            Some("bold-italic") => "<m:sty m:val=\"bi\"/>",
+            Some("italic") => "<m:sty m:val=\"i\"/>",
+            Some("normal") => "<m:sty m:val=\"p\"/>",
+            Some("double-struck") => "<m:scr m:val=\"double-struck\"/>",
+            Some("script") => "<m:scr m:val=\"script\"/>",
+            Some("fraktur") => "<m:scr m:val=\"fraktur\"/>",
+            Some("sans-serif") => "<m:scr m:val=\"sans-serif\"/>",
+            Some("monospace") => "<m:scr m:val=\"monospace\"/>",
+            _ if upright => "<m:sty m:val=\"p\"/>",
+            _ => "",
+        };
+        out.push_str("<m:r>");
+        if !style.is_empty() {
+            out.push_str(&format!("<m:rPr>{}</m:rPr>", style));
+        }
+        out.push_str(&format!(
+            "<m:t xml:space=\"preserve\">{}</m:t></m:r>",
+            escape_xml(text)
+        ));
+    };
+    let element = |out: &mut String, tag: &str, node: &Node| {
+        out.push_str(&format!("<m:{}>", tag));
+        write_omml(node, variant, out);
+        out.push_str(&format!("</m:{}>", tag));
+    };
+    match node {
+        // Function names are upright, like MathML's multi-letter identifiers
+        Node::Ident(name) => run(out, name, name.chars().count() > 1),
+        Node::Number(text) | Node::Operator(text) | Node::Delimiter(text) => run(out, text, false),
+        Node::Text(text) => {
+            out.push_str(&format!(
+                "<m:r><m:rPr><m:nor/></m:rPr><m:t xml:space=\"preserve\">{}</m:t></m:r>",
+                escape_xml(text)
+            ));
+        }
+        Node::Space(width) => {
+            let em: f32 = width.trim_end_matches("em").parse().unwrap_or(0.0);
+            let space = if em <= 0.0 {
+                String::new()
+            } else if em < 0.25 {
+                "\u{2009}".to_string()
+            } else if em < 1.0 {
+                "\u{2004}".to_string()
+            } else {
+                "\u{2003}".repeat(em.round() as usize)
+            };
+            if !space.is_empty() {
+                run(out, &space, false);
+            }
+        }
+        Node::Row(nodes) => {
+            for node in nodes {
+                write_omml(node, variant, out);
+            }
+        }
+        Node::Fraction {
+            numerator,
+            denominator,
+            line,
+        } => {
+            out.push_str("<m:f>");
+            if !line {
+                out.push_str("<m:fPr><m:type m:val=\"noBar\"/></m:fPr>");
+            }
+            element(out, "num", numerator);
+            element(out, "den", denominator);
+            out.push_str("</m:f>");
+        }
+        Node::Root { index, body } => {
+            out.push_str("<m:rad>");
+            match index {
+                Some(index) => element(out, "deg", index),
+                None => out.push_str("<m:radPr><m:degHide m:val=\"1\"/></m:radPr><m:deg/>"),
+            }
+            element(out, "e", body);
+            out.push_str("</m:rad>");
+        }
+        Node::Scripts {
+            base,
+            sub,
+            sup,
+            limits: true,
+        } => match base.as_ref() {
+            Node::Operator(op) if LIMIT_OPERATORS.contains(&op.as_str()) => {
+                out.push_str(&format!(
+                    "<m:nary><m:naryPr><m:chr m:val=\"{}\"/><m:limLoc m:val=\"undOvr\"/>",
+                    escape_xml(op)
+                ));
+                if sub.is_none() {
+                    out.push_str("<m:subHide m:val=\"1\"/>");
+                }
+                if sup.is_none() {
+                    out.push_str("<m:supHide m:val=\"1\"/>");
+                }
+                out.push_str("</m:naryPr>");
+                element(out, "sub", sub.as_deref().unwrap_or(&Node::default()));
+                element(out, "sup", sup.as_deref().unwrap_or(&Node::default()));
+                out.push_str("<m:e/></m:nary>");
+            }
+            _ => {
+                if sup.is_some() {
+                    out.push_str("<m:limUpp><m:e>");
+                }
+                match sub {
+                    Some(sub) => {
+                        out.push_str("<m:limLow>");
+                        element(out, "e", base);
+                        element(out, "lim", sub);
+                        out.push_str("</m:limLow>");
+                    }
+                    None => write_omml(base, variant, out),
+                }
+                if let Some(sup) = sup {
+                    out.push_str("</m:e>");
+                    element(out, "lim", sup);
+                    out.push_str("</m:limUpp>");
+                }
+            }
+        },
+        Node::Scripts { base, sub, sup, .. } => {
+            let tag = match (sub, sup) {
+                (Some(_), Some(_)) => "sSubSup",
+                (Some(_), None) => "sSub",
+                (None, Some(_)) => "sSup",
+                (None, None) => return write_omml(base, variant, out),
+            };
+            out.push_str(&format!("<m:{}>", tag));
+            element(out, "e", base);
+            if let Some(sub) = sub {
+                element(out, "sub", sub);
+            }
+            if let Some(sup) = sup {
+                element(out, "sup", sup);
+            }
+            out.push_str(&format!("</m:{}>", tag));
+        }
+        Node::Fenced { open, close, body } => {
+            out.push_str(&format!(
+                "<m:d><m:dPr><m:begChr m:val=\"{}\"/><m:endChr m:val=\"{}\"/></m:dPr>",
+                escape_xml(open),
+                escape_xml(close)
+            ));
+            element(out, "e", body);
+            out.push_str("</m:d>");
+        }
+        Node::Table {
+            rows,
+            aligned: true,
+        } => {
+            // Word aligns the rows of an equation array at their first `&`
+            out.push_str("<m:eqArr>");
+            for row in rows {
+                out.push_str("<m:e>");
+                for (index, cell) in row.iter().enumerate() {
+                    if index > 0 {
+                        out.push_str("<m:r><m:t>&amp;</m:t></m:r>");
+                    }
+                    write_omml(cell, variant, out);
+                }
+                out.push_str("</m:e>");
+            }
+            out.push_str("</m:eqArr>");
+        }
+        Node::Table { rows, .. } => {
+            out.push_str("<m:m>");
+            for row in rows {
+                out.push_str("<m:mr>");
+                for cell in row {
+                    element(out, "e", cell);
+                }
+                out.push_str("</m:mr>");
+            }
+            out.push_str("</m:m>");
+        }
+        Node::Styled { variant, body } => write_omml(body, Some(variant), out),
+        Node::Accent {
+            body,
+            accent,
+            under,
+        } => {
+            let (tag, properties) = match (accent.as_str(), under) {
+                ("¯", false) => (
+                    "bar",
+                    "<m:barPr><m:pos m:val=\"top\"/></m:barPr>".to_string(),
+                ),
+                ("_", true) => (
+                    "bar",
+                    "<m:barPr><m:pos m:val=\"bot\"/></m:barPr>".to_string(),
+                ),
+                (_, false) => (
+                    "acc",
+                    format!(
+                        "<m:accPr><m:chr m:val=\"{}\"/></m:accPr>",
+                        escape_xml(accent)
+                    ),
+                ),
+                (_, true) => (
+                    "groupChr",
+                    format!(
+                        "<m:groupChrPr><m:chr m:val=\"{}\"/><m:pos m:val=\"bot\"/></m:groupChrPr>",
+                        escape_xml(accent)
+                    ),
+                ),
+            };
+            out.push_str(&format!("<m:{}>{}", tag, properties));
+            element(out, "e", body);
+            out.push_str(&format!("</m:{}>", tag));
+        }
+    }
+}
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// LaTeX command (without the backslash) for a Greek letter or symbol, for converting math
+/// back from Unicode. ASCII characters have none.
+pub(crate) fn command_for(symbol: &str) -> Option<&'static str> {
+    if symbol.is_ascii() {
+        return None;
+    }
+    GREEK
+        .iter()
+        .chain(SYMBOLS)
+        .find(|(_, value)| *value == symbol)
+        .map(|(name, _)| *name)
+}
+
+/// LaTeX accent command for an accent character, combining or not.
+pub(crate) fn accent_for(accent: char) -> Option<&'static str> {
+    let accent = match accent {
+        '\u{302}' => '^',
+        '\u{303}' => '~',
+        '\u{304}' | '\u{305}' => '¯',
+        '\u{307}' => '˙',
+        '\u{308}' => '¨',
+        '\u{301}' => '´',
+        '\u{300}' => '`',
+        '\u{306}' => '˘',
+        '\u{30C}' => 'ˇ',
+        '\u{20D7}' | '\u{20D1}' => '→',
+        other => other,
+    };
+    let mut buffer = [0; 4];
+    let accent = &*accent.encode_utf8(&mut buffer);
+    ACCENTS
+        .iter()
+        .find(|(_, value)| *value == accent)
+        .map(|(name, _)| *name)
+}
+
+/// Whether `name` is a function LaTeX sets upright, like `sin`.
+pub(crate) fn is_function(name: &str) -> bool {
+    FUNCTIONS.contains(&name)
+}
+
+/// Big operators whose scripts go under and over.
+const LIMIT_OPERATORS: &[&str] = &["∑", "∏", "∐", "⋃", "⋂", "⨁", "⨂", "⋁", "⋀"];
+
+/// Functions whose scripts go under and over.
+const LIMIT_FUNCTIONS: &[&str] = &[
+    "lim", "liminf", "limsup", "max", "min", "sup", "inf", "det", "gcd", "Pr", "argmax", "argmin",
+];
+
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "sinh", "cosh", "tanh", "coth", "arcsin", "arccos",
+    "arctan", "log", "ln", "lg", "exp", "lim", "liminf", "limsup", "max", "min", "sup", "inf",
+    "det", "dim", "ker", "deg", "gcd", "hom", "arg", "Pr", "mod", "argmax", "argmin",
+];
+
+const GREEK: &[(&str, &str)] = &[
+    ("alpha", "α"),
+    ("beta", "β"),
+    ("gamma", "γ"),
+    ("delta", "δ"),
+    ("epsilon", "ϵ"),
+    ("varepsilon", "ε"),
+    ("zeta", "ζ"),
+    ("eta", "η"),
+    ("theta", "θ"),
+    ("vartheta", "ϑ"),
+    ("iota", "ι"),
+    ("kappa", "κ"),
+    ("lambda", "λ"),
+    ("mu", "μ"),
+    ("nu", "ν"),
+    ("xi", "ξ"),
+    ("pi", "π"),
+    ("varpi", "ϖ"),
+    ("rho", "ρ"),
+    ("varrho", "ϱ"),
+    ("sigma", "σ"),
+    ("varsigma", "ς"),
+    ("tau", "τ"),
+    ("upsilon", "υ"),
+    ("phi", "ϕ"),
+    ("varphi", "φ"),
+    ("chi", "χ"),
+    ("psi", "ψ"),
+    ("omega", "ω"),
+    ("Gamma", "Γ"),
+    ("Delta", "Δ"),
+    ("Theta", "Θ"),
+    ("Lambda", "Λ"),
+    ("Xi", "Ξ"),
+    ("Pi", "Π"),
+    ("Sigma", "Σ"),
+    ("Upsilon", "Υ"),
+    ("Phi", "Φ"),
+    ("Psi", "Ψ"),
+    ("Omega", "Ω"),
+    ("ell", "ℓ"),
+    ("hbar", "ℏ"),
+    ("imath", "ı"),
+    ("jmath", "ȷ"),
+    ("aleph", "ℵ"),
+    ("Re", "ℜ"),
+    ("Im", "ℑ"),
+    ("wp", "℘"),
+];
+
+const SYMBOLS: &[(&str, &str)] = &[
+    ("sum", "∑"),
+    ("prod", "∏"),
+    ("coprod", "∐"),
+    ("int", "∫"),
+    ("iint", "∬"),
+    ("iiint", "∭"),
+    ("oint", "∮"),
+    ("bigcup", "⋃"),
+    ("bigcap", "⋂"),
+    ("bigoplus", "⨁"),
+    ("bigotimes", "⨂"),
+    ("bigvee", "⋁"),
+    ("bigwedge", "⋀"),
+    ("times", "×"),
+    ("div", "÷"),
+    ("cdot", "⋅"),
+    ("ast", "∗"),
+    ("star", "⋆"),
+    ("circ", "∘"),
+    ("bullet", "∙"),
+    ("pm", "±"),
+    ("mp", "∓"),
+    ("oplus", "⊕"),
+    ("otimes", "⊗"),
+    ("cup", "∪"),
+    ("cap", "∩"),
+    ("setminus", "∖"),
+    ("wedge", "∧"),
+    ("land", "∧"),
+    ("vee", "∨"),
+    ("lor", "∨"),
+    ("neg", "¬"),
+    ("lnot", "¬"),
+    ("leq", "≤"),
+    ("le", "≤"),
+    ("geq", "≥"),
+    ("ge", "≥"),
+    ("neq", "≠"),
+    ("ne", "≠"),
+    ("ll", "≪"),
+    ("gg", "≫"),
+    ("approx", "≈"),
+    ("sim", "∼"),
+    ("simeq", "≃"),
+    ("cong", "≅"),
+    ("equiv", "≡"),
+    ("propto", "∝"),
+    ("prec", "≺"),
+    ("succ", "≻"),
+    ("in", "∈"),
+    ("notin", "∉"),
+    ("ni", "∋"),
+    ("subset", "⊂"),
+    ("supset", "⊃"),
+    ("subseteq", "⊆"),
+    ("supseteq", "⊇"),
+    ("emptyset", "∅"),
+    ("varnothing", "∅"),
+    ("forall", "∀"),
+    ("exists", "∃"),
+    ("nexists", "∄"),
+    ("partial", "∂"),
+    ("nabla", "∇"),
+    ("infty", "∞"),
+    ("angle", "∠"),
+    ("perp", "⊥"),
+    ("parallel", "∥"),
+    ("mid", "∣"),
+    ("to", "→"),
+    ("rightarrow", "→"),
+    ("leftarrow", "←"),
+    ("gets", "←"),
+    ("leftrightarrow", "↔"),
+    ("Rightarrow", "⇒"),
+    ("Leftarrow", "⇐"),
+    ("Leftrightarrow", "⇔"),
+    ("implies", "⟹"),
+    ("iff", "⟺"),
+    ("mapsto", "↦"),
+    ("uparrow", "↑"),
+    ("downarrow", "↓"),
+    ("longrightarrow", "⟶"),
+    ("longleftarrow", "⟵"),
+    ("ldots", "…"),
+    ("dots", "…"),
+    ("cdots", "⋯"),
+    ("vdots", "⋮"),
+    ("ddots", "⋱"),
+    ("prime", "′"),
+    ("degree", "°"),
+    ("langle", "⟨"),
+    ("rangle", "⟩"),
+    ("lfloor", "⌊"),
+    ("rfloor", "⌋"),
+    ("lceil", "⌈"),
+    ("rceil", "⌉"),
+    ("lbrace", "{"),
+    ("rbrace", "}"),
+    ("vert", "|"),
+    ("lvert", "|"),
+    ("rvert", "|"),
+    ("Vert", "‖"),
+    ("lVert", "‖"),
+    ("rVert", "‖"),
+    ("backslash", "∖"),
+    ("therefore", "∴"),
+    ("because", "∵"),
+];
+
+const VARIANTS: &[(&str, &str)] = &[
+    ("mathrm", "normal"),
+    ("mathup", "normal"),
+    ("mathbf", "bold"),
+    ("boldsymbol", "bold-italic"),
+    ("bm", "bold-italic"),
+    ("mathit", "italic"),
+    ("mathbb", "double-struck"),
+    ("mathcal", "script"),
+    ("mathscr", "script"),
+    ("mathfrak", "fraktur"),
+    ("mathsf", "sans-serif"),
+    ("mathtt", "monospace"),
+];
+
+const ACCENTS: &[(&str, &str)] = &[
+    ("hat", "^"),
+    ("widehat", "^"),
+    ("check", "ˇ"),
+    ("tilde", "~"),
+    ("widetilde", "~"),
+    ("bar", "¯"),
+    ("overline", "¯"),
+    ("underline", "_"),
+    ("vec", "→"),
+    ("overrightarrow", "→"),
+    ("dot", "˙"),
+    ("ddot", "¨"),
+    ("acute", "´"),
+    ("grave", "`"),
+    ("breve", "˘"),
+    ("overbrace", "⏞"),
+    ("underbrace", "⏟"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The MathML for inline `latex`, without the `<math>` element around it.
+    fn mathml(latex: &str) -> String {
+        let math = to_mathml(latex, false);
+        math.strip_prefix("<math xmlns=\"http://www.w3.org/1998/Math/MathML\">")
+            .and_then(|math| math.strip_suffix("</math>"))
+            .expect("BUG: to_mathml writes a <math> element")
+            .to_string()
+    }
+
+    #[test]
+    fn fractions_roots_and_binomials() {
+        assert_eq!(
+            to_mathml(r"x^2 + \frac{1}{\sqrt[3]{y}}", false),
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\"><mrow><msup><mi>x</mi><mn>2</mn>\
+             </msup><mo>+</mo><mfrac><mn>1</mn><mroot><mi>y</mi><mn>3</mn></mroot></mfrac></mrow>\
+             </math>"
+        );
+        assert_eq!(
+            mathml(r"\binom{n}{k}"),
+            "<mrow><mo fence=\"true\" stretchy=\"true\">(</mo><mfrac linethickness=\"0\"><mi>n</mi>\
+             <mi>k</mi></mfrac><mo fence=\"true\" stretchy=\"true\">)</mo></mrow>"
+        );
+    }
+
+    #[test]
+    fn scripts_and_primes_attach_to_their_base() {
+        assert_eq!(
+            mathml("x_i^2"),
+            "<msubsup><mi>x</mi><mi>i</mi><mn>2</mn></msubsup>"
+        );
+        assert_eq!(
+            mathml("f''(x)"),
+            "<mrow><msup><mi>f</mi><mo>′′</mo></msup><mo>(</mo><mi>x</mi><mo>)</mo></mrow>"
+        );
+        // Without braces a script is a single digit, as in LaTeX
+        assert_eq!(
+            mathml("x^12"),
+            "<mrow><msup><mi>x</mi><mn>1</mn></msup><mn>2</mn></mrow>"
+        );
+        assert_eq!(
+            parse(r"\sum_{i=1}^n \mathbf{v}_i"),
+            Node::Row(vec![
+                Node::Scripts {
+                    base: Box::new(Node::Operator("∑".into())),
+                    sub: Some(Box::new(Node::Row(vec![
+                        Node::Ident("i".into()),
+                        Node::Operator("=".into()),
+                        Node::Number("1".into()),
+                    ]))),
+                    sup: Some(Box::new(Node::Ident("n".into()))),
+                    limits: true,
+                },
+                Node::Scripts {
+                    base: Box::new(Node::Styled {
+                        variant: "bold",
+                        body: Box::new(Node::Ident("v".into())),
+                    }),
+                    sub: Some(Box::new(Node::Ident("i".into()))),
+                    sup: None,
+                    limits: false,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn matrices_become_fenced_tables() {
+        assert_eq!(
+            mathml(r"\begin{pmatrix} a & b \\ c & d \end{pmatrix}"),
+            "<mrow><mo fence=\"true\" stretchy=\"true\">(</mo><mtable><mtr><mtd><mi>a</mi></mtd>\
+             <mtd><mi>b</mi></mtd></mtr><mtr><mtd><mi>c</mi></mtd><mtd><mi>d</mi></mtd></mtr>\
+             </mtable><mo fence=\"true\" stretchy=\"true\">)</mo></mrow>"
+        );
+        let cases = to_mathml(
+            r"f(x) = \begin{cases} 1 & x > 0 \\ \text{else} & 0 \end{cases}",
+            true,
+        );
+        assert!(cases.contains("display=\"block\""));
+        assert!(cases.contains("<mo fence=\"true\" stretchy=\"true\">{</mo><mtable>"));
+        assert!(cases.contains("<mtext>else</mtext>"));
+    }
+
+    #[test]
+    fn aligned_environments_alternate_column_alignment() {
+        assert_eq!(
+            mathml(r"\begin{aligned} x &= 1 \\ y &= 2 \\ \end{aligned}"),
+            "<mtable displaystyle=\"true\" columnalign=\"right left\"><mtr><mtd><mi>x</mi></mtd>\
+             <mtd><mrow><mo>=</mo><mn>1</mn></mrow></mtd></mtr><mtr><mtd><mi>y</mi></mtd><mtd>\
+             <mrow><mo>=</mo><mn>2</mn></mrow></mtd></mtr></mtable>"
+        );
+        assert!(to_omml(r"\begin{aligned} x &= 1 \end{aligned}").starts_with("<m:oMath><m:eqArr>"));
+    }
+
+    #[test]
+    fn text_is_kept_verbatim() {
+        assert_eq!(mathml(r"\text{a + b}"), "<mtext>a + b</mtext>");
+        assert_eq!(
+            to_omml(r"\text{a + b}"),
+            "<m:oMath><m:r><m:rPr><m:nor/></m:rPr><m:t xml:space=\"preserve\">a + b</m:t></m:r>\
+             </m:oMath>"
+        );
+    }
+
+    #[test]
+    fn unknown_commands_are_kept_as_source_text() {
+        assert_eq!(mathml(r"\unknown"), "<mtext>\\unknown</mtext>");
+        assert_eq!(
+            mathml(r"\foo{x}"),
+            "<mrow><mtext>\\foo</mtext><mi>x</mi></mrow>"
+        );
+    }
+
+    #[test]
+    fn markup_characters_are_escaped() {
+        assert_eq!(
+            mathml("a < b"),
+            "<mrow><mi>a</mi><mo>&lt;</mo><mi>b</mi></mrow>"
+        );
+        assert_eq!(mathml(r"\text{R&D}"), "<mtext>R&amp;D</mtext>");
+        assert_eq!(
+            mathml(r"\left< x \right>"),
+            "<mrow><mo fence=\"true\" stretchy=\"true\">&lt;</mo><mi>x</mi>\
+             <mo fence=\"true\" stretchy=\"true\">&gt;</mo></mrow>"
+        );
+        assert_eq!(
+            to_omml(r"\left< x \right>"),
+            "<m:oMath><m:d><m:dPr><m:begChr m:val=\"&lt;\"/><m:endChr m:val=\"&gt;\"/></m:dPr>\
+             <m:e><m:r><m:t xml:space=\"preserve\">x</m:t></m:r></m:e></m:d></m:oMath>"
+        );
+    }
+
+    #[test]
+    fn latex_becomes_omml() {
+        assert_eq!(
+            to_omml(r"\frac{a}{b_1}"),
+            "<m:oMath><m:f><m:num><m:r><m:t xml:space=\"preserve\">a</m:t></m:r></m:num><m:den>\
+             <m:sSub><m:e><m:r><m:t xml:space=\"preserve\">b</m:t></m:r></m:e><m:sub><m:r>\
+             <m:t xml:space=\"preserve\">1</m:t></m:r></m:sub></m:sSub></m:den></m:f></m:oMath>"
+        );
+    }
+}
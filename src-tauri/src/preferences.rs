@@ -70,6 +70,26 @@ pub struct Preferences {
     #[serde(rename = "numberSections")]
     pub number_sections: bool,
 
+    /// Typst numbering pattern for referenceable figures (e.g. "1", "I", "1.a")
+    #[serde(default = "default_figure_numbering")]
+    pub figure_numbering: String,
+
+    /// Typst numbering pattern for referenceable tables
+    #[serde(default = "default_table_numbering")]
+    pub table_numbering: String,
+
+    /// Typst numbering pattern for labelled equations (e.g. "(1)")
+    #[serde(default = "default_equation_numbering")]
+    pub equation_numbering: String,
+
+    /// BibTeX (.bib) or Hayagriva (.yml) files, relative to the content directory
+    #[serde(default)]
+    pub bibliography: Vec<String>,
+
+    /// Citation style: a Typst built-in style name (e.g. "apa") or the path of a .csl file
+    #[serde(default = "default_csl")]
+    pub csl: String,
+
     /// Default width for inserted images (e.g., "80%", "320px")
     pub default_image_width: String,
 
@@ -135,6 +155,24 @@ pub struct Preferences {
     /// Optional explicit path to Typst binary (used as a final fallback)
     #[serde(default)]
     pub typst_path: Option<String>,
+
+    /// Typst compiler: "embedded" (in-process, kept warm between renders) or "external"
+    /// (spawn the `typst` binary). Builds without the embedded compiler always use the binary.
+    #[serde(default = "default_typst_backend")]
+    pub typst_backend: String,
+
+    /// Size limit of the render cache in megabytes; least recently used PDFs are evicted
+    /// beyond it. 0 disables the cache.
+    #[serde(default = "default_render_cache_max_mb")]
+    pub render_cache_max_mb: u32,
+
+    /// LaTeX prepended to every TikZ diagram, before the project's `tikz-preamble.tex`.
+    #[serde(default)]
+    pub tikz_preamble: String,
+
+    /// Diagrams rendered at once; 0 uses one job per CPU core.
+    #[serde(default)]
+    pub diagram_parallel_jobs: u32,
 }
 
 /// Page margin configuration
@@ -183,6 +221,22 @@ fn default_paragraph_spacing() -> String {
     "0.65em".to_string()
 }
 
+fn default_figure_numbering() -> String {
+    "1".to_string()
+}
+
+fn default_table_numbering() -> String {
+    "1".to_string()
+}
+
+fn default_equation_numbering() -> String {
+    "(1)".to_string()
+}
+
+fn default_csl() -> String {
+    "ieee".to_string()
+}
+
 fn default_cover_image_width() -> String {
     "60%".to_string()
 }
@@ -211,6 +265,11 @@ impl Default for Preferences {
             cover_image: String::new(),
             cover_image_width: "60%".to_string(),
             number_sections: true,
+            figure_numbering: default_figure_numbering(),
+            table_numbering: default_table_numbering(),
+            equation_numbering: default_equation_numbering(),
+            bibliography: Vec::new(),
+            csl: default_csl(),
             default_image_width: "80%".to_string(),
             default_image_alignment: "center".to_string(),
             fonts: Fonts {
@@ -233,10 +292,41 @@ impl Default for Preferences {
             preserve_scroll_position: true,
             confirm_exit_on_unsaved: true,
             typst_path: None,
+            typst_backend: default_typst_backend(),
+            render_cache_max_mb: default_render_cache_max_mb(),
+            tikz_preamble: String::new(),
+            diagram_parallel_jobs: 0,
+        }
+    }
+}
+
+/// Deep-merge `overlay` into `base`, replacing non-object values. Used to layer preference
+/// files and per-document overrides over the defaults.
+pub(crate) fn merge_json(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    use serde_json::Value;
+    match (base, overlay) {
+        (Value::Object(base_map), Value::Object(overlay_map)) => {
+            for (key, value) in overlay_map {
+                match base_map.get_mut(&key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base_map.insert(key, value);
+                    }
+                }
+            }
         }
+        (base, overlay) => *base = overlay,
     }
 }
 
+fn default_typst_backend() -> String {
+    "embedded".to_string()
+}
+
+fn default_render_cache_max_mb() -> u32 {
+    256
+}
+
 fn default_theme_id() -> String {
     "default".to_string()
 }
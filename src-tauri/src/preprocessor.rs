@@ -1,17 +1,38 @@
+use crate::admonitions;
+use crate::crossref;
+use crate::diagrams::DiagramRegistry;
+use crate::edit_map::EditMap;
+use crate::front_matter;
+use crate::includes;
 use anyhow::Result;
 use pulldown_cmark::{CodeBlockKind, CowStr, Event, Options, Parser, Tag};
-use serde::Serialize;
-use std::collections::{HashMap, HashSet};
-use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct EditorPosition {
+    /// Included file the position is in; `None` for the document itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
     pub offset: usize,
     pub line: usize,
     pub column: usize,
+    /// For positions in an included file, the include directive in the document.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directive: Option<DocumentPosition>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// A position in the document itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DocumentPosition {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PdfPosition {
     pub page: usize,
     pub x: f32,
@@ -34,47 +55,310 @@ pub struct SourceMapPayload {
 #[derive(Debug, Clone)]
 pub struct AnchorMeta {
     pub id: String,
+    /// Included file the anchor is in; `None` for the document itself.
+    pub file: Option<PathBuf>,
     pub offset: usize,
     pub line: usize,
     pub column: usize,
+    /// The include directive that pulled in `file`.
+    pub directive: Option<DocumentPosition>,
 }
 
 #[derive(Debug, Clone)]
 pub struct PreprocessorOutput {
     pub markdown: String,
     pub anchors: Vec<AnchorMeta>,
-    pub tikz_blocks: Vec<TikzBlockMeta>,
+    pub diagram_blocks: Vec<DiagramBlockMeta>,
+    /// Preference overrides from the document's YAML front matter.
+    pub prefs_overrides: serde_json::Map<String, serde_json::Value>,
+    /// Problems worth showing the user that did not stop the render.
+    pub warnings: Vec<String>,
+    /// Files pulled in by include directives, and include targets that could not be read,
+    /// which the output also depends on.
+    pub included_files: Vec<PathBuf>,
+    /// How `markdown` relates to the user's document, across every transformation applied.
+    pub edit_map: EditMap,
+    /// Where the text of `markdown` came from, for positions in included files.
+    pub origins: Origins,
+}
+
+/// The included files of a [`PreprocessorOutput`], and how its markdown relates to the text
+/// with includes expanded.
+#[derive(Debug, Clone, Default)]
+pub struct Origins {
+    /// Directory included files are named relative to.
+    root_dir: PathBuf,
+    includes: Vec<includes::IncludedFile>,
+    /// Relates the include-expanded text to the document.
+    include_map: EditMap,
+    /// Relates the output's markdown to the include-expanded text.
+    expanded_map: EditMap,
+}
+
+impl PreprocessorOutput {
+    /// Byte offset in the user's document of `offset` in `markdown`.
+    pub fn to_original(&self, offset: usize) -> usize {
+        self.edit_map.to_original(offset)
+    }
+
+    /// Apply a further transformation to `markdown` (e.g. image path rewriting), extending the
+    /// edit map with the edits it reports.
+    pub fn transform(&mut self, transform: impl FnOnce(&str) -> (String, EditMap)) {
+        let (markdown, edits) = transform(&self.markdown);
+        self.markdown = markdown;
+        self.edit_map = self.edit_map.then(&edits);
+        self.origins.expanded_map = self.origins.expanded_map.then(&edits);
+    }
+
+    /// "line N", or "line N of `file`" when `offset` in `markdown` came from an included file.
+    /// `source` is the user's document.
+    pub fn describe_position(&self, source: &str, offset: usize) -> String {
+        let origins = &self.origins;
+        locate(
+            &origins.includes,
+            &origins.include_map,
+            source,
+            origins.expanded_map.to_original(offset),
+        )
+        .describe(&origins.root_dir)
+    }
 }
 
 #[derive(Debug, Clone)]
-pub struct TikzBlockMeta {
+pub struct DiagramBlockMeta {
+    /// `<language>-<content hash>-<n>`, where `n` counts identical diagrams, so an unchanged
+    /// diagram keeps its id and asset path across renders.
     pub id: String,
-    pub diagram: String,
-    pub preamble: Option<String>,
+    /// Fence language, lowercase.
+    pub language: String,
+    /// Hash of everything in the fence that determines the rendered diagram.
+    pub content_hash: String,
+    pub source: String,
+    /// Fence options other than `scale`, `format` and the layout options (`width`, `align`,
+    /// `caption`), for the renderer (e.g. TikZ `preamble`).
+    /// Repeated options are joined with commas.
+    pub options: BTreeMap<String, String>,
+    pub format: DiagramFormat,
     pub asset_path: String,
-    pub asset_extension: &'static str,
+}
+
+/// Output requested with a diagram fence's `format=` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiagramFormat {
+    /// Vector SVG (also `format=vector`, and `format=pdf`: Typst cannot place PDF images, so
+    /// a vector PDF request is drawn as SVG).
+    #[default]
+    Svg,
+    /// Raster image. Also used for vector diagrams when `dvisvgm` is missing to convert them
+    /// (see [`DiagramRegistry::rasterizes`]).
+    Png,
+}
+
+impl DiagramFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "svg" | "vector" | "pdf" => Some(DiagramFormat::Svg),
+            "png" | "raster" => Some(DiagramFormat::Png),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DiagramFormat::Svg => "svg",
+            DiagramFormat::Png => "png",
+        }
+    }
+
+    pub fn is_vector(self) -> bool {
+        self != DiagramFormat::Png
+    }
+
+    /// Extension of the asset file Typst loads.
+    pub fn asset_extension(self) -> &'static str {
+        if self.is_vector() {
+            "svg"
+        } else {
+            "png"
+        }
+    }
 }
 
 /// Transform user markdown by injecting invisible Typst anchors used for scroll synchronisation.
-pub fn preprocess_markdown(markdown: &str) -> Result<PreprocessorOutput> {
-    let tikz = inject_tikz_blocks(markdown);
-    let anchor_result = inject_anchors(&tikz.markdown)?;
+/// A leading YAML front matter block is stripped and returned as preference overrides, include
+/// directives are resolved against `base_dir` (see `includes`; `document` is the file being
+/// rendered, if saved), `> [!NOTE]` callouts become admonitions (see `admonitions`), fences of
+/// the languages in `diagrams` are replaced by diagram placeholders, and cross-reference labels
+/// and references are rewritten (see `crossref`).
+pub fn preprocess_markdown(
+    markdown: &str,
+    document: Option<&Path>,
+    base_dir: &Path,
+    diagrams: &DiagramRegistry,
+) -> Result<PreprocessorOutput> {
+    let (front_matter, body) = front_matter::extract(markdown);
+    let included = includes::expand(&body, document, base_dir);
+    let admonitions = admonitions::apply(&included.markdown);
+    let diagram = inject_diagram_blocks(&admonitions.markdown, diagrams);
+    let crossrefs = crossref::apply(&diagram.markdown);
+    let diagram_map = admonitions.edit_map.then(&diagram.edit_map);
+    let edit_map = diagram_map.then(&crossrefs.edit_map);
+    let mut anchor_result = inject_anchors(&crossrefs.markdown)?;
+
+    // Anchors are found in the rewritten text; report their positions in the file they came
+    // from. Front matter is blanked in place, so `body` shares the document's offsets and lines.
+    for anchor in &mut anchor_result.anchors {
+        let expanded = edit_map.to_original(anchor.offset);
+        let position = locate(&included.includes, &included.edit_map, &body, expanded);
+        if position.file.is_some() {
+            let offset = included.edit_map.to_original(expanded).min(body.len());
+            let (line, column) = offset_to_line_column(&body, offset);
+            anchor.directive = Some(DocumentPosition {
+                offset,
+                line,
+                column,
+            });
+        }
+        anchor.file = position.file.map(Path::to_path_buf);
+        anchor.offset = position.offset;
+        (anchor.line, anchor.column) = (position.line, position.column);
+    }
+
+    let root_dir = base_dir
+        .canonicalize()
+        .unwrap_or_else(|_| base_dir.to_path_buf());
+    let mut warnings = front_matter.warnings;
+    warnings.extend(included.warnings.iter().cloned());
+    warnings.extend(diagrams.warnings().iter().cloned());
+    let diagram_position = |offset: usize| {
+        locate(
+            &included.includes,
+            &included.edit_map,
+            &body,
+            admonitions.edit_map.to_original(offset),
+        )
+        .describe(&root_dir)
+    };
+    for (offset, format) in &diagram.rejected_formats {
+        warnings.push(format!(
+            "Unsupported diagram format `{}` on {}; use `svg`, `pdf` or `png`",
+            format,
+            diagram_position(*offset)
+        ));
+    }
+    for offset in &diagram.rasterized {
+        warnings.push(format!(
+            "Diagram on {} is drawn as PNG because dvisvgm is not installed",
+            diagram_position(*offset)
+        ));
+    }
+    for reference in &crossrefs.unresolved {
+        let position = locate(
+            &included.includes,
+            &included.edit_map,
+            &body,
+            diagram_map.to_original(reference.offset),
+        );
+        warnings.push(format!(
+            "Unresolved reference `@{}` on {}",
+            reference.label,
+            position.describe(&root_dir)
+        ));
+    }
+
     Ok(PreprocessorOutput {
         markdown: anchor_result.markdown,
         anchors: anchor_result.anchors,
-        tikz_blocks: tikz.blocks,
+        diagram_blocks: diagram.blocks,
+        prefs_overrides: front_matter.overrides,
+        warnings,
+        included_files: includes::files(&included.includes)
+            .into_iter()
+            .chain(included.missing)
+            .collect(),
+        edit_map: included
+            .edit_map
+            .then(&edit_map)
+            .then(&anchor_result.edit_map),
+        origins: Origins {
+            root_dir,
+            includes: included.includes,
+            include_map: included.edit_map,
+            expanded_map: edit_map.then(&anchor_result.edit_map),
+        },
     })
 }
 
+/// Where an offset of the include-expanded text came from.
+struct SourcePosition<'a> {
+    file: Option<&'a Path>,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl SourcePosition<'_> {
+    /// "line N", or "line N of `file`" for an included file.
+    fn describe(&self, root_dir: &Path) -> String {
+        match self.file {
+            Some(file) => format!(
+                "line {} of `{}`",
+                self.line + 1,
+                file.strip_prefix(root_dir).unwrap_or(file).display()
+            ),
+            None => format!("line {}", self.line + 1),
+        }
+    }
+}
+
+/// Where `offset` of the include-expanded text came from. `include_map` relates that text to
+/// `body`, the document.
+fn locate<'a>(
+    includes: &'a [includes::IncludedFile],
+    include_map: &EditMap,
+    body: &str,
+    offset: usize,
+) -> SourcePosition<'a> {
+    match includes::locate(includes, offset) {
+        Some((file, offset)) => {
+            let (line, column) = offset_to_line_column(file.source(), offset);
+            SourcePosition {
+                file: Some(file.path()),
+                offset,
+                line,
+                column,
+            }
+        }
+        None => {
+            let offset = include_map.to_original(offset).min(body.len());
+            let (line, column) = offset_to_line_column(body, offset);
+            SourcePosition {
+                file: None,
+                offset,
+                line,
+                column,
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-struct TikzTransformResult {
+struct DiagramTransformResult {
     markdown: String,
-    blocks: Vec<TikzBlockMeta>,
+    blocks: Vec<DiagramBlockMeta>,
+    edit_map: EditMap,
+    /// Fence offsets with the `format=` values that are not supported (e.g. `eps`).
+    rejected_formats: Vec<(usize, String)>,
+    /// Fence offsets of vector diagrams drawn as PNG because `dvisvgm` is missing.
+    rasterized: Vec<usize>,
 }
 
-fn inject_tikz_blocks(markdown: &str) -> TikzTransformResult {
-    let mut replacements: Vec<(usize, usize, String, TikzBlockMeta)> = Vec::new();
-    let mut current: Option<TikzBlockInProgress> = None;
+fn inject_diagram_blocks(markdown: &str, diagrams: &DiagramRegistry) -> DiagramTransformResult {
+    let mut replacements: Vec<(usize, usize, String, DiagramBlockMeta)> = Vec::new();
+    let mut current: Option<DiagramBlockInProgress> = None;
+    let mut occurrences: HashMap<String, usize> = HashMap::new();
+    let mut rejected_formats = Vec::new();
+    let mut rasterized = Vec::new();
 
     let parser = Parser::new_ext(
         markdown,
@@ -89,18 +373,27 @@ fn inject_tikz_blocks(markdown: &str) -> TikzTransformResult {
     for (event, range) in parser.into_offset_iter() {
         match event {
             Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
-                if let Some(options) = parse_tikz_fence(&info) {
-                    current = Some(TikzBlockInProgress {
+                if let Some(options) = parse_diagram_fence(&info, diagrams) {
+                    current = Some(DiagramBlockInProgress {
                         start: range.start,
                         options,
                         content: String::new(),
-                        id: format!("tikz-{}", Uuid::new_v4().simple()),
                     });
                 }
             }
             Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(_))) => {
                 if let Some(active) = current.take() {
-                    let (placeholder, meta) = build_tikz_placeholder(&active);
+                    if let Some(format) = &active.options.rejected_format {
+                        rejected_formats.push((active.start, format.clone()));
+                    }
+                    if active.options.rasterized {
+                        rasterized.push(active.start);
+                    }
+                    let content_hash = diagram_content_hash(&active);
+                    let ordinal = occurrences.entry(content_hash.clone()).or_default();
+                    *ordinal += 1;
+                    let (placeholder, meta) =
+                        build_diagram_placeholder(&active, content_hash, *ordinal);
                     replacements.push((active.start, range.end, placeholder, meta));
                 }
             }
@@ -119,43 +412,63 @@ fn inject_tikz_blocks(markdown: &str) -> TikzTransformResult {
     }
 
     if replacements.is_empty() {
-        return TikzTransformResult {
+        return DiagramTransformResult {
             markdown: markdown.to_owned(),
             blocks: Vec::new(),
+            edit_map: EditMap::identity(),
+            rejected_formats,
+            rasterized,
         };
     }
 
     let mut output = markdown.to_owned();
     let mut blocks = Vec::new();
+    let mut splices = Vec::new();
     for (start, end, replacement, meta) in replacements.into_iter().rev() {
         if start <= end && end <= output.len() {
             output.replace_range(start..end, &replacement);
             blocks.push(meta);
+            splices.push((start..end, replacement.len()));
         }
     }
     blocks.reverse();
 
-    TikzTransformResult {
+    DiagramTransformResult {
         markdown: output,
         blocks,
+        edit_map: EditMap::from_splices(splices),
+        rejected_formats,
+        rasterized,
     }
 }
 
 #[derive(Debug, Clone, Default)]
-struct TikzFenceOptions {
+struct DiagramFenceOptions {
+    language: String,
     scale: Option<String>,
-    preamble: Option<String>,
+    format: DiagramFormat,
+    /// Layout options only affect how the asset is placed, so they are not part of its hash.
+    width: Option<String>,
+    align: Option<&'static str>,
+    caption: Option<String>,
+    extra: BTreeMap<String, String>,
+    /// A `format=` value that was ignored.
+    rejected_format: Option<String>,
+    /// Asked for vector output that has to be rasterized instead.
+    rasterized: bool,
 }
 
 #[derive(Debug, Clone)]
-struct TikzBlockInProgress {
+struct DiagramBlockInProgress {
     start: usize,
-    options: TikzFenceOptions,
+    options: DiagramFenceOptions,
     content: String,
-    id: String,
 }
 
-fn parse_tikz_fence(info: &CowStr<'_>) -> Option<TikzFenceOptions> {
+fn parse_diagram_fence(
+    info: &CowStr<'_>,
+    diagrams: &DiagramRegistry,
+) -> Option<DiagramFenceOptions> {
     let raw = info.trim();
     if raw.is_empty() {
         return None;
@@ -170,11 +483,14 @@ fn parse_tikz_fence(info: &CowStr<'_>) -> Option<TikzFenceOptions> {
     let normalized_lang = language
         .trim_matches(|c: char| matches!(c, ',' | '{' | '}' | '[' | ']' | '(' | ')' | ';'))
         .to_ascii_lowercase();
-    if normalized_lang != "tikz" {
+    if !diagrams.handles(&normalized_lang) {
         return None;
     }
 
-    let mut options = TikzFenceOptions::default();
+    let mut options = DiagramFenceOptions {
+        language: normalized_lang,
+        ..Default::default()
+    };
 
     for token in tokens {
         let cleaned = token.trim();
@@ -187,30 +503,75 @@ fn parse_tikz_fence(info: &CowStr<'_>) -> Option<TikzFenceOptions> {
                 .trim()
                 .trim_matches(|c: char| matches!(c, ',' | '{' | '}' | '[' | ']' | '(' | ')' | ';'))
                 .to_ascii_lowercase();
-            let normalized_value = normalize_option_value(value);
+            let Some(value) = normalize_option_value(value) else {
+                continue;
+            };
 
             match key.as_str() {
-                "scale" => {
-                    if let Some(value) = normalized_value {
-                        options.scale = Some(value);
+                "scale" => options.scale = Some(value),
+                "format" => match DiagramFormat::parse(&value) {
+                    Some(format) => options.format = format,
+                    None => options.rejected_format = Some(value),
+                },
+                "width" => options.width = Some(value),
+                "align" => {
+                    options.align = match value.to_ascii_lowercase().as_str() {
+                        "left" => Some("left"),
+                        "center" | "centre" => Some("center"),
+                        "right" => Some("right"),
+                        _ => options.align,
                     }
                 }
-                "preamble" => {
-                    if let Some(value) = normalized_value {
-                        options.preamble = Some(value);
-                    }
+                "caption" => options.caption = Some(value),
+                _ => {
+                    options
+                        .extra
+                        .entry(key)
+                        .and_modify(|existing| {
+                            existing.push(',');
+                            existing.push_str(&value);
+                        })
+                        .or_insert(value);
                 }
-                _ => {}
             }
         }
     }
 
+    if options.format.is_vector() && diagrams.rasterizes(&options.language) {
+        options.format = DiagramFormat::Png;
+        options.rasterized = true;
+    }
     Some(options)
 }
 
-fn build_tikz_placeholder(block: &TikzBlockInProgress) -> (String, TikzBlockMeta) {
-    let asset_extension = "png";
-    let asset_path = format!("tikz/{}.{}", block.id, asset_extension);
+fn diagram_content_hash(block: &DiagramBlockInProgress) -> String {
+    let mut hasher = Sha256::new();
+    let mut field = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+    field(block.options.language.as_bytes());
+    field(block.options.format.name().as_bytes());
+    field(block.content.as_bytes());
+    for (key, value) in &block.options.extra {
+        field(key.as_bytes());
+        field(value.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn build_diagram_placeholder(
+    block: &DiagramBlockInProgress,
+    content_hash: String,
+    ordinal: usize,
+) -> (String, DiagramBlockMeta) {
+    let language = &block.options.language;
+    let slug: String = language
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let id = format!("{}-{}-{}", slug, &content_hash[..16], ordinal);
+    let asset_path = format!("diagrams/{}.{}", id, block.options.format.asset_extension());
 
     let mut args = Vec::new();
     args.push(format!("asset: \"{}\"", asset_path));
@@ -221,18 +582,29 @@ fn build_tikz_placeholder(block: &TikzBlockInProgress) -> (String, TikzBlockMeta
     } else {
         args.push("scale: auto".to_string());
     }
+    if let Some(width) = block.options.width.as_ref() {
+        args.push(format!("width: \"{}\"", typst_comment_string(width)));
+    }
+    if let Some(align) = block.options.align {
+        args.push(format!("align: \"{}\"", align));
+    }
+    if let Some(caption) = block.options.caption.as_ref() {
+        args.push(format!("caption: \"{}\"", typst_comment_string(caption)));
+    }
 
     let mut placeholder = String::new();
-    placeholder.push_str("<!--raw-typst #tikz_render(");
+    placeholder.push_str("<!--raw-typst #diagram_render(");
     placeholder.push_str(&args.join(", "));
     placeholder.push_str(") -->\n");
 
-    let meta = TikzBlockMeta {
-        id: block.id.clone(),
-        diagram: block.content.clone(),
-        preamble: block.options.preamble.clone(),
+    let meta = DiagramBlockMeta {
+        id,
+        language: language.clone(),
+        content_hash,
+        source: block.content.clone(),
+        options: block.options.extra.clone(),
+        format: block.options.format,
         asset_path,
-        asset_extension,
     };
 
     (placeholder, meta)
@@ -253,6 +625,12 @@ fn escape_typst_string(input: &str) -> String {
     escaped
 }
 
+/// Escape `input` for a Typst string literal inside a `<!--raw-typst -->` comment, which a
+/// literal `-->` would close.
+pub(crate) fn typst_comment_string(input: &str) -> String {
+    escape_typst_string(input).replace("-->", "--\\u{3e}")
+}
+
 fn format_scale_value(raw: &str) -> String {
     let trimmed = raw.trim();
     if trimmed.is_empty() {
@@ -280,7 +658,7 @@ fn format_scale_value(raw: &str) -> String {
         }
         formatted
     } else {
-        format!("\"{}\"", escape_typst_string(trimmed))
+        format!("\"{}\"", typst_comment_string(trimmed))
     }
 }
 
@@ -386,12 +764,34 @@ mod tests {
     use pulldown_cmark::CowStr;
 
     #[test]
-    fn parse_tikz_options_with_quotes() {
+    fn parse_diagram_options_with_quotes() {
+        let diagrams = DiagramRegistry::builtin();
         let info =
             CowStr::from(r#"tikz scale=0.75 preamble="\usetikzlibrary{calc}" format='vector'"#);
-        let options = parse_tikz_fence(&info).expect("tikz fence should parse");
+        let options = parse_diagram_fence(&info, &diagrams).expect("tikz fence should parse");
+        assert_eq!(options.language, "tikz");
         assert_eq!(options.scale.as_deref(), Some("0.75"));
-        assert_eq!(options.preamble.as_deref(), Some(r"\usetikzlibrary{calc}"));
+        assert_eq!(
+            options.extra.get("preamble").map(String::as_str),
+            Some(r"\usetikzlibrary{calc}")
+        );
+        assert_eq!(options.format, DiagramFormat::Svg);
+
+        let pdf = parse_diagram_fence(&CowStr::from("tikz format=pdf"), &diagrams).unwrap();
+        assert_eq!(pdf.format, DiagramFormat::Svg);
+        assert!(pdf.rejected_format.is_none());
+        let png = parse_diagram_fence(&CowStr::from("dot format=png"), &diagrams).unwrap();
+        assert_eq!(png.format, DiagramFormat::Png);
+        let default = parse_diagram_fence(&CowStr::from("tikz"), &diagrams).unwrap();
+        assert_eq!(default.format.asset_extension(), "svg");
+        assert!(parse_diagram_fence(&CowStr::from("rust"), &diagrams).is_none());
+
+        let presets = parse_diagram_fence(
+            &CowStr::from(r#"tikz preset="pgfplots, circuits" preset=x"#),
+            &diagrams,
+        )
+        .unwrap();
+        assert_eq!(presets.extra["preset"], "pgfplots, circuits,x");
     }
 
     #[test]
@@ -404,18 +804,112 @@ mod tests {
 ```
 "#;
 
-        let transformed = inject_tikz_blocks(markdown);
-        assert!(transformed.markdown.contains("#tikz_render"));
+        let diagrams = DiagramRegistry::builtin();
+        let transformed = inject_diagram_blocks(markdown, &diagrams);
+        assert!(transformed.markdown.contains("#diagram_render"));
         assert!(transformed.markdown.contains("scale: auto"));
         assert!(!transformed.markdown.contains("```tikz"));
         assert_eq!(transformed.blocks.len(), 1);
         let meta = &transformed.blocks[0];
         assert_eq!(
-            meta.preamble.as_deref(),
+            meta.options.get("preamble").map(String::as_str),
             Some(r"\usetikzlibrary{arrows.meta}")
         );
-        assert!(meta.asset_path.starts_with("tikz/"));
-        assert_eq!(meta.asset_extension, "png");
+        assert!(meta.asset_path.starts_with("diagrams/tikz-"));
+        assert!(meta.asset_path.ends_with(".png"));
+
+        // Ids follow the content, so they survive edits elsewhere and repeats stay distinct.
+        let repeated = format!("Edited intro\n{}\n{}", &markdown[6..], &markdown[6..]);
+        let again = inject_diagram_blocks(&repeated, &diagrams);
+        assert_eq!(again.blocks[0].id, meta.id);
+        assert!(meta.id.ends_with("-1"));
+        assert!(again.blocks[1].id.ends_with("-2"));
+        assert_eq!(again.blocks[1].content_hash, meta.content_hash);
+    }
+
+    #[test]
+    fn included_anchors_point_at_their_directive() {
+        let dir = std::env::temp_dir().join(format!("tf-anchors-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("chapter.md"), "# Chapter\n\nBody.\n").unwrap();
+        let markdown = "# Intro\n\n<!-- include: chapter.md -->\n\nOutro.\n";
+        let output =
+            preprocess_markdown(markdown, None, &dir, &DiagramRegistry::builtin()).unwrap();
+
+        let included: Vec<_> = output
+            .anchors
+            .iter()
+            .filter(|anchor| anchor.file.is_some())
+            .collect();
+        assert_eq!(included.len(), 2);
+        let directive = markdown.find("<!--").unwrap();
+        for anchor in included {
+            assert_eq!(
+                anchor.directive,
+                Some(DocumentPosition {
+                    offset: directive,
+                    line: 2,
+                    column: 0,
+                })
+            );
+        }
+        let outro = output.anchors.last().unwrap();
+        assert_eq!(
+            (outro.file.as_ref(), outro.directive, outro.line),
+            (None, None, 4)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unsupported_and_rasterized_formats_are_reported() {
+        let markdown =
+            "# Title\n\n```dot format=eps\ndigraph {}\n```\n\n```tikz\n\\draw (0,0);\n```\n";
+        let diagrams = DiagramRegistry::builtin().with_dvisvgm(false);
+        let output = preprocess_markdown(markdown, None, Path::new("."), &diagrams).unwrap();
+
+        let [dot, tikz] = &output.diagram_blocks[..] else {
+            panic!("expected two blocks");
+        };
+        assert_eq!(dot.format, DiagramFormat::Svg);
+        assert_eq!(tikz.format, DiagramFormat::Png);
+        assert!(tikz.asset_path.ends_with(".png"));
+        assert_eq!(
+            output.warnings,
+            vec![
+                "Unsupported diagram format `eps` on line 3; use `svg`, `pdf` or `png`",
+                "Diagram on line 7 is drawn as PNG because dvisvgm is not installed",
+            ]
+        );
+    }
+
+    #[test]
+    fn layout_options_reach_the_template_but_not_the_asset() {
+        let diagrams = DiagramRegistry::builtin();
+        let plain = inject_diagram_blocks("```dot\ndigraph { a -> b }\n```\n", &diagrams);
+        let laid_out = inject_diagram_blocks(
+            "```dot width=60% align=left caption=\"States --> done\"\ndigraph { a -> b }\n```\n",
+            &diagrams,
+        );
+        assert!(laid_out.markdown.contains(r#"width: "60%", align: "left""#));
+        assert!(laid_out
+            .markdown
+            .contains(r#"caption: "States --\u{3e} done") -->"#));
+        assert!(laid_out.blocks[0].options.is_empty());
+        assert_eq!(laid_out.blocks[0].id, plain.blocks[0].id);
+    }
+
+    #[test]
+    fn option_values_cannot_close_the_placeholder_comment() {
+        let output = inject_diagram_blocks(
+            "```dot width=\"5cm-->\" scale=\"x-->\"\ndigraph { a -> b }\n```\n",
+            &DiagramRegistry::builtin(),
+        );
+        let placeholder = output.markdown.lines().next().unwrap();
+        assert!(placeholder.contains(r#"width: "5cm--\u{3e}""#));
+        assert!(placeholder.contains(r#"scale: "x--\u{3e}""#));
+        assert_eq!(placeholder.matches("-->").count(), 1);
+        assert!(placeholder.ends_with(") -->"));
     }
 }
 
@@ -423,6 +917,7 @@ mod tests {
 struct AnchorTransformResult {
     markdown: String,
     anchors: Vec<AnchorMeta>,
+    edit_map: EditMap,
 }
 
 fn inject_anchors(markdown: &str) -> Result<AnchorTransformResult> {
@@ -438,9 +933,11 @@ fn inject_anchors(markdown: &str) -> Result<AnchorTransformResult> {
         insertions.push((0, doc_anchor));
         anchors.push(AnchorMeta {
             id: doc_id.clone(),
+            file: None,
             offset: 0,
             line: 0,
             column: 0,
+            directive: None,
         });
         seen_offsets.insert(0usize);
     }
@@ -480,7 +977,8 @@ fn inject_anchors(markdown: &str) -> Result<AnchorTransformResult> {
             let insertion_offset = range.start;
 
             // If we're inserting into a blockquote line (starts with '>'), SKIP it entirely.
-            // Blockquotes (including admonitions) will get anchored via their inner paragraphs.
+            // Blockquotes will get anchored via their inner paragraphs; admonitions no longer
+            // have `>` markers by now and get anchors inside them like any other content.
             let mut line_start = insertion_offset;
             while line_start > 0 && markdown.as_bytes()[line_start - 1] != b'\n' {
                 line_start -= 1;
@@ -503,14 +1001,21 @@ fn inject_anchors(markdown: &str) -> Result<AnchorTransformResult> {
             insertions.push((insertion_offset, anchor_markup));
             anchors.push(AnchorMeta {
                 id,
+                file: None,
                 offset: range.start,
                 line,
                 column,
+                directive: None,
             });
         }
     }
 
     insertions.sort_by_key(|(offset, _)| *offset);
+    let edit_map = EditMap::from_splices(
+        insertions
+            .iter()
+            .map(|(offset, snippet)| (*offset..*offset, snippet.len())),
+    );
     let mut output = markdown.to_owned();
     for (offset, snippet) in insertions.into_iter().rev() {
         output.insert_str(offset, &snippet);
@@ -519,6 +1024,7 @@ fn inject_anchors(markdown: &str) -> Result<AnchorTransformResult> {
     Ok(AnchorTransformResult {
         markdown: output,
         anchors,
+        edit_map,
     })
 }
 
@@ -539,7 +1045,7 @@ fn is_block_level(tag: &Tag<'_>) -> bool {
     )
 }
 
-fn offset_to_line_column(source: &str, offset: usize) -> (usize, usize) {
+pub(crate) fn offset_to_line_column(source: &str, offset: usize) -> (usize, usize) {
     let mut line = 0;
     let mut column = 0;
     for ch in source[..offset].chars() {
@@ -580,9 +1086,14 @@ pub fn attach_pdf_positions(
         .map(|anchor| AnchorEntry {
             id: anchor.id.clone(),
             editor: EditorPosition {
+                file: anchor
+                    .file
+                    .as_ref()
+                    .map(|file| file.to_string_lossy().to_string()),
                 offset: anchor.offset,
                 line: anchor.line,
                 column: anchor.column,
+                directive: anchor.directive,
             },
             pdf: positions.get(&anchor.id).cloned(),
         })
@@ -599,9 +1110,14 @@ pub fn anchors_to_lookup(anchors: &[AnchorMeta]) -> HashMap<String, EditorPositi
             (
                 anchor.id.clone(),
                 EditorPosition {
+                    file: anchor
+                        .file
+                        .as_ref()
+                        .map(|file| file.to_string_lossy().to_string()),
                     offset: anchor.offset,
                     line: anchor.line,
                     column: anchor.column,
+                    directive: anchor.directive,
                 },
             )
         })
@@ -0,0 +1,242 @@
+//! Long-lived preview state for open documents.
+//!
+//! Live preview re-renders the same document on every edit. A [`PreviewSession`] remembers the
+//! last preprocessed content per document so unchanged sources (e.g. a preferences-only change)
+//! skip the preprocessor unless a file they include changed. [`PhaseTimer`] measures each
+//! pipeline phase for the `compiled` event. Build files themselves are only rewritten when their
+//! bytes change (see `render_pipeline::write_if_changed`), and the embedded compiler keeps its
+//! own incremental state between compiles.
+
+use crate::preprocessor::PreprocessorOutput;
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+/// Open documents whose state is kept around; the least recently rendered is dropped first.
+const MAX_SESSIONS: usize = 8;
+
+lazy_static::lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<String, PreviewSession>> = Mutex::new(HashMap::new());
+}
+
+struct PreviewSession {
+    input_hash: String,
+    prepared: PreprocessorOutput,
+    /// Modification times of the included files when `prepared` was made.
+    included: Vec<(PathBuf, Option<SystemTime>)>,
+    last_used: Instant,
+}
+
+fn modified_times(files: &[PathBuf]) -> Vec<(PathBuf, Option<SystemTime>)> {
+    files
+        .iter()
+        .map(|file| {
+            let modified = fs::metadata(file).and_then(|m| m.modified()).ok();
+            (file.clone(), modified)
+        })
+        .collect()
+}
+
+/// Wall-clock time spent in each render phase, in milliseconds.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RenderTimings {
+    pub preprocess_ms: f64,
+    pub tikz_ms: f64,
+    pub compile_ms: f64,
+    pub query_ms: f64,
+    pub total_ms: f64,
+    /// True when the preprocessed content was reused from the previous render.
+    pub preprocess_cached: bool,
+    /// True when the PDF came from the render cache instead of a compile.
+    pub cache_hit: bool,
+}
+
+/// Measures consecutive phases of a render.
+pub struct PhaseTimer {
+    started: Instant,
+    lap: Instant,
+}
+
+impl PhaseTimer {
+    pub fn start() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            lap: now,
+        }
+    }
+
+    /// Milliseconds since the previous lap (or the start).
+    pub fn lap(&mut self) -> f64 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.lap).as_secs_f64() * 1000.0;
+        self.lap = now;
+        elapsed
+    }
+
+    pub fn total(&self) -> f64 {
+        self.started.elapsed().as_secs_f64() * 1000.0
+    }
+}
+
+/// Return the prepared content for `document`, running `prepare` only when `inputs` differ
+/// from the previous render of that document or an included file changed (including one that
+/// could not be read before). The flag is `true` on reuse.
+pub fn prepare_content(
+    document: &str,
+    inputs: &[&str],
+    prepare: impl FnOnce() -> Result<PreprocessorOutput>,
+) -> Result<(PreprocessorOutput, bool)> {
+    let mut hasher = Sha256::new();
+    for input in inputs {
+        hasher.update((input.len() as u64).to_le_bytes());
+        hasher.update(input.as_bytes());
+    }
+    let input_hash = hex::encode(hasher.finalize());
+
+    if let Ok(mut sessions) = SESSIONS.lock() {
+        if let Some(session) = sessions.get_mut(document) {
+            if session.input_hash == input_hash
+                && modified_times(&session.prepared.included_files) == session.included
+            {
+                session.last_used = Instant::now();
+                return Ok((session.prepared.clone(), true));
+            }
+        }
+    }
+
+    let prepared = prepare()?;
+
+    if let Ok(mut sessions) = SESSIONS.lock() {
+        sessions.insert(
+            document.to_string(),
+            PreviewSession {
+                input_hash,
+                included: modified_times(&prepared.included_files),
+                prepared: prepared.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        while sessions.len() > MAX_SESSIONS {
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, session)| session.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => sessions.remove(&key),
+                None => break,
+            };
+        }
+    }
+
+    Ok((prepared, false))
+}
+
+/// Forget the preview state of a document (e.g. when its tab is closed).
+pub fn end_session(document: &str) {
+    if let Ok(mut sessions) = SESSIONS.lock() {
+        sessions.remove(document);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagrams::DiagramRegistry;
+    use crate::preprocessor::preprocess_markdown;
+    use std::path::Path;
+
+    fn prepare(source: &str) -> Result<PreprocessorOutput> {
+        preprocess_markdown(source, None, Path::new("."), &DiagramRegistry::builtin())
+    }
+
+    fn document() -> String {
+        format!("session-test-{}", uuid::Uuid::new_v4())
+    }
+
+    #[test]
+    fn unchanged_inputs_reuse_the_prepared_content() {
+        let document = document();
+
+        let (_, cached) = prepare_content(&document, &["# One"], || prepare("# One")).unwrap();
+        assert!(!cached);
+        let (reused, cached) =
+            prepare_content(&document, &["# One"], || panic!("prepared twice")).unwrap();
+        assert!(cached);
+        assert!(reused.markdown.contains("One"));
+        end_session(&document);
+    }
+
+    #[test]
+    fn changed_inputs_prepare_again() {
+        let document = document();
+
+        prepare_content(&document, &["# One"], || prepare("# One")).unwrap();
+        let (second, cached) = prepare_content(&document, &["# Two"], || prepare("# Two")).unwrap();
+        assert!(!cached);
+        assert!(second.markdown.contains("Two"));
+        end_session(&document);
+    }
+
+    #[test]
+    fn changed_included_files_prepare_again() {
+        let dir = std::env::temp_dir().join(format!("tf-session-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let chapter = dir.join("one.md");
+        fs::write(&chapter, "# One\n").unwrap();
+        let source = "<!-- include: one.md -->\n";
+        let prepare = || preprocess_markdown(source, None, &dir, &DiagramRegistry::builtin());
+        let document = document();
+
+        prepare_content(&document, &[source], prepare).unwrap();
+        let (_, cached) = prepare_content(&document, &[source], prepare).unwrap();
+        assert!(cached);
+
+        let file = fs::File::options().write(true).open(&chapter).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        let (_, cached) = prepare_content(&document, &[source], prepare).unwrap();
+        assert!(!cached);
+
+        end_session(&document);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn included_files_that_appear_prepare_again() {
+        let dir = std::env::temp_dir().join(format!("tf-session-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = "<!-- include: later.md -->\n";
+        let prepare = || preprocess_markdown(source, None, &dir, &DiagramRegistry::builtin());
+        let document = document();
+
+        let (first, _) = prepare_content(&document, &[source], prepare).unwrap();
+        assert!(!first.warnings.is_empty());
+        let (_, cached) = prepare_content(&document, &[source], prepare).unwrap();
+        assert!(cached);
+
+        fs::write(dir.join("later.md"), "# Later\n").unwrap();
+        let (second, cached) = prepare_content(&document, &[source], prepare).unwrap();
+        assert!(!cached);
+        assert!(second.markdown.contains("Later"));
+
+        end_session(&document);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ended_sessions_prepare_again() {
+        let document = document();
+
+        prepare_content(&document, &["# One"], || prepare("# One")).unwrap();
+        end_session(&document);
+        let (_, cached) = prepare_content(&document, &["# One"], || prepare("# One")).unwrap();
+        assert!(!cached);
+        end_session(&document);
+    }
+}
@@ -0,0 +1,354 @@
+//! Book projects: a manifest listing chapter files that render as one document.
+//!
+//! A project directory holds a `tideflow.toml`:
+//!
+//! ```toml
+//! title = "Field Notes"
+//! output = "build/field-notes.pdf"
+//! chapters = ["index.md", "chapters/01-intro.md", "chapters/02-method.md"]
+//! bibliography = "references.bib"
+//!
+//! [preferences]
+//! papersize = "a4"
+//! numberSections = true
+//! ```
+//!
+//! or a Quarto-style `_quarto.yml` whose `book` section lists the chapters (parts are
+//! flattened) and whose `format.typst` options become preferences. The chapters are joined
+//! through include directives with a page break before each one, so they share one table of
+//! contents and one heading, figure and citation numbering. Project preferences are applied
+//! like a document's front matter; the chapters' own front matter is ignored.
+
+use crate::environment::RenderEnvironment;
+use crate::renderer::{self, RenderedDocument};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value as JsonValue};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const TOML_MANIFEST: &str = "tideflow.toml";
+pub const QUARTO_MANIFEST: &str = "_quarto.yml";
+
+/// Typst between two chapters.
+const CHAPTER_BREAK: &str = "<!--raw-typst #pagebreak(weak: true) -->";
+
+#[derive(Debug, Clone)]
+pub struct Project {
+    pub manifest: PathBuf,
+    /// Directory of the manifest; chapters and preference paths are relative to it.
+    pub root: PathBuf,
+    /// Chapter files in reading order, as listed.
+    pub chapters: Vec<String>,
+    /// Exported PDF, relative to `root`.
+    pub output: PathBuf,
+    /// Preference overrides in `prefs.json` shape.
+    pub prefs: Map<String, JsonValue>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TomlManifest {
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    output: Option<String>,
+    #[serde(default)]
+    chapters: Vec<String>,
+    #[serde(default)]
+    bibliography: Option<JsonValue>,
+    #[serde(default)]
+    preferences: Map<String, JsonValue>,
+}
+
+impl Project {
+    /// Load the project of `path`: a manifest, a directory holding one, or a file inside a
+    /// project directory.
+    pub fn load(path: &Path) -> Result<Self> {
+        let manifest = find_manifest(path)?;
+        let text = fs::read_to_string(&manifest)
+            .with_context(|| format!("Cannot read {}", manifest.display()))?;
+        let project = Self::parse(&manifest, &text)?;
+        if let Some(missing) = project
+            .chapters
+            .iter()
+            .find(|chapter| !project.root.join(chapter).is_file())
+        {
+            return Err(anyhow!(
+                "Chapter `{}` listed in {} does not exist",
+                missing,
+                manifest.display()
+            ));
+        }
+        Ok(project)
+    }
+
+    fn parse(manifest: &Path, text: &str) -> Result<Self> {
+        let root = manifest.parent().unwrap_or(Path::new(".")).to_path_buf();
+        let is_toml = manifest.extension().and_then(|e| e.to_str()) == Some("toml");
+        let (title, output, chapters, prefs) = if is_toml {
+            parse_toml(text)?
+        } else {
+            parse_quarto(text)?
+        };
+        if chapters.is_empty() {
+            return Err(anyhow!("{} lists no chapters", manifest.display()));
+        }
+
+        let mut prefs = prefs;
+        if let Some(title) = title {
+            prefs
+                .entry("cover_title")
+                .or_insert(JsonValue::String(title));
+        }
+        let output = output.unwrap_or_else(|| {
+            let name = root
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| "book".to_string());
+            PathBuf::from(name)
+        });
+        Ok(Self {
+            manifest: manifest.to_path_buf(),
+            root,
+            chapters,
+            output: output.with_extension("pdf"),
+            prefs,
+        })
+    }
+
+    /// The markdown the chapters are rendered from: project preferences as front matter, then
+    /// one include per chapter.
+    pub fn document(&self) -> String {
+        let mut document = String::new();
+        if !self.prefs.is_empty() {
+            document.push_str("---\n");
+            document.push_str(&serde_yaml::to_string(&self.prefs).unwrap_or_default());
+            document.push_str("---\n\n");
+        }
+        let chapters: Vec<String> = self
+            .chapters
+            .iter()
+            .map(|chapter| format!("<!-- include: {} -->\n", chapter))
+            .collect();
+        document.push_str(&chapters.join(&format!("\n{}\n\n", CHAPTER_BREAK)));
+        document
+    }
+}
+
+fn find_manifest(path: &Path) -> Result<PathBuf> {
+    let is_manifest = |path: &Path| {
+        path.file_name()
+            .is_some_and(|name| name == TOML_MANIFEST || name == QUARTO_MANIFEST)
+    };
+    if path.is_file() && is_manifest(path) {
+        return Ok(path.to_path_buf());
+    }
+    let start = if path.is_dir() {
+        Some(path)
+    } else {
+        path.parent()
+    };
+    start
+        .into_iter()
+        .flat_map(Path::ancestors)
+        .flat_map(|dir| [dir.join(TOML_MANIFEST), dir.join(QUARTO_MANIFEST)])
+        .find(|candidate| candidate.is_file())
+        .ok_or_else(|| {
+            anyhow!(
+                "No {} or {} found for {}",
+                TOML_MANIFEST,
+                QUARTO_MANIFEST,
+                path.display()
+            )
+        })
+}
+
+type Parsed = (
+    Option<String>,
+    Option<PathBuf>,
+    Vec<String>,
+    Map<String, JsonValue>,
+);
+
+fn parse_toml(text: &str) -> Result<Parsed> {
+    let manifest: TomlManifest = toml::from_str(text)?;
+    let mut prefs = manifest.preferences;
+    if let Some(bibliography) = manifest.bibliography {
+        prefs.insert("bibliography".to_string(), bibliography);
+    }
+    Ok((
+        manifest.title,
+        manifest.output.map(PathBuf::from),
+        manifest.chapters,
+        prefs,
+    ))
+}
+
+fn parse_quarto(text: &str) -> Result<Parsed> {
+    let value: JsonValue = serde_yaml::from_str(text)?;
+    let book = value.get("book");
+    let title = book
+        .and_then(|book| book.get("title"))
+        .or_else(|| value.get("title"))
+        .and_then(JsonValue::as_str)
+        .map(str::to_string);
+
+    let mut chapters = Vec::new();
+    for key in ["chapters", "appendices"] {
+        if let Some(entries) = book.and_then(|book| book.get(key)) {
+            collect_chapters(entries, &mut chapters);
+        }
+    }
+
+    let output_dir = value
+        .pointer("/project/output-dir")
+        .and_then(JsonValue::as_str)
+        .unwrap_or(".");
+    let output = book
+        .and_then(|book| book.get("output-file"))
+        .and_then(JsonValue::as_str)
+        .map(|file| Path::new(output_dir).join(file));
+
+    let mut prefs = Map::new();
+    if let Some(JsonValue::Object(options)) = value.pointer("/format/typst") {
+        for (key, option) in options {
+            let key = match key.as_str() {
+                "number-sections" => "numberSections".to_string(),
+                key => key.replace('-', "_"),
+            };
+            prefs.insert(key, option.clone());
+        }
+    }
+    for key in ["bibliography", "csl"] {
+        if let Some(option) = value.get(key) {
+            prefs.insert(key.to_string(), option.clone());
+        }
+    }
+    Ok((title, output, chapters, prefs))
+}
+
+/// Chapter files of a Quarto `chapters` list, whose entries are files or parts with their own
+/// `chapters`. A part given as a file is a chapter too.
+fn collect_chapters(entries: &JsonValue, chapters: &mut Vec<String>) {
+    for entry in entries.as_array().into_iter().flatten() {
+        match entry {
+            JsonValue::String(file) => chapters.push(file.clone()),
+            JsonValue::Object(part) => {
+                if let Some(file) = part.get("part").and_then(JsonValue::as_str) {
+                    if file.ends_with(".md") || file.ends_with(".qmd") {
+                        chapters.push(file.to_string());
+                    }
+                }
+                if let Some(nested) = part.get("chapters") {
+                    collect_chapters(nested, chapters);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Render the project of `path` for preview. The manifest path keys the preview workspace.
+pub async fn render_project(
+    env: Arc<dyn RenderEnvironment>,
+    path: &str,
+) -> Result<RenderedDocument> {
+    let project = Project::load(Path::new(path))?;
+    let manifest = project.manifest.to_string_lossy().to_string();
+    renderer::render_markdown_source(env, &manifest, &project.document(), &project.root).await
+}
+
+/// Export the project of `path` to its output PDF.
+pub async fn export_project(env: Arc<dyn RenderEnvironment>, path: &str) -> Result<String> {
+    let project = Project::load(Path::new(path))?;
+    let manifest = project.manifest.to_string_lossy().to_string();
+    let output = project.root.join(&project.output);
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    renderer::export_markdown_source(env, &manifest, &project.document(), &project.root, &output)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarto_parts_and_appendices_are_flattened() {
+        let quarto = "book:\n  chapters:\n    - index.md\n    - part: Methods\n      chapters: [method.md, data.md]\n    - part: intro.qmd\n      chapters: [a.md]\n  appendices: [extra.md]\n";
+        let project = Project::parse(Path::new("/book/_quarto.yml"), quarto).unwrap();
+        assert_eq!(
+            project.chapters,
+            [
+                "index.md",
+                "method.md",
+                "data.md",
+                "intro.qmd",
+                "a.md",
+                "extra.md"
+            ]
+        );
+    }
+
+    #[test]
+    fn quarto_typst_options_become_preferences() {
+        let quarto = "project:\n  output-dir: out\nbook:\n  title: Notes\n  output-file: notes\n  chapters: [index.md]\nbibliography: refs.bib\nformat:\n  typst:\n    toc: true\n    number-sections: true\n    toc-title: Contents\n";
+        let project = Project::parse(Path::new("/book/_quarto.yml"), quarto).unwrap();
+        assert_eq!(project.output, Path::new("out/notes.pdf"));
+        assert_eq!(project.prefs["toc"], true);
+        assert_eq!(project.prefs["numberSections"], true);
+        assert_eq!(project.prefs["toc_title"], "Contents");
+        assert_eq!(project.prefs["bibliography"], "refs.bib");
+        assert_eq!(project.prefs["cover_title"], "Notes");
+    }
+
+    #[test]
+    fn toml_projects_include_their_chapters_with_page_breaks() {
+        let toml = "chapters = [\"a.md\", \"b.md\"]\n\n[preferences]\ncover_title = \"Own\"\n";
+        let project = Project::parse(Path::new("/book/tideflow.toml"), toml).unwrap();
+        // Named after the project directory by default
+        assert_eq!(project.output, Path::new("book.pdf"));
+        let document = project.document();
+        assert!(document.starts_with("---\ncover_title: Own\n---\n"));
+        assert!(document.ends_with(&format!(
+            "<!-- include: a.md -->\n\n{}\n\n<!-- include: b.md -->\n",
+            CHAPTER_BREAK
+        )));
+    }
+
+    #[test]
+    fn manifests_without_chapters_are_rejected() {
+        let error = Project::parse(Path::new("/book/_quarto.yml"), "project: {}\n").unwrap_err();
+        assert_eq!(error.to_string(), "/book/_quarto.yml lists no chapters");
+    }
+
+    #[test]
+    fn loading_finds_the_manifest_and_checks_the_chapters() {
+        let dir = std::env::temp_dir().join(format!("tideflow-project-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("chapters")).unwrap();
+        fs::write(
+            dir.join(TOML_MANIFEST),
+            "chapters = [\"chapters/one.md\", \"chapters/two.md\"]\n",
+        )
+        .unwrap();
+        fs::write(dir.join("chapters/one.md"), "# One\n").unwrap();
+
+        let error = Project::load(&dir.join("chapters/one.md")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Chapter `chapters/two.md` listed in {} does not exist",
+                dir.join(TOML_MANIFEST).display()
+            )
+        );
+
+        fs::write(dir.join("chapters/two.md"), "# Two\n").unwrap();
+        let project = Project::load(&dir.join("chapters/one.md")).unwrap();
+        assert_eq!(project.manifest, dir.join(TOML_MANIFEST));
+        assert_eq!(project.root, dir);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
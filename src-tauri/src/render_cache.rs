@@ -0,0 +1,620 @@
+//! Content-addressed cache of compiled PDFs.
+//!
+//! A render is keyed by everything that decides its output: the `content.md` that is compiled,
+//! the effective `prefs.json`, the installed template and theme files, the Typst compiler
+//! version, the diagram renderers and their settings, and the size and modification time of
+//! local images the document references. A preview or export with a known key copies the cached
+//! PDF instead of compiling, and previews also reuse the anchor positions recorded with it.
+//! Entries live in `<build>/cache/` and the least recently used ones are evicted once the cache
+//! grows past `render_cache_max_mb`.
+
+use crate::build_workspace;
+use crate::citations;
+use crate::preprocessor::{PdfPosition, SourceMapPayload};
+use crate::render_pipeline::{self, RenderConfig, TypstBackend};
+use anyhow::Result;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Used when `render_cache_max_mb` is missing from the preferences.
+const DEFAULT_MAX_MB: u64 = 256;
+
+static HITS: AtomicUsize = AtomicUsize::new(0);
+static MISSES: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// `typst --version` output per binary, so the version is only asked for once.
+    static ref TYPST_VERSIONS: Mutex<HashMap<PathBuf, String>> = Mutex::new(HashMap::new());
+    static ref IMAGE_REFERENCE: Regex = Regex::new(
+        r#"!\[[^\]]*\]\(<?([^)>\s]+)|<img[^>]*\ssrc=["']([^"']+)["']|#(?:fig|image)\(\s*["']([^"']+)["']"#
+    )
+    .expect("BUG: Invalid regex pattern for image references");
+}
+
+/// Cache usage since the app started, plus what is currently on disk.
+#[derive(Debug, Clone, Default)]
+pub struct CacheUsage {
+    pub entries: usize,
+    pub bytes: u64,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// Sidecar of a cached PDF. Exports do not query anchor positions, so their entries have none.
+#[derive(Debug, Serialize, Deserialize)]
+struct EntryMeta {
+    positions: Option<HashMap<String, PdfPosition>>,
+}
+
+/// The cache entry for one render.
+pub struct RenderCache {
+    dir: PathBuf,
+    key: String,
+    max_bytes: u64,
+}
+
+impl RenderCache {
+    /// Key the render described by `config`, whose template and `prefs.json` are already
+    /// installed. `markdown` is the exact `content.md` being compiled and `diagrams` the
+    /// diagram registry's fingerprint. Returns `None` when the cache is disabled
+    /// (`render_cache_max_mb` is 0) or the inputs cannot be read.
+    pub fn open(
+        config: &RenderConfig,
+        backend: &TypstBackend,
+        markdown: &str,
+        prefs: &JsonValue,
+        diagrams: &str,
+    ) -> Option<Self> {
+        let max_mb = prefs
+            .get("render_cache_max_mb")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(DEFAULT_MAX_MB);
+        if max_mb == 0 {
+            return None;
+        }
+
+        match render_key(config, backend, markdown, prefs, diagrams) {
+            Ok(key) => Some(Self {
+                dir: config.env.build_dir().ok()?.join("cache"),
+                key,
+                max_bytes: max_mb * 1024 * 1024,
+            }),
+            Err(e) => {
+                println!("[render_cache] not caching this render: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Copy the cached PDF to `output` and return its anchor positions. Entries stored by an
+    /// export have no positions and do not count as a hit here.
+    pub fn fetch_preview(&self, output: &Path) -> Option<HashMap<String, PdfPosition>> {
+        self.fetch(output, true).and_then(|meta| meta.positions)
+    }
+
+    /// Copy the cached PDF to `output`; true on a hit.
+    pub fn fetch_pdf(&self, output: &Path) -> bool {
+        self.fetch(output, false).is_some()
+    }
+
+    fn fetch(&self, output: &Path, need_positions: bool) -> Option<EntryMeta> {
+        let found = fs::read(self.meta_path())
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<EntryMeta>(&bytes).ok())
+            .filter(|meta| !need_positions || meta.positions.is_some())
+            .filter(|_| fs::copy(self.pdf_path(), output).is_ok());
+
+        if found.is_some() {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            // Keep the entry at the young end of the LRU order.
+            if let Ok(file) = fs::File::options().write(true).open(self.pdf_path()) {
+                let _ = file.set_modified(SystemTime::now());
+            }
+        } else {
+            MISSES.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
+
+    /// Remember a freshly compiled PDF. Pass the source map for previews; an export (`None`)
+    /// never replaces an entry that has positions. Failures are logged and otherwise ignored.
+    pub fn store(&self, pdf: &Path, source_map: Option<&SourceMapPayload>) {
+        if source_map.is_none() && self.meta_path().exists() {
+            return;
+        }
+        let meta = EntryMeta {
+            positions: source_map.map(|map| {
+                map.anchors
+                    .iter()
+                    .filter_map(|anchor| Some((anchor.id.clone(), anchor.pdf.clone()?)))
+                    .collect()
+            }),
+        };
+        if let Err(e) = self.write_entry(pdf, &meta) {
+            println!("[render_cache] failed to store {}: {}", self.key, e);
+            return;
+        }
+        if let Err(e) = evict(&self.dir, self.max_bytes) {
+            println!("[render_cache] eviction failed: {}", e);
+        }
+    }
+
+    fn write_entry(&self, pdf: &Path, meta: &EntryMeta) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Parallel jobs may store the same key; rename so readers never see a partial file.
+        let suffix = uuid::Uuid::new_v4().simple().to_string();
+        let pdf_tmp = self.dir.join(format!("{}.pdf.{}.tmp", self.key, suffix));
+        let meta_tmp = self.dir.join(format!("{}.json.{}.tmp", self.key, suffix));
+        fs::copy(pdf, &pdf_tmp)?;
+        fs::write(&meta_tmp, serde_json::to_vec(meta)?)?;
+        fs::rename(&pdf_tmp, self.pdf_path())?;
+        fs::rename(&meta_tmp, self.meta_path())?;
+        Ok(())
+    }
+
+    fn pdf_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.pdf", self.key))
+    }
+
+    fn meta_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.json", self.key))
+    }
+}
+
+fn render_key(
+    config: &RenderConfig,
+    backend: &TypstBackend,
+    markdown: &str,
+    prefs: &JsonValue,
+    diagrams: &str,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut section = |name: &str, bytes: &[u8]| {
+        hasher.update(name.as_bytes());
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+
+    section("markdown", markdown.as_bytes());
+    section("prefs", &serde_json::to_vec(prefs)?);
+    section("typst", typst_version(backend).as_bytes());
+    section("packages", package_versions(config).as_bytes());
+    // Diagram assets are keyed by content, but renderer changes (a shared TikZ preamble, a
+    // configured command) redraw them in place.
+    section("diagrams", diagrams.as_bytes());
+    section(
+        "template",
+        &fs::read(config.build_dir.join("tideflow.typ"))?,
+    );
+    // Theme files, and the bibliography files `setup_prefs` copied into the workspace. They
+    // are named relative to their directory, so every job's workspace keys them alike.
+    let shared_themes = build_workspace::shared_dir(&config.env.build_dir()?).join("themes");
+    for (name, dir) in [
+        ("shared-themes", shared_themes),
+        ("themes", config.build_dir.join("themes")),
+        (
+            "bibliography",
+            config.build_dir.join(citations::BIBLIOGRAPHY_DIR),
+        ),
+    ] {
+        for file in files_under(&dir) {
+            let relative = file.strip_prefix(&dir).unwrap_or(&file);
+            section(
+                &format!("{}/{}", name, relative.to_string_lossy()),
+                &fs::read(&file)?,
+            );
+        }
+    }
+
+    // Images are read by path at compile time; key them by size and modification time.
+    let cover = prefs.get("cover_image").and_then(|v| v.as_str());
+    let references = IMAGE_REFERENCE
+        .captures_iter(markdown)
+        .filter_map(|caps| caps.iter().skip(1).flatten().next())
+        .map(|m| m.as_str())
+        .chain(cover);
+    for reference in references {
+        if let Some(path) = resolve_local(reference, &config.typst_root) {
+            if let Ok(metadata) = fs::metadata(&path) {
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                    .map(|since| since.as_nanos())
+                    .unwrap_or_default();
+                section(
+                    &path.to_string_lossy(),
+                    format!("{}:{}", metadata.len(), modified).as_bytes(),
+                );
+            }
+        }
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn typst_version(backend: &TypstBackend) -> String {
+    let typst_path = match backend {
+        // The embedded compiler is pinned by the Typst crates in Cargo.lock (see build.rs).
+        TypstBackend::Embedded => return format!("embedded {}", env!("TIDEFLOW_TYPST_CRATES")),
+        TypstBackend::External(path) => path,
+    };
+
+    let mut versions = TYPST_VERSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    versions
+        .entry(typst_path.clone())
+        .or_insert_with(|| {
+            render_pipeline::typst_command(typst_path)
+                .arg("--version")
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+                .unwrap_or_else(|| typst_path.to_string_lossy().to_string())
+        })
+        .clone()
+}
+
+/// The `namespace/name/version` package directories under each Typst package root, so adding
+/// or upgrading a bundled package (cmarker, mitex) invalidates earlier renders.
+fn package_versions(config: &RenderConfig) -> String {
+    let mut versions = Vec::new();
+    for root in render_pipeline::collect_typst_package_paths(config) {
+        for entry in walkdir::WalkDir::new(&root)
+            .min_depth(3)
+            .max_depth(3)
+            .sort_by_file_name()
+            .into_iter()
+            .flatten()
+            .filter(|entry| entry.file_type().is_dir())
+        {
+            versions.push(entry.path().to_string_lossy().to_string());
+        }
+    }
+    versions.join("\n")
+}
+
+/// Local file behind an image reference: absolute paths as they are, `/…` paths relative to
+/// the Typst root. URLs yield `None`.
+fn resolve_local(reference: &str, typst_root: &Path) -> Option<PathBuf> {
+    if reference.contains("://") || reference.starts_with("data:") {
+        return None;
+    }
+    let path = PathBuf::from(reference);
+    if path.exists() {
+        return Some(path);
+    }
+    let rooted = typst_root.join(reference.trim_start_matches('/'));
+    rooted.exists().then_some(rooted)
+}
+
+/// Every file below `dir`, in a stable order.
+fn files_under(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&current) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+struct Entry {
+    key: String,
+    bytes: u64,
+    last_used: SystemTime,
+}
+
+/// Cached entries in `dir`, least recently used first.
+fn entries(dir: &Path) -> Vec<Entry> {
+    let mut entries: HashMap<String, Entry> = HashMap::new();
+    let Ok(files) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    for file in files.flatten() {
+        let path = file.path();
+        let (Some(key), Some(extension)) = (
+            path.file_stem().and_then(|s| s.to_str()),
+            path.extension().and_then(|e| e.to_str()),
+        ) else {
+            continue;
+        };
+        if extension != "pdf" && extension != "json" {
+            continue;
+        }
+        let Ok(metadata) = file.metadata() else {
+            continue;
+        };
+        let entry = entries.entry(key.to_string()).or_insert(Entry {
+            key: key.to_string(),
+            bytes: 0,
+            last_used: SystemTime::UNIX_EPOCH,
+        });
+        entry.bytes += metadata.len();
+        if extension == "pdf" {
+            entry.last_used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        }
+    }
+    let mut entries: Vec<Entry> = entries.into_values().collect();
+    entries.sort_by_key(|entry| entry.last_used);
+    entries
+}
+
+/// Drop least recently used entries until the cache fits in `max_bytes`.
+fn evict(dir: &Path, max_bytes: u64) -> Result<()> {
+    let entries = entries(dir);
+    let mut total: u64 = entries.iter().map(|entry| entry.bytes).sum();
+    for entry in entries {
+        if total <= max_bytes {
+            break;
+        }
+        for extension in ["pdf", "json"] {
+            let path = dir.join(format!("{}.{}", entry.key, extension));
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        total -= entry.bytes;
+    }
+    Ok(())
+}
+
+/// Current cache size under `build_root` and the hit/miss counts of this session.
+pub fn usage(build_root: &Path) -> CacheUsage {
+    let entries = entries(&build_root.join("cache"));
+    CacheUsage {
+        entries: entries.len(),
+        bytes: entries.iter().map(|entry| entry.bytes).sum(),
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+    }
+}
+
+/// Remove every cached render and reset the hit/miss counts.
+pub fn clear(build_root: &Path) -> Result<()> {
+    let dir = build_root.join("cache");
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    HITS.store(0, Ordering::Relaxed);
+    MISSES.store(0, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::FsEnvironment;
+    use crate::preprocessor::{AnchorEntry, EditorPosition};
+    use crate::render_scheduler::CancelToken;
+
+    fn scratch() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tf-cache-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn anchor(id: &str, page: usize) -> AnchorEntry {
+        AnchorEntry {
+            id: id.to_string(),
+            editor: EditorPosition {
+                file: None,
+                offset: 0,
+                line: 0,
+                column: 0,
+                directive: None,
+            },
+            pdf: Some(PdfPosition {
+                page,
+                x: 0.0,
+                y: 0.0,
+            }),
+        }
+    }
+
+    #[test]
+    fn keys_follow_the_rendered_inputs() {
+        let dir = scratch();
+        let env = FsEnvironment::new(&dir);
+        let config = RenderConfig {
+            env: &env,
+            build_dir: dir.join(".build").join("doc"),
+            content_dir: dir.clone(),
+            typst_root: dir.clone(),
+            cancel: CancelToken::default(),
+        };
+        fs::create_dir_all(&config.build_dir).unwrap();
+        fs::write(
+            config.build_dir.join("tideflow.typ"),
+            "#include \"content.md\"",
+        )
+        .unwrap();
+        let prefs = serde_json::json!({ "toc": true });
+        let key = |markdown: &str, prefs: &JsonValue| {
+            RenderCache::open(&config, &TypstBackend::Embedded, markdown, prefs, "")
+                .unwrap()
+                .key
+        };
+
+        assert_eq!(key("# A", &prefs), key("# A", &prefs));
+        assert_ne!(key("# A", &prefs), key("# B", &prefs));
+        assert_ne!(
+            key("# A", &prefs),
+            key("# A", &serde_json::json!({ "toc": false }))
+        );
+        assert!(RenderCache::open(
+            &config,
+            &TypstBackend::Embedded,
+            "# A",
+            &serde_json::json!({ "render_cache_max_mb": 0 }),
+            ""
+        )
+        .is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn preview_and_export_workspaces_share_keys() {
+        let dir = scratch();
+        let env = FsEnvironment::new(&dir);
+        let key = |job: &str, theme: &str| {
+            let config = RenderConfig {
+                env: &env,
+                build_dir: dir.join(".build").join(job),
+                content_dir: dir.clone(),
+                typst_root: dir.clone(),
+                cancel: CancelToken::default(),
+            };
+            let themes = config.build_dir.join("themes");
+            fs::create_dir_all(&themes).unwrap();
+            fs::write(
+                config.build_dir.join("tideflow.typ"),
+                "#include \"content.md\"",
+            )
+            .unwrap();
+            fs::write(themes.join("theme.typ"), theme).unwrap();
+            RenderCache::open(
+                &config,
+                &TypstBackend::Embedded,
+                "# A",
+                &JsonValue::Null,
+                "",
+            )
+            .unwrap()
+            .key
+        };
+
+        assert_eq!(key("preview", "#let x = 1"), key("export", "#let x = 1"));
+        assert_ne!(key("preview", "#let x = 1"), key("export", "#let x = 2"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn previews_hit_only_entries_with_positions() {
+        let dir = scratch();
+        let pdf = dir.join("render.pdf");
+        fs::write(&pdf, "%PDF export").unwrap();
+        let cache = RenderCache {
+            dir: dir.join("cache"),
+            key: "key".to_string(),
+            max_bytes: u64::MAX,
+        };
+        let output = dir.join("out.pdf");
+
+        assert!(!cache.fetch_pdf(&output));
+        cache.store(&pdf, None);
+        assert!(cache.fetch_pdf(&output));
+        assert!(cache.fetch_preview(&output).is_none());
+
+        let source_map = SourceMapPayload {
+            anchors: vec![anchor("tf-1", 2)],
+        };
+        cache.store(&pdf, Some(&source_map));
+        let positions = cache.fetch_preview(&output).unwrap();
+        assert_eq!(positions["tf-1"].page, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exports_do_not_replace_preview_entries() {
+        let dir = scratch();
+        let preview = dir.join("preview.pdf");
+        let export = dir.join("export.pdf");
+        fs::write(&preview, "%PDF preview").unwrap();
+        fs::write(&export, "%PDF export").unwrap();
+        let cache = RenderCache {
+            dir: dir.join("cache"),
+            key: "key".to_string(),
+            max_bytes: u64::MAX,
+        };
+        let output = dir.join("out.pdf");
+
+        let source_map = SourceMapPayload {
+            anchors: vec![anchor("tf-1", 1)],
+        };
+        cache.store(&preview, Some(&source_map));
+        cache.store(&export, None);
+        assert!(cache.fetch_preview(&output).is_some());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "%PDF preview");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted_first() {
+        let dir = scratch();
+        let now = SystemTime::now();
+        for (key, age) in [("old", 30), ("recent", 10), ("newest", 0)] {
+            fs::write(dir.join(format!("{}.json", key)), "{}").unwrap();
+            let pdf = dir.join(format!("{}.pdf", key));
+            fs::write(&pdf, vec![0u8; 1000]).unwrap();
+            let file = fs::File::options().write(true).open(&pdf).unwrap();
+            file.set_modified(now - std::time::Duration::from_secs(age))
+                .unwrap();
+        }
+
+        evict(&dir, 2100).unwrap();
+        let kept: Vec<String> = entries(&dir).into_iter().map(|entry| entry.key).collect();
+        assert_eq!(kept, vec!["recent", "newest"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_stores_are_not_entries() {
+        let dir = scratch();
+        fs::write(dir.join("entry.json"), "{}").unwrap();
+        fs::write(dir.join("entry.pdf"), "%PDF").unwrap();
+        fs::write(dir.join("entry.pdf.abc.tmp"), "x").unwrap();
+
+        let keys: Vec<String> = entries(&dir).into_iter().map(|entry| entry.key).collect();
+        assert_eq!(keys, vec!["entry"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn package_versions_list_each_installed_package() {
+        let dir = std::env::temp_dir().join(format!("tf-cache-{}", uuid::Uuid::new_v4()));
+        let packages = dir.join("typst").join("packages").join("preview");
+        fs::create_dir_all(packages.join("cmarker").join("0.1.6")).unwrap();
+        fs::write(packages.join("cmarker").join("0.1.6").join("lib.typ"), "").unwrap();
+        let env = FsEnvironment::new(&dir);
+        let config = RenderConfig {
+            env: &env,
+            build_dir: dir.join(".build"),
+            content_dir: dir.clone(),
+            typst_root: dir.clone(),
+            cancel: CancelToken::default(),
+        };
+
+        let before = package_versions(&config);
+        assert!(before.ends_with(&format!("cmarker{}0.1.6", std::path::MAIN_SEPARATOR)));
+        fs::create_dir_all(packages.join("mitex").join("0.2.4")).unwrap();
+        assert_ne!(package_versions(&config), before);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
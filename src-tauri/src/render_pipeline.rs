@@ -3,46 +3,64 @@
 /// This module extracts common setup logic for preferences, templates, assets, and Typst
 /// compilation that was previously duplicated 3x across render_markdown, export_markdown,
 /// and render_typst functions.
+use crate::build_workspace;
+use crate::citations::Bibliography;
+use crate::diagnostics::{self, TypstCompileError};
+use crate::environment::RenderEnvironment;
+use crate::preferences::merge_json;
+use crate::preprocessor::PdfPosition;
+use crate::render_scheduler::{CancelToken, RenderCancelled};
 use crate::utils;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use serde_json::Value as JsonValue;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use tauri::{AppHandle, Emitter, Manager};
 
 #[cfg(target_os = "windows")]
 use std::os::windows::process::CommandExt;
 
 /// Configuration for a render operation
 pub struct RenderConfig<'a> {
-    pub app_handle: &'a AppHandle,
+    pub env: &'a dyn RenderEnvironment,
     pub build_dir: PathBuf,
     pub content_dir: PathBuf, // App's content directory (for templates/prefs)
     pub typst_root: PathBuf,  // Root directory for Typst compilation
+    pub cancel: CancelToken,  // Tripped when a newer preview supersedes this render
 }
 
 /// Result of preferences setup including updated JSON value
 #[allow(dead_code)]
 pub struct PrefsSetupResult {
     pub prefs_json: JsonValue,
+    /// Citation keys of the bibliography files copied into the build directory.
+    pub bibliography: Bibliography,
 }
 
 /// Collect Typst package roots that ship with the app or were copied into the user profile.
 /// Typst expects the directory structure `preview/<pkg>/<version>`.
-fn collect_typst_package_paths(config: &RenderConfig) -> Vec<PathBuf> {
+pub(crate) fn collect_typst_package_paths(config: &RenderConfig) -> Vec<PathBuf> {
+    let resource_dir = config.env.resource_dir();
+    collect_typst_package_paths_from(resource_dir.as_deref(), &config.content_dir)
+}
+
+/// Same as [`collect_typst_package_paths`] but driven by explicit directories.
+pub(crate) fn collect_typst_package_paths_from(
+    resource_dir: Option<&Path>,
+    content_dir: &Path,
+) -> Vec<PathBuf> {
     let mut paths = Vec::new();
     let mut dedupe = HashSet::new();
 
-    if let Ok(resource_dir) = config.app_handle.path().resource_dir() {
+    if let Some(resource_dir) = resource_dir {
         let packaged = resource_dir.join("content").join("typst").join("packages");
         if packaged.exists() && dedupe.insert(packaged.clone()) {
             paths.push(packaged);
         }
     }
 
-    let user_packages = config.content_dir.join("typst").join("packages");
+    let user_packages = content_dir.join("typst").join("packages");
     if user_packages.exists() && dedupe.insert(user_packages.clone()) {
         paths.push(user_packages);
     }
@@ -62,7 +80,11 @@ fn collect_typst_package_paths(config: &RenderConfig) -> Vec<PathBuf> {
 }
 
 pub(crate) fn typst_package_env(config: &RenderConfig) -> Option<String> {
-    let paths = collect_typst_package_paths(config);
+    join_package_paths(&collect_typst_package_paths(config))
+}
+
+/// Join package roots into a `TYPST_PACKAGE_PATH` value using the platform separator.
+pub(crate) fn join_package_paths(paths: &[PathBuf]) -> Option<String> {
     if paths.is_empty() {
         None
     } else {
@@ -152,6 +174,7 @@ pub fn typst_command<S: AsRef<std::ffi::OsStr>>(exe: S) -> Command {
 }
 
 /// Recursively copy a directory
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
 fn copy_directory(src: &Path, dst: &Path) -> Result<()> {
     if dst.exists() {
         fs::remove_dir_all(dst)?;
@@ -171,13 +194,46 @@ fn copy_directory(src: &Path, dst: &Path) -> Result<()> {
     Ok(())
 }
 
-/// Sync theme assets from template source to build directory
-fn sync_theme_assets(template_src: &Path, build_dir: &Path) -> Result<()> {
-    if let Some(parent) = template_src.parent() {
-        let themes_src = parent.join("themes");
-        if themes_src.exists() {
-            let themes_dst = build_dir.join("themes");
-            copy_directory(&themes_src, &themes_dst)?;
+/// Write `contents` to `path` unless the file already holds exactly these bytes.
+/// Returns whether the file was written. Leaving unchanged files alone keeps their
+/// timestamps stable and avoids churn on every preview render.
+pub(crate) fn write_if_changed(path: &Path, contents: &[u8]) -> Result<bool> {
+    if let Ok(existing) = fs::read(path) {
+        if existing == contents {
+            return Ok(false);
+        }
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)?;
+    Ok(true)
+}
+
+/// Mirror `src` into `dst`, rewriting only changed files and removing files that no
+/// longer exist in `src`.
+fn sync_directory(src: &Path, dst: &Path) -> Result<()> {
+    let mut expected = HashSet::new();
+    for entry in walkdir::WalkDir::new(src) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(src)?.to_path_buf();
+        write_if_changed(&dst.join(&relative), &fs::read(entry.path())?)?;
+        expected.insert(relative);
+    }
+
+    if dst.exists() {
+        for entry in walkdir::WalkDir::new(dst) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(dst)?;
+            if !expected.contains(relative) {
+                let _ = fs::remove_file(entry.path());
+            }
         }
     }
     Ok(())
@@ -229,18 +285,18 @@ fn detect_image_format(path: &Path) -> Result<Option<&'static str>> {
 
 /// Handle cover image path rewriting and copying to assets directory.
 /// Returns the updated prefs JSON value with cover_image path rewritten if necessary.
-fn handle_cover_image(prefs_val: &mut JsonValue, app_handle: &AppHandle) -> Result<()> {
+fn handle_cover_image(prefs_val: &mut JsonValue, env: &dyn RenderEnvironment) -> Result<()> {
     if let Some(ci) = prefs_val.get("cover_image").and_then(|v| v.as_str()) {
         if !ci.is_empty() && !ci.starts_with("/assets/") {
             let mut img_path = PathBuf::from(ci);
             if !img_path.is_absolute() {
-                let maybe = utils::get_content_dir(app_handle)?.join(&img_path);
+                let maybe = env.content_dir()?.join(&img_path);
                 if maybe.exists() {
                     img_path = maybe;
                 }
             }
             if img_path.exists() {
-                let assets_dir = utils::get_assets_dir(app_handle)?;
+                let assets_dir = env.assets_dir()?;
 
                 // Detect actual image format and correct extension if needed
                 let detected_ext = detect_image_format(&img_path)?;
@@ -264,9 +320,15 @@ fn handle_cover_image(prefs_val: &mut JsonValue, app_handle: &AppHandle) -> Resu
                 let mut fname = utils::sanitize_filename(&format!("{}.{}", stem, correct_ext));
                 let mut dest = assets_dir.join(&fname);
 
-                // Deduplicate if necessary
+                // Deduplicate if necessary. An existing asset with the same bytes is reused, so
+                // front matter covers (re-resolved on every render) are copied only once.
+                let bytes = fs::read(&img_path)?;
                 let mut counter: u32 = 1;
                 while dest.exists() {
+                    if fs::read(&dest).is_ok_and(|existing| existing == bytes) {
+                        prefs_val["cover_image"] = JsonValue::String(format!("/assets/{}", fname));
+                        return Ok(());
+                    }
                     fname =
                         utils::sanitize_filename(&format!("{}-{}.{}", stem, counter, correct_ext));
                     dest = assets_dir.join(&fname);
@@ -284,21 +346,24 @@ fn handle_cover_image(prefs_val: &mut JsonValue, app_handle: &AppHandle) -> Resu
     Ok(())
 }
 
-/// Setup preferences for rendering: read canonical prefs.json, handle cover image,
-/// write to build directory, and emit debug events.
-pub fn setup_prefs(config: &RenderConfig, path_type: &str) -> Result<PrefsSetupResult> {
-    let canonical_prefs = config.content_dir.join("prefs.json");
-
-    let mut prefs_val = if canonical_prefs.exists() {
-        let txt = fs::read_to_string(&canonical_prefs)?;
-        config.app_handle.emit("prefs-dump", &txt).ok();
-        serde_json::from_str::<JsonValue>(&txt)?
-    } else {
-        JsonValue::Object(serde_json::Map::new())
-    };
+/// Setup preferences for rendering: read canonical prefs.json, apply the document's front
+/// matter overrides, handle cover image, write to build directory, and emit debug events.
+pub fn setup_prefs(
+    config: &RenderConfig,
+    path_type: &str,
+    overrides: &serde_json::Map<String, JsonValue>,
+) -> Result<PrefsSetupResult> {
+    let mut prefs_val = config.env.preferences()?;
+    if !overrides.is_empty() {
+        merge_json(&mut prefs_val, JsonValue::Object(overrides.clone()));
+    }
 
     // Handle cover image rewriting
-    handle_cover_image(&mut prefs_val, config.app_handle)?;
+    handle_cover_image(&mut prefs_val, config.env)?;
+
+    // Copy bibliography files next to the template and point the preferences at the copies
+    let bibliography =
+        Bibliography::install(&mut prefs_val, &config.content_dir, &config.build_dir)?;
 
     // Emit render-debug event
     let toc_flag = prefs_val
@@ -316,54 +381,30 @@ pub fn setup_prefs(config: &RenderConfig, path_type: &str) -> Result<PrefsSetupR
         "papersize": prefs_val.get("papersize"),
         "timestamp": chrono::Utc::now().to_rfc3339(),
     });
-    config.app_handle.emit("render-debug", dbg).ok();
+    config.env.emit("render-debug", dbg);
 
     // Write to build directory
     let prefs_json = serde_json::to_string_pretty(&prefs_val)?;
-    fs::write(config.build_dir.join("prefs.json"), &prefs_json)?;
+    write_if_changed(&config.build_dir.join("prefs.json"), prefs_json.as_bytes())?;
 
     Ok(PrefsSetupResult {
         prefs_json: prefs_val,
+        bibliography,
     })
 }
 
 /// Setup template for rendering: copy tideflow.typ and sync theme assets,
 /// emit template inspection events.
 pub fn setup_template(config: &RenderConfig, path_type: &str) -> Result<()> {
-    // Determine template source (prefer dev workspace during development)
-    let mut template_src = if let Ok(cwd) = std::env::current_dir() {
-        let dev_tpl = cwd.join("src-tauri").join("content").join("tideflow.typ");
-        if dev_tpl.exists() {
-            dev_tpl
-        } else {
-            config.content_dir.join("tideflow.typ")
-        }
-    } else {
-        config.content_dir.join("tideflow.typ")
-    };
-
-    if !template_src.exists() {
-        // Attempt to restore the template from resources into the user content directory
-        if let Ok(restored) = utils::ensure_tideflow_template_exists(config.app_handle) {
-            if restored.exists() {
-                template_src = restored;
-            }
-        }
-    }
-
-    if !template_src.exists() {
-        return Err(anyhow!(
-            "tideflow.typ template not found at {}",
-            template_src.display()
-        ));
-    }
-
-    // Copy template to build directory
-    let template_dst = config.build_dir.join("tideflow.typ");
-    fs::copy(&template_src, &template_dst)?;
+    let template_src = config.env.template_path()?;
+    let shared_dir = build_workspace::shared_dir(&config.env.build_dir()?);
 
-    // Sync theme assets
-    sync_theme_assets(&template_src, &config.build_dir)?;
+    install_template(
+        &template_src,
+        &config.build_dir,
+        &shared_dir,
+        &config.typst_root,
+    )?;
 
     // Emit template inspection event
     if let Ok(tpl_txt) = fs::read_to_string(&template_src) {
@@ -376,7 +417,7 @@ pub fn setup_template(config: &RenderConfig, path_type: &str) -> Result<()> {
             "snippet": snippet,
             "timestamp": chrono::Utc::now().to_rfc3339()
         });
-        config.app_handle.emit("template-inspect", evt).ok();
+        config.env.emit("template-inspect", evt);
 
         if !has_conditional {
             let warn = serde_json::json!({
@@ -384,27 +425,171 @@ pub fn setup_template(config: &RenderConfig, path_type: &str) -> Result<()> {
                 "template_path": template_src.to_string_lossy(),
                 "timestamp": chrono::Utc::now().to_rfc3339()
             });
-            config.app_handle.emit("template-warning", warn).ok();
+            config.env.emit("template-warning", warn);
+        }
+    }
+
+    Ok(())
+}
+
+lazy_static::lazy_static! {
+    // Serializes updates of the shared theme directory between concurrent jobs.
+    static ref SHARED_THEMES_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+}
+
+/// Copy the template into the job's build directory. Its theme directory is mirrored once into
+/// `shared_dir` and the template's `themes/` import is rewritten to that root-relative path, so
+/// jobs never write theme files another job is compiling against. When `shared_dir` is outside
+/// `typst_root` (and so cannot be imported), the themes are copied next to the template instead.
+pub(crate) fn install_template(
+    template_src: &Path,
+    build_dir: &Path,
+    shared_dir: &Path,
+    typst_root: &Path,
+) -> Result<()> {
+    let mut template = fs::read_to_string(template_src)?;
+
+    let themes_src = template_src.parent().map(|parent| parent.join("themes"));
+    if let Some(themes_src) = themes_src.filter(|dir| dir.exists()) {
+        match shared_dir.strip_prefix(typst_root) {
+            Ok(relative) => {
+                {
+                    let _guard = SHARED_THEMES_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+                    sync_directory(&themes_src, &shared_dir.join("themes"))?;
+                }
+                let shared_import = format!(
+                    "#import \"/{}/themes/",
+                    relative.to_string_lossy().replace('\\', "/")
+                );
+                template = template.replace("#import \"themes/", &shared_import);
+            }
+            Err(_) => sync_directory(&themes_src, &build_dir.join("themes"))?,
         }
     }
 
+    write_if_changed(&build_dir.join("tideflow.typ"), template.as_bytes())?;
     Ok(())
 }
 
-/// Compile Typst to PDF with proper error handling and timeout
-pub fn compile_typst(config: &RenderConfig, typst_path: &Path, output_file: &str) -> Result<()> {
-    ensure_cmarker_assets(config);
-    let package_env = typst_package_env(config);
+/// Which compiler turns `tideflow.typ` into a PDF.
+pub enum TypstBackend {
+    /// In-process compiler with a persistent world (requires the `embedded-typst` feature).
+    Embedded,
+    /// The `typst` command line binary at this path.
+    External(PathBuf),
+}
+
+impl TypstBackend {
+    /// Pick the backend from the `typst_backend` preference ("embedded" or "external").
+    /// Builds without the embedded compiler always use the external binary.
+    pub fn select(config: &RenderConfig, prefs: &JsonValue) -> Result<Self> {
+        let requested = prefs
+            .get("typst_backend")
+            .and_then(|v| v.as_str())
+            .unwrap_or("embedded");
+        if requested != "external" && cfg!(feature = "embedded-typst") {
+            return Ok(TypstBackend::Embedded);
+        }
+
+        let typst_path = config
+            .env
+            .typst_path()
+            .context("Typst binary not found. Please install Typst system-wide or download and place in bin/typst/<platform>/ directory.")?;
+        Ok(TypstBackend::External(typst_path))
+    }
+}
+
+/// Output of a Typst compile. The embedded backend keeps the laid-out document so anchor
+/// positions can be read directly instead of running `typst query`.
+#[derive(Default)]
+pub struct CompiledTypst {
+    #[cfg(feature = "embedded-typst")]
+    document: Option<typst::layout::PagedDocument>,
+}
+
+impl CompiledTypst {
+    /// Positions of the given labels, or `None` if the document is only available as a PDF.
+    #[cfg_attr(not(feature = "embedded-typst"), allow(unused_variables))]
+    pub fn label_positions(&self, labels: &[&str]) -> Option<HashMap<String, PdfPosition>> {
+        #[cfg(feature = "embedded-typst")]
+        {
+            if let Some(document) = self.document.as_ref() {
+                return Some(crate::typst_world::label_positions(document, labels));
+            }
+        }
+        None
+    }
+}
+
+/// Compile Typst to PDF with the selected backend
+pub fn compile_typst(
+    config: &RenderConfig,
+    backend: &TypstBackend,
+    output_file: &str,
+) -> Result<CompiledTypst> {
+    match backend {
+        TypstBackend::Embedded => compile_embedded(config, output_file),
+        TypstBackend::External(typst_path) => {
+            ensure_cmarker_assets(config);
+            let package_env = typst_package_env(config);
+            compile_typst_with(
+                typst_path,
+                &config.build_dir,
+                &config.typst_root,
+                package_env.as_deref(),
+                output_file,
+                &config.cancel,
+            )?;
+            Ok(CompiledTypst::default())
+        }
+    }
+}
+
+#[cfg(feature = "embedded-typst")]
+fn compile_embedded(config: &RenderConfig, output_file: &str) -> Result<CompiledTypst> {
+    let package_paths = collect_typst_package_paths(config);
+    let document = crate::typst_world::compile_pdf(
+        &config.build_dir,
+        &config.typst_root,
+        &package_paths,
+        output_file,
+    )?;
+    // In-process compiles cannot be interrupted (see `render_scheduler`); drop the result if it
+    // is already stale.
+    config.cancel.check()?;
+    Ok(CompiledTypst {
+        document: Some(document),
+    })
+}
+
+#[cfg(not(feature = "embedded-typst"))]
+fn compile_embedded(_config: &RenderConfig, _output_file: &str) -> Result<CompiledTypst> {
+    Err(anyhow!(
+        "This build does not include the embedded Typst compiler; set typst_backend to \"external\""
+    ))
+}
 
+/// Run `typst compile` for `tideflow.typ` inside `build_dir` without needing an `AppHandle`.
+/// The child is killed as soon as `cancel` is tripped.
+pub(crate) fn compile_typst_with(
+    typst_path: &Path,
+    build_dir: &Path,
+    typst_root: &Path,
+    package_env: Option<&str>,
+    output_file: &str,
+    cancel: &CancelToken,
+) -> Result<()> {
     // Spawn process with timeout (30 seconds)
     use std::time::Duration;
 
     let mut command = typst_command(typst_path);
-    command.current_dir(&config.build_dir);
+    command.current_dir(build_dir);
     command.args([
         "compile",
+        "--diagnostic-format",
+        "short",
         "--root",
-        config.typst_root.to_string_lossy().as_ref(),
+        typst_root.to_string_lossy().as_ref(),
         "tideflow.typ",
         output_file,
     ]);
@@ -426,11 +611,16 @@ pub fn compile_typst(config: &RenderConfig, typst_path: &Path, output_file: &str
         match child.try_wait()? {
             Some(status) => break status,
             None => {
+                if cancel.is_cancelled() {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(RenderCancelled.into());
+                }
                 if start.elapsed() > timeout {
                     child.kill()?;
                     return Err(anyhow!("Typst compilation timeout after 30 seconds"));
                 }
-                std::thread::sleep(Duration::from_millis(100));
+                std::thread::sleep(Duration::from_millis(20));
             }
         }
     };
@@ -450,15 +640,19 @@ pub fn compile_typst(config: &RenderConfig, typst_path: &Path, output_file: &str
         let stdout_str = String::from_utf8_lossy(&stdout);
         let stderr_str = String::from_utf8_lossy(&stderr);
 
-        return Err(anyhow!(
-            "Typst compile failed (status {}).\nSTDOUT:\n{}\nSTDERR:\n{}",
-            status,
-            stdout_str.trim(),
-            stderr_str.trim()
-        ));
+        return Err(TypstCompileError {
+            output: format!(
+                "Typst compile failed (status {}).\nSTDOUT:\n{}\nSTDERR:\n{}",
+                status,
+                stdout_str.trim(),
+                stderr_str.trim()
+            ),
+            diagnostics: diagnostics::parse_short(&stderr_str),
+        }
+        .into());
     }
 
-    let output_path = config.build_dir.join(output_file);
+    let output_path = build_dir.join(output_file);
     if !output_path.exists() {
         return Err(anyhow!(
             "Typst compile completed but PDF missing at {}",
@@ -468,3 +662,73 @@ pub fn compile_typst(config: &RenderConfig, typst_path: &Path, output_file: &str
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::FsEnvironment;
+    use crate::front_matter;
+
+    const PNG: &[u8] = &[0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0];
+
+    /// Run the preference setup of two consecutive renders with `cover_image` set in front
+    /// matter, returning the rewritten cover paths and the files left in `assets/`.
+    fn render_cover_twice(root: &Path, cover_image: &str) -> (Vec<String>, Vec<String>) {
+        let env = FsEnvironment::new(root);
+        let config = RenderConfig {
+            env: &env,
+            build_dir: root.join(".build"),
+            content_dir: root.to_path_buf(),
+            typst_root: root.to_path_buf(),
+            cancel: CancelToken::default(),
+        };
+        fs::create_dir_all(&config.build_dir).unwrap();
+
+        let mut covers = Vec::new();
+        for _ in 0..2 {
+            let mut overrides = serde_json::Map::new();
+            overrides.insert("cover_image".to_string(), cover_image.into());
+            front_matter::resolve_relative_paths(&mut overrides, root);
+            let prefs = setup_prefs(&config, "test", &overrides).unwrap();
+            covers.push(
+                prefs.prefs_json["cover_image"]
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+
+        let mut assets: Vec<String> = fs::read_dir(env.assets_dir().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assets.sort();
+        (covers, assets)
+    }
+
+    #[test]
+    fn front_matter_cover_is_copied_to_assets_once() {
+        let root = std::env::temp_dir().join(format!("tf-pipeline-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("cover.png"), PNG).unwrap();
+
+        let (covers, assets) = render_cover_twice(&root, "cover.png");
+        assert_eq!(covers, vec!["/assets/cover.png", "/assets/cover.png"]);
+        assert_eq!(assets, vec!["cover.png"]);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn front_matter_cover_already_in_assets_is_reused() {
+        let root = std::env::temp_dir().join(format!("tf-pipeline-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(root.join("assets")).unwrap();
+        fs::write(root.join("assets").join("cover.png"), PNG).unwrap();
+
+        let (covers, assets) = render_cover_twice(&root, "assets/cover.png");
+        assert_eq!(covers, vec!["/assets/cover.png", "/assets/cover.png"]);
+        assert_eq!(assets, vec!["cover.png"]);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
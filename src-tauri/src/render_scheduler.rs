@@ -0,0 +1,330 @@
+//! Latest-wins scheduling for renders.
+//!
+//! Each job writes to its own build workspace (see `build_workspace`), so jobs for different
+//! documents, or a preview and an export of the same document, run in parallel up to
+//! [`MAX_PARALLEL_RENDERS`]. Preview requests are coalesced per document: a newer preview
+//! supersedes any older one for the same document, whether it is still waiting (it returns
+//! [`RenderCancelled`] without running) or already compiling (its [`CancelToken`] is tripped,
+//! and the `typst` and diagram tool children are killed). The embedded compiler cannot be
+//! interrupted: a superseded preview keeps its slot until that compile finishes, and only
+//! then returns [`RenderCancelled`]. Exports are never cancelled, run one at a time per
+//! document, and are handed free render capacity before waiting previews.
+
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+/// Renders allowed to compile at the same time.
+const MAX_PARALLEL_RENDERS: usize = 4;
+
+/// Returned by renders that were superseded by a newer preview of the same document.
+#[derive(Debug, Error)]
+#[error("Render cancelled: a newer preview of this document was requested")]
+pub struct RenderCancelled;
+
+/// Shared flag checked by long-running render steps.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fail with [`RenderCancelled`] once the token has been tripped.
+    pub fn check(&self) -> Result<(), RenderCancelled> {
+        if self.is_cancelled() {
+            Err(RenderCancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// What a caller wants to run. Documents are keyed by path; empty for unsaved content.
+pub enum RenderJob<'a> {
+    /// Live preview of a document.
+    Preview(&'a str),
+    /// Final export (PDF or images) of a document; never cancelled.
+    Export(&'a str),
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    running: usize,
+    exports_waiting: usize,
+    next_ticket: u64,
+    /// Newest preview ticket per document; older tickets are stale.
+    latest_preview: HashMap<String, u64>,
+    /// Previews currently holding a slot, by document.
+    running_previews: HashMap<String, CancelToken>,
+    /// Documents with an export currently holding a slot.
+    running_exports: HashSet<String>,
+}
+
+struct RenderScheduler {
+    state: Mutex<SchedulerState>,
+    notify: Notify,
+}
+
+lazy_static! {
+    static ref SCHEDULER: RenderScheduler = RenderScheduler {
+        state: Mutex::new(SchedulerState::default()),
+        notify: Notify::new(),
+    };
+}
+
+enum SlotOwner {
+    Preview { document: String, ticket: u64 },
+    Export { document: String },
+}
+
+/// Exclusive right to use a document's workspace for one job kind; released on drop.
+pub struct RenderSlot {
+    token: CancelToken,
+    owner: SlotOwner,
+}
+
+impl RenderSlot {
+    pub fn token(&self) -> &CancelToken {
+        &self.token
+    }
+}
+
+impl Drop for RenderSlot {
+    fn drop(&mut self) {
+        let mut state = lock_state();
+        state.running -= 1;
+        match &self.owner {
+            SlotOwner::Preview { document, ticket } => {
+                state.running_previews.remove(document);
+                if state.latest_preview.get(document) == Some(ticket) {
+                    state.latest_preview.remove(document);
+                }
+            }
+            SlotOwner::Export { document } => {
+                state.running_exports.remove(document);
+            }
+        }
+        drop(state);
+        SCHEDULER.notify.notify_waiters();
+    }
+}
+
+fn lock_state() -> MutexGuard<'static, SchedulerState> {
+    SCHEDULER.state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Wait for a render slot. Previews fail with [`RenderCancelled`] if a newer preview of the
+/// same document arrives before they get to run.
+pub async fn acquire(job: RenderJob<'_>) -> Result<RenderSlot, RenderCancelled> {
+    match job {
+        RenderJob::Preview(document) => acquire_preview(document).await,
+        RenderJob::Export(document) => Ok(acquire_export(document).await),
+    }
+}
+
+async fn acquire_preview(document: &str) -> Result<RenderSlot, RenderCancelled> {
+    let ticket = {
+        let mut state = lock_state();
+        state.next_ticket += 1;
+        let ticket = state.next_ticket;
+        state.latest_preview.insert(document.to_string(), ticket);
+        // Supersede the in-flight preview of this document, if any.
+        if let Some(token) = state.running_previews.get(document) {
+            token.cancel();
+        }
+        ticket
+    };
+    // Wake older waiters for this document so they can bow out.
+    SCHEDULER.notify.notify_waiters();
+
+    loop {
+        // Register before checking so a release between the check and the await is not missed.
+        let notified = SCHEDULER.notify.notified();
+        {
+            let mut state = lock_state();
+            if state.latest_preview.get(document) != Some(&ticket) {
+                return Err(RenderCancelled);
+            }
+            // Capacity claimed by waiting exports is not available to previews.
+            let has_capacity = state.running + state.exports_waiting < MAX_PARALLEL_RENDERS;
+            if has_capacity && !state.running_previews.contains_key(document) {
+                let token = CancelToken::default();
+                state.running += 1;
+                state
+                    .running_previews
+                    .insert(document.to_string(), token.clone());
+                return Ok(RenderSlot {
+                    token,
+                    owner: SlotOwner::Preview {
+                        document: document.to_string(),
+                        ticket,
+                    },
+                });
+            }
+        }
+        notified.await;
+    }
+}
+
+async fn acquire_export(document: &str) -> RenderSlot {
+    let _waiting = ExportWaiting::register();
+    loop {
+        let notified = SCHEDULER.notify.notified();
+        {
+            let mut state = lock_state();
+            if state.running < MAX_PARALLEL_RENDERS && !state.running_exports.contains(document) {
+                state.running += 1;
+                state.running_exports.insert(document.to_string());
+                break;
+            }
+        }
+        notified.await;
+    }
+    RenderSlot {
+        token: CancelToken::default(),
+        owner: SlotOwner::Export {
+            document: document.to_string(),
+        },
+    }
+}
+
+/// Reserves capacity for a waiting export, even if the waiting task is dropped.
+struct ExportWaiting;
+
+impl ExportWaiting {
+    fn register() -> Self {
+        lock_state().exports_waiting += 1;
+        ExportWaiting
+    }
+}
+
+impl Drop for ExportWaiting {
+    fn drop(&mut self) {
+        lock_state().exports_waiting -= 1;
+        SCHEDULER.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    lazy_static! {
+        // The scheduler is process-wide; tests that fill its capacity must not overlap.
+        static ref SERIAL: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    }
+
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn document() -> String {
+        format!("scheduler-test-{}", uuid::Uuid::new_v4())
+    }
+
+    #[tokio::test]
+    async fn newer_previews_cancel_the_running_one() {
+        let _serial = SERIAL.lock().await;
+        let doc = document();
+
+        let running = acquire(RenderJob::Preview(&doc)).await.unwrap();
+        let newer_doc = doc.clone();
+        let newer =
+            tokio::spawn(async move { acquire(RenderJob::Preview(&newer_doc)).await.map(drop) });
+        settle().await;
+        assert!(running.token().is_cancelled());
+
+        drop(running);
+        newer.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiting_previews_are_dropped_for_newer_ones() {
+        let _serial = SERIAL.lock().await;
+        let doc = document();
+
+        let running = acquire(RenderJob::Preview(&doc)).await.unwrap();
+        let waiting_doc = doc.clone();
+        let waiting =
+            tokio::spawn(async move { acquire(RenderJob::Preview(&waiting_doc)).await.map(drop) });
+        settle().await;
+        let newest_doc = doc.clone();
+        let newest =
+            tokio::spawn(async move { acquire(RenderJob::Preview(&newest_doc)).await.map(drop) });
+        settle().await;
+        assert!(waiting.await.unwrap().is_err());
+
+        drop(running);
+        newest.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_documents_and_exports_run_alongside_a_preview() {
+        let _serial = SERIAL.lock().await;
+        let doc = document();
+
+        let running = acquire(RenderJob::Preview(&doc)).await.unwrap();
+        let other = acquire(RenderJob::Preview(&document())).await.unwrap();
+        let export = acquire(RenderJob::Export(&doc)).await.unwrap();
+        assert!(!running.token().is_cancelled());
+        assert!(!other.token().is_cancelled());
+
+        drop((running, other, export));
+    }
+
+    #[tokio::test]
+    async fn exports_of_a_document_run_one_at_a_time() {
+        let _serial = SERIAL.lock().await;
+        let doc = document();
+
+        let export = acquire(RenderJob::Export(&doc)).await.unwrap();
+        let export_doc = doc.clone();
+        let second_export = tokio::spawn(async move {
+            drop(acquire(RenderJob::Export(&export_doc)).await.unwrap());
+        });
+        settle().await;
+        assert!(!second_export.is_finished());
+
+        drop(export);
+        second_export.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiting_exports_go_ahead_of_queued_previews() {
+        let _serial = SERIAL.lock().await;
+        let mut running = Vec::new();
+        for _ in 0..MAX_PARALLEL_RENDERS {
+            running.push(acquire(RenderJob::Preview(&document())).await.unwrap());
+        }
+
+        // The export task keeps its slot until the test drops it.
+        let export_doc = document();
+        let export = tokio::spawn(async move { acquire(RenderJob::Export(&export_doc)).await });
+        settle().await;
+        let preview_doc = document();
+        let preview =
+            tokio::spawn(async move { acquire(RenderJob::Preview(&preview_doc)).await.map(drop) });
+        settle().await;
+
+        running.pop();
+        settle().await;
+        assert!(export.is_finished());
+        assert!(!preview.is_finished());
+
+        let export = export.await.unwrap().unwrap();
+        running.clear();
+        drop(export);
+        preview.await.unwrap().unwrap();
+    }
+}
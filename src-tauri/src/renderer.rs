@@ -1,10 +1,19 @@
+use crate::build_workspace::{self, JobKind};
+use crate::citations;
+use crate::diagnostics::{self, TypstCompileError};
+use crate::diagrams::{self, DiagramRegistry, DiagramSettings};
+use crate::edit_map;
+use crate::environment::RenderEnvironment;
+use crate::front_matter;
 use crate::preprocessor::{
     attach_pdf_positions, pdf_positions_from_query, preprocess_markdown, AnchorMeta, PdfPosition,
-    SourceMapPayload,
+    PreprocessorOutput, SourceMapPayload,
 };
-use crate::render_pipeline::{self, RenderConfig};
+use crate::preview_session::{self, PhaseTimer, RenderTimings};
+use crate::render_cache::RenderCache;
+use crate::render_pipeline::{self, CompiledTypst, RenderConfig, TypstBackend};
+use crate::render_scheduler::{self, CancelToken, RenderJob};
 use crate::tex;
-use crate::tikz;
 use crate::utils;
 use anyhow::{anyhow, Context, Result};
 use serde::Serialize;
@@ -14,18 +23,86 @@ use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::SystemTime;
-use tauri::{AppHandle, Emitter};
 use tokio::sync::Mutex;
 
+/// Output of the compiles used to locate diagnostics.
+const PROBE_PDF: &str = "probe.pdf";
+
 #[derive(Debug, Serialize)]
 pub struct RenderedDocument {
     pub pdf_path: String,
     pub source_map: SourceMapPayload,
+    /// Per-phase timings for preview renders.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<RenderTimings>,
+    /// Non-fatal problems found while preparing the document (e.g. unknown front matter keys).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
 }
 
-// A global mutex to ensure only one render happens at a time
-lazy_static::lazy_static! {
-    static ref RENDER_MUTEX: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
+/// Point compile diagnostics at the user's document: directly when they fall inside
+/// `content.md`, otherwise, for previews, by recompiling prefixes of it (see
+/// [`diagnostics::locate_by_probing`]). `content.md` is restored afterwards.
+fn locate_diagnostics(
+    mut error: anyhow::Error,
+    config: &RenderConfig,
+    backend: &TypstBackend,
+    preprocess: &PreprocessorOutput,
+    source: &str,
+    kind: JobKind,
+) -> anyhow::Error {
+    let Some(compile_error) = error.downcast_mut::<TypstCompileError>() else {
+        return error;
+    };
+    diagnostics::map_to_source(&mut compile_error.diagnostics, preprocess, source);
+    // Probing costs up to a dozen more compiles. Exports cannot be cancelled by a newer edit,
+    // so they report the error right away; the next preview locates it.
+    if kind != JobKind::Preview {
+        return error;
+    }
+
+    let content_md = config.build_dir.join("content.md");
+    let Ok(installed) = fs::read(&content_md) else {
+        return error;
+    };
+    diagnostics::locate_by_probing(
+        &mut compile_error.diagnostics,
+        preprocess,
+        source,
+        |markdown| {
+            config.cancel.check().ok()?;
+            fs::write(&content_md, markdown).ok()?;
+            match render_pipeline::compile_typst(config, backend, PROBE_PDF) {
+                Ok(_) => Some(Vec::new()),
+                Err(e) => e
+                    .downcast::<TypstCompileError>()
+                    .ok()
+                    .map(|e| e.diagnostics),
+            }
+        },
+    );
+    if let Err(e) = fs::write(&content_md, installed) {
+        println!(
+            "[renderer] warning: failed to restore content.md after locating diagnostics: {}",
+            e
+        );
+    }
+    let _ = fs::remove_file(config.build_dir.join(PROBE_PDF));
+    error
+}
+
+/// Report non-fatal problems with a document to the UI as a `render-warnings` event.
+pub(crate) fn emit_warnings(env: &dyn RenderEnvironment, document: &str, warnings: &[String]) {
+    if warnings.is_empty() {
+        return;
+    }
+    for warning in warnings {
+        println!("[renderer] warning in {}: {}", document, warning);
+    }
+    env.emit(
+        "render-warnings",
+        serde_json::json!({ "file": document, "warnings": warnings }),
+    );
 }
 
 // Map of file paths to last modification time to avoid duplicate renders
@@ -34,26 +111,44 @@ lazy_static::lazy_static! {
         Arc::new(Mutex::new(std::collections::HashMap::new()));
 }
 
+// External images, which cmarker cannot fetch
+lazy_static::lazy_static! {
+    static ref EXTERNAL_MARKDOWN_IMAGE: regex::Regex =
+        regex::Regex::new(r"!\[[^\]]*\]\(https?://[^)]+\)")
+            .expect("BUG: Invalid regex pattern for external markdown images");
+    static ref EXTERNAL_HTML_IMAGE: regex::Regex =
+        regex::Regex::new(r#"<img[^>]*src=["']https?://[^"']+["'][^>]*>"#)
+            .expect("BUG: Invalid regex pattern for external HTML images");
+}
+
 fn build_source_map(
-    app_handle: &AppHandle,
-    typst_path: &Path,
-    build_dir: &Path,
-    content_dir: &Path,
+    config: &RenderConfig,
+    backend: &TypstBackend,
+    compiled: &CompiledTypst,
     anchors: &[AnchorMeta],
 ) -> SourceMapPayload {
+    let env = config.env;
+    let build_dir = config.build_dir.as_path();
     if anchors.is_empty() {
         return SourceMapPayload::default();
     }
 
+    // The embedded compiler still has the laid-out document; read label positions directly.
+    let labels: Vec<&str> = anchors.iter().map(|anchor| anchor.id.as_str()).collect();
+    if let Some(pdf_lookup) = compiled.label_positions(&labels) {
+        if pdf_lookup.is_empty() {
+            env.emit("typst-query-failed", "no-positions-found".into());
+        }
+        return attach_pdf_positions(anchors, &pdf_lookup);
+    }
+
     let mut pdf_lookup: HashMap<String, PdfPosition> = HashMap::new();
-    let root_arg = content_dir.to_string_lossy().to_string();
-    let render_config = RenderConfig {
-        app_handle,
-        build_dir: build_dir.to_path_buf(),
-        content_dir: content_dir.to_path_buf(),
-        typst_root: content_dir.to_path_buf(),
+    let TypstBackend::External(typst_path) = backend else {
+        env.emit("typst-query-failed", "no-positions-found".into());
+        return attach_pdf_positions(anchors, &pdf_lookup);
     };
-    let package_env = render_pipeline::typst_package_env(&render_config);
+    let root_arg = config.typst_root.to_string_lossy().to_string();
+    let package_env = render_pipeline::typst_package_env(config);
     // If the Typst binary is an older 0.13.x release, its `query` selector
     // syntax differs from newer releases and several selector variants we
     // might try here will fail with errors such as "unknown variable:
@@ -63,9 +158,9 @@ fn build_source_map(
     //
     // We detect this by running `typst --version` and checking for
     // "0.13." in the output.
-    let mut version_cmd = render_pipeline::typst_command(&typst_path);
-    if let Some(env) = package_env.as_ref() {
-        version_cmd.env("TYPST_PACKAGE_PATH", env);
+    let mut version_cmd = render_pipeline::typst_command(typst_path);
+    if let Some(package_path) = package_env.as_ref() {
+        version_cmd.env("TYPST_PACKAGE_PATH", package_path);
     }
     if let Ok(ver_out) = version_cmd.arg("--version").output() {
         if ver_out.status.success() {
@@ -77,7 +172,7 @@ fn build_source_map(
                 if !LOGGED_ONCE.swap(true, Ordering::Relaxed) {
                     println!("[renderer] detected Typst version 0.13.x ({}); skipping typst query for all renders", ver_txt.trim());
                 }
-                let _ = app_handle.emit("typst-query-failed", "typst-0.13-incompatible");
+                env.emit("typst-query-failed", "typst-0.13-incompatible".into());
                 return attach_pdf_positions(anchors, &pdf_lookup);
             }
         }
@@ -94,6 +189,10 @@ fn build_source_map(
     ];
     let mut tried_any = false;
     for selector in selector_variants.iter() {
+        // A newer preview is waiting; the positions would be thrown away anyway.
+        if config.cancel.is_cancelled() {
+            break;
+        }
         let args = [
             "query",
             "--format",
@@ -104,9 +203,9 @@ fn build_source_map(
             selector,
         ];
         println!("[renderer] running typst query args: {:?}", args);
-        let mut query_cmd = render_pipeline::typst_command(&typst_path);
-        if let Some(env) = package_env.as_ref() {
-            query_cmd.env("TYPST_PACKAGE_PATH", env);
+        let mut query_cmd = render_pipeline::typst_command(typst_path);
+        if let Some(package_path) = package_env.as_ref() {
+            query_cmd.env("TYPST_PACKAGE_PATH", package_path);
         }
         let query_result = query_cmd.current_dir(build_dir).args(&args).output();
 
@@ -130,7 +229,7 @@ fn build_source_map(
                 );
             }
             // Emit stdout to the frontend for easier debugging in DevTools
-            let _ = app_handle.emit("typst-query-stdout", stdout_txt.clone());
+            env.emit("typst-query-stdout", stdout_txt.clone().into());
             if let Err(e) = std::fs::write(&err_dump, stderr_txt.as_bytes()) {
                 println!("[renderer] failed to write typst query stderr dump: {}", e);
             } else {
@@ -140,7 +239,7 @@ fn build_source_map(
                 );
             }
             // Emit stderr to the frontend so the UI can show precise Typst errors
-            let _ = app_handle.emit("typst-query-stderr", stderr_txt.clone());
+            env.emit("typst-query-stderr", stderr_txt.clone().into());
 
             // If stderr indicates a selector-syntax incompatibility (common in
             // older Typst releases), stop trying additional selectors and
@@ -151,7 +250,7 @@ fn build_source_map(
                 || stderr_txt.contains("only element functions can be used as selectors")
             {
                 println!("[renderer] typst query stderr indicates incompatible selector syntax; emitting typst-query-failed and aborting selector loop");
-                let _ = app_handle.emit("typst-query-failed", stderr_txt.clone());
+                env.emit("typst-query-failed", stderr_txt.clone().into());
                 return attach_pdf_positions(anchors, &pdf_lookup);
             }
 
@@ -204,15 +303,18 @@ fn build_source_map(
     // the frontend can fall back to PDF-text extraction immediately.
     if pdf_lookup.is_empty() {
         // Emit an event so the frontend can fall back to PDF-text extraction.
-        // Use the provided app_handle reference; emission failures are ignored.
-        let _ = app_handle.emit("typst-query-failed", "no-positions-found");
+        // Emission failures are ignored by the environment.
+        env.emit("typst-query-failed", "no-positions-found".into());
     }
 
     attach_pdf_positions(anchors, &pdf_lookup)
 }
 
 /// Renders a Markdown file to PDF using Typst
-pub async fn render_markdown(app_handle: &AppHandle, file_path: &str) -> Result<RenderedDocument> {
+pub async fn render_markdown(
+    env: Arc<dyn RenderEnvironment>,
+    file_path: &str,
+) -> Result<RenderedDocument> {
     let path = Path::new(file_path);
 
     // Only render markdown files
@@ -229,79 +331,193 @@ pub async fn render_markdown(app_handle: &AppHandle, file_path: &str) -> Result<
         return Err(anyhow!("File does not exist: {}", file_path));
     }
 
-    // Acquire render lock to prevent multiple simultaneous renders
-    let _lock = RENDER_MUTEX.lock().await;
-
     // Check if file has been modified since last render
     let metadata = fs::metadata(file_path)?;
     let mod_time = metadata.modified()?;
 
     // NOTE: Removed optimization that skipped rendering when file timestamp unchanged.
     // Preferences can change without touching the markdown file; we still need a fresh render.
-    let mut last_render_times = LAST_RENDER_TIMES.lock().await;
 
-    // Use Typst to render for preview
-    let content_dir = utils::get_content_dir(app_handle)?;
-    let build_dir = content_dir.join(".build");
-    fs::create_dir_all(&build_dir)?;
+    let md_content_raw = fs::read_to_string(path)?;
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let document = render_markdown_source(env, file_path, &md_content_raw, base_dir).await?;
+
+    // Update last render time
+    LAST_RENDER_TIMES
+        .lock()
+        .await
+        .insert(file_path.to_string(), mod_time);
+
+    Ok(document)
+}
+
+/// Render the markdown `md_content_raw` of `file_path` (which keys the preview workspace and
+/// session; it need not exist on disk), resolving relative paths against `base_dir`.
+pub(crate) async fn render_markdown_source(
+    env: Arc<dyn RenderEnvironment>,
+    file_path: &str,
+    md_content_raw: &str,
+    base_dir: &Path,
+) -> Result<RenderedDocument> {
+    // Wait for the render slot; a newer preview of this file supersedes this one
+    let slot = render_scheduler::acquire(RenderJob::Preview(file_path)).await?;
+
+    // Preprocessing, diagrams and compiling block, so they run off the async runtime's threads
+    let cancel = slot.token().clone();
+    let (file_path, md_content_raw) = (file_path.to_string(), md_content_raw.to_string());
+    let base_dir = base_dir.to_path_buf();
+    let document = tauri::async_runtime::spawn_blocking(move || {
+        render_markdown_blocking(env.as_ref(), &file_path, &md_content_raw, &base_dir, cancel)
+    })
+    .await??;
+    drop(slot);
+    Ok(document)
+}
+
+/// The blocking part of [`render_markdown_source`], run while holding the render slot.
+fn render_markdown_blocking(
+    env: &dyn RenderEnvironment,
+    file_path: &str,
+    md_content_raw: &str,
+    base_dir: &Path,
+    cancel: CancelToken,
+) -> Result<RenderedDocument> {
+    // Use Typst to render for preview, in this document's own preview workspace
+    let content_dir = env.content_dir()?;
+    let build_dir = build_workspace::prepare(env, file_path, JobKind::Preview)?;
 
     // Setup render configuration
     let config = RenderConfig {
-        app_handle,
+        env,
         build_dir: build_dir.clone(),
         content_dir: content_dir.clone(),
         typst_root: content_dir.clone(),
+        cancel,
     };
 
-    // Setup preferences (handles cover image rewriting and debug events)
-    render_pipeline::setup_prefs(&config, "markdown")?;
+    let mut timer = PhaseTimer::start();
+    let mut timings = RenderTimings::default();
 
     // 2) Copy the markdown content to build/content.md (with preprocessing + image path rewrites)
     // We write two files:
     // - content.md (clean, no preview-only tokens) used for export and canonical build state
     // - content.preview.md (preview-only, includes non-printing/preview tokens next to anchors)
-    // During preview compilation we temporarily copy content.preview.md over content.md so the
+    // During preview compilation we temporarily install content.preview.md as content.md so the
     // template and Typst query can see the preview-only tokens. Export remains untouched.
-    let md_content_raw = fs::read_to_string(path)?;
-    let base_dir = path.parent().unwrap_or(Path::new("."));
     // Resolve assets/ paths to the global content/assets directory so images work from any doc folder
-    let assets_root = utils::get_assets_dir(app_handle).ok();
+    let assets_root = env.assets_dir().ok();
     let assets_root_ref = assets_root.as_deref();
 
-    // Clean (export) version: do NOT inject visible tokens
-    let preprocess = preprocess_markdown(&md_content_raw)?;
-    let md_content_clean =
-        utils::rewrite_image_paths_in_markdown(&preprocess.markdown, base_dir, assets_root_ref);
-    fs::write(build_dir.join("content.md"), &md_content_clean)?;
+    let diagrams = DiagramRegistry::load(env);
+
+    // Clean (export) version: do NOT inject visible tokens. Reused from the preview session
+    // when neither the source nor the path inputs changed since the last render.
+    let (mut prepared, preprocess_cached) = preview_session::prepare_content(
+        file_path,
+        &[
+            "markdown",
+            md_content_raw,
+            &base_dir.to_string_lossy(),
+            &assets_root_ref
+                .map(|p| p.to_string_lossy())
+                .unwrap_or_default(),
+            &diagrams.languages().join(" "),
+            &diagrams.has_dvisvgm().to_string(),
+        ],
+        || {
+            let mut preprocess = preprocess_markdown(
+                md_content_raw,
+                Some(Path::new(file_path)),
+                base_dir,
+                &diagrams,
+            )?;
+            front_matter::resolve_relative_paths(&mut preprocess.prefs_overrides, base_dir);
+            preprocess.transform(|markdown| {
+                utils::rewrite_image_paths_in_markdown(markdown, base_dir, assets_root_ref)
+            });
+            Ok(preprocess)
+        },
+    )?;
+
+    // Setup preferences with the document's front matter applied (handles cover image
+    // rewriting, bibliography files and debug events), then resolve citations against them
+    let prefs = render_pipeline::setup_prefs(&config, "markdown", &prepared.prefs_overrides)?;
+    citations::apply(&mut prepared, md_content_raw, &prefs.bibliography);
+    let preprocess = &prepared;
+    let md_content_clean = &preprocess.markdown;
+    emit_warnings(env, file_path, &preprocess.warnings);
+    let content_md = build_dir.join("content.md");
+    render_pipeline::write_if_changed(&content_md, md_content_clean.as_bytes())?;
 
     // Preview version currently mirrors clean content (hooks exist for future preview tokens)
     let md_content_preview = md_content_clean.clone();
-    fs::write(build_dir.join("content.preview.md"), &md_content_preview)?;
+    render_pipeline::write_if_changed(
+        &build_dir.join("content.preview.md"),
+        md_content_preview.as_bytes(),
+    )?;
     // Also write debug copies into workspace for developer inspection
     if let Ok(cwd) = std::env::current_dir() {
         let dbg_dir = cwd.join("src-tauri").join("gen_debug");
-        let _ = std::fs::create_dir_all(&dbg_dir);
-        let _ = std::fs::write(dbg_dir.join("content.md"), &md_content_clean);
-        let _ = std::fs::write(dbg_dir.join("content.preview.md"), &md_content_preview);
+        let _ = render_pipeline::write_if_changed(
+            &dbg_dir.join("content.md"),
+            md_content_clean.as_bytes(),
+        );
+        let _ = render_pipeline::write_if_changed(
+            &dbg_dir.join("content.preview.md"),
+            md_content_preview.as_bytes(),
+        );
     }
 
-    tikz::prepare_tikz_assets(app_handle, &preprocess.tikz_blocks, &build_dir)?;
-
     // Setup template (copies template and syncs theme assets)
     render_pipeline::setup_template(&config, "markdown")?;
+    timings.preprocess_ms = timer.lap();
+    timings.preprocess_cached = preprocess_cached;
 
-    // 4) Get bundled Typst binary path
-    let typst_path = utils::get_typst_path(app_handle)
-        .context("Typst binary not found. Please install Typst system-wide or download and place in bin/typst/<platform>/ directory.")?;
+    // 4) Pick the Typst backend (embedded compiler or bundled binary)
+    let backend = TypstBackend::select(&config, &prefs.prefs_json)?;
+
+    // Unchanged inputs: serve the previous PDF and anchor positions
+    let preview_pdf = build_dir.join("preview.pdf");
+    let diagram_settings = DiagramSettings::resolve(env, base_dir, &prefs.prefs_json);
+    let cache = RenderCache::open(
+        &config,
+        &backend,
+        &md_content_preview,
+        &prefs.prefs_json,
+        &diagrams.fingerprint(&diagram_settings),
+    );
+    if let Some(positions) = cache
+        .as_ref()
+        .and_then(|cache| cache.fetch_preview(&preview_pdf))
+    {
+        timings.cache_hit = true;
+        timings.total_ms = timer.total();
+        return Ok(RenderedDocument {
+            pdf_path: preview_pdf.to_string_lossy().to_string(),
+            source_map: attach_pdf_positions(&preprocess.anchors, &positions),
+            timings: Some(timings),
+            warnings: preprocess.warnings.clone(),
+        });
+    }
+
+    let diagram_failures = diagrams::prepare_diagram_assets(
+        env,
+        &diagrams,
+        &preprocess.diagram_blocks,
+        &build_dir,
+        &diagram_settings,
+        &config.cancel,
+    )?;
+    timings.tikz_ms = timer.lap();
+    config.cancel.check()?;
 
     // Compile preview PDF
-    // For preview, temporarily install the preview content into content.md so the
+    // For preview, install the preview content into content.md (only when it differs) so the
     // template and typst query see the preview-only tokens. We'll restore the clean
     // content.md after compilation.
-    let preview_src = build_dir.join("content.preview.md");
-    let content_md = build_dir.join("content.md");
-    if preview_src.exists() {
-        if let Err(e) = fs::copy(&preview_src, &content_md) {
+    let install_preview = md_content_preview != *md_content_clean;
+    if install_preview {
+        if let Err(e) = fs::write(&content_md, &md_content_preview) {
             println!(
                 "[renderer] warning: failed to install preview content for compile: {}",
                 e
@@ -309,38 +525,60 @@ pub async fn render_markdown(app_handle: &AppHandle, file_path: &str) -> Result<
         }
     }
 
-    render_pipeline::compile_typst(&config, &typst_path, "preview.pdf")?;
-    let preview_pdf = build_dir.join("preview.pdf");
+    let compiled = render_pipeline::compile_typst(&config, &backend, "preview.pdf");
 
     // Restore the clean content.md so the build directory reflects canonical (export) content.
-    if let Err(e) = fs::write(build_dir.join("content.md"), &md_content_clean) {
-        println!(
-            "[renderer] warning: failed to restore clean content.md after preview compile: {}",
-            e
-        );
+    if install_preview {
+        if let Err(e) = fs::write(&content_md, md_content_clean) {
+            println!(
+                "[renderer] warning: failed to restore clean content.md after preview compile: {}",
+                e
+            );
+        }
     }
-
-    // Update last render time
-    last_render_times.insert(file_path.to_string(), mod_time);
+    let compiled = compiled.map_err(|e| {
+        locate_diagnostics(
+            e,
+            &config,
+            &backend,
+            preprocess,
+            md_content_raw,
+            JobKind::Preview,
+        )
+    })?;
+    timings.compile_ms = timer.lap();
 
     // Use the anchor list from the clean preprocess (anchors are identical between preview and clean)
-    let source_map = build_source_map(
-        app_handle,
-        &typst_path,
-        &build_dir,
-        &content_dir,
-        &preprocess.anchors,
-    );
+    let source_map = build_source_map(&config, &backend, &compiled, &preprocess.anchors);
+    config.cancel.check()?;
+    // Failed diagrams are retried and reported on the next render, so skip the cache.
+    if let Some(cache) = cache.as_ref().filter(|_| diagram_failures == 0) {
+        cache.store(&preview_pdf, Some(&source_map));
+    }
+    timings.query_ms = timer.lap();
+    timings.total_ms = timer.total();
+
     let document = RenderedDocument {
         pdf_path: preview_pdf.to_string_lossy().to_string(),
         source_map,
+        timings: Some(timings),
+        warnings: preprocess.warnings.clone(),
     };
 
     Ok(document)
 }
 
 /// Export markdown to final PDF location using Typst
-pub async fn export_markdown(app_handle: &AppHandle, file_path: &str) -> Result<String> {
+pub async fn export_markdown(env: Arc<dyn RenderEnvironment>, file_path: &str) -> Result<String> {
+    export_markdown_to(env, file_path, None).await
+}
+
+/// Export markdown to `destination`, or next to the source file when `None`.
+pub async fn export_markdown_to(
+    env: Arc<dyn RenderEnvironment>,
+    file_path: &str,
+    destination: Option<&Path>,
+) -> Result<String> {
     let path = Path::new(file_path);
 
     // Only export markdown files
@@ -357,92 +595,172 @@ pub async fn export_markdown(app_handle: &AppHandle, file_path: &str) -> Result<
         return Err(anyhow!("File does not exist: {}", file_path));
     }
 
-    // Acquire render lock to prevent multiple simultaneous renders
-    let _lock = RENDER_MUTEX.lock().await;
+    let md_content_raw = fs::read_to_string(path)?;
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    // Next to the source file unless a destination was given
+    let final_pdf = match destination {
+        Some(dest) => dest.to_path_buf(),
+        None => path.with_extension("pdf"),
+    };
+    export_markdown_source(env, file_path, &md_content_raw, base_dir, &final_pdf).await
+}
+
+/// Export the markdown `md_content_raw` of `file_path` (which keys the export workspace; it
+/// need not exist on disk) to `final_pdf`, resolving relative paths against `base_dir`.
+pub(crate) async fn export_markdown_source(
+    env: Arc<dyn RenderEnvironment>,
+    file_path: &str,
+    md_content_raw: &str,
+    base_dir: &Path,
+    final_pdf: &Path,
+) -> Result<String> {
+    // Exports go ahead of queued previews and are never cancelled
+    let slot = render_scheduler::acquire(RenderJob::Export(file_path)).await?;
+
+    // Preprocessing, diagrams and compiling block, so they run off the async runtime's threads
+    let cancel = slot.token().clone();
+    let (file_path, md_content_raw) = (file_path.to_string(), md_content_raw.to_string());
+    let (base_dir, final_pdf) = (base_dir.to_path_buf(), final_pdf.to_path_buf());
+    let exported = tauri::async_runtime::spawn_blocking(move || {
+        export_markdown_blocking(
+            env.as_ref(),
+            &file_path,
+            &md_content_raw,
+            &base_dir,
+            &final_pdf,
+            cancel,
+        )
+    })
+    .await??;
+    drop(slot);
+    Ok(exported)
+}
 
-    // Setup similar to preview, but write to final path next to source
-    let content_dir = utils::get_content_dir(app_handle)?;
-    let build_dir = content_dir.join(".build");
-    fs::create_dir_all(&build_dir)?;
+/// The blocking part of [`export_markdown_source`], run while holding the render slot.
+fn export_markdown_blocking(
+    env: &dyn RenderEnvironment,
+    file_path: &str,
+    md_content_raw: &str,
+    base_dir: &Path,
+    final_pdf: &Path,
+    cancel: CancelToken,
+) -> Result<String> {
+    // Setup similar to preview (in the document's export workspace), but write to final path
+    // next to source
+    let content_dir = env.content_dir()?;
+    let build_dir = build_workspace::prepare(env, file_path, JobKind::Export)?;
 
     // Setup render configuration
     let config = RenderConfig {
-        app_handle,
+        env,
         build_dir: build_dir.clone(),
         content_dir: content_dir.clone(),
         typst_root: content_dir.clone(),
+        cancel,
     };
 
-    // Setup preferences
-    render_pipeline::setup_prefs(&config, "markdown-export")?;
-
     // 2) Copy the markdown content to build/content.md (with image path rewrites)
-    let md_content_raw = fs::read_to_string(path)?;
-    let base_dir = path.parent().unwrap_or(Path::new("."));
-    let assets_root = utils::get_assets_dir(app_handle).ok();
+    let assets_root = env.assets_dir().ok();
     let assets_root_ref = assets_root.as_deref();
     // For export, do NOT inject visible tokens — output must be clean for users
-    let preprocess = preprocess_markdown(&md_content_raw)?;
-    let md_content =
-        utils::rewrite_image_paths_in_markdown(&preprocess.markdown, base_dir, assets_root_ref);
-    fs::write(build_dir.join("content.md"), md_content)?;
-    tikz::prepare_tikz_assets(app_handle, &preprocess.tikz_blocks, &build_dir)?;
+    let diagrams = DiagramRegistry::load(env);
+    let mut preprocess = preprocess_markdown(
+        md_content_raw,
+        Some(Path::new(file_path)),
+        base_dir,
+        &diagrams,
+    )?;
+    front_matter::resolve_relative_paths(&mut preprocess.prefs_overrides, base_dir);
+    preprocess.transform(|markdown| {
+        utils::rewrite_image_paths_in_markdown(markdown, base_dir, assets_root_ref)
+    });
+
+    // Setup preferences with the document's front matter applied
+    let prefs =
+        render_pipeline::setup_prefs(&config, "markdown-export", &preprocess.prefs_overrides)?;
+    citations::apply(&mut preprocess, md_content_raw, &prefs.bibliography);
+    emit_warnings(env, file_path, &preprocess.warnings);
+    fs::write(build_dir.join("content.md"), &preprocess.markdown)?;
 
     // Setup template
     render_pipeline::setup_template(&config, "markdown-export")?;
 
-    // Get bundled Typst binary path
-    let typst_path = utils::get_typst_path(app_handle)
-        .context("Typst binary not found. Please install Typst system-wide or download and place in bin/typst/<platform>/ directory.")?;
+    let backend = TypstBackend::select(&config, &prefs.prefs_json)?;
 
-    // Compile to final PDF next to source file
-    let final_pdf = Path::new(file_path).with_extension("pdf");
+    // Compile in the build directory, then copy to the final PDF location
     let final_pdf_name = final_pdf
         .file_name()
         .and_then(|n| n.to_str())
         .ok_or_else(|| anyhow!("Invalid output filename"))?;
 
-    render_pipeline::compile_typst(&config, &typst_path, final_pdf_name)?;
+    let built_pdf = build_dir.join(final_pdf_name);
+    let diagram_settings = DiagramSettings::resolve(env, base_dir, &prefs.prefs_json);
+    let cache = RenderCache::open(
+        &config,
+        &backend,
+        &preprocess.markdown,
+        &prefs.prefs_json,
+        &diagrams.fingerprint(&diagram_settings),
+    );
+    if !cache
+        .as_ref()
+        .is_some_and(|cache| cache.fetch_pdf(&built_pdf))
+    {
+        let diagram_failures = diagrams::prepare_diagram_assets(
+            env,
+            &diagrams,
+            &preprocess.diagram_blocks,
+            &build_dir,
+            &diagram_settings,
+            &config.cancel,
+        )?;
+        render_pipeline::compile_typst(&config, &backend, final_pdf_name).map_err(|e| {
+            locate_diagnostics(
+                e,
+                &config,
+                &backend,
+                &preprocess,
+                md_content_raw,
+                JobKind::Export,
+            )
+        })?;
+        if let Some(cache) = cache.as_ref().filter(|_| diagram_failures == 0) {
+            cache.store(&built_pdf, None);
+        }
+    }
+    if let Some(parent) = final_pdf.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(&built_pdf, final_pdf)?;
 
     if !final_pdf.exists() {
         return Err(anyhow!("Export PDF not found at {}", final_pdf.display()));
     }
 
     // Emit absolute path of final PDF to UI
-    app_handle
-        .emit("exported", final_pdf.to_string_lossy().to_string())
-        .ok();
+    env.emit("exported", final_pdf.to_string_lossy().to_string().into());
 
     Ok(final_pdf.to_string_lossy().to_string())
 }
 
 /// Renders Typst content directly to PDF (always full render)
 pub async fn render_typst(
-    app_handle: &AppHandle,
+    env: &dyn RenderEnvironment,
     content: &str,
     _format: &str,
     current_file: Option<&str>,
 ) -> Result<RenderedDocument> {
-    // Acquire render lock to prevent multiple simultaneous renders
-    let _lock = RENDER_MUTEX.lock().await;
+    // Wait for the render slot; a newer preview of this document supersedes this one
+    let slot = render_scheduler::acquire(RenderJob::Preview(current_file.unwrap_or(""))).await?;
 
-    // Get path to Typst binary (fail fast if missing)
-    let typst_path = utils::get_typst_path(app_handle)
-        .context("Typst binary not found. Please install Typst system-wide or download and place in bin/typst/<platform>/ directory.")?;
+    let mut timer = PhaseTimer::start();
+    let mut timings = RenderTimings::default();
 
-    // Create .build directory if it doesn't exist
-    let content_dir = utils::get_content_dir(app_handle)?;
-    let build_dir = content_dir.join(".build");
-    fs::create_dir_all(&build_dir)?;
+    // Create the document's preview workspace if it doesn't exist
+    let content_dir = env.content_dir()?;
+    let build_dir = build_workspace::prepare(env, current_file.unwrap_or(""), JobKind::Preview)?;
 
-    // Create a temporary content file
     let uuid = uuid::Uuid::new_v4();
-    let temp_content_name = format!("temp_{}.md", uuid);
-    let temp_content_path = build_dir.join(&temp_content_name);
-
-    // Preprocess content to rewrite image paths so Typst/cmarker can resolve them properly
-    // For ad-hoc typst renders, include visible tokens to aid preview extraction
-    let preprocess = preprocess_markdown(content)?;
 
     // Determine base directory for image path resolution
     // Use the current file's parent directory if available, otherwise fall back to content_dir
@@ -454,59 +772,118 @@ pub async fn render_typst(
     } else {
         content_dir.clone()
     };
-
-    // Rewrite image paths so Typst can resolve them
-    let assets_root = utils::get_assets_dir(app_handle).ok();
+    let assets_root = env.assets_dir().ok();
     let assets_root_ref = assets_root.as_deref();
-    let mut processed =
-        utils::rewrite_image_paths_in_markdown(&preprocess.markdown, &base_dir, assets_root_ref);
-
-    // Filter out external image URLs that cmarker can't fetch
-    let re_external_img = regex::Regex::new(r"!\[[^\]]*\]\(https?://[^)]+\)")
-        .expect("BUG: Invalid regex pattern for external markdown images");
-    processed = re_external_img.replace_all(&processed, "").to_string();
 
-    let re_external_html = regex::Regex::new(r#"<img[^>]*src=["']https?://[^"']+["'][^>]*>"#)
-        .expect("BUG: Invalid regex pattern for external HTML images");
-    processed = re_external_html.replace_all(&processed, "").to_string();
-
-    // Remove citation references [@ref] that would cause "label does not exist" errors
-    let re_citations =
-        regex::Regex::new(r"\[@[^\]]+\]").expect("BUG: Invalid regex pattern for citations");
-    processed = re_citations.replace_all(&processed, "").to_string();
-
-    fs::write(&temp_content_path, &processed)?;
+    // Preprocess content to rewrite image paths so Typst/cmarker can resolve them properly.
+    // Reused from the document's preview session when the content is unchanged.
+    let diagrams = DiagramRegistry::load(env);
+    let (mut prepared, preprocess_cached) = preview_session::prepare_content(
+        current_file.unwrap_or(""),
+        &[
+            "typst-temp",
+            content,
+            &base_dir.to_string_lossy(),
+            &assets_root_ref
+                .map(|p| p.to_string_lossy())
+                .unwrap_or_default(),
+            &diagrams.languages().join(" "),
+            &diagrams.has_dvisvgm().to_string(),
+        ],
+        || {
+            // For ad-hoc typst renders, include visible tokens to aid preview extraction
+            let mut preprocess =
+                preprocess_markdown(content, current_file.map(Path::new), &base_dir, &diagrams)?;
+            front_matter::resolve_relative_paths(&mut preprocess.prefs_overrides, &base_dir);
+
+            // Rewrite image paths so Typst can resolve them
+            preprocess.transform(|markdown| {
+                utils::rewrite_image_paths_in_markdown(markdown, &base_dir, assets_root_ref)
+            });
+
+            // Filter out external image URLs that cmarker can't fetch
+            preprocess.transform(|markdown| {
+                edit_map::replace_all(&EXTERNAL_MARKDOWN_IMAGE, markdown, |_| String::new())
+            });
+            preprocess.transform(|markdown| {
+                edit_map::replace_all(&EXTERNAL_HTML_IMAGE, markdown, |_| String::new())
+            });
+
+            Ok(preprocess)
+        },
+    )?;
 
     // Setup render configuration - always use content_dir as Typst root
     let config = RenderConfig {
-        app_handle,
+        env,
         build_dir: build_dir.clone(),
         content_dir: content_dir.clone(),
         typst_root: content_dir.clone(),
+        cancel: slot.token().clone(),
     };
 
-    // Setup preferences
-    render_pipeline::setup_prefs(&config, "typst-temp")?;
+    // Setup preferences, resolve citations against the bibliography and pick the Typst backend
+    let prefs = render_pipeline::setup_prefs(&config, "typst-temp", &prepared.prefs_overrides)?;
+    citations::apply(&mut prepared, content, &prefs.bibliography);
+    let preprocess = &prepared;
+    emit_warnings(env, current_file.unwrap_or(""), &preprocess.warnings);
+    let backend = TypstBackend::select(&config, &prefs.prefs_json)?;
 
     // Ensure the content is available as content.md (required by template)
-    fs::copy(&temp_content_path, build_dir.join("content.md"))?;
-    tikz::prepare_tikz_assets(app_handle, &preprocess.tikz_blocks, &build_dir)?;
+    render_pipeline::write_if_changed(
+        &build_dir.join("content.md"),
+        preprocess.markdown.as_bytes(),
+    )?;
 
     // Setup template
     render_pipeline::setup_template(&config, "typst-temp")?;
+    timings.preprocess_ms = timer.lap();
+    timings.preprocess_cached = preprocess_cached;
 
     // Determine output file name
     let output_file_name = format!("temp_{}.pdf", uuid);
     let output_path = build_dir.join(&output_file_name);
 
-    // Compile with Typst - if it fails, just skip rendering silently
-    let compile_result = render_pipeline::compile_typst(&config, &typst_path, &output_file_name);
+    // Unchanged inputs: serve the previous PDF and anchor positions
+    let diagram_settings = DiagramSettings::resolve(env, &base_dir, &prefs.prefs_json);
+    let cache = RenderCache::open(
+        &config,
+        &backend,
+        &preprocess.markdown,
+        &prefs.prefs_json,
+        &diagrams.fingerprint(&diagram_settings),
+    );
+    if let Some(positions) = cache
+        .as_ref()
+        .and_then(|cache| cache.fetch_preview(&output_path))
+    {
+        timings.cache_hit = true;
+        timings.total_ms = timer.total();
+        return Ok(RenderedDocument {
+            pdf_path: output_path.to_string_lossy().to_string(),
+            source_map: attach_pdf_positions(&preprocess.anchors, &positions),
+            timings: Some(timings),
+            warnings: preprocess.warnings.clone(),
+        });
+    }
 
-    // Clean up the temporary content file
-    let _ = fs::remove_file(&temp_content_path);
+    let diagram_failures = diagrams::prepare_diagram_assets(
+        env,
+        &diagrams,
+        &preprocess.diagram_blocks,
+        &build_dir,
+        &diagram_settings,
+        &config.cancel,
+    )?;
+    timings.tikz_ms = timer.lap();
+    config.cancel.check()?;
 
-    // If compilation failed or output not created, propagate error
-    compile_result?;
+    // Compile with Typst; propagate failures to the caller
+    let compiled =
+        render_pipeline::compile_typst(&config, &backend, &output_file_name).map_err(|e| {
+            locate_diagnostics(e, &config, &backend, preprocess, content, JobKind::Preview)
+        })?;
+    timings.compile_ms = timer.lap();
 
     if !output_path.exists() {
         return Err(anyhow!(
@@ -515,34 +892,35 @@ pub async fn render_typst(
         ));
     }
 
-    let source_map = build_source_map(
-        app_handle,
-        &typst_path,
-        &build_dir,
-        &content_dir,
-        &preprocess.anchors,
-    );
+    let source_map = build_source_map(&config, &backend, &compiled, &preprocess.anchors);
+    config.cancel.check()?;
+    if let Some(cache) = cache.as_ref().filter(|_| diagram_failures == 0) {
+        cache.store(&output_path, Some(&source_map));
+    }
+    timings.query_ms = timer.lap();
+    timings.total_ms = timer.total();
+
     Ok(RenderedDocument {
         pdf_path: output_path.to_string_lossy().to_string(),
         source_map,
+        timings: Some(timings),
+        warnings: preprocess.warnings.clone(),
     })
 }
 
 pub async fn render_latex(
-    app_handle: &AppHandle,
+    env: &dyn RenderEnvironment,
     content: &str,
     current_file: Option<&str>,
 ) -> Result<RenderedDocument> {
-    // Ensure only one render happens at once so we don't thrash the filesystem
-    let _lock = RENDER_MUTEX.lock().await;
+    // One preview per document at a time; a newer one supersedes this render
+    let _slot = render_scheduler::acquire(RenderJob::Preview(current_file.unwrap_or(""))).await?;
 
-    let tectonic_path = utils::get_tectonic_path(app_handle).context(
+    let tectonic_path = env.tectonic_path().context(
         "Tectonic binary not found. Please install or bundle Tectonic to enable LaTeX rendering.",
     )?;
 
-    let content_dir = utils::get_content_dir(app_handle)?;
-    let build_dir = content_dir.join(".build");
-    fs::create_dir_all(&build_dir)?;
+    let build_dir = build_workspace::prepare(env, current_file.unwrap_or(""), JobKind::Preview)?;
     let latex_dir = build_dir.join("latex");
     fs::create_dir_all(&latex_dir)?;
 
@@ -621,6 +999,8 @@ pub async fn render_latex(
     Ok(RenderedDocument {
         pdf_path: final_pdf.to_string_lossy().to_string(),
         source_map: SourceMapPayload::default(),
+        timings: None,
+        warnings: Vec::new(),
     })
 }
 
@@ -650,3 +1030,79 @@ fn prepare_latex_preview_file(
 
     (None, latex_dir.join(format!("{}.tex", preview_base)))
 }
+
+// The stand-in `typst` below is a shell script.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::diagnostics::{Diagnostic, Severity};
+    use crate::environment::FsEnvironment;
+    use crate::render_scheduler::CancelToken;
+
+    /// Hand `locate_diagnostics` an error the direct mapping cannot place, compiling with a
+    /// stand-in `typst` that counts its runs. Returns how many compiles were spent.
+    fn probe_compiles(kind: JobKind) -> usize {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("tf-renderer-{}", uuid::Uuid::new_v4()));
+        let build_dir = dir.join("build");
+        fs::create_dir_all(&build_dir).unwrap();
+        let runs = dir.join("runs");
+        let typst = dir.join("typst");
+        fs::write(
+            &typst,
+            format!(
+                "#!/bin/sh\necho run >> '{}'\necho 'error: boom' >&2\nexit 1\n",
+                runs.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&typst, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let source = "# Title\n\nFirst paragraph.\n\nSecond paragraph.\n";
+        let preprocess =
+            preprocess_markdown(source, None, &dir, &DiagramRegistry::builtin()).unwrap();
+        fs::write(build_dir.join("content.md"), &preprocess.markdown).unwrap();
+        let env = FsEnvironment::new(&dir);
+        let config = RenderConfig {
+            env: &env,
+            build_dir: build_dir.clone(),
+            content_dir: dir.clone(),
+            typst_root: dir.clone(),
+            cancel: CancelToken::default(),
+        };
+        let error = TypstCompileError {
+            output: "error: boom".to_string(),
+            diagnostics: vec![Diagnostic::new(Severity::Error, "boom")],
+        };
+
+        let error = locate_diagnostics(
+            error.into(),
+            &config,
+            &TypstBackend::External(typst),
+            &preprocess,
+            source,
+            kind,
+        );
+        assert!(error.downcast_ref::<TypstCompileError>().is_some());
+        assert_eq!(
+            fs::read_to_string(build_dir.join("content.md")).unwrap(),
+            preprocess.markdown
+        );
+        let compiles = fs::read_to_string(&runs)
+            .map(|runs| runs.lines().count())
+            .unwrap_or(0);
+        fs::remove_dir_all(&dir).unwrap();
+        compiles
+    }
+
+    #[test]
+    fn export_errors_are_not_located_by_probing() {
+        assert_eq!(probe_compiles(JobKind::Export), 0);
+    }
+
+    #[test]
+    fn preview_errors_are_located_by_probing() {
+        assert!(probe_compiles(JobKind::Preview) > 0);
+    }
+}
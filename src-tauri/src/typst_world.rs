@@ -0,0 +1,470 @@
+//! In-process Typst compiler (the `embedded-typst` feature).
+//!
+//! A [`TideflowWorld`] per build entry file is kept alive between renders so fonts, package
+//! files (cmarker, mitex) and parsed sources (template, themes) stay in memory. Every compile
+//! re-checks the files it touches; unchanged files keep their parsed `Source`, which lets comemo
+//! reuse most of the previous layout. Packages resolve from the bundled package roots first,
+//! then from Typst's regular package cache (downloading into it when needed), mirroring
+//! `TYPST_PACKAGE_PATH`.
+
+use crate::diagnostics::{Diagnostic, Severity as DiagnosticSeverity, TypstCompileError};
+use crate::preprocessor::PdfPosition;
+use anyhow::{anyhow, Result};
+use chrono::Datelike;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+use typst::diag::{FileError, FileResult, Severity, SourceDiagnostic, Warned};
+use typst::foundations::{Bytes, Datetime, Label};
+use typst::layout::PagedDocument;
+use typst::syntax::package::PackageSpec;
+use typst::syntax::{FileId, Source, VirtualPath};
+use typst::text::{Font, FontBook};
+use typst::utils::{LazyHash, PicoStr};
+use typst::{Library, World};
+use typst_kit::download::{Downloader, ProgressSink};
+use typst_kit::fonts::{FontSearcher, Fonts};
+use typst_kit::package::PackageStorage;
+
+lazy_static! {
+    // Font discovery scans the whole system; do it once per process.
+    static ref FONTS: Fonts = FontSearcher::new().search();
+    // Persistent worlds keyed by entry file, replaced only when the root or package roots change.
+    static ref WORLDS: Mutex<HashMap<PathBuf, TideflowWorld>> = Mutex::new(HashMap::new());
+}
+
+/// Worlds kept warm at once (one per build workspace); the least recently used is dropped.
+const MAX_WORLDS: usize = 6;
+
+/// Compile `<build_dir>/tideflow.typ` with `typst_root` as the project root and write the PDF
+/// to `<build_dir>/<output_file>`. Returns the laid-out document for introspection.
+pub fn compile_pdf(
+    build_dir: &Path,
+    typst_root: &Path,
+    package_paths: &[PathBuf],
+    output_file: &str,
+) -> Result<PagedDocument> {
+    let main_path = build_dir.join("tideflow.typ");
+
+    // Take the world out of the map so compiles in other workspaces are not blocked on the lock.
+    // The scheduler never runs two jobs in the same workspace at once.
+    let cached = WORLDS
+        .lock()
+        .map_err(|_| anyhow!("Embedded Typst world lock poisoned"))?
+        .remove(&main_path)
+        .filter(|world| world.matches(typst_root, package_paths));
+    let mut world = match cached {
+        Some(world) => world,
+        None => TideflowWorld::new(typst_root, &main_path, package_paths)?,
+    };
+    world.reset();
+
+    let result = compile_world(&world, build_dir, output_file);
+
+    let mut worlds = WORLDS
+        .lock()
+        .map_err(|_| anyhow!("Embedded Typst world lock poisoned"))?;
+    worlds.insert(main_path, world);
+    while worlds.len() > MAX_WORLDS {
+        let oldest = worlds
+            .iter()
+            .min_by_key(|(_, world)| world.last_used)
+            .map(|(path, _)| path.clone());
+        match oldest {
+            Some(path) => worlds.remove(&path),
+            None => break,
+        };
+    }
+    drop(worlds);
+
+    // Drop memoized results that have not been used for a few compiles.
+    comemo::evict(10);
+
+    result
+}
+
+fn compile_world(
+    world: &TideflowWorld,
+    build_dir: &Path,
+    output_file: &str,
+) -> Result<PagedDocument> {
+    let Warned { output, warnings } = typst::compile::<PagedDocument>(world);
+    let document = output.map_err(|errors| {
+        compile_error(
+            "Typst compile failed.",
+            to_diagnostics(world, errors.iter().chain(warnings.iter())),
+        )
+    })?;
+
+    let pdf = typst_pdf::pdf(&document, &typst_pdf::PdfOptions::default()).map_err(|errors| {
+        compile_error(
+            "Typst PDF export failed.",
+            to_diagnostics(world, errors.iter()),
+        )
+    })?;
+    fs::write(build_dir.join(output_file), pdf)?;
+    Ok(document)
+}
+
+/// Resolve anchor labels (`tf-...`) to their page and position in points.
+pub fn label_positions(document: &PagedDocument, labels: &[&str]) -> HashMap<String, PdfPosition> {
+    let mut positions = HashMap::new();
+    for id in labels {
+        let label = Label::new(PicoStr::intern(id));
+        let Ok(content) = document.introspector.query_label(label) else {
+            continue;
+        };
+        let Some(location) = content.location() else {
+            continue;
+        };
+        let position = document.introspector.position(location);
+        positions.insert(
+            id.to_string(),
+            PdfPosition {
+                page: position.page.get(),
+                x: position.point.x.to_pt() as f32,
+                y: position.point.y.to_pt() as f32,
+            },
+        );
+    }
+    positions
+}
+
+/// Render diagnostics like `typst compile --diagnostic-format short`.
+fn compile_error(summary: &str, diagnostics: Vec<Diagnostic>) -> TypstCompileError {
+    let mut output = summary.to_string();
+    for diagnostic in &diagnostics {
+        output.push('\n');
+        output.push_str(&diagnostic.to_string());
+    }
+    TypstCompileError {
+        output,
+        diagnostics,
+    }
+}
+
+fn to_diagnostics<'a>(
+    world: &TideflowWorld,
+    diagnostics: impl Iterator<Item = &'a SourceDiagnostic>,
+) -> Vec<Diagnostic> {
+    diagnostics
+        .map(|diagnostic| {
+            let severity = match diagnostic.severity {
+                Severity::Error => DiagnosticSeverity::Error,
+                Severity::Warning => DiagnosticSeverity::Warning,
+            };
+            let mut converted = Diagnostic::new(severity, diagnostic.message.as_str());
+            converted.hints = diagnostic.hints.iter().map(|h| h.to_string()).collect();
+            let location = diagnostic.span.id().and_then(|id| {
+                let source = world.source(id).ok()?;
+                let range = source.range(diagnostic.span)?;
+                let line = source.byte_to_line(range.start)? + 1;
+                let column = source.byte_to_column(range.start)? + 1;
+                Some((display_path(id), line, column))
+            });
+            match location {
+                Some((file, line, column)) => converted.at(file, line, column),
+                None => converted,
+            }
+        })
+        .collect()
+}
+
+fn display_path(id: FileId) -> String {
+    let path = id.vpath().as_rootless_path().display().to_string();
+    match id.package() {
+        Some(spec) => format!("{}/{}", spec, path),
+        None => path,
+    }
+}
+
+/// Long-lived [`World`] rooted at the content directory.
+struct TideflowWorld {
+    root: PathBuf,
+    main: FileId,
+    package_paths: Vec<PathBuf>,
+    library: LazyHash<Library>,
+    book: LazyHash<FontBook>,
+    packages: PackageStorage,
+    slots: Mutex<HashMap<FileId, FileSlot>>,
+    last_used: Instant,
+}
+
+impl TideflowWorld {
+    fn new(root: &Path, main_path: &Path, package_paths: &[PathBuf]) -> Result<Self> {
+        let vpath = VirtualPath::within_root(main_path, root).ok_or_else(|| {
+            anyhow!(
+                "Build directory {} is not inside the Typst root {}",
+                main_path.display(),
+                root.display()
+            )
+        })?;
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            main: FileId::new(None, vpath),
+            package_paths: package_paths.to_vec(),
+            library: LazyHash::new(Library::builder().build()),
+            book: LazyHash::new(FONTS.book.clone()),
+            packages: PackageStorage::new(
+                None,
+                None,
+                Downloader::new(concat!("tideflow/", env!("CARGO_PKG_VERSION"))),
+            ),
+            slots: Mutex::new(HashMap::new()),
+            last_used: Instant::now(),
+        })
+    }
+
+    fn matches(&self, root: &Path, package_paths: &[PathBuf]) -> bool {
+        self.root == root && self.package_paths == package_paths
+    }
+
+    /// Start a new compile: every file is re-validated on first access.
+    fn reset(&mut self) {
+        self.last_used = Instant::now();
+        if let Ok(slots) = self.slots.get_mut() {
+            for slot in slots.values_mut() {
+                slot.reset();
+            }
+        }
+    }
+
+    fn package_root(&self, spec: &PackageSpec) -> FileResult<PathBuf> {
+        for base in &self.package_paths {
+            let dir = base
+                .join(spec.namespace.as_str())
+                .join(spec.name.as_str())
+                .join(spec.version.to_string());
+            if dir.exists() {
+                return Ok(dir);
+            }
+        }
+        self.packages
+            .prepare_package(spec, &mut ProgressSink)
+            .map_err(FileError::Package)
+    }
+
+    fn read(&self, id: FileId) -> FileResult<Vec<u8>> {
+        let root = match id.package() {
+            Some(spec) => self.package_root(spec)?,
+            None => self.root.clone(),
+        };
+        let path = id.vpath().resolve(&root).ok_or(FileError::AccessDenied)?;
+        if path.is_dir() {
+            return Err(FileError::IsDirectory);
+        }
+        fs::read(&path).map_err(|e| FileError::from_io(e, &path))
+    }
+
+    fn with_slot<T>(&self, id: FileId, f: impl FnOnce(&mut FileSlot) -> T) -> T {
+        let mut slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+        f(slots.entry(id).or_insert_with(|| FileSlot::new(id)))
+    }
+}
+
+impl World for TideflowWorld {
+    fn library(&self) -> &LazyHash<Library> {
+        &self.library
+    }
+
+    fn book(&self) -> &LazyHash<FontBook> {
+        &self.book
+    }
+
+    fn main(&self) -> FileId {
+        self.main
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        self.with_slot(id, |slot| slot.source(|| self.read(id)))
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        self.with_slot(id, |slot| slot.file(|| self.read(id)))
+    }
+
+    fn font(&self, index: usize) -> Option<Font> {
+        FONTS.fonts.get(index)?.get()
+    }
+
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        let now = chrono::Local::now();
+        let date = match offset {
+            None => now.date_naive(),
+            Some(hours) => (now.naive_utc() + chrono::Duration::hours(hours)).date(),
+        };
+        Datetime::from_ymd(
+            date.year(),
+            date.month().try_into().ok()?,
+            date.day().try_into().ok()?,
+        )
+    }
+}
+
+/// Cached views of one file: parsed source and raw bytes.
+struct FileSlot {
+    id: FileId,
+    source: SlotCell<Source>,
+    file: SlotCell<Bytes>,
+}
+
+impl FileSlot {
+    fn new(id: FileId) -> Self {
+        Self {
+            id,
+            source: SlotCell::new(),
+            file: SlotCell::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.source.accessed = false;
+        self.file.accessed = false;
+    }
+
+    fn source(&mut self, load: impl FnOnce() -> FileResult<Vec<u8>>) -> FileResult<Source> {
+        let id = self.id;
+        self.source.get_or_init(load, |bytes, previous| {
+            let text = decode_utf8(&bytes)?;
+            match previous {
+                // Incremental reparse keeps unchanged syntax nodes (and their spans) stable.
+                Some(mut source) => {
+                    source.replace(text);
+                    Ok(source)
+                }
+                None => Ok(Source::new(id, text.to_string())),
+            }
+        })
+    }
+
+    fn file(&mut self, load: impl FnOnce() -> FileResult<Vec<u8>>) -> FileResult<Bytes> {
+        self.file
+            .get_or_init(load, |bytes, _| Ok(Bytes::new(bytes)))
+    }
+}
+
+struct SlotCell<T> {
+    data: Option<FileResult<T>>,
+    fingerprint: u64,
+    accessed: bool,
+}
+
+impl<T: Clone> SlotCell<T> {
+    fn new() -> Self {
+        Self {
+            data: None,
+            fingerprint: 0,
+            accessed: false,
+        }
+    }
+
+    fn get_or_init(
+        &mut self,
+        load: impl FnOnce() -> FileResult<Vec<u8>>,
+        convert: impl FnOnce(Vec<u8>, Option<T>) -> FileResult<T>,
+    ) -> FileResult<T> {
+        // Already validated during this compile.
+        if std::mem::replace(&mut self.accessed, true) {
+            if let Some(data) = &self.data {
+                return data.clone();
+            }
+        }
+
+        let result = load().and_then(|bytes| {
+            let fingerprint = fingerprint(&bytes);
+            if let Some(Ok(previous)) = &self.data {
+                if fingerprint == self.fingerprint {
+                    return Ok(previous.clone());
+                }
+            }
+            self.fingerprint = fingerprint;
+            let previous = self.data.take().and_then(Result::ok);
+            convert(bytes, previous)
+        });
+        self.data = Some(result.clone());
+        result
+    }
+}
+
+fn fingerprint(bytes: &[u8]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn decode_utf8(bytes: &[u8]) -> FileResult<&str> {
+    let text = std::str::from_utf8(bytes).map_err(|_| FileError::InvalidUtf8)?;
+    Ok(text.strip_prefix('\u{feff}').unwrap_or(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Typst root holding `build/tideflow.typ` with `main` as its content.
+    fn workspace(main: &str) -> (PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(format!("tf-typst-world-{}", uuid::Uuid::new_v4()));
+        let build_dir = root.join("build");
+        fs::create_dir_all(&build_dir).unwrap();
+        fs::write(build_dir.join("tideflow.typ"), main).unwrap();
+        (root, build_dir)
+    }
+
+    #[test]
+    fn minimal_documents_compile_to_pdf() {
+        let (root, build_dir) = workspace("= Hello\n\nWorld <tf-1>\n");
+
+        let document = compile_pdf(&build_dir, &root, &[], "out.pdf").unwrap();
+        assert_eq!(document.pages.len(), 1);
+        assert!(fs::read(build_dir.join("out.pdf"))
+            .unwrap()
+            .starts_with(b"%PDF"));
+        let positions = label_positions(&document, &["tf-1", "tf-missing"]);
+        assert_eq!(positions["tf-1"].page, 1);
+        assert!(!positions.contains_key("tf-missing"));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn bundled_packages_resolve_from_the_package_roots() {
+        let (root, build_dir) = workspace(
+            "#import \"@preview/cmarker:0.1.6\": render\n#render(\"# From *cmarker*\")\n",
+        );
+        let packages = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("content")
+            .join("typst")
+            .join("packages");
+
+        compile_pdf(&build_dir, &root, &[packages], "out.pdf").unwrap();
+        assert!(build_dir.join("out.pdf").exists());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn source_errors_become_located_diagnostics() {
+        let (root, build_dir) = workspace("Hello\n\n#undefined-function()\n");
+
+        let error = compile_pdf(&build_dir, &root, &[], "out.pdf").unwrap_err();
+        let error = error.downcast_ref::<TypstCompileError>().unwrap();
+        let diagnostic = &error.diagnostics[0];
+        assert_eq!(diagnostic.severity, DiagnosticSeverity::Error);
+        assert!(
+            diagnostic.message.contains("unknown variable"),
+            "{}",
+            diagnostic.message
+        );
+        assert_eq!(diagnostic.file.as_deref(), Some("build/tideflow.typ"));
+        assert_eq!(diagnostic.line, Some(3));
+        assert!(error.output.starts_with("Typst compile failed."));
+        assert!(!build_dir.join("out.pdf").exists());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -1,4 +1,4 @@
-//! Filesystem utilities for copying directories and sanitizing filenames.
+//! Filesystem utilities for copying directories, sanitizing filenames and writing assets.
 
 use anyhow::{anyhow, Result};
 use regex::Regex;
@@ -6,6 +6,7 @@ use std::fs;
 use std::path::Path;
 use std::thread;
 use std::time::Duration;
+use uuid::Uuid;
 
 /// Copy a file with retry logic for transient failures
 pub fn copy_file_with_retry(source: &Path, destination: &Path, max_retries: u32) -> Result<u64> {
@@ -91,3 +92,43 @@ pub fn sanitize_filename(filename: &str) -> String {
 
     sanitized
 }
+
+/// Write `bytes` into `assets_dir` under the sanitized `file_name`, adding a short UUID before
+/// the extension when that name is taken. Returns a relative path like "assets/<filename>"
+/// suitable for Markdown insertion.
+pub fn write_asset(assets_dir: &Path, file_name: &str, bytes: &[u8]) -> Result<String> {
+    fs::create_dir_all(assets_dir)?;
+    let base = unique_asset_name(assets_dir, file_name, &|_| false);
+    fs::write(assets_dir.join(&base), bytes)?;
+    Ok(format!("assets/{}", base))
+}
+
+/// The sanitized `file_name`, with a short UUID before the extension when a file of that name
+/// exists in `assets_dir` or `taken` claims it.
+pub fn unique_asset_name(
+    assets_dir: &Path,
+    file_name: &str,
+    taken: &dyn Fn(&str) -> bool,
+) -> String {
+    let base = sanitize_filename(file_name);
+    let dest_path = assets_dir.join(&base);
+    if !dest_path.exists() && !taken(&base) {
+        return base;
+    }
+
+    // Insert a short UUID before extension
+    let (stem, ext) = match dest_path.file_stem().and_then(|s| s.to_str()) {
+        Some(stem) => {
+            let ext = dest_path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            (stem.to_string(), ext.to_string())
+        }
+        None => ("image".to_string(), "".to_string()),
+    };
+    let short = Uuid::new_v4().to_string();
+    let short = &short[0..8];
+    if ext.is_empty() {
+        format!("{}-{}", stem, short)
+    } else {
+        format!("{}-{}.{}", stem, short, ext)
+    }
+}
@@ -2,7 +2,7 @@
 //!
 //! This module provides essential utilities organized by domain:
 //! - `paths`: Directory path resolution (app, content, assets, templates, styles, typst binary)
-//! - `filesystem`: File operations (directory copying, filename sanitization)
+//! - `filesystem`: File operations (directory copying, filename sanitization, asset writing)
 //! - `initialization`: Application setup (directory creation, resource copying, default configs)
 //! - `typst`: Typst-specific utilities (image path rewriting for Markdown/HTML/Typst)
 //! - `logger`: Production-safe logging utilities
@@ -14,7 +14,7 @@ pub mod paths;
 pub mod typst;
 
 // Re-export commonly used functions for backward compatibility
-pub use filesystem::sanitize_filename;
+pub use filesystem::{sanitize_filename, unique_asset_name, write_asset};
 pub use initialization::{ensure_tideflow_template_exists, initialize_app_directories};
 pub use paths::{
     get_app_dir, get_assets_dir, get_content_dir, get_pdfium_library_path, get_tectonic_path,
@@ -2,7 +2,7 @@
 
 use anyhow::{anyhow, Result};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tauri::{AppHandle, Manager};
 
 /// Get the app's base directory
@@ -65,42 +65,13 @@ pub fn get_styles_dir(app_handle: &AppHandle) -> Result<PathBuf> {
 
 /// Get the Typst binary path based on platform
 pub fn get_typst_path(app_handle: &AppHandle) -> Result<PathBuf> {
-    // First, try to find typst on the system PATH
-    if let Ok(path) = std::env::var("PATH") {
-        for dir in std::env::split_paths(&path) {
-            let typst_path = if cfg!(target_os = "windows") {
-                dir.join("typst.exe")
-            } else {
-                dir.join("typst")
-            };
-
-            if typst_path.exists() {
-                return Ok(typst_path);
-            }
-        }
+    // First, try to find typst on the system PATH (or `which` on Unix)
+    if let Some(found) = find_on_system_path("typst") {
+        return Ok(found);
     }
 
-    // On Unix-like systems, try `which typst` as an additional check (covers AppImage environments)
     #[cfg(unix)]
     {
-        if let Ok(output) = std::process::Command::new("sh")
-            .arg("-c")
-            .arg("which typst || true")
-            .output()
-        {
-            if output.status.success() {
-                if let Ok(found) = String::from_utf8(output.stdout) {
-                    let found = found.trim();
-                    if !found.is_empty() {
-                        let p = PathBuf::from(found);
-                        if p.exists() {
-                            return Ok(p);
-                        }
-                    }
-                }
-            }
-        }
-
         // Also check common system locations that some distributions and AppImages use
         let common_paths = [
             "/usr/bin/typst",
@@ -178,6 +149,12 @@ pub fn get_typst_path(app_handle: &AppHandle) -> Result<PathBuf> {
 
 /// Locate the bundled Pdfium dynamic library for the current platform.
 pub fn get_pdfium_library_path(app_handle: &AppHandle) -> Result<PathBuf> {
+    let resource_dir = app_handle.path().resource_dir().ok();
+    find_pdfium_library(resource_dir.as_deref())
+}
+
+/// Locate Pdfium relative to the dev workspace or an explicit resource directory.
+pub fn find_pdfium_library(resource_dir: Option<&Path>) -> Result<PathBuf> {
     let mut candidates = Vec::new();
 
     if let Ok(current_dir) = std::env::current_dir() {
@@ -191,7 +168,7 @@ pub fn get_pdfium_library_path(app_handle: &AppHandle) -> Result<PathBuf> {
         );
     }
 
-    if let Ok(resource_dir) = app_handle.path().resource_dir() {
+    if let Some(resource_dir) = resource_dir {
         candidates.push(
             resource_dir
                 .join("bin")
@@ -212,6 +189,63 @@ pub fn get_pdfium_library_path(app_handle: &AppHandle) -> Result<PathBuf> {
     ))
 }
 
+/// Location of a bundled tool inside `<resource_dir>/bin/<tool>/<platform>/`.
+pub fn bundled_binary_path(resource_dir: &Path, tool: &str) -> PathBuf {
+    let binary_name = if cfg!(target_os = "windows") {
+        format!("{}.exe", tool)
+    } else {
+        tool.to_string()
+    };
+    resource_dir
+        .join("bin")
+        .join(tool)
+        .join(platform_dir())
+        .join(binary_name)
+}
+
+/// Search the `PATH` environment variable (and `which` on Unix) for an executable.
+/// `binary` is the bare name; `.exe` is appended on Windows.
+pub fn find_on_system_path(binary: &str) -> Option<PathBuf> {
+    let file_name = if cfg!(target_os = "windows") {
+        format!("{}.exe", binary)
+    } else {
+        binary.to_string()
+    };
+
+    if let Ok(path_var) = std::env::var("PATH") {
+        for dir in std::env::split_paths(&path_var) {
+            let candidate = dir.join(&file_name);
+            if candidate.exists() {
+                return Some(candidate);
+            }
+        }
+    }
+
+    // On Unix-like systems, try `which` as an additional check (covers AppImage environments)
+    #[cfg(unix)]
+    {
+        if let Ok(output) = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("which {} || true", binary))
+            .output()
+        {
+            if output.status.success() {
+                if let Ok(found) = String::from_utf8(output.stdout) {
+                    let found = found.trim();
+                    if !found.is_empty() {
+                        let path = PathBuf::from(found);
+                        if path.exists() {
+                            return Some(path);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    None
+}
+
 #[cfg(target_os = "windows")]
 fn library_name() -> &'static str {
     "pdfium.dll"
@@ -244,38 +278,8 @@ fn platform_dir() -> &'static str {
 
 /// Locate the bundled or system Tectonic binary.
 pub fn get_tectonic_path(app_handle: &AppHandle) -> Result<PathBuf> {
-    if let Ok(path_var) = std::env::var("PATH") {
-        for dir in std::env::split_paths(&path_var) {
-            let candidate = if cfg!(target_os = "windows") {
-                dir.join("tectonic.exe")
-            } else {
-                dir.join("tectonic")
-            };
-            if candidate.exists() {
-                return Ok(candidate);
-            }
-        }
-    }
-
-    #[cfg(unix)]
-    {
-        if let Ok(output) = std::process::Command::new("sh")
-            .arg("-c")
-            .arg("which tectonic || true")
-            .output()
-        {
-            if output.status.success() {
-                if let Ok(found) = String::from_utf8(output.stdout) {
-                    let found = found.trim();
-                    if !found.is_empty() {
-                        let path = PathBuf::from(found);
-                        if path.exists() {
-                            return Ok(path);
-                        }
-                    }
-                }
-            }
-        }
+    if let Some(found) = find_on_system_path("tectonic") {
+        return Ok(found);
     }
 
     let resource_dir = app_handle
@@ -1,5 +1,6 @@
 //! Typst-specific utilities for image path rewriting in Markdown.
 
+use crate::edit_map::{self, EditMap};
 use crate::utils::filesystem::sanitize_filename;
 use regex::Regex;
 use std::borrow::Cow;
@@ -13,11 +14,13 @@ use std::path::{Path, PathBuf};
 /// - Resolve relative paths against `base_dir`
 /// - Normalize Windows paths to use forward slashes
 /// - If a path contains spaces or parentheses, wrap in angle brackets in Markdown form
+///
+/// Also returns the edits made to `input`, for mapping positions back to the document.
 pub fn rewrite_image_paths_in_markdown(
     input: &str,
     base_dir: &Path,
     assets_root: Option<&Path>,
-) -> String {
+) -> (String, EditMap) {
     // Helper to decide if a path is a URL-like that we should not touch
     fn is_external(p: &str) -> bool {
         let lower = p.to_ascii_lowercase();
@@ -202,7 +205,7 @@ pub fn rewrite_image_paths_in_markdown(
     // We'll conservatively capture inside the parentheses and split off a title if present.
     let re_md_img = Regex::new(r"!\[([^\]]*)\]\(([^)]+)\)")
         .expect("BUG: Invalid regex pattern for markdown images");
-    let result = re_md_img.replace_all(input, |caps: &regex::Captures| {
+    let (result, md_edits) = edit_map::replace_all(&re_md_img, input, |caps| {
         let alt_text = caps.get(1).map(|m| m.as_str()).unwrap_or("");
         let inside = caps.get(2).map(|m| m.as_str()).unwrap_or("").trim();
 
@@ -274,7 +277,7 @@ pub fn rewrite_image_paths_in_markdown(
     // Replace HTML <img ... src="..."> occurrences
     let re_html_img = Regex::new(r#"<img([^>]*?)\s+src=([\"'])([^\"']+)([\"'])([^>]*)>"#)
         .expect("BUG: Invalid regex pattern for HTML images");
-    let result = re_html_img.replace_all(&result, |caps: &regex::Captures| {
+    let (result, html_edits) = edit_map::replace_all(&re_html_img, &result, |caps| {
         let before = caps.get(1).map(|m| m.as_str()).unwrap_or("");
         let quote = caps.get(2).map(|m| m.as_str()).unwrap_or("\"");
         let src = caps.get(3).map(|m| m.as_str()).unwrap_or("");
@@ -326,7 +329,7 @@ pub fn rewrite_image_paths_in_markdown(
     // Replace raw Typst calls: #fig("path" ...) and #image('path' ...)
     let re_raw_typst = Regex::new(r#"#(fig|image)\(\s*([\"'])([^\"']+)([\"'])"#)
         .expect("BUG: Invalid regex pattern for raw Typst calls");
-    let result = re_raw_typst.replace_all(&result, |caps: &regex::Captures| {
+    let (result, typst_edits) = edit_map::replace_all(&re_raw_typst, &result, |caps| {
         let func = caps.get(1).map(|m| m.as_str()).unwrap_or("fig");
         let quote = caps.get(2).map(|m| m.as_str()).unwrap_or("\"");
         let path = caps.get(3).map(|m| m.as_str()).unwrap_or("");
@@ -336,5 +339,5 @@ pub fn rewrite_image_paths_in_markdown(
         format!("#{}({}{}{}", func, quote, abs, quote)
     });
 
-    result.into_owned()
+    (result, md_edits.then(&html_edits).then(&typst_edits))
 }
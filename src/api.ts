@@ -52,6 +52,15 @@ export async function createFile(
   return invoke('create_file', { name, template, dirPath });
 }
 
+// Convert a Word document into a new Markdown file; images go to assets/
+export async function importDocx(
+  sourcePath: string,
+  name?: string,
+  dirPath?: string
+): Promise<string> {
+  return invoke('import_docx', { sourcePath, name, dirPath });
+}
+
 export async function deleteFile(path: string): Promise<void> {
   return invoke('delete_file', { path });
 }
@@ -203,10 +212,30 @@ export function renderTypst(content: string, format: string, currentFile?: strin
   return promise;
 }
 
+export async function closePreviewSession(filePath: string): Promise<void> {
+  await invoke('close_preview_session', { filePath });
+}
+
+/** True when a render was abandoned because a newer preview of the same document started. */
+export function isRenderCancelled(err: unknown): boolean {
+  return String(err).includes('Render cancelled');
+}
+
 export async function exportMarkdown(filePath: string): Promise<string> {
   return invoke('export_markdown', { filePath });
 }
 
+// Book projects: `projectPath` is a tideflow.toml/_quarto.yml manifest, its directory, or a
+// file inside the project
+export async function renderProject(projectPath: string): Promise<RenderedDocument> {
+  const raw = await invoke<BackendRenderedDocument>('render_project', { projectPath });
+  return normalizeRenderedDocument(raw);
+}
+
+export async function exportProject(projectPath: string): Promise<string> {
+  return invoke('export_project', { projectPath });
+}
+
 // Preferences operations
 interface BackendPreferences {
   theme_id?: string;
@@ -223,6 +252,11 @@ interface BackendPreferences {
   cover_image_width?: string;
   numberSections?: boolean; // backend serialized camelCase
   number_sections?: boolean; // tolerate snake just in case
+  figure_numbering?: string;
+  table_numbering?: string;
+  equation_numbering?: string;
+  bibliography?: string[];
+  csl?: string;
   default_image_width: string;
   default_image_alignment: string;
   fonts: { main: string; mono: string };
@@ -242,6 +276,10 @@ interface BackendPreferences {
   confirm_exit_on_unsaved?: boolean;
   // Optional typst_path forwarded from backend
   typst_path?: string;
+  typst_backend?: 'embedded' | 'external';
+  render_cache_max_mb?: number;
+  tikz_preamble?: string;
+  diagram_parallel_jobs?: number;
 }
 
 export async function getPreferences(): Promise<Preferences> {
@@ -258,6 +296,11 @@ export async function getPreferences(): Promise<Preferences> {
     cover_image: raw.cover_image ?? '',
     cover_image_width: raw.cover_image_width ?? '60%',
     number_sections: raw.numberSections ?? raw.number_sections ?? true,
+    figure_numbering: raw.figure_numbering ?? '1',
+    table_numbering: raw.table_numbering ?? '1',
+    equation_numbering: raw.equation_numbering ?? '(1)',
+    bibliography: raw.bibliography ?? [],
+    csl: raw.csl ?? 'ieee',
     default_image_width: raw.default_image_width,
     default_image_alignment: raw.default_image_alignment,
     fonts: raw.fonts,
@@ -277,6 +320,10 @@ export async function getPreferences(): Promise<Preferences> {
     confirm_exit_on_unsaved: raw.confirm_exit_on_unsaved ?? true,
     // Optional typst_path is forwarded from the backend
     typst_path: raw.typst_path ?? undefined,
+    typst_backend: raw.typst_backend ?? 'embedded',
+    render_cache_max_mb: raw.render_cache_max_mb ?? 256,
+    tikz_preamble: raw.tikz_preamble ?? '',
+    diagram_parallel_jobs: raw.diagram_parallel_jobs ?? 0,
   };
 }
 
@@ -295,6 +342,11 @@ export async function setPreferences(preferences: Preferences): Promise<void> {
     cover_image: preferences.cover_image,
     cover_image_width: preferences.cover_image_width,
     numberSections: preferences.number_sections, // Send as numberSections for Rust
+    figure_numbering: preferences.figure_numbering,
+    table_numbering: preferences.table_numbering,
+    equation_numbering: preferences.equation_numbering,
+    bibliography: preferences.bibliography,
+    csl: preferences.csl,
     default_image_width: preferences.default_image_width,
     default_image_alignment: preferences.default_image_alignment,
     fonts: preferences.fonts,
@@ -314,6 +366,10 @@ export async function setPreferences(preferences: Preferences): Promise<void> {
     confirm_exit_on_unsaved: preferences.confirm_exit_on_unsaved,
     // Forward typst_path to backend if present
     typst_path: preferences.typst_path,
+    typst_backend: preferences.typst_backend,
+    render_cache_max_mb: preferences.render_cache_max_mb,
+    tikz_preamble: preferences.tikz_preamble,
+    diagram_parallel_jobs: preferences.diagram_parallel_jobs,
   };
   await invoke('set_preferences', { preferences: outbound });
 }
@@ -326,6 +382,7 @@ export async function applyPreferences(): Promise<void> {
 export async function getCacheStats(): Promise<{
   cached_documents: number;
   cache_size_mb: number;
+  cache_size_bytes: number;
   cache_hits: number;
   cache_misses: number;
 }> {
@@ -482,3 +539,45 @@ export async function exportAsSvg(
     currentFile: currentFile || null
   });
 }
+
+// Export as a standalone HTML page; images are embedded unless inlineAssets is false,
+// in which case they are copied into a <name>_files folder next to the page
+export async function exportAsHtml(
+  content: string,
+  destination: string,
+  currentFile?: string | null,
+  inlineAssets = true
+): Promise<string> {
+  return invoke('export_as_html', {
+    content,
+    destination,
+    currentFile: currentFile || null,
+    inlineAssets
+  });
+}
+
+// Export as an EPUB 3 e-book with one chapter per H1/H2 section
+export async function exportAsEpub(
+  content: string,
+  destination: string,
+  currentFile?: string | null
+): Promise<string> {
+  return invoke('export_as_epub', {
+    content,
+    destination,
+    currentFile: currentFile || null
+  });
+}
+
+// Export as a Word document with styles from the document's preferences
+export async function exportAsDocx(
+  content: string,
+  destination: string,
+  currentFile?: string | null
+): Promise<string> {
+  return invoke('export_as_docx', {
+    content,
+    destination,
+    currentFile: currentFile || null
+  });
+}
@@ -196,10 +196,53 @@ const AdvancedTab: React.FC<AdvancedTabProps> = ({ local, mutate, setDirty, pend
             </div>
             <div className="helper-text">Milliseconds to wait before re-rendering PDF while typing</div>
           </label>
+          <label>Render Cache Size (MB)
+            <input
+              type="number"
+              min="0"
+              step="64"
+              value={local.render_cache_max_mb}
+              onChange={e => mutate({ render_cache_max_mb: Math.max(0, parseInt((e.target as HTMLInputElement).value || '0', 10)) })}
+            />
+            <div className="helper-text">Unchanged documents reuse their last PDF; 0 disables the cache</div>
+          </label>
+          <label>Parallel Diagram Jobs
+            <input
+              type="number"
+              min="0"
+              step="1"
+              value={local.diagram_parallel_jobs}
+              onChange={e => mutate({ diagram_parallel_jobs: Math.max(0, parseInt((e.target as HTMLInputElement).value || '0', 10)) })}
+            />
+            <div className="helper-text">Diagrams rendered at the same time; 0 uses one per CPU core</div>
+          </label>
+        </div>
+
+        <div className="design-section">
+          <h4>TikZ Diagrams</h4>
+          <label>Shared Preamble
+            <textarea
+              rows={4}
+              value={local.tikz_preamble}
+              onChange={e => mutate({ tikz_preamble: (e.target as HTMLTextAreaElement).value })}
+              placeholder="\usetikzlibrary{shapes.geometric}"
+            />
+            <div className="helper-text">Added to every diagram, before the project's tikz-preamble.tex. Fences can also load <code>preset=circuits</code> or <code>preset=pgfplots</code></div>
+          </label>
         </div>
 
         <div className="design-section">
           <h4>Typst Compiler</h4>
+          <label>Compiler
+            <select
+              value={local.typst_backend}
+              onChange={e => mutate({ typst_backend: (e.target as HTMLSelectElement).value as 'embedded' | 'external' })}
+            >
+              <option value="embedded">Built-in (fastest preview)</option>
+              <option value="external">Typst binary</option>
+            </select>
+            <div className="helper-text">The binary below is only used when "Typst binary" is selected</div>
+          </label>
           <label>
             Typst Binary Path (optional)
             <div className="typst-path-row">
@@ -23,6 +23,49 @@ const StructureTab: React.FC<StructureTabProps> = ({ local, mutate, handleBrowse
           <input type="checkbox" checked={local.number_sections} onChange={e => mutate({ number_sections: e.target.checked })} />
           <span>Number Sections</span>
         </label>
+        <div style={{ display: 'flex', gap: '16px' }}>
+          <label>Figure Numbering
+            <input
+              placeholder="1"
+              value={local.figure_numbering}
+              onChange={e => mutate({ figure_numbering: e.target.value })}
+            />
+          </label>
+          <label>Table Numbering
+            <input
+              placeholder="1"
+              value={local.table_numbering}
+              onChange={e => mutate({ table_numbering: e.target.value })}
+            />
+          </label>
+          <label>Equation Numbering
+            <input
+              placeholder="(1)"
+              value={local.equation_numbering}
+              onChange={e => mutate({ equation_numbering: e.target.value })}
+            />
+          </label>
+        </div>
+        <div className="helper-text">Typst numbering patterns for elements labelled with {'{#fig:…}'}, {'{#tbl:…}'} and {'{#eq:…}'} and referenced with @fig:…</div>
+        <div style={{ display: 'flex', gap: '16px' }}>
+          <label>Bibliography
+            <input
+              placeholder="references.bib"
+              value={local.bibliography.join(', ')}
+              onChange={e => mutate({
+                bibliography: e.target.value.split(',').map(path => path.trim()),
+              })}
+            />
+          </label>
+          <label>Citation Style
+            <input
+              placeholder="ieee"
+              value={local.csl}
+              onChange={e => mutate({ csl: e.target.value })}
+            />
+          </label>
+        </div>
+        <div className="helper-text">.bib or .yml files in the content folder, cited with [@key]; the style is a Typst style name (apa, chicago-author-date, …) or a .csl file</div>
         <div style={{ display: 'flex', alignItems: 'center', gap: '16px' }}>
           <label className="checkbox-label">
             <input type="checkbox" checked={local.toc} onChange={e => mutate({ toc: e.target.checked })} />
@@ -26,6 +26,7 @@ import { detectDocumentKind, isMarkdownFile, isLatexFile } from '../utils/docume
 import { listen } from '@tauri-apps/api/event';
 import { EditorSelection } from '@codemirror/state';
 import { EditorView } from 'codemirror';
+import { showCompileDiagnostics } from '../utils/compileDiagnostics';
 
 const Editor: React.FC = () => {
   // Store state
@@ -143,6 +144,14 @@ const Editor: React.FC = () => {
     setActiveAnchorId,
   });
 
+  // Underline the lines reported by the last compile (cleared by the next result)
+  const compileDiagnostics = useEditorStore((state) => state.editor.compileStatus.diagnostics);
+  useEffect(() => {
+    const view = editorStateRefs.editorViewRef.current;
+    if (!view) return;
+    showCompileDiagnostics(view, compileDiagnostics ?? []);
+  }, [compileDiagnostics, editorStateRefs.editorViewRef]);
+
   // Respond to cursor jump requests (e.g., PDF double-click)
   useEffect(() => {
     if (pendingCursorOffset == null) return;
@@ -18,7 +18,7 @@ const StatusBar: React.FC = () => {
     }
 
     if (compileStatus.status === 'running') {
-      return 'Rendering PDF...';
+      return compileStatus.message ?? 'Rendering PDF...';
     }
 
     if (compileStatus.status === 'error') {
@@ -1,7 +1,7 @@
 import React, { useCallback, useEffect, useRef } from 'react';
 import { useEditorStore } from '../stores/editorStore';
 import { useUIStore } from '../stores/uiStore';
-import { readMarkdownFile } from '../api';
+import { closePreviewSession, readMarkdownFile } from '../api';
 import { handleError } from '../utils/errorHandler';
 import './TabBar.css';
 import { INSTRUCTIONS_DOC } from '../instructionsDoc';
@@ -50,6 +50,7 @@ const TabBar: React.FC = () => {
   const handleCloseTab = (e: React.MouseEvent, filePath: string) => {
     e.stopPropagation();
     removeOpenFile(filePath);
+    closePreviewSession(filePath).catch(() => { /* best effort */ });
   };
 
   const openFilesRef = useRef(openFiles);
@@ -8,7 +8,7 @@ import Dropdown from './Dropdown';
 import { invoke } from '@tauri-apps/api/core';
 import { save, open } from '@tauri-apps/plugin-dialog';
 import { handleError, showSuccess } from '../utils/errorHandler';
-import { readMarkdownFile, createFile, writeMarkdownFile, exportAsPng, exportAsSvg } from '../api';
+import { readMarkdownFile, createFile, writeMarkdownFile, exportAsPng, exportAsSvg, exportAsHtml, exportAsEpub, exportAsDocx, importDocx } from '../api';
 import { scrubRawTypstAnchors } from '../utils/scrubAnchors';
 import { detectDocumentKind } from '../utils/document';
 import './Toolbar.css';
@@ -112,6 +112,28 @@ const Toolbar: React.FC = () => {
     }
   };
 
+  const handleImportDocx = async () => {
+    try {
+      const result = await open({
+        multiple: false,
+        filters: [{ name: 'Word Documents', extensions: ['docx'] }]
+      });
+      const sourcePath = Array.isArray(result) ? result?.[0] : result;
+      if (!sourcePath) return;
+
+      const filePath = await importDocx(sourcePath);
+      const content = await readMarkdownFile(filePath);
+      addOpenFile(filePath);
+      setCurrentFile(filePath);
+      setContent(content);
+      addRecentFile(filePath);
+      addToast({ type: 'success', message: 'Word document imported' });
+    } catch (err) {
+      addToast({ type: 'error', message: 'Failed to import Word document' });
+      handleError(err, { operation: 'import DOCX', component: 'Toolbar' });
+    }
+  };
+
   const handleSaveFile = async () => {
     const { currentFile, content, modified } = editor;
     if (!currentFile || !modified) return;
@@ -292,6 +314,72 @@ const Toolbar: React.FC = () => {
     }
   };
 
+  const handleExportHTML = async () => {
+    try {
+      let dest = await save({
+        title: 'Export as HTML',
+        filters: [{ name: 'HTML Page', extensions: ['html'] }],
+        defaultPath: 'document.html'
+      }).catch(() => null);
+
+      if (!dest) return;
+      if (!/\.html?$/i.test(dest)) dest = dest + '.html';
+
+      await exportAsHtml(editor.content, dest, editor.currentFile);
+
+      showSuccess(`Exported HTML: ${dest}`);
+      addToast({ type: 'success', message: 'HTML exported successfully!' });
+      setExportDropdownOpen(false);
+    } catch (err) {
+      addToast({ type: 'error', message: 'Failed to export HTML' });
+      handleError(err, { operation: 'export HTML', component: 'Toolbar' });
+    }
+  };
+
+  const handleExportEPUB = async () => {
+    try {
+      let dest = await save({
+        title: 'Export as EPUB',
+        filters: [{ name: 'EPUB E-book', extensions: ['epub'] }],
+        defaultPath: 'document.epub'
+      }).catch(() => null);
+
+      if (!dest) return;
+      if (!dest.toLowerCase().endsWith('.epub')) dest = dest + '.epub';
+
+      await exportAsEpub(editor.content, dest, editor.currentFile);
+
+      showSuccess(`Exported EPUB: ${dest}`);
+      addToast({ type: 'success', message: 'EPUB exported successfully!' });
+      setExportDropdownOpen(false);
+    } catch (err) {
+      addToast({ type: 'error', message: 'Failed to export EPUB' });
+      handleError(err, { operation: 'export EPUB', component: 'Toolbar' });
+    }
+  };
+
+  const handleExportDOCX = async () => {
+    try {
+      let dest = await save({
+        title: 'Export as Word',
+        filters: [{ name: 'Word Document', extensions: ['docx'] }],
+        defaultPath: 'document.docx'
+      }).catch(() => null);
+
+      if (!dest) return;
+      if (!dest.toLowerCase().endsWith('.docx')) dest = dest + '.docx';
+
+      await exportAsDocx(editor.content, dest, editor.currentFile);
+
+      showSuccess(`Exported Word document: ${dest}`);
+      addToast({ type: 'success', message: 'Word document exported successfully!' });
+      setExportDropdownOpen(false);
+    } catch (err) {
+      addToast({ type: 'error', message: 'Failed to export Word document' });
+      handleError(err, { operation: 'export DOCX', component: 'Toolbar' });
+    }
+  };
+
   return (
     <div className="toolbar">
       <input
@@ -371,6 +459,9 @@ const Toolbar: React.FC = () => {
           <button type="button" onClick={handleNewFile} title="New File (Ctrl+N)">
             📄 New
           </button>
+          <button type="button" onClick={handleImportDocx} title="Import a Word document as Markdown">
+            📥 Import Word
+          </button>
           <button
             type="button"
             onClick={closeAllFiles}
@@ -504,6 +595,30 @@ const Toolbar: React.FC = () => {
               >
                 🎨 Export as SVG
               </button>
+              <button
+                type="button"
+                className="dropdown-item"
+                onClick={handleExportHTML}
+                title="Export as a standalone HTML page"
+              >
+                🌐 Export as HTML
+              </button>
+              <button
+                type="button"
+                className="dropdown-item"
+                onClick={handleExportEPUB}
+                title="Export as an EPUB e-book"
+              >
+                📚 Export as EPUB
+              </button>
+              <button
+                type="button"
+                className="dropdown-item"
+                onClick={handleExportDOCX}
+                title="Export as a Word document"
+              >
+                📝 Export as Word
+              </button>
             </Dropdown>
           </div>
         </div>
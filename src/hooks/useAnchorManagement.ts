@@ -7,6 +7,7 @@ import { useEffect, useRef } from 'react';
 import { EditorView } from 'codemirror';
 import type { SourceMap } from '../types';
 import type { EditorStateRefs } from './useEditorState';
+import { anchorPosition } from '../utils/document';
 
 interface UseAnchorManagementParams {
   editorStateRefs: EditorStateRefs;
@@ -103,12 +104,14 @@ export function useAnchorManagement(params: UseAnchorManagementParams) {
       }
       return;
     }
+    // Anchors from included chapters scroll to their include directive
+    const position = anchorPosition(anchor);
 
     if (process.env.NODE_ENV !== 'production') {
       console.debug('[useAnchorManagement] scrolling editor to anchor', {
         activeAnchorId,
-        offset: anchor.editor.offset,
-        line: anchor.editor.line,
+        offset: position.offset,
+        line: position.line,
         syncMode: syncModeRef.current
       });
     }
@@ -118,7 +121,7 @@ export function useAnchorManagement(params: UseAnchorManagementParams) {
     lastScrollTimeRef.current = Date.now();
 
     // Use scrollIntoView with double RAF to prevent jarring jumps
-    const effect = EditorView.scrollIntoView(anchor.editor.offset, { y: 'center' });
+    const effect = EditorView.scrollIntoView(position.offset, { y: 'center' });
     view.dispatch({ effects: effect });
 
     // Clear programmatic flag after animation completes (double RAF for smoother transition)
@@ -164,11 +167,12 @@ export function useAnchorManagement(params: UseAnchorManagementParams) {
 
       if (oldAnchor) {
         // Find closest anchor in new map by line number
+        const oldLine = anchorPosition(oldAnchor).line;
         let closest = anchors[0];
-        let minDiff = Math.abs(anchors[0].editor.line - oldAnchor.editor.line);
+        let minDiff = Math.abs(anchorPosition(anchors[0]).line - oldLine);
 
         for (const anchor of anchors) {
-          const diff = Math.abs(anchor.editor.line - oldAnchor.editor.line);
+          const diff = Math.abs(anchorPosition(anchor).line - oldLine);
           if (diff < minDiff) {
             minDiff = diff;
             closest = anchor;
@@ -178,9 +182,9 @@ export function useAnchorManagement(params: UseAnchorManagementParams) {
         if (process.env.NODE_ENV !== 'production') {
           console.debug('[useAnchorManagement] anchor ID changed, preserving position', {
             oldId: activeAnchorId,
-            oldLine: oldAnchor.editor.line,
+            oldLine,
             newId: closest.id,
-            newLine: closest.editor.line,
+            newLine: anchorPosition(closest).line,
             diff: minDiff
           });
         }
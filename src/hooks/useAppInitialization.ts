@@ -13,7 +13,7 @@ import { loadSession } from '../utils/session';
 import { initErrorHandler } from '../utils/errorHandler';
 import { logger } from '../utils/logger';
 import { INSTRUCTIONS_DOC } from '../instructionsDoc';
-import type { BackendRenderedDocument, Preferences } from '../types';
+import type { BackendRenderedDocument, CompileErrorPayload, Preferences, RenderWarningsPayload, DiagramProgressPayload } from '../types';
 import { TIMING } from '../constants/timing';
 
 const initLogger = logger.createScoped('AppInit');
@@ -132,7 +132,10 @@ export function useAppInitialization() {
 
         // Register compiled event listener
         const unlistenCompiled = await listen<BackendRenderedDocument>('compiled', (evt) => {
-          const { pdf_path, source_map } = evt.payload;
+          const { pdf_path, source_map, timings } = evt.payload;
+          if (timings) {
+            initLogger.debug('Render timings', timings);
+          }
           const editorState = useEditorStore.getState();
           editorState.setCompileStatus({ status: 'ok', pdf_path, source_map });
           editorState.setSourceMap(source_map);
@@ -157,16 +160,56 @@ export function useAppInitialization() {
         register(unlistenCompiled);
 
         // Register compile error listener
-        const unlistenCompileError = await listen<string>('compile-error', (evt) => {
-          initLogger.error('Compile error', evt.payload);
+        const unlistenCompileError = await listen<CompileErrorPayload>('compile-error', (evt) => {
+          const { file, message, diagnostics } = evt.payload;
+          initLogger.error('Compile error', message);
           const editorState = useEditorStore.getState();
           const uiState = useUIStore.getState();
-          editorState.setCompileStatus({ status: 'error', message: 'Compile failed', details: evt.payload });
+          // Only underline diagnostics in the document that actually failed
+          const isCurrent = !file || file === editorState.editor.currentFile;
+          editorState.setCompileStatus({
+            status: 'error',
+            message: 'Compile failed',
+            details: message,
+            diagnostics: isCurrent ? diagnostics : undefined,
+          });
           editorState.setSourceMap(null);
           uiState.addToast({ type: 'error', message: 'Failed to compile document' });
         });
         register(unlistenCompileError);
 
+        // Register render warnings listener (e.g. unknown front matter keys). Toast only when
+        // a document's warnings change so live preview does not repeat the same toast.
+        const lastWarnings = new Map<string, string>();
+        const unlistenRenderWarnings = await listen<RenderWarningsPayload>('render-warnings', (evt) => {
+          const { file, warnings } = evt.payload;
+          initLogger.warn('Render warnings', { file, warnings });
+          const key = warnings.join('\n');
+          if (lastWarnings.get(file) === key) {
+            return;
+          }
+          lastWarnings.set(file, key);
+          useUIStore.getState().addToast({
+            type: 'warning',
+            message: warnings.length === 1 ? warnings[0] : `${warnings[0]} (+${warnings.length - 1} more)`,
+          });
+        });
+        register(unlistenRenderWarnings);
+
+        // Register TikZ progress listener so long diagram builds show how far along they are
+        const unlistenDiagramProgress = await listen<DiagramProgressPayload>('diagram-progress', (evt) => {
+          const { done, total } = evt.payload;
+          const editorState = useEditorStore.getState();
+          if (editorState.editor.compileStatus.status !== 'running') {
+            return;
+          }
+          editorState.setCompileStatus({
+            status: 'running',
+            message: done < total ? `Rendering diagrams (${done}/${total})...` : undefined,
+          });
+        });
+        register(unlistenDiagramProgress);
+
         // Register preferences dump listener (debug)
         const unlistenPrefsDump = await listen<string>('prefs-dump', (evt) => {
           try {
@@ -19,6 +19,7 @@ import type { DocumentKind } from '../types';
 import type { EditorStateRefs } from './useEditorState';
 import { logger } from '../utils/logger';
 import { stex } from '@codemirror/legacy-modes/mode/stex';
+import { compileDiagnosticsField, compileDiagnosticsTheme } from '../utils/compileDiagnostics';
 
 const useCodeMirrorSetupLogger = logger.createScoped('useCodeMirrorSetup');
 
@@ -246,6 +247,10 @@ export function useCodeMirrorSetup(params: UseCodeMirrorSetupParams) {
         // Custom state field for editor-specific state
         editorCustomState,
 
+        // Underlines for lines reported by the last compile
+        compileDiagnosticsField,
+        compileDiagnosticsTheme,
+
         // Search configuration (wrapped in compartment for future reconfiguration)
         searchConfigCompartment.of(search({
           top: true,
@@ -4,11 +4,12 @@
  */
 
 import { useCallback } from 'react';
-import { renderTypst, renderLatex, cleanupTempPdfs } from '../api';
+import { renderTypst, renderLatex, cleanupTempPdfs, isRenderCancelled } from '../api';
 import type { DocumentKind, SourceMap, SyncMode } from '../types';
 import type { EditorStateRefs } from './useEditorState';
 import { logger } from '../utils/logger';
 import { deriveRenderError } from '../utils/renderErrors';
+import { useEditorStore } from '../stores/editorStore';
 
 // Create scoped logger
 const useContentManagementLogger = logger.createScoped('useContentManagement');
@@ -62,7 +63,11 @@ export function useContentManagement(params: UseContentManagementParams) {
       }
       autoRenderInFlightRef.current = true;
       const wasSourceMapNull = !sourceMap;
-      setCompileStatus({ status: 'running' });
+      // Keep the previous diagnostics underlined until this render reports back
+      setCompileStatus({
+        status: 'running',
+        diagnostics: useEditorStore.getState().editor.compileStatus.diagnostics,
+      });
 
       const document = documentKind === 'latex'
         ? await renderLatex(content, currentFile)
@@ -96,11 +101,17 @@ export function useContentManagement(params: UseContentManagementParams) {
       if (signal?.aborted) {
         return;
       }
+      // Superseded by a newer render of this document; its result will update the status.
+      if (isRenderCancelled(err)) {
+        return;
+      }
       const friendly = deriveRenderError(err, 'Auto-render failed');
       setCompileStatus({
         status: 'error',
         message: friendly.message,
-        details: friendly.details
+        details: friendly.details,
+        // Keep the structured diagnostics delivered with the `compile-error` event
+        diagnostics: useEditorStore.getState().editor.compileStatus.diagnostics,
       });
       setSourceMap(null);
     } finally {
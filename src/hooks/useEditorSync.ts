@@ -8,7 +8,7 @@ import type { DocumentKind, SourceAnchor, SourceMap, SyncMode } from '../types';
 import { TIMING, ANCHOR } from '../constants/timing';
 import type { EditorStateRefs } from './useEditorState';
 import { logger } from '../utils/logger';
-import { findDocumentBodyRange } from '../utils/document';
+import { anchorPosition, findDocumentBodyRange } from '../utils/document';
 
 const useEditorSyncLogger = logger.createScoped('useEditorSync');
 
@@ -64,8 +64,8 @@ export function useEditorSync(params: UseEditorSyncParams) {
       }
       return;
     }
-    const map = sourceMapRef.current;
-    if (!map || map.anchors.length === 0) return;
+    const anchors = sourceMapRef.current?.anchors ?? [];
+    if (anchors.length === 0) return;
 
     const top = scrollEl.scrollTop;
     const bottom = top + scrollEl.clientHeight;
@@ -81,13 +81,14 @@ export function useEditorSync(params: UseEditorSyncParams) {
     let closestScore = Number.POSITIVE_INFINITY;
 
     // First pass: try to find anchor in or near viewport
-    for (const anchor of map.anchors) {
-      const isInViewport = anchor.editor.line >= topLine && anchor.editor.line <= bottomLine;
-      const isJustAfter = anchor.editor.line > bottomLine && anchor.editor.line <= bottomLine + ANCHOR.NEARBY_SEARCH_WINDOW;
-      const isJustBefore = anchor.editor.line < topLine && anchor.editor.line >= topLine - ANCHOR.NEARBY_SEARCH_WINDOW;
+    for (const anchor of anchors) {
+      const { line } = anchorPosition(anchor);
+      const isInViewport = line >= topLine && line <= bottomLine;
+      const isJustAfter = line > bottomLine && line <= bottomLine + ANCHOR.NEARBY_SEARCH_WINDOW;
+      const isJustBefore = line < topLine && line >= topLine - ANCHOR.NEARBY_SEARCH_WINDOW;
 
       if (isInViewport || isJustAfter || isJustBefore) {
-        const diff = Math.abs(anchor.editor.line - centerLine);
+        const diff = Math.abs(line - centerLine);
         let score = diff;
 
         // Slight preference for anchors in viewport vs just outside
@@ -104,8 +105,8 @@ export function useEditorSync(params: UseEditorSyncParams) {
 
     // If no nearby anchor found, use simple closest by absolute distance
     if (!closest) {
-      for (const anchor of map.anchors) {
-        const diff = Math.abs(anchor.editor.line - centerLine);
+      for (const anchor of anchors) {
+        const diff = Math.abs(anchorPosition(anchor).line - centerLine);
         if (diff < closestScore) {
           closest = anchor;
           closestScore = diff;
@@ -116,7 +117,7 @@ export function useEditorSync(params: UseEditorSyncParams) {
     if (!closest) return;
 
     if (process.env.NODE_ENV !== 'production') {
-      useEditorSyncLogger.debug('computed closest anchor', { id: closest.id, line: closest.editor.line, centerLine });
+      useEditorSyncLogger.debug('computed closest anchor', { id: closest.id, line: anchorPosition(closest).line, centerLine });
       useEditorSyncLogger.debug('syncModeRef, activeAnchorIdRef', { syncMode: syncModeRef.current, activeAnchor: activeAnchorIdRef.current });
     }
 
@@ -166,13 +167,13 @@ export function useEditorSync(params: UseEditorSyncParams) {
   const handleSelectionChange = useCallback((offset: number) => {
     const view = editorViewRef.current;
     if (!view) return;
-    const map = sourceMapRef.current;
+    const anchors = sourceMapRef.current?.anchors ?? [];
 
-    if (map && map.anchors.length > 0) {
-      let closest = map.anchors[0];
-      let bestDist = Math.abs(closest.editor.offset - offset);
-      for (const anchor of map.anchors) {
-        const dist = Math.abs(anchor.editor.offset - offset);
+    if (anchors.length > 0) {
+      let closest = anchors[0];
+      let bestDist = Math.abs(anchorPosition(closest).offset - offset);
+      for (const anchor of anchors) {
+        const dist = Math.abs(anchorPosition(anchor).offset - offset);
         if (dist < bestDist) {
           closest = anchor;
           bestDist = dist;
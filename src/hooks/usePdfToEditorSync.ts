@@ -7,7 +7,7 @@ import { useEffect } from 'react';
 import { TIMING } from '../constants/timing';
 import type { SourceMap, SyncMode } from '../types';
 import { useEditorStore } from '../stores/editorStore';
-import { findDocumentBodyRange } from '../utils/document';
+import { anchorPosition, findDocumentBodyRange } from '../utils/document';
 import { logger } from '../utils/logger';
 
 // Create scoped logger
@@ -251,7 +251,7 @@ export function usePdfToEditorSync(params: UsePdfToEditorSyncParams): void {
         if (!anchor) {
           return;
         }
-        requestCursorAt(anchor.editor.offset);
+        requestCursorAt(anchorPosition(anchor).offset);
         lastProgrammaticScrollAt.current = Date.now();
         setActiveAnchorId(anchorId);
         return;
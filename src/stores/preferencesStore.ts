@@ -47,6 +47,11 @@ export const defaultPreferences: Preferences = {
   cover_image: '',
   cover_image_width: '60%',
   number_sections: true,
+  figure_numbering: '1',
+  table_numbering: '1',
+  equation_numbering: '(1)',
+  bibliography: [],
+  csl: 'ieee',
   default_image_width: '80%',
   default_image_alignment: 'center',
   fonts: {
@@ -67,6 +72,10 @@ export const defaultPreferences: Preferences = {
   focused_preview_enabled: false,
   preserve_scroll_position: true,
   confirm_exit_on_unsaved: true,
+  typst_backend: 'embedded',
+  render_cache_max_mb: 256,
+  tikz_preamble: '',
+  diagram_parallel_jobs: 0,
 };
 
 // Preferences-specific store state
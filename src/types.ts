@@ -29,6 +29,11 @@ export interface Preferences {
   cover_image: string;
   cover_image_width: string;
   number_sections: boolean;
+  figure_numbering: string;
+  table_numbering: string;
+  equation_numbering: string;
+  bibliography: string[];
+  csl: string;
   default_image_width: string;
   default_image_alignment: string;
   fonts: Fonts;
@@ -49,6 +54,31 @@ export interface Preferences {
   confirm_exit_on_unsaved: boolean;
   // Optional explicit path to Typst binary (used as a final fallback)
   typst_path?: string;
+  // Typst compiler: in-process ('embedded') or the typst binary ('external')
+  typst_backend: 'embedded' | 'external';
+  // Render cache size limit in MB (0 disables the cache)
+  render_cache_max_mb: number;
+  tikz_preamble: string;
+  diagram_parallel_jobs: number;
+}
+
+export interface CompileDiagnostic {
+  severity: 'error' | 'warning';
+  message: string;
+  hints: string[];
+  /** Build file the compiler reported (template, package, ...). */
+  file: string | null;
+  line: number | null;
+  column: number | null;
+  /** 1-based position in the edited document, when the diagnostic points into it. */
+  source_line: number | null;
+  source_column: number | null;
+}
+
+export interface CompileErrorPayload {
+  file: string | null;
+  message: string;
+  diagnostics: CompileDiagnostic[];
 }
 
 export interface CompileStatus {
@@ -57,6 +87,7 @@ export interface CompileStatus {
   details?: string;
   pdf_path?: string;
   source_map?: SourceMap;
+  diagnostics?: CompileDiagnostic[];
 }
 
 export type ImageAlignment = 'left' | 'center' | 'right';
@@ -73,12 +104,19 @@ export type DocumentKind = 'markdown' | 'latex';
 
 export type SyncMode = 'auto' | 'two-way' | 'locked-to-pdf' | 'locked-to-editor';
 
-export interface EditorLocation {
+export interface DocumentPosition {
   offset: number;
   line: number;
   column: number;
 }
 
+export interface EditorLocation extends DocumentPosition {
+  /** Included file the anchor came from; absent for the open document itself. */
+  file?: string;
+  /** For anchors in an included file, the include directive in the open document. */
+  directive?: DocumentPosition;
+}
+
 export interface PdfAnchorPosition {
   page: number;
   x: number;
@@ -95,9 +133,32 @@ export interface SourceMap {
   anchors: SourceAnchor[];
 }
 
+export interface RenderTimings {
+  preprocess_ms: number;
+  tikz_ms: number;
+  compile_ms: number;
+  query_ms: number;
+  total_ms: number;
+  preprocess_cached: boolean;
+  cache_hit: boolean;
+}
+
 export interface BackendRenderedDocument {
   pdf_path: string;
   source_map: SourceMap;
+  timings?: RenderTimings;
+  warnings?: string[];
+}
+
+export interface RenderWarningsPayload {
+  file: string;
+  warnings: string[];
+}
+
+export interface DiagramProgressPayload {
+  done: number;
+  total: number;
+  id: string;
 }
 
 export interface RenderedDocument {
@@ -0,0 +1,53 @@
+/**
+ * CodeMirror extension that underlines lines reported by the last Typst compile.
+ * Diagnostics arrive with 1-based `source_line`/`source_column` positions already
+ * mapped back to the edited document by the backend.
+ */
+
+import { StateEffect, StateField, type Range } from '@codemirror/state';
+import { Decoration, EditorView, type DecorationSet } from '@codemirror/view';
+import type { CompileDiagnostic } from '../types';
+
+const setDiagnosticsEffect = StateEffect.define<CompileDiagnostic[]>();
+
+const errorLine = Decoration.line({ class: 'cm-compile-error-line' });
+const warningLine = Decoration.line({ class: 'cm-compile-warning-line' });
+
+export const compileDiagnosticsField = StateField.define<DecorationSet>({
+  create: () => Decoration.none,
+  update: (decorations, tr) => {
+    let next = decorations.map(tr.changes);
+    for (const effect of tr.effects) {
+      if (!effect.is(setDiagnosticsEffect)) continue;
+      const ranges: Range<Decoration>[] = [];
+      const seen = new Set<number>();
+      for (const diagnostic of effect.value) {
+        const lineNumber = diagnostic.source_line;
+        if (!lineNumber || lineNumber > tr.state.doc.lines || seen.has(lineNumber)) continue;
+        seen.add(lineNumber);
+        const line = tr.state.doc.line(lineNumber);
+        const decoration = diagnostic.severity === 'error' ? errorLine : warningLine;
+        ranges.push(decoration.range(line.from));
+      }
+      next = Decoration.set(ranges, true);
+    }
+    return next;
+  },
+  provide: (field) => EditorView.decorations.from(field),
+});
+
+export const compileDiagnosticsTheme = EditorView.theme({
+  '.cm-compile-error-line': {
+    textDecoration: 'underline wavy #dc2626',
+    textUnderlineOffset: '3px',
+  },
+  '.cm-compile-warning-line': {
+    textDecoration: 'underline wavy #d97706',
+    textUnderlineOffset: '3px',
+  },
+});
+
+/** Replace the underlined lines with those of `diagnostics` (empty clears them). */
+export function showCompileDiagnostics(view: EditorView, diagnostics: CompileDiagnostic[]): void {
+  view.dispatch({ effects: setDiagnosticsEffect.of(diagnostics) });
+}
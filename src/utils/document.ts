@@ -1,4 +1,4 @@
-import type { DocumentKind } from '../types';
+import type { DocumentKind, DocumentPosition, SourceAnchor } from '../types';
 
 const markdownExtensions = new Set(['md', 'markdown', 'qmd']);
 const latexExtensions = new Set(['tex', 'latex']);
@@ -35,3 +35,8 @@ export function findDocumentBodyRange(content: string): { start: number; end: nu
     end: Math.max(start, Math.min(end, content.length)),
   };
 }
+
+/** Where an anchor is in the open document: anchors from included files sit at their include directive. */
+export function anchorPosition(anchor: SourceAnchor): DocumentPosition {
+  return anchor.editor.directive ?? anchor.editor;
+}